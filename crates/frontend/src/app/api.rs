@@ -1,4 +1,4 @@
-use inference_types::{BulkTestEvent, InferenceEvent, StepCandidates};
+use inference_types::{BulkTestEvent, InferenceEvent, StepCandidates, StepThroughState};
 use leptos::prelude::*;
 use wasm_bindgen::{JsCast, closure::Closure};
 use web_sys::{EventSource, MessageEvent};
@@ -31,10 +31,19 @@ pub async fn fetch_system_prompt(agent_id: i32) -> Result<String, String> {
     resp.text().await.map_err(|e| e.to_string())
 }
 
-/// POST /infer — creates a session for the given agent and prompt.
-/// Returns the session_id string on success.
-pub async fn start_inference(prompt: String, agent_id: i32) -> Result<String, String> {
-    let body = serde_json::json!({ "prompt": prompt, "agent_id": agent_id });
+/// POST /infer — creates a session for the given agent and prompt, as the next
+/// turn of `conversation_id` (or of a new conversation when `None`).
+/// Returns `(session_id, conversation_id)` on success.
+pub async fn start_inference(
+    prompt: String,
+    agent_id: i32,
+    conversation_id: Option<String>,
+) -> Result<(String, String), String> {
+    let body = serde_json::json!({
+        "prompt": prompt,
+        "agent_id": agent_id,
+        "conversation_id": conversation_id,
+    });
     let resp = gloo_net::http::Request::post("/infer")
         .header("Content-Type", "application/json")
         .body(body.to_string())
@@ -48,10 +57,84 @@ pub async fn start_inference(prompt: String, agent_id: i32) -> Result<String, St
     }
 
     let json: serde_json::Value = resp.json().await.map_err(|e| e.to_string())?;
-    json["session_id"]
+    let session_id = json["session_id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| "missing session_id in response".to_string())?;
+    let conversation_id = json["conversation_id"]
         .as_str()
         .map(str::to_string)
-        .ok_or_else(|| "missing session_id in response".to_string())
+        .ok_or_else(|| "missing conversation_id in response".to_string())?;
+    Ok((session_id, conversation_id))
+}
+
+/// POST /step — starts a step-through session for the given agent and prompt.
+/// Returns `(session_id, initial_state)` on success.
+pub async fn start_step_session(
+    prompt: String,
+    agent_id: i32,
+) -> Result<(String, StepThroughState), String> {
+    #[derive(serde::Deserialize)]
+    struct StartStepResponse {
+        session_id: String,
+        state: StepThroughState,
+    }
+
+    let body = serde_json::json!({ "prompt": prompt, "agent_id": agent_id });
+    let resp = gloo_net::http::Request::post("/step")
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .map_err(|e| e.to_string())?
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+
+    let r = resp
+        .json::<StartStepResponse>()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((r.session_id, r.state))
+}
+
+/// POST /step/:session_id/advance — commits the next critical point.
+/// `token_id` forces a specific constrained candidate instead of the argmax.
+pub async fn advance_step_session(
+    session_id: &str,
+    token_id: Option<u32>,
+) -> Result<StepThroughState, String> {
+    let body = serde_json::json!({ "token_id": token_id });
+    let resp = gloo_net::http::Request::post(&format!("/step/{session_id}/advance"))
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .map_err(|e| e.to_string())?
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+
+    resp.json::<StepThroughState>()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// DELETE /step/:session_id — abandons a step-through session.
+pub async fn close_step_session(session_id: &str) -> Result<(), String> {
+    let resp = gloo_net::http::Request::delete(&format!("/step/{session_id}"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    Ok(())
 }
 
 /// Opens an SSE connection to GET /infer/stream/:session_id.
@@ -91,6 +174,11 @@ pub fn open_sse_stream(
                 set_streaming.set(false);
                 es_done.close();
             }
+            Ok(InferenceEvent::Cancelled) => {
+                set_status.set("Cancelled".to_string());
+                set_streaming.set(false);
+                es_done.close();
+            }
             Err(_) => {} // ignore unparseable frames
         }
     });
@@ -111,6 +199,18 @@ pub fn open_sse_stream(
     std::mem::forget(es);
 }
 
+/// DELETE /infer/{session_id} — stops a running generation.
+pub async fn cancel_inference(session_id: &str) -> Result<(), String> {
+    let resp = gloo_net::http::Request::delete(&format!("/infer/{session_id}"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    Ok(())
+}
+
 /// Summary of one stored bulk test run.
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct BulkTestRunSummary {
@@ -118,8 +218,87 @@ pub struct BulkTestRunSummary {
     pub agent_id: i64,
     pub started_at: String,
     pub completed_at: Option<String>,
+    /// `running`, `complete` or `cancelled`.
+    pub status: String,
     pub total: Option<i64>,
     pub success_count: Option<i64>,
+    /// Local validation set the run used; `None` for the marketing DB examples.
+    #[serde(default)]
+    pub validation_set_id: Option<i64>,
+    /// Whether the run can be re-run with its recorded configuration.
+    #[serde(default)]
+    pub has_config: bool,
+}
+
+/// One imported validation set.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ValidationSetSummary {
+    pub id: i64,
+    pub name: String,
+    pub example_count: i64,
+}
+
+/// GET /agents/:agent_id/validation-sets — the agent's imported validation sets.
+pub async fn fetch_validation_sets(agent_id: i32) -> Result<Vec<ValidationSetSummary>, String> {
+    let resp = gloo_net::http::Request::get(&format!("/agents/{agent_id}/validation-sets"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json::<Vec<ValidationSetSummary>>()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// One stored version of an agent's kappa values, offsets and temperature.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct WeightSetSummary {
+    pub id: i64,
+    pub version: i64,
+    pub name: String,
+    pub logit_temperature: f64,
+    pub created_at: String,
+    pub active: bool,
+}
+
+/// GET /agents/:agent_id/weight-sets — the agent's weight sets, newest first.
+pub async fn fetch_weight_sets(agent_id: i32) -> Result<Vec<WeightSetSummary>, String> {
+    let resp = gloo_net::http::Request::get(&format!("/agents/{agent_id}/weight-sets"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json::<Vec<WeightSetSummary>>()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// POST /weight-sets/:set_id/activate — make a weight set the live one.
+pub async fn activate_weight_set(set_id: i64) -> Result<WeightSetSummary, String> {
+    let resp = gloo_net::http::Request::post(&format!("/weight-sets/{set_id}/activate"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json::<WeightSetSummary>().await.map_err(|e| e.to_string())
+}
+
+/// POST /agents/:agent_id/weight-sets/rollback — re-activate the previous version.
+pub async fn rollback_weight_set(agent_id: i32) -> Result<WeightSetSummary, String> {
+    let resp = gloo_net::http::Request::post(&format!("/agents/{agent_id}/weight-sets/rollback"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json::<WeightSetSummary>().await.map_err(|e| e.to_string())
 }
 
 /// GET /bulk-tests — list the 50 most recent runs.
@@ -167,10 +346,135 @@ pub async fn fetch_bulk_test_run(run_id: i64) -> Result<Vec<TestResult>, String>
         .collect())
 }
 
-/// POST /bulk-test — creates a bulk test run for the given agent.
+/// GET /bulk-tests/{run_id}/metrics — confusion matrix and per-category
+/// metrics of a run.
+pub async fn fetch_bulk_test_metrics(run_id: i64) -> Result<RunMetrics, String> {
+    let resp = gloo_net::http::Request::get(&format!("/bulk-tests/{run_id}/metrics"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json::<RunMetrics>().await.map_err(|e| e.to_string())
+}
+
+/// Response from GET /bulk-tests/{run_id}/metrics.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct RunMetrics {
+    /// Of the examples that chose a category.
+    pub accuracy: Accuracy,
+    /// Examples that finished without choosing a category.
+    pub unclassified: usize,
+    pub confusion: ConfusionMatrix,
+    pub categories: Vec<CategoryMetrics>,
+    pub top_k: Vec<TopKAccuracy>,
+    pub confused_pairs: Vec<ConfusedPair>,
+}
+
+/// Counts of (actual, predicted) category pairs.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ConfusionMatrix {
+    pub categories: Vec<String>,
+    /// `counts[actual][predicted]`, indexed like `categories`.
+    pub counts: Vec<Vec<usize>>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct CategoryMetrics {
+    pub category_name: String,
+    /// Examples whose actual category this is.
+    pub support: usize,
+    /// `None` when the category was never predicted.
+    pub precision: Option<f64>,
+    /// `None` when the category never was the actual one.
+    pub recall: Option<f64>,
+    pub f1: Option<f64>,
+}
+
+/// Share of examples with a correct category among the `k` best candidates.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct TopKAccuracy {
+    pub k: usize,
+    pub accuracy: Accuracy,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ConfusedPair {
+    pub actual: String,
+    pub predicted: String,
+    pub count: usize,
+}
+
+/// GET /bulk-tests/compare — compares run `b` against run `a` on their
+/// shared examples.
+pub async fn compare_bulk_test_runs(a: i64, b: i64) -> Result<RunComparison, String> {
+    let resp = gloo_net::http::Request::get(&format!("/bulk-tests/compare?a={a}&b={b}"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json::<RunComparison>().await.map_err(|e| e.to_string())
+}
+
+/// Response from GET /bulk-tests/compare.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct RunComparison {
+    /// Examples present in both runs.
+    pub shared: usize,
+    pub only_in_a: usize,
+    pub only_in_b: usize,
+    /// Accuracy of each run on the shared examples.
+    pub a: Accuracy,
+    pub b: Accuracy,
+    /// Right in A, wrong in B.
+    pub regressions: Vec<ExampleChange>,
+    /// Wrong in A, right in B.
+    pub fixes: Vec<ExampleChange>,
+    pub mcnemar: McNemar,
+    pub categories: Vec<CategoryDelta>,
+}
+
+/// An example whose correctness differs between two runs.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ExampleChange {
+    pub example_id: i64,
+    pub example_text: String,
+    pub correct_categories: Vec<String>,
+    pub chosen_a: Option<String>,
+    pub chosen_b: Option<String>,
+}
+
+/// McNemar's test on the examples the two runs disagree on.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+pub struct McNemar {
+    pub chi_squared: f64,
+    pub p_value: f64,
+}
+
+/// Accuracy of both runs on one category's shared examples.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct CategoryDelta {
+    pub category_name: String,
+    pub a: Accuracy,
+    pub b: Accuracy,
+    pub regressions: usize,
+    pub fixes: usize,
+}
+
+/// POST /bulk-test — creates a bulk test run for the given agent, against
+/// `validation_set_id` or the marketing DB examples when `None`.
 /// Returns `(bulk_test_id, run_id)` on success.
-pub async fn start_bulk_test(agent_id: i32) -> Result<(String, i64), String> {
-    let body = serde_json::json!({ "agent_id": agent_id });
+pub async fn start_bulk_test(
+    agent_id: i32,
+    validation_set_id: Option<i64>,
+) -> Result<(String, i64), String> {
+    let body = serde_json::json!({
+        "agent_id": agent_id,
+        "validation_set_id": validation_set_id,
+    });
     let resp = gloo_net::http::Request::post("/bulk-test")
         .header("Content-Type", "application/json")
         .body(body.to_string())
@@ -182,7 +486,11 @@ pub async fn start_bulk_test(agent_id: i32) -> Result<(String, i64), String> {
     if !resp.ok() {
         return Err(format!("HTTP {}", resp.status()));
     }
+    bulk_test_started(resp).await
+}
 
+/// `(bulk_test_id, run_id)` from the response of a request that started a run.
+async fn bulk_test_started(resp: gloo_net::http::Response) -> Result<(String, i64), String> {
     let json: serde_json::Value = resp.json().await.map_err(|e| e.to_string())?;
     let bulk_test_id = json["bulk_test_id"]
         .as_str()
@@ -194,6 +502,38 @@ pub async fn start_bulk_test(agent_id: i32) -> Result<(String, i64), String> {
     Ok((bulk_test_id, run_id))
 }
 
+/// POST /bulk-tests/{run_id}/rerun — starts a new bulk test with a past run's
+/// recorded configuration. Returns `(bulk_test_id, run_id)` of the new run.
+pub async fn rerun_bulk_test(run_id: i64) -> Result<(String, i64), String> {
+    let resp = gloo_net::http::Request::post(&format!("/bulk-tests/{run_id}/rerun"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if resp.status() == 409 {
+        return Err(format!(
+            "run {run_id} cannot be replayed exactly — the model or server settings have changed \
+             (see GET /bulk-tests/{run_id}/config)"
+        ));
+    }
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    bulk_test_started(resp).await
+}
+
+/// DELETE /bulk-test/{bulk_test_id} — stops a running bulk test. Results
+/// already streamed are kept.
+pub async fn cancel_bulk_test(bulk_test_id: &str) -> Result<(), String> {
+    let resp = gloo_net::http::Request::delete(&format!("/bulk-test/{bulk_test_id}"))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    Ok(())
+}
+
 /// A single completed bulk test result (extracted from BulkTestEvent::Result).
 #[derive(Clone, Debug)]
 pub struct TestResult {
@@ -205,12 +545,19 @@ pub struct TestResult {
     pub steps: Vec<StepCandidates>,
 }
 
-/// POST /bulk-tests/{run_id}/apply-weights — saves per-category kappa values to the DB.
+/// POST /bulk-tests/{run_id}/apply-weights — saves per-category kappa values,
+/// logit offsets and the agent's logit temperature to the DB.
 pub async fn apply_weights(
     run_id: i64,
     weights: &std::collections::HashMap<String, f64>,
+    offsets: &std::collections::HashMap<String, f64>,
+    temperature: Option<f64>,
 ) -> Result<ApplyWeightsResponse, String> {
-    let body = serde_json::json!({ "weights": weights });
+    let body = serde_json::json!({
+        "weights": weights,
+        "offsets": offsets,
+        "temperature": temperature,
+    });
     let resp = gloo_net::http::Request::post(&format!("/bulk-tests/{run_id}/apply-weights"))
         .header("Content-Type", "application/json")
         .body(body.to_string())
@@ -229,11 +576,45 @@ pub async fn apply_weights(
 pub struct ApplyWeightsResponse {
     pub updated: usize,
     pub unmatched_categories: Vec<String>,
+    /// Version number of the weight set the values were saved as.
+    pub version: i64,
+}
+
+/// Settings for POST /bulk-tests/{run_id}/optimize.
+#[derive(Clone, Debug)]
+pub struct OptimizeOptions {
+    /// Fraction of the examples held out from the fit.
+    pub test_fraction: f64,
+    /// k-fold cross-validation over the rest, if set.
+    pub folds: Option<usize>,
+    /// `cross_entropy`, `hinge` or `coordinate_search`.
+    pub objective: String,
+    pub allow_negative: bool,
+    /// Also fit per-category logit offsets.
+    pub learn_offsets: bool,
+    /// Also fit a global logit temperature.
+    pub learn_temperature: bool,
 }
 
 /// POST /bulk-tests/{run_id}/optimize — returns optimal per-category weights.
-pub async fn optimize_weights(run_id: i64) -> Result<OptimizeResponse, String> {
+pub async fn optimize_weights(
+    run_id: i64,
+    options: &OptimizeOptions,
+) -> Result<OptimizeResponse, String> {
+    let body = serde_json::json!({
+        "test_fraction": options.test_fraction,
+        "folds": options.folds,
+        "optimizer": {
+            "objective": options.objective,
+            "allow_negative": options.allow_negative,
+            "learn_offsets": options.learn_offsets,
+            "learn_temperature": options.learn_temperature,
+        },
+    });
     let resp = gloo_net::http::Request::post(&format!("/bulk-tests/{run_id}/optimize"))
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .map_err(|e| e.to_string())?
         .send()
         .await
         .map_err(|e| e.to_string())?;
@@ -243,12 +624,85 @@ pub async fn optimize_weights(run_id: i64) -> Result<OptimizeResponse, String> {
     resp.json::<OptimizeResponse>().await.map_err(|e| e.to_string())
 }
 
+/// POST /bulk-tests/{run_id}/rescore — re-ranks the run's recorded
+/// categories under candidate weights, without running the model.
+pub async fn rescore_run(
+    run_id: i64,
+    weights: &std::collections::HashMap<String, f64>,
+    offsets: &std::collections::HashMap<String, f64>,
+    fallback_kappa: f64,
+    temperature: f64,
+) -> Result<RescoreResponse, String> {
+    let body = serde_json::json!({
+        "weights": weights,
+        "offsets": offsets,
+        "fallback_kappa": fallback_kappa,
+        "temperature": temperature,
+    });
+    let resp = gloo_net::http::Request::post(&format!("/bulk-tests/{run_id}/rescore"))
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .map_err(|e| e.to_string())?
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.ok() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json::<RescoreResponse>().await.map_err(|e| e.to_string())
+}
+
+/// Response from POST /bulk-tests/{run_id}/rescore (the summary fields; the
+/// per-example choices and confusion matrix are not shown here).
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct RescoreResponse {
+    pub rescored_accuracy: Accuracy,
+    /// Examples that were wrong and are now right.
+    pub fixed: Vec<i64>,
+    /// Examples that were right and are now wrong.
+    pub broken: Vec<i64>,
+}
+
+/// Correct classifications out of the examples scored.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+pub struct Accuracy {
+    pub correct: usize,
+    pub total: usize,
+}
+
+/// One cross-validation fold: fitted on the other folds, scored on this one.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct HeldOutScore {
+    pub held_out: Accuracy,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct CrossValidation {
+    pub folds: Vec<HeldOutScore>,
+    pub mean_accuracy: f64,
+    pub std_accuracy: f64,
+}
+
 /// Response from POST /bulk-tests/{run_id}/optimize.
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct OptimizeResponse {
     pub weights: std::collections::HashMap<String, f64>,
+    #[serde(default)]
+    pub offsets: std::collections::HashMap<String, f64>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
     pub examples_used: usize,
     pub examples_skipped: usize,
+    pub test_accuracy: Option<Accuracy>,
+    pub cross_validation: Option<CrossValidation>,
+    /// Objective value over the fit's iterations.
+    pub loss_curve: Vec<LossPoint>,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+pub struct LossPoint {
+    pub iteration: usize,
+    pub loss: f64,
 }
 
 /// Opens an SSE connection to GET /bulk-test/stream/:bulk_test_id.
@@ -315,6 +769,16 @@ pub fn open_bulk_test_stream(
                 set_running.set(false);
                 es_done.close();
             }
+            Ok(BulkTestEvent::Cancelled {
+                completed,
+                success_count,
+            }) => {
+                set_status.set(format!(
+                    "Cancelled after {completed} examples — {success_count} correct"
+                ));
+                set_running.set(false);
+                es_done.close();
+            }
             Err(_) => {}
         }
     });
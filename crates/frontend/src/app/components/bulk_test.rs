@@ -1,53 +1,263 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 
 use inference_types::StepCandidates;
 use leptos::ev;
 use leptos::prelude::*;
 
-use crate::app::api::{self, BulkTestRunSummary, OptimizeResponse, TestResult};
+use crate::app::api::{
+    self, BulkTestRunSummary, OptimizeResponse, TestResult, ValidationSetSummary, WeightSetSummary,
+};
 use crate::app::components::{AgentSelector, CandidatePanel, TokenStreamView};
 
-/// Estimate how many examples would be classified correctly if each category's
-/// kappa were replaced with the values in `kappas`.
-///
-/// For categories absent from `kappas`, `fallback_kappa` is used (pass `10.0`
-/// to simulate the original default, or `0.0` for logit-only).
-///
-/// Returns `(correct, total)`.  Examples with no `category_top_tokens` in any
-/// step are skipped and not counted in `total`.
-fn simulate_accuracy(
-    results: &[TestResult],
-    kappas: &HashMap<String, f64>,
-    fallback_kappa: f64,
-) -> (usize, usize) {
-    let mut correct = 0usize;
-    let mut total = 0usize;
-
-    for result in results {
-        // Find the first step that has category_top_tokens populated.
-        let Some(step) = result.steps.iter().find(|s| !s.category_top_tokens.is_empty()) else {
-            continue;
-        };
+/// A request that starts a bulk test, resolving to `(bulk_test_id, run_id)`.
+type RunStart = Pin<Box<dyn Future<Output = Result<(String, i64), String>>>>;
 
-        total += 1;
+/// Accuracy of a run re-scored by the server under the two baselines and
+/// the optimised weights.
+#[derive(Clone)]
+struct Simulations {
+    /// Logit only (kappa = 0 for all).
+    logit_only: api::RescoreResponse,
+    /// The original kappa = 10.
+    default: api::RescoreResponse,
+    optimised: api::RescoreResponse,
+}
 
-        // Pick the category with the highest simulated total score.
-        let best = step.category_top_tokens.iter().max_by(|a, b| {
-            let kappa_a = kappas.get(&a.category_name).copied().unwrap_or(fallback_kappa);
-            let kappa_b = kappas.get(&b.category_name).copied().unwrap_or(fallback_kappa);
-            let score_a = a.best_token.logit as f64 + kappa_a * a.sim_score as f64;
-            let score_b = b.best_token.logit as f64 + kappa_b * b.sim_score as f64;
-            score_a.partial_cmp(&score_b).unwrap_or(std::cmp::Ordering::Equal)
-        });
+async fn simulate(run_id: i64, resp: &OptimizeResponse) -> Result<Simulations, String> {
+    let empty = HashMap::new();
+    Ok(Simulations {
+        logit_only: api::rescore_run(run_id, &empty, &empty, 0.0, 1.0).await?,
+        default: api::rescore_run(run_id, &empty, &empty, 10.0, 1.0).await?,
+        optimised: api::rescore_run(
+            run_id,
+            &resp.weights,
+            &resp.offsets,
+            10.0,
+            resp.temperature.unwrap_or(1.0),
+        )
+        .await?,
+    })
+}
 
-        if let Some(token) = best {
-            if result.correct_categories.contains(&token.category_name) {
-                correct += 1;
-            }
+/// Run metrics: top-k accuracy, per-category precision / recall / F1 (worst
+/// F1 first), the most-confused pairs and the confusion matrix.
+fn metrics_view(m: api::RunMetrics) -> impl IntoView {
+    let fmt_pct = |v: Option<f64>| v.map_or_else(|| "—".into(), |v| format!("{:.0}%", v * 100.0));
+    let fmt_acc = |a: api::Accuracy| {
+        if a.total == 0 {
+            "—".to_string()
+        } else {
+            format!("{}/{} ({:.0}%)", a.correct, a.total, a.correct as f64 / a.total as f64 * 100.0)
         }
+    };
+    let mut categories = m.categories;
+    categories.sort_by(|a, b| a.f1.unwrap_or(0.0).total_cmp(&b.f1.unwrap_or(0.0)));
+    let summary = std::iter::once(format!("Accuracy {}", fmt_acc(m.accuracy)))
+        .chain(m.top_k.iter().map(|t| format!("top-{} {}", t.k, fmt_acc(t.accuracy))))
+        .chain((m.unclassified > 0).then(|| format!("{} without a category", m.unclassified)))
+        .collect::<Vec<_>>()
+        .join(" · ");
+    let matrix = m.confusion;
+    view! {
+        <div style="margin-top:0.5rem; font-size:0.82rem;">
+            <p style="color:#aaa; margin:0 0 0.5rem;">{summary}</p>
+            <table style="border-collapse:collapse; margin-bottom:0.75rem;">
+                <thead>
+                    <tr style="background:#1e1e1e;">
+                        <th style="text-align:left; padding:3px 10px">"Category"</th>
+                        <th style="text-align:right; padding:3px 10px">"Support"</th>
+                        <th style="text-align:right; padding:3px 10px">"Precision"</th>
+                        <th style="text-align:right; padding:3px 10px">"Recall"</th>
+                        <th style="text-align:right; padding:3px 10px">"F1"</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    {categories.into_iter().map(|c| {
+                        let f1_color = match c.f1 {
+                            Some(f) if f < 0.5 => "#f44336",
+                            Some(f) if f < 0.8 => "#ff9800",
+                            Some(_) => "#4caf50",
+                            None => "#aaa",
+                        };
+                        view! {
+                            <tr style="border-bottom:1px solid #2a2a2a;">
+                                <td style="padding:3px 10px; font-family:monospace;">{c.category_name}</td>
+                                <td style="padding:3px 10px; text-align:right; font-family:monospace;">{c.support}</td>
+                                <td style="padding:3px 10px; text-align:right; font-family:monospace;">{fmt_pct(c.precision)}</td>
+                                <td style="padding:3px 10px; text-align:right; font-family:monospace;">{fmt_pct(c.recall)}</td>
+                                <td style=format!("padding:3px 10px; text-align:right; font-family:monospace; color:{f1_color};")>
+                                    {fmt_pct(c.f1)}
+                                </td>
+                            </tr>
+                        }
+                    }).collect_view()}
+                </tbody>
+            </table>
+            {(!m.confused_pairs.is_empty()).then(|| view! {
+                <p style="color:#aaa; margin:0 0 0.25rem;">"Most confused (actual → chosen)"</p>
+                <ul style="margin:0 0 0.75rem; padding-left:1.2rem; font-family:monospace;">
+                    {m.confused_pairs.into_iter().map(|p| view! {
+                        <li>{format!("{} → {} ×{}", p.actual, p.predicted, p.count)}</li>
+                    }).collect_view()}
+                </ul>
+            })}
+            <details>
+                <summary style="cursor:pointer; color:#aaa; user-select:none;">
+                    "Confusion matrix (rows actual, columns chosen)"
+                </summary>
+                <table style="border-collapse:collapse; margin-top:0.4rem; font-family:monospace;">
+                    <thead>
+                        <tr style="background:#1e1e1e;">
+                            <th style="padding:2px 6px"></th>
+                            {matrix.categories.iter().enumerate().map(|(i, name)| view! {
+                                <th style="padding:2px 6px; text-align:right;" title=name.clone()>{i + 1}</th>
+                            }).collect_view()}
+                        </tr>
+                    </thead>
+                    <tbody>
+                        {matrix.categories.iter().zip(&matrix.counts).enumerate().map(|(i, (name, row))| view! {
+                            <tr style="border-bottom:1px solid #2a2a2a;">
+                                <td style="padding:2px 6px; text-align:left;">{format!("{} {name}", i + 1)}</td>
+                                {row.iter().enumerate().map(|(j, &count)| {
+                                    let color = match count {
+                                        0 => "#444",
+                                        _ if i == j => "#4caf50",
+                                        _ => "#f44336",
+                                    };
+                                    view! {
+                                        <td style=format!("padding:2px 6px; text-align:right; color:{color};")>{count}</td>
+                                    }
+                                }).collect_view()}
+                            </tr>
+                        }).collect_view()}
+                    </tbody>
+                </table>
+            </details>
+        </div>
     }
+}
 
-    (correct, total)
+/// Diff of run B against run A: accuracy on the shared examples with
+/// McNemar's test, per-category deltas and the regressed and fixed examples.
+fn comparison_view(a_id: i64, b_id: i64, c: api::RunComparison) -> impl IntoView {
+    let fmt_acc = |a: api::Accuracy| {
+        if a.total == 0 {
+            "—".to_string()
+        } else {
+            format!("{}/{} ({:.0}%)", a.correct, a.total, a.correct as f64 / a.total as f64 * 100.0)
+        }
+    };
+    let rate = |a: api::Accuracy| if a.total == 0 { 0.0 } else { a.correct as f64 / a.total as f64 };
+    let significance = if c.mcnemar.p_value < 0.05 { "significant" } else { "not significant" };
+    let summary = format!(
+        "{} shared examples ({} only in A, {} only in B). A: {} → B: {}. \
+         McNemar χ² = {:.2}, p = {:.3} ({significance} at 5%).",
+        c.shared,
+        c.only_in_a,
+        c.only_in_b,
+        fmt_acc(c.a),
+        fmt_acc(c.b),
+        c.mcnemar.chi_squared,
+        c.mcnemar.p_value,
+    );
+    let changes_view = |title: String, color: &'static str, changes: Vec<api::ExampleChange>| {
+        (!changes.is_empty()).then(|| view! {
+            <p style=format!("color:{color}; margin:0.5rem 0 0.25rem;")>{title}</p>
+            <table style="border-collapse:collapse; margin-bottom:0.5rem;">
+                <thead>
+                    <tr style="background:#1e1e1e;">
+                        <th style="text-align:left; padding:3px 8px">"Example"</th>
+                        <th style="text-align:left; padding:3px 8px">"Correct"</th>
+                        <th style="text-align:left; padding:3px 8px">"A chose"</th>
+                        <th style="text-align:left; padding:3px 8px">"B chose"</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    {changes.into_iter().map(|e| view! {
+                        <tr style="border-bottom:1px solid #2a2a2a;">
+                            <td style="padding:3px 8px;" title=format!("#{}", e.example_id)>{e.example_text}</td>
+                            <td style="padding:3px 8px; font-family:monospace;">{e.correct_categories.join(", ")}</td>
+                            <td style="padding:3px 8px; font-family:monospace;">{e.chosen_a.unwrap_or_else(|| "—".into())}</td>
+                            <td style="padding:3px 8px; font-family:monospace;">{e.chosen_b.unwrap_or_else(|| "—".into())}</td>
+                        </tr>
+                    }).collect_view()}
+                </tbody>
+            </table>
+        })
+    };
+    view! {
+        <div style="margin-top:0.5rem; font-size:0.82rem;">
+            <p style="font-weight:bold; margin:0 0 0.25rem;">{format!("Run {b_id} vs. run {a_id}")}</p>
+            <p style="color:#aaa; margin:0 0 0.5rem;">{summary}</p>
+            <table style="border-collapse:collapse;">
+                <thead>
+                    <tr style="background:#1e1e1e;">
+                        <th style="text-align:left; padding:3px 8px">"Category"</th>
+                        <th style="text-align:right; padding:3px 8px">"A"</th>
+                        <th style="text-align:right; padding:3px 8px">"B"</th>
+                        <th style="text-align:right; padding:3px 8px">"Δ"</th>
+                        <th style="text-align:right; padding:3px 8px">"Fixed"</th>
+                        <th style="text-align:right; padding:3px 8px">"Regressed"</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    {c.categories.into_iter().map(|d| {
+                        let delta = (rate(d.b) - rate(d.a)) * 100.0;
+                        let color = if delta > 0.5 { "#4caf50" } else if delta < -0.5 { "#f44336" } else { "#aaa" };
+                        view! {
+                            <tr style="border-bottom:1px solid #2a2a2a;">
+                                <td style="padding:3px 8px; font-family:monospace;">{d.category_name}</td>
+                                <td style="padding:3px 8px; text-align:right; font-family:monospace;">{fmt_acc(d.a)}</td>
+                                <td style="padding:3px 8px; text-align:right; font-family:monospace;">{fmt_acc(d.b)}</td>
+                                <td style=format!("padding:3px 8px; text-align:right; font-family:monospace; color:{color};")>
+                                    {format!("{delta:+.0} pp")}
+                                </td>
+                                <td style="padding:3px 8px; text-align:right; font-family:monospace;">{d.fixes}</td>
+                                <td style="padding:3px 8px; text-align:right; font-family:monospace;">{d.regressions}</td>
+                            </tr>
+                        }
+                    }).collect_view()}
+                </tbody>
+            </table>
+            {changes_view(format!("Regressions ({})", c.regressions.len()), "#f44336", c.regressions)}
+            {changes_view(format!("Fixes ({})", c.fixes.len()), "#4caf50", c.fixes)}
+        </div>
+    }
+}
+
+/// Small SVG line chart of the optimiser's loss curve.
+fn loss_curve_view(curve: Vec<api::LossPoint>) -> Option<impl IntoView> {
+    const W: f64 = 340.0;
+    const H: f64 = 80.0;
+    let (first, last) = (curve.first()?, curve.last()?);
+    let max_loss = curve.iter().map(|p| p.loss).fold(f64::MIN, f64::max);
+    let min_loss = curve.iter().map(|p| p.loss).fold(f64::MAX, f64::min);
+    let span_x = (last.iteration - first.iteration).max(1) as f64;
+    let span_y = (max_loss - min_loss).max(1e-12);
+    let points = curve
+        .iter()
+        .map(|p| {
+            let x = (p.iteration - first.iteration) as f64 / span_x * W;
+            let y = H - (p.loss - min_loss) / span_y * H;
+            format!("{x:.1},{y:.1}")
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(view! {
+        <div style="margin-bottom:0.75rem;">
+            <p style="font-size:0.78rem; color:#888; margin:0 0 0.25rem;">
+                {format!(
+                    "Loss {:.4} → {:.4} over {} iterations",
+                    first.loss, last.loss, last.iteration
+                )}
+            </p>
+            <svg width=W height=H style="background:#1a1a1a; border:1px solid #2a2a2a;">
+                <polyline points=points fill="none" stroke="#4caf50" stroke-width="1.5" />
+            </svg>
+        </div>
+    })
 }
 
 #[component]
@@ -67,16 +277,35 @@ pub fn BulkTestPage() -> impl IntoView {
 
     // The run_id of the currently-displayed results (set when loading a past run)
     let (current_run_id, set_current_run_id) = signal::<Option<i64>>(None);
+    // Stream id of the run in progress, for the Stop button
+    let (bulk_test_id, set_bulk_test_id) = signal::<Option<String>>(None);
     // Optimisation results
     let (optimize_result, set_optimize_result) = signal::<Option<OptimizeResponse>>(None);
+    let (simulations, set_simulations) = signal::<Option<Simulations>>(None);
+    // Per-category metrics of the displayed run
+    let (run_metrics, set_run_metrics) = signal::<Option<api::RunMetrics>>(None);
+    let (metrics_error, set_metrics_error) = signal::<Option<String>>(None);
     let (optimize_running, set_optimize_running) = signal(false);
     let (optimize_error, set_optimize_error) = signal::<Option<String>>(None);
+    // Held-out evaluation: percentage of examples kept out of the fit, and
+    // cross-validation folds (0 = off).
+    let (test_percent, set_test_percent) = signal(0_u32);
+    let (cv_folds, set_cv_folds) = signal(0_usize);
+    let (objective, set_objective) = signal("cross_entropy".to_string());
+    let (allow_negative, set_allow_negative) = signal(false);
+    let (learn_offsets, set_learn_offsets) = signal(false);
+    let (learn_temperature, set_learn_temperature) = signal(false);
     let (apply_running, set_apply_running) = signal(false);
     let (apply_status, set_apply_status) = signal::<Option<String>>(None);
 
     // Previous runs — loaded once on mount
     let (past_runs, set_past_runs) = signal::<Vec<BulkTestRunSummary>>(vec![]);
     let (past_runs_error, set_past_runs_error) = signal::<Option<String>>(None);
+    // Runs picked for comparison (A = baseline, B = candidate) and the result
+    let (compare_a, set_compare_a) = signal::<Option<i64>>(None);
+    let (compare_b, set_compare_b) = signal::<Option<i64>>(None);
+    let (comparison, set_comparison) =
+        signal::<Option<Result<(i64, i64, api::RunComparison), String>>>(None);
     leptos::task::spawn_local(async move {
         match api::fetch_bulk_test_runs().await {
             Ok(runs) => set_past_runs.set(runs),
@@ -84,6 +313,55 @@ pub fn BulkTestPage() -> impl IntoView {
         }
     });
 
+    // Examples to test against: the marketing DB (`None`) or an imported set.
+    let (validation_sets, set_validation_sets) = signal::<Vec<ValidationSetSummary>>(vec![]);
+    let (validation_set_id, set_validation_set_id) = signal::<Option<i64>>(None);
+    Effect::new(move |_| {
+        set_validation_sets.set(vec![]);
+        set_validation_set_id.set(None);
+        let Some(aid) = agent_id.get() else {
+            return;
+        };
+        leptos::task::spawn_local(async move {
+            match api::fetch_validation_sets(aid).await {
+                Ok(sets) => set_validation_sets.set(sets),
+                Err(e) => set_status.set(format!("Failed to load validation sets: {e}")),
+            }
+        });
+    });
+
+    // Saved versions of the agent's weights, newest first.
+    let (weight_sets, set_weight_sets) = signal::<Vec<WeightSetSummary>>(vec![]);
+    let (weight_set_status, set_weight_set_status) = signal::<Option<String>>(None);
+    let reload_weight_sets = move || {
+        let Some(aid) = agent_id.get_untracked() else {
+            return;
+        };
+        leptos::task::spawn_local(async move {
+            match api::fetch_weight_sets(aid).await {
+                Ok(sets) => set_weight_sets.set(sets),
+                Err(e) => set_weight_set_status.set(Some(format!("Failed to load weight sets: {e}"))),
+            }
+        });
+    };
+    Effect::new(move |_| {
+        set_weight_sets.set(vec![]);
+        set_weight_set_status.set(None);
+        if agent_id.get().is_some() {
+            reload_weight_sets();
+        }
+    });
+    let on_weight_set_change = move |result: Result<WeightSetSummary, String>| {
+        match result {
+            Ok(set) => set_weight_set_status.set(Some(format!(
+                "Activated v{} — {}",
+                set.version, set.name
+            ))),
+            Err(e) => set_weight_set_status.set(Some(format!("Error: {e}"))),
+        }
+        reload_weight_sets();
+    };
+
     // Right panel width (px) — draggable from the left edge
     let (panel_width, set_panel_width) = signal(460_f64);
     let is_dragging = RwSignal::new(false);
@@ -103,11 +381,8 @@ pub fn BulkTestPage() -> impl IntoView {
         is_dragging.set(false);
     });
 
-    let on_run = move || {
-        let Some(aid) = agent_id.get_untracked() else {
-            set_status.set("Select a VC agent first".to_string());
-            return;
-        };
+    // Clears the page and streams the run `start` launches.
+    let launch = move |start: RunStart| {
         set_results.set(vec![]);
         set_selected_result_idx.set(None);
         set_expanded_steps.set(vec![]);
@@ -117,12 +392,14 @@ pub fn BulkTestPage() -> impl IntoView {
         set_running.set(true);
 
         leptos::task::spawn_local(async move {
-            match api::start_bulk_test(aid).await {
+            match start.await {
                 Ok((bulk_test_id, run_id)) => {
                     set_current_run_id.set(Some(run_id));
                     set_optimize_result.set(None);
                     set_optimize_error.set(None);
+                    set_run_metrics.set(None);
                     set_status.set(format!("Running — {bulk_test_id}"));
+                    set_bulk_test_id.set(Some(bulk_test_id.clone()));
                     api::open_bulk_test_stream(
                         bulk_test_id,
                         set_results,
@@ -139,6 +416,14 @@ pub fn BulkTestPage() -> impl IntoView {
         });
     };
 
+    let on_run = move || {
+        let Some(aid) = agent_id.get_untracked() else {
+            set_status.set("Select a VC agent first".to_string());
+            return;
+        };
+        launch(Box::pin(api::start_bulk_test(aid, validation_set_id.get_untracked())));
+    };
+
     view! {
         // ── Right panel: fixed to right edge, draggable left border ─────────
         <Show when=move || selected_result_idx.get().is_some()>
@@ -222,6 +507,116 @@ pub fn BulkTestPage() -> impl IntoView {
                     set_selected_agent_id=set_agent_id
                 />
 
+                <Show when=move || !validation_sets.get().is_empty()>
+                    <div style="margin-top:0.5rem;">
+                        <label for="validation-set-select">"Examples "</label>
+                        <select
+                            id="validation-set-select"
+                            on:change=move |ev| {
+                                set_validation_set_id.set(event_target_value(&ev).parse::<i64>().ok());
+                            }
+                        >
+                            <option value="" selected=move || validation_set_id.get().is_none()>
+                                "Marketing DB examples"
+                            </option>
+                            {move || {
+                                validation_sets
+                                    .get()
+                                    .into_iter()
+                                    .map(|set| {
+                                        let id = set.id;
+                                        view! {
+                                            <option
+                                                value=id.to_string()
+                                                selected=move || validation_set_id.get() == Some(id)
+                                            >
+                                                {format!("{} ({} examples)", set.name, set.example_count)}
+                                            </option>
+                                        }
+                                    })
+                                    .collect_view()
+                            }}
+                        </select>
+                    </div>
+                </Show>
+
+                <Show when=move || !weight_sets.get().is_empty()>
+                    <details style="margin-top:0.5rem;">
+                        <summary style="cursor:pointer; font-size:0.9rem; color:#aaa; user-select:none;">
+                            {move || {
+                                let sets = weight_sets.get();
+                                match sets.iter().find(|w| w.active) {
+                                    Some(w) => format!("Weight sets (active: v{} — {})", w.version, w.name),
+                                    None => "Weight sets".to_string(),
+                                }
+                            }}
+                        </summary>
+                        <table style="width:100%; border-collapse:collapse; margin-top:0.4rem; font-size:0.85rem;">
+                            <thead>
+                                <tr style="background:#1e1e1e;">
+                                    <th style="text-align:left; padding:3px 6px">"Version"</th>
+                                    <th style="text-align:left; padding:3px 6px">"Name"</th>
+                                    <th style="text-align:left; padding:3px 6px">"Created"</th>
+                                    <th style="text-align:right; padding:3px 6px">"Temperature"</th>
+                                    <th style="padding:3px 6px"></th>
+                                </tr>
+                            </thead>
+                            <tbody>
+                                {move || weight_sets.get().into_iter().map(|set| {
+                                    let created = set.created_at.get(..16).unwrap_or(&set.created_at).to_string();
+                                    let set_id = set.id;
+                                    view! {
+                                        <tr style="border-bottom:1px solid #2a2a2a;">
+                                            <td style="padding:3px 6px; font-family:monospace;">
+                                                {format!("v{}", set.version)}
+                                            </td>
+                                            <td style="padding:3px 6px;">{set.name}</td>
+                                            <td style="padding:3px 6px; color:#aaa;">{created}</td>
+                                            <td style="padding:3px 6px; text-align:right; font-family:monospace;">
+                                                {format!("{:.3}", set.logit_temperature)}
+                                            </td>
+                                            <td style="padding:3px 6px;">
+                                                {if set.active {
+                                                    view! { <span style="color:#4caf50;">"active"</span> }.into_any()
+                                                } else {
+                                                    view! {
+                                                        <button
+                                                            style="font-size:0.8rem; padding:1px 8px;"
+                                                            on:click=move |_| {
+                                                                leptos::task::spawn_local(async move {
+                                                                    on_weight_set_change(api::activate_weight_set(set_id).await);
+                                                                });
+                                                            }
+                                                        >
+                                                            "Activate"
+                                                        </button>
+                                                    }.into_any()
+                                                }}
+                                            </td>
+                                        </tr>
+                                    }
+                                }).collect_view()}
+                            </tbody>
+                        </table>
+                        <button
+                            style="margin-top:0.4rem; font-size:0.8rem;"
+                            on:click=move |_| {
+                                let Some(aid) = agent_id.get_untracked() else { return; };
+                                leptos::task::spawn_local(async move {
+                                    on_weight_set_change(api::rollback_weight_set(aid).await);
+                                });
+                            }
+                        >
+                            "Roll back to previous version"
+                        </button>
+                        <Show when=move || weight_set_status.get().is_some()>
+                            <span style="margin-left:0.5rem; font-size:0.82rem; color:#aaa;">
+                                {move || weight_set_status.get().unwrap_or_default()}
+                            </span>
+                        </Show>
+                    </details>
+                </Show>
+
                 <div style="margin-top:0.75rem;">
                     <button
                         disabled=move || running.get() || agent_id.get().is_none()
@@ -229,6 +624,20 @@ pub fn BulkTestPage() -> impl IntoView {
                     >
                         "Run Bulk Test"
                     </button>
+                    <Show when=move || running.get() && bulk_test_id.get().is_some()>
+                        " "
+                        <button on:click=move |_| {
+                            if let Some(id) = bulk_test_id.get_untracked() {
+                                leptos::task::spawn_local(async move {
+                                    if let Err(e) = api::cancel_bulk_test(&id).await {
+                                        set_status.set(format!("Cancel failed: {e}"));
+                                    }
+                                });
+                            }
+                        }>
+                            "Stop"
+                        </button>
+                    </Show>
                 </div>
 
                 <p id="status">{status}</p>
@@ -249,8 +658,10 @@ pub fn BulkTestPage() -> impl IntoView {
                                 <tr style="background:#1e1e1e;">
                                     <th style="text-align:left; padding:3px 6px">"Agent"</th>
                                     <th style="text-align:left; padding:3px 6px">"Started"</th>
+                                    <th style="text-align:left; padding:3px 6px">"Examples"</th>
                                     <th style="text-align:right; padding:3px 6px">"Pass rate"</th>
                                     <th style="padding:3px 6px"></th>
+                                    <th style="padding:3px 6px" title="Pick a baseline (A) and a run to compare with it (B)">"Compare"</th>
                                 </tr>
                             </thead>
                             <tbody>
@@ -261,12 +672,29 @@ pub fn BulkTestPage() -> impl IntoView {
                                         (Some(s), Some(t)) => format!("{s}/{t}"),
                                         _ => "—".to_string(),
                                     };
+                                    let pass_label = if run.status == "cancelled" {
+                                        format!("{pass_label} (cancelled)")
+                                    } else {
+                                        pass_label
+                                    };
                                     let started = run.started_at.get(..16).unwrap_or(&run.started_at).to_string();
+                                    // Sets of other agents, and deleted ones, are not listed; show their ID.
+                                    let examples_label = match run.validation_set_id {
+                                        Some(set_id) => validation_sets
+                                            .get()
+                                            .into_iter()
+                                            .find(|set| set.id == set_id)
+                                            .map(|set| set.name)
+                                            .unwrap_or_else(|| format!("Set {set_id}")),
+                                        None => "Marketing DB".to_string(),
+                                    };
                                     let run_id = run.id;
+                                    let has_config = run.has_config;
                                     view! {
                                         <tr style="border-bottom:1px solid #2a2a2a;">
                                             <td style="padding:3px 6px; font-family:monospace;">{run.agent_id}</td>
                                             <td style="padding:3px 6px; color:#aaa;">{started}</td>
+                                            <td style="padding:3px 6px;">{examples_label}</td>
                                             <td style="padding:3px 6px; text-align:right; font-family:monospace;">{pass_label}</td>
                                             <td style="padding:3px 6px;">
                                                 <button
@@ -278,6 +706,7 @@ pub fn BulkTestPage() -> impl IntoView {
                                                         set_selected_step_idx.set(None);
                                                         set_optimize_result.set(None);
                                                         set_optimize_error.set(None);
+                                                        set_run_metrics.set(None);
                                                         set_current_run_id.set(Some(run_id));
                                                         set_status.set(format!("Loading run {run_id}…"));
                                                         leptos::task::spawn_local(async move {
@@ -298,12 +727,65 @@ pub fn BulkTestPage() -> impl IntoView {
                                                 >
                                                     "Load"
                                                 </button>
+                                                <button
+                                                    style="font-size:0.8rem; padding:1px 8px; margin-left:2px;"
+                                                    title="Run again with this run's recorded configuration"
+                                                    disabled=move || !has_config || running.get()
+                                                    on:click=move |_| launch(Box::pin(api::rerun_bulk_test(run_id)))
+                                                >
+                                                    "Re-run"
+                                                </button>
+                                            </td>
+                                            <td style="padding:3px 6px; white-space:nowrap;">
+                                                <button
+                                                    style=move || format!(
+                                                        "font-size:0.8rem; padding:1px 6px;{}",
+                                                        if compare_a.get() == Some(run_id) { " background:#1976d2;" } else { "" }
+                                                    )
+                                                    on:click=move |_| set_compare_a.set(Some(run_id))
+                                                >
+                                                    "A"
+                                                </button>
+                                                <button
+                                                    style=move || format!(
+                                                        "font-size:0.8rem; padding:1px 6px; margin-left:2px;{}",
+                                                        if compare_b.get() == Some(run_id) { " background:#1976d2;" } else { "" }
+                                                    )
+                                                    on:click=move |_| set_compare_b.set(Some(run_id))
+                                                >
+                                                    "B"
+                                                </button>
                                             </td>
                                         </tr>
                                     }
                                 }).collect_view()}
                             </tbody>
                         </table>
+                        <button
+                            style="margin-top:0.4rem; font-size:0.8rem;"
+                            disabled=move || compare_a.get().is_none() || compare_b.get().is_none()
+                            on:click=move |_| {
+                                let (Some(a), Some(b)) = (compare_a.get_untracked(), compare_b.get_untracked()) else {
+                                    return;
+                                };
+                                set_comparison.set(None);
+                                leptos::task::spawn_local(async move {
+                                    let result = api::compare_bulk_test_runs(a, b).await.map(|c| (a, b, c));
+                                    set_comparison.set(Some(result));
+                                });
+                            }
+                        >
+                            {move || match (compare_a.get(), compare_b.get()) {
+                                (Some(a), Some(b)) => format!("Compare run {b} against run {a}"),
+                                _ => "Pick runs A and B to compare".to_string(),
+                            }}
+                        </button>
+                        {move || comparison.get().map(|result| match result {
+                            Ok((a, b, c)) => comparison_view(a, b, c).into_any(),
+                            Err(e) => view! {
+                                <p style="color:#f44336; font-size:0.85rem;">{format!("Comparison failed: {e}")}</p>
+                            }.into_any(),
+                        })}
                     </details>
                 </Show>
 
@@ -324,6 +806,30 @@ pub fn BulkTestPage() -> impl IntoView {
                     }}
                 </Show>
 
+                // ── Run metrics ──────────────────────────────────────────────
+                <Show when=move || current_run_id.get().is_some() && !results.get().is_empty() && !running.get()>
+                    <div style="margin-top:1rem;">
+                        <button on:click=move |_| {
+                            let Some(rid) = current_run_id.get_untracked() else { return; };
+                            set_metrics_error.set(None);
+                            leptos::task::spawn_local(async move {
+                                match api::fetch_bulk_test_metrics(rid).await {
+                                    Ok(m) => set_run_metrics.set(Some(m)),
+                                    Err(e) => set_metrics_error.set(Some(e)),
+                                }
+                            });
+                        }>
+                            {move || if run_metrics.get().is_some() { "Refresh Run Metrics" } else { "Show Run Metrics" }}
+                        </button>
+                        <Show when=move || metrics_error.get().is_some()>
+                            <p style="color:#f44336; font-size:0.85rem; margin-top:0.4rem;">
+                                {move || metrics_error.get().unwrap_or_default()}
+                            </p>
+                        </Show>
+                        {move || run_metrics.get().map(metrics_view)}
+                    </div>
+                </Show>
+
                 // ── Optimise weights ─────────────────────────────────────────
                 <Show when=move || current_run_id.get().is_some() && !results.get().is_empty()>
                     <div style="margin-top:1rem;">
@@ -332,14 +838,27 @@ pub fn BulkTestPage() -> impl IntoView {
                             on:click=move |_| {
                                 let Some(rid) = current_run_id.get_untracked() else { return; };
                                 set_optimize_result.set(None);
+                                set_simulations.set(None);
                                 set_optimize_error.set(None);
                                 set_apply_status.set(None);
                                 set_optimize_running.set(true);
+                                let options = api::OptimizeOptions {
+                                    test_fraction: test_percent.get_untracked() as f64 / 100.0,
+                                    folds: Some(cv_folds.get_untracked()).filter(|&k| k >= 2),
+                                    objective: objective.get_untracked(),
+                                    allow_negative: allow_negative.get_untracked(),
+                                    learn_offsets: learn_offsets.get_untracked(),
+                                    learn_temperature: learn_temperature.get_untracked(),
+                                };
                                 leptos::task::spawn_local(async move {
-                                    match api::optimize_weights(rid).await {
+                                    match api::optimize_weights(rid, &options).await {
                                         Ok(resp) => {
+                                            match simulate(rid, &resp).await {
+                                                Ok(sims) => set_simulations.set(Some(sims)),
+                                                Err(e) => set_optimize_error
+                                                    .set(Some(format!("Re-scoring failed: {e}"))),
+                                            }
                                             set_optimize_result.set(Some(resp));
-                                            set_optimize_error.set(None);
                                         }
                                         Err(e) => set_optimize_error.set(Some(e)),
                                     }
@@ -349,6 +868,58 @@ pub fn BulkTestPage() -> impl IntoView {
                         >
                             {move || if optimize_running.get() { "Optimising…" } else { "Optimise Category Weights" }}
                         </button>
+                        <label style="margin-left:0.75rem; font-size:0.85rem; color:#aaa;">
+                            "Objective "
+                            <select on:change=move |ev| set_objective.set(event_target_value(&ev))>
+                                <option value="cross_entropy">"Cross-entropy"</option>
+                                <option value="hinge">"Hinge"</option>
+                                <option value="coordinate_search">"Accuracy (coordinate search)"</option>
+                            </select>
+                        </label>
+                        <label style="margin-left:0.75rem; font-size:0.85rem; color:#aaa;">
+                            <input
+                                type="checkbox"
+                                prop:checked=move || allow_negative.get()
+                                on:change=move |ev| set_allow_negative.set(event_target_checked(&ev))
+                            />
+                            " Allow negative kappa"
+                        </label>
+                        <label style="margin-left:0.75rem; font-size:0.85rem; color:#aaa;">
+                            <input
+                                type="checkbox"
+                                prop:checked=move || learn_offsets.get()
+                                on:change=move |ev| set_learn_offsets.set(event_target_checked(&ev))
+                            />
+                            " Learn offsets"
+                        </label>
+                        <label style="margin-left:0.75rem; font-size:0.85rem; color:#aaa;">
+                            <input
+                                type="checkbox"
+                                prop:checked=move || learn_temperature.get()
+                                on:change=move |ev| set_learn_temperature.set(event_target_checked(&ev))
+                            />
+                            " Learn temperature"
+                        </label>
+                        <label style="margin-left:0.75rem; font-size:0.85rem; color:#aaa;">
+                            "Test hold-out % "
+                            <input
+                                type="number" min="0" max="50" style="width:4em;"
+                                prop:value=move || test_percent.get().to_string()
+                                on:change=move |ev| {
+                                    set_test_percent.set(event_target_value(&ev).parse().unwrap_or(0).min(50));
+                                }
+                            />
+                        </label>
+                        <label style="margin-left:0.75rem; font-size:0.85rem; color:#aaa;">
+                            "CV folds "
+                            <input
+                                type="number" min="0" max="20" style="width:4em;"
+                                prop:value=move || cv_folds.get().to_string()
+                                on:change=move |ev| {
+                                    set_cv_folds.set(event_target_value(&ev).parse().unwrap_or(0).min(20));
+                                }
+                            />
+                        </label>
 
                         <Show when=move || optimize_error.get().is_some()>
                             <p style="color:#f44336; font-size:0.85rem; margin-top:0.4rem;">
@@ -360,26 +931,45 @@ pub fn BulkTestPage() -> impl IntoView {
                             {move || {
                                 let resp = optimize_result.get()?;
                                 let weights_for_apply = resp.weights.clone();
-
-                                // Simulated accuracy with optimised kappas.
-                                let rs = results.get();
-                                let (sim_correct, sim_total) =
-                                    simulate_accuracy(&rs, &resp.weights, 10.0);
-                                // Baseline: logit-only (kappa = 0 for all).
-                                let empty: HashMap<String, f64> = HashMap::new();
-                                let (logit_correct, logit_total) =
-                                    simulate_accuracy(&rs, &empty, 0.0);
-                                // Baseline: original kappa = 10.
-                                let (k10_correct, k10_total) =
-                                    simulate_accuracy(&rs, &empty, 10.0);
+                                let offsets_for_apply = resp.offsets.clone();
+                                let temperature = resp.temperature;
 
                                 let fmt_acc = |c: usize, t: usize| -> String {
                                     if t == 0 { "—".into() }
                                     else { format!("{c}/{t} ({:.0}%)", c as f64 / t as f64 * 100.0) }
                                 };
+                                // Re-scored on the server; "—" until they arrive or if that failed.
+                                let sims = simulations.get();
+                                let sim_acc = |pick: fn(&Simulations) -> &api::RescoreResponse| {
+                                    sims.as_ref()
+                                        .map(|s| pick(s).rescored_accuracy)
+                                        .map_or_else(|| "—".into(), |a| fmt_acc(a.correct, a.total))
+                                };
+                                let optimised_color = match &sims {
+                                    Some(s) if s.optimised.rescored_accuracy.correct
+                                        < s.default.rescored_accuracy.correct => "#f44336",
+                                    _ => "#4caf50",
+                                };
+                                let flips = sims.as_ref().map(|s| {
+                                    format!(
+                                        "{} fixed, {} broken",
+                                        s.optimised.fixed.len(),
+                                        s.optimised.broken.len()
+                                    )
+                                });
 
-                                let mut entries: Vec<(String, f64)> = resp.weights.clone().into_iter().collect();
-                                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                                let mut names: Vec<String> =
+                                    resp.weights.keys().chain(resp.offsets.keys()).cloned().collect();
+                                names.sort();
+                                names.dedup();
+                                let entries: Vec<(String, Option<f64>, Option<f64>)> = names
+                                    .into_iter()
+                                    .map(|cat| {
+                                        let kappa = resp.weights.get(&cat).copied();
+                                        let offset = resp.offsets.get(&cat).copied();
+                                        (cat, kappa, offset)
+                                    })
+                                    .collect();
                                 Some(view! {
                                     <div style="margin-top:0.75rem;">
                                         <p style="font-size:0.82rem; color:#aaa; margin:0 0 0.5rem;">
@@ -401,52 +991,104 @@ pub fn BulkTestPage() -> impl IntoView {
                                                 <tr style="border-bottom:1px solid #2a2a2a;">
                                                     <td style="padding:3px 12px; color:#aaa;">"Logit only (kappa = 0)"</td>
                                                     <td style="padding:3px 12px; text-align:right; font-family:monospace;">
-                                                        {fmt_acc(logit_correct, logit_total)}
+                                                        {sim_acc(|s| &s.logit_only)}
                                                     </td>
                                                 </tr>
                                                 <tr style="border-bottom:1px solid #2a2a2a;">
                                                     <td style="padding:3px 12px; color:#aaa;">"Default (kappa = 10)"</td>
                                                     <td style="padding:3px 12px; text-align:right; font-family:monospace;">
-                                                        {fmt_acc(k10_correct, k10_total)}
+                                                        {sim_acc(|s| &s.default)}
                                                     </td>
                                                 </tr>
-                                                <tr>
-                                                    <td style="padding:3px 12px; font-weight:bold;">"Optimised kappas (estimated)"</td>
+                                                <tr style="border-bottom:1px solid #2a2a2a;">
+                                                    <td style="padding:3px 12px; font-weight:bold;">"Optimised kappas (in-sample)"</td>
                                                     <td style=format!(
                                                         "padding:3px 12px; text-align:right; font-family:monospace; \
-                                                         font-weight:bold; color:{};",
-                                                        if sim_correct >= k10_correct { "#4caf50" } else { "#f44336" }
+                                                         font-weight:bold; color:{optimised_color};"
                                                     )>
-                                                        {fmt_acc(sim_correct, sim_total)}
+                                                        {sim_acc(|s| &s.optimised)}
                                                     </td>
                                                 </tr>
+                                                {flips.map(|f| view! {
+                                                    <tr style="border-bottom:1px solid #2a2a2a;">
+                                                        <td style="padding:3px 12px; color:#aaa;">"Changes vs. recorded choices"</td>
+                                                        <td style="padding:3px 12px; text-align:right; font-family:monospace;">{f}</td>
+                                                    </tr>
+                                                })}
+                                                {resp.test_accuracy.map(|a| view! {
+                                                    <tr style="border-bottom:1px solid #2a2a2a;">
+                                                        <td style="padding:3px 12px;">"Optimised kappas (held-out test)"</td>
+                                                        <td style="padding:3px 12px; text-align:right; font-family:monospace;">
+                                                            {fmt_acc(a.correct, a.total)}
+                                                        </td>
+                                                    </tr>
+                                                })}
+                                                {resp.cross_validation.clone().map(|cv| {
+                                                    let per_fold = cv
+                                                        .folds
+                                                        .iter()
+                                                        .map(|f| fmt_acc(f.held_out.correct, f.held_out.total))
+                                                        .collect::<Vec<_>>()
+                                                        .join(", ");
+                                                    view! {
+                                                        <tr title=per_fold>
+                                                            <td style="padding:3px 12px;">
+                                                                {format!("Cross-validation ({} folds)", cv.folds.len())}
+                                                            </td>
+                                                            <td style="padding:3px 12px; text-align:right; font-family:monospace;">
+                                                                {format!(
+                                                                    "{:.0}% ± {:.0}%",
+                                                                    cv.mean_accuracy * 100.0,
+                                                                    cv.std_accuracy * 100.0
+                                                                )}
+                                                            </td>
+                                                        </tr>
+                                                    }
+                                                })}
                                             </tbody>
                                         </table>
+                                        {loss_curve_view(resp.loss_curve.clone())}
+                                        {temperature.map(|t| view! {
+                                            <p style="font-size:0.82rem; color:#aaa; margin:0 0 0.5rem;">
+                                                {format!("Logit temperature: {t:.4}")}
+                                            </p>
+                                        })}
                                         <table style="border-collapse:collapse; font-size:0.85rem; min-width:340px;">
                                             <thead>
                                                 <tr style="background:#1e1e1e;">
                                                     <th style="text-align:left; padding:3px 10px">"Category"</th>
                                                     <th style="text-align:right; padding:3px 10px">"Optimal kappa"</th>
                                                     <th style="text-align:right; padding:3px 10px">"Δ from 10.0"</th>
+                                                    <th style="text-align:right; padding:3px 10px">"Offset"</th>
                                                 </tr>
                                             </thead>
                                             <tbody>
-                                                {entries.into_iter().map(|(cat, w)| {
-                                                    let delta = w - 10.0;
-                                                    let delta_color = if delta > 0.5 { "#4caf50" }
-                                                                      else if delta < -0.5 { "#f44336" }
-                                                                      else { "#aaa" };
-                                                    let delta_str = format!("{:+.3}", delta);
+                                                {entries.into_iter().map(|(cat, w, offset)| {
+                                                    let delta = w.map(|w| w - 10.0);
+                                                    let delta_color = match delta {
+                                                        Some(d) if d > 0.5 => "#4caf50",
+                                                        Some(d) if d < -0.5 => "#f44336",
+                                                        _ => "#aaa",
+                                                    };
+                                                    let fmt_opt = |v: Option<f64>, f: fn(f64) -> String| {
+                                                        v.map(f).unwrap_or_else(|| "—".into())
+                                                    };
+                                                    let w_str = fmt_opt(w, |w| format!("{w:.4}"));
+                                                    let delta_str = fmt_opt(delta, |d| format!("{d:+.3}"));
+                                                    let offset_str = fmt_opt(offset, |o| format!("{o:+.3}"));
                                                     view! {
                                                         <tr style="border-bottom:1px solid #2a2a2a;">
                                                             <td style="padding:3px 10px; font-family:monospace;">{cat}</td>
                                                             <td style="padding:3px 10px; text-align:right; font-family:monospace;">
-                                                                {format!("{:.4}", w)}
+                                                                {w_str}
                                                             </td>
                                                             <td style=format!("padding:3px 10px; text-align:right; \
                                                                               font-family:monospace; color:{delta_color};")>
                                                                 {delta_str}
                                                             </td>
+                                                            <td style="padding:3px 10px; text-align:right; font-family:monospace;">
+                                                                {offset_str}
+                                                            </td>
                                                         </tr>
                                                     }
                                                 }).collect_view()}
@@ -457,24 +1099,31 @@ pub fn BulkTestPage() -> impl IntoView {
                                                 disabled=move || apply_running.get()
                                                 on:click={
                                                     let weights = weights_for_apply.clone();
+                                                    let offsets = offsets_for_apply.clone();
                                                     move |_| {
                                                         let Some(rid) = current_run_id.get_untracked() else { return; };
                                                         let weights = weights.clone();
+                                                        let offsets = offsets.clone();
                                                         set_apply_running.set(true);
                                                         set_apply_status.set(None);
                                                         leptos::task::spawn_local(async move {
-                                                            match api::apply_weights(rid, &weights).await {
+                                                            match api::apply_weights(rid, &weights, &offsets, temperature).await {
                                                                 Ok(r) => {
                                                                     let msg = if r.unmatched_categories.is_empty() {
-                                                                        format!("Saved — {} message(s) updated.", r.updated)
+                                                                        format!(
+                                                                            "Saved as v{} — {} categories updated.",
+                                                                            r.version, r.updated
+                                                                        )
                                                                     } else {
                                                                         format!(
-                                                                            "Saved — {} message(s) updated. Unmatched: {}",
+                                                                            "Saved as v{} — {} categories updated. Unmatched: {}",
+                                                                            r.version,
                                                                             r.updated,
                                                                             r.unmatched_categories.join(", ")
                                                                         )
                                                                     };
                                                                     set_apply_status.set(Some(msg));
+                                                                    reload_weight_sets();
                                                                 }
                                                                 Err(e) => set_apply_status.set(Some(format!("Error: {e}"))),
                                                             }
@@ -1,11 +1,13 @@
 mod agent_selector;
 mod bulk_test;
 mod candidate_panel;
+mod pending_step;
 mod prompt_input;
 mod token_stream;
 
 pub use agent_selector::AgentSelector;
 pub use bulk_test::BulkTestPage;
 pub use candidate_panel::CandidatePanel;
+pub use pending_step::PendingStepPanel;
 pub use prompt_input::PromptInput;
 pub use token_stream::TokenStreamView;
@@ -0,0 +1,55 @@
+use inference_types::StepCandidates;
+use leptos::prelude::*;
+
+/// Candidates at the next critical point of a step-through session.
+/// "Next Token" commits the proposal (first row); "Force" commits that row instead.
+#[component]
+pub fn PendingStepPanel(step: StepCandidates, on_force: Callback<u32>) -> impl IntoView {
+    let proposal_id = step.chosen.token_id;
+
+    view! {
+        <div style="margin-top:1rem; padding:0.5rem; background:#1e1e1e; border-radius:4px;">
+            <h3 style="margin:0 0 0.5rem">"Next critical point"</h3>
+            <table style="width:100%; font-family:monospace; font-size:0.85rem; border-collapse:collapse;">
+                <thead>
+                    <tr>
+                        <th style="text-align:left; padding:2px 6px">"Token"</th>
+                        <th style="text-align:right; padding:2px 6px">"Prob"</th>
+                        <th style="text-align:right; padding:2px 6px">"Logit"</th>
+                        <th style="text-align:right; padding:2px 6px">"Emb. Logit"</th>
+                        <th style="padding:2px 6px"></th>
+                    </tr>
+                </thead>
+                <tbody>
+                    {step.top_constrained.into_iter().map(|t| {
+                        let token_id = t.token_id;
+                        let is_proposal = token_id == proposal_id;
+                        let bg = if is_proposal { "background:rgba(126,184,247,0.12);" } else { "" };
+                        view! {
+                            <tr style=bg>
+                                <td style="padding:2px 6px">{format!("{:?}", t.text)}</td>
+                                <td style="text-align:right; padding:2px 6px">{format!("{:.4}", t.probability)}</td>
+                                <td style="text-align:right; padding:2px 6px">{format!("{:.4}", t.logit)}</td>
+                                <td style="text-align:right; padding:2px 6px">{format!("{:.4}", t.embedding_logit)}</td>
+                                <td style="text-align:right; padding:2px 6px">
+                                    {if is_proposal {
+                                        view! { <span style="color:#7eb8f7;">"proposal"</span> }.into_any()
+                                    } else {
+                                        view! {
+                                            <button
+                                                style="font-size:0.8rem; padding:1px 8px; margin:0;"
+                                                on:click=move |_| on_force.run(token_id)
+                                            >
+                                                "Force"
+                                            </button>
+                                        }.into_any()
+                                    }}
+                                </td>
+                            </tr>
+                        }
+                    }).collect_view()}
+                </tbody>
+            </table>
+        </div>
+    }
+}
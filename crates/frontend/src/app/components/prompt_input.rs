@@ -24,7 +24,7 @@ pub fn PromptInput(
                     "Generate"
                 </button>
                 <button
-                    disabled=move || !streaming.get()
+                    disabled=move || streaming.get()
                     on:click=move |_| on_next_token()
                 >
                     "Next Token"
@@ -1,10 +1,12 @@
 pub mod api;
 pub mod components;
 
-use inference_types::StepCandidates;
+use inference_types::{StepCandidates, StepThroughState};
 use leptos::prelude::*;
 
-use components::{AgentSelector, BulkTestPage, CandidatePanel, PromptInput, TokenStreamView};
+use components::{
+    AgentSelector, BulkTestPage, CandidatePanel, PendingStepPanel, PromptInput, TokenStreamView,
+};
 
 #[derive(Clone, Copy, PartialEq)]
 enum Page {
@@ -52,6 +54,24 @@ fn InferencePage() -> impl IntoView {
     let (streaming, set_streaming) = signal(false);
     let (steps, set_steps) = signal::<Vec<StepCandidates>>(vec![]);
     let (selected_idx, set_selected_idx) = signal::<Option<usize>>(None);
+    // Step-through mode: the live server session and its next critical point
+    let (step_session, set_step_session) = signal::<Option<String>>(None);
+    let (pending, set_pending) = signal::<Option<StepCandidates>>(None);
+    // Generate continues this conversation until "New conversation" is clicked
+    let (conversation_id, set_conversation_id) = signal::<Option<String>>(None);
+    // Session of the generation currently streaming, for the Stop button
+    let (infer_session, set_infer_session) = signal::<Option<String>>(None);
+
+    // Abandon any step-through session in progress (frees its server context).
+    let close_step_session = move || {
+        set_pending.set(None);
+        if let Some(sid) = step_session.get_untracked() {
+            set_step_session.set(None);
+            leptos::task::spawn_local(async move {
+                let _ = api::close_step_session(&sid).await;
+            });
+        }
+    };
 
     let on_generate = move || {
         let p = prompt.get_untracked();
@@ -62,15 +82,19 @@ fn InferencePage() -> impl IntoView {
         if p.trim().is_empty() {
             return;
         }
+        close_step_session();
         set_steps.set(vec![]);
         set_selected_idx.set(None);
         set_status.set("Starting…".to_string());
         set_streaming.set(true);
 
+        let conversation = conversation_id.get_untracked();
         leptos::task::spawn_local(async move {
-            match api::start_inference(p, aid).await {
-                Ok(session_id) => {
+            match api::start_inference(p, aid, conversation).await {
+                Ok((session_id, conversation)) => {
+                    set_conversation_id.set(Some(conversation));
                     set_status.set(format!("Streaming {session_id}"));
+                    set_infer_session.set(Some(session_id.clone()));
                     api::open_sse_stream(session_id, set_steps, set_status, set_streaming);
                 }
                 Err(e) => {
@@ -81,10 +105,60 @@ fn InferencePage() -> impl IntoView {
         });
     };
 
-    let on_next_token = move || {
-        set_status.set("Step-through not yet implemented".to_string());
+    let apply_step_state = move |state: StepThroughState| {
+        set_steps.update(|v| v.extend(state.committed));
+        if state.pending.is_some() {
+            set_status.set("Paused at critical point — Next Token commits the proposal".to_string());
+        } else {
+            set_status.set("Done".to_string());
+            set_step_session.set(None);
+        }
+        set_pending.set(state.pending);
+    };
+
+    // Advance the step-through session, starting one first if needed.
+    // `token_id` forces a constrained candidate instead of the proposal.
+    let advance = move |token_id: Option<u32>| {
+        if let Some(sid) = step_session.get_untracked() {
+            set_status.set("Stepping…".to_string());
+            leptos::task::spawn_local(async move {
+                match api::advance_step_session(&sid, token_id).await {
+                    Ok(state) => apply_step_state(state),
+                    Err(e) => set_status.set(format!("Error: {e}")),
+                }
+            });
+            return;
+        }
+
+        let p = prompt.get_untracked();
+        let Some(aid) = agent_id.get_untracked() else {
+            set_status.set("Select a VC agent first".to_string());
+            return;
+        };
+        if p.trim().is_empty() {
+            return;
+        }
+        set_steps.set(vec![]);
+        set_selected_idx.set(None);
+        set_pending.set(None);
+        set_status.set("Starting step-through…".to_string());
+
+        leptos::task::spawn_local(async move {
+            match api::start_step_session(p, aid).await {
+                Ok((session_id, state)) => {
+                    if state.pending.is_some() {
+                        set_step_session.set(Some(session_id));
+                    }
+                    apply_step_state(state);
+                }
+                Err(e) => set_status.set(format!("Error: {e}")),
+            }
+        });
     };
 
+    let on_next_token = move || advance(None);
+    let on_force = Callback::new(move |token_id: u32| advance(Some(token_id)));
+
     view! {
         <div>
             <h1>"Heuristic Guidance"</h1>
@@ -102,10 +176,45 @@ fn InferencePage() -> impl IntoView {
                 on_next_token=on_next_token
             />
 
-            <p id="status">{status}</p>
+            <p id="status">
+                {status}
+                <Show when=move || streaming.get() && infer_session.get().is_some()>
+                    " "
+                    <button on:click=move |_| {
+                        if let Some(sid) = infer_session.get_untracked() {
+                            leptos::task::spawn_local(async move {
+                                if let Err(e) = api::cancel_inference(&sid).await {
+                                    set_status.set(format!("Cancel failed: {e}"));
+                                }
+                            });
+                        }
+                    }>
+                        "Stop"
+                    </button>
+                </Show>
+            </p>
+
+            <Show when=move || conversation_id.get().is_some()>
+                <p>
+                    "Follow-up questions continue the current conversation. "
+                    <button
+                        disabled=move || streaming.get()
+                        on:click=move |_| set_conversation_id.set(None)
+                    >
+                        "New conversation"
+                    </button>
+                </p>
+            </Show>
 
             <TokenStreamView steps=steps set_selected_idx=set_selected_idx />
 
+            <Show when=move || pending.get().is_some()>
+                {move || {
+                    let step = pending.get()?;
+                    Some(view! { <PendingStepPanel step=step on_force=on_force /> })
+                }}
+            </Show>
+
             <Show when=move || selected_idx.get().is_some()>
                 {move || {
                     let idx = selected_idx.get()?;
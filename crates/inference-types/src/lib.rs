@@ -39,6 +39,36 @@ pub struct StepCandidates {
     pub category_top_tokens: Vec<CategoryTopToken>,
 }
 
+/// How the engine picks a token at each critical point, from the
+/// bias-adjusted candidates that survive the grammar mask.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SamplerSettings {
+    /// Always commit the highest adjusted-logit candidate.
+    #[default]
+    Greedy,
+    /// Draw from the softmax distribution after the optional truncations.
+    /// Truncations are computed on the untempered distribution and applied in
+    /// the order top-k → top-p → min-p; `temperature` then reshapes the survivors.
+    Random {
+        /// Softmax temperature applied to adjusted logits (must be > 0).
+        temperature: f32,
+        /// Keep only the `k` most likely candidates.
+        #[serde(default)]
+        top_k: Option<usize>,
+        /// Keep the smallest set of candidates whose cumulative probability reaches `p`.
+        #[serde(default)]
+        top_p: Option<f32>,
+        /// Drop candidates whose probability is below `min_p` times the top probability.
+        #[serde(default)]
+        min_p: Option<f32>,
+        /// RNG seed. The same seed, prompt and model reproduce the same choices;
+        /// `None` seeds from the clock.
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+}
+
 /// Events streamed from the inference engine to the server and frontend.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -49,6 +79,61 @@ pub enum InferenceEvent {
     Done { full_text: String },
     /// An error occurred during generation.
     Error { message: String },
+    /// Generation was cancelled before it finished.
+    Cancelled,
+}
+
+/// Snapshot of an interactive step-through session, returned when the session
+/// starts and after every advance.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StepThroughState {
+    /// Tokens committed by the last advance: the chosen (or forced) token first,
+    /// followed by any grammar fast-forward tokens. Empty when the session starts.
+    pub committed: Vec<StepCandidates>,
+    /// Candidates at the next critical point. `chosen` is the token that will be
+    /// committed if the caller does not force a different one.
+    /// `None` once generation has finished.
+    pub pending: Option<StepCandidates>,
+    /// All text committed so far.
+    pub full_text: String,
+}
+
+/// One complete (or length-capped) response explored by beam search.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ScoredPath {
+    pub text: String,
+    pub token_ids: Vec<TokenID>,
+    /// Log-probability of each token under the grammar-constrained softmax over
+    /// adjusted logits. 0.0 for grammar-forced fast-forward tokens.
+    pub token_log_probs: Vec<f32>,
+    /// Sum of `token_log_probs`.
+    pub log_prob: f32,
+    /// False when the path hit `max_tokens` before the grammar finished.
+    pub complete: bool,
+}
+
+/// Result of a constrained beam search.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BeamSearchResult {
+    /// Surviving paths: complete ones first, then those cut off by
+    /// `max_tokens`, each group best first.
+    pub paths: Vec<ScoredPath>,
+}
+
+/// One approved response scored as a whole, used to rank every candidate
+/// instead of reporting only the greedy pick.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RankedResponse {
+    pub category_name: String,
+    /// The full response: `Category: {name}\n\n{message}`.
+    pub text: String,
+    pub token_count: usize,
+    /// Total log-probability of the model emitting `text` right after the prompt.
+    pub log_prob: f32,
+    /// `log_prob / token_count`, comparable across responses of different lengths.
+    pub mean_log_prob: f32,
+    /// Total log-probability with the embedding logit biases w(v) applied.
+    pub adjusted_log_prob: f32,
 }
 
 /// A single test case result streamed during a bulk test run.
@@ -68,6 +153,8 @@ pub enum BulkTestEvent {
     },
     /// All test cases have finished.
     Done { total: usize, success_count: usize },
+    /// The run was cancelled; `completed` test cases finished before it stopped.
+    Cancelled { completed: usize, success_count: usize },
     /// A fatal error aborted the bulk test.
     Error { message: String },
 }
@@ -0,0 +1,250 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use llama_cpp_2::{
+    llama_backend::LlamaBackend,
+    model::{LlamaModel, params::LlamaModelParams},
+};
+use llguidance::toktrie::TokenizerEnv;
+
+use crate::embedding::LlamaEmbedder;
+use crate::engine::InferenceConfig;
+use crate::inference::{ContinuationScore, LlamaLlm, Llm, MultiSequenceLlm, ScoringLlm};
+use crate::llama_tokenizer::LlamaTokenizerEnv;
+use crate::token::{Canidates, TokenID};
+
+/// Tokenizer shared by every request: the grammar's `TokenizerEnv` plus decoding.
+pub trait Tokenizer: TokenizerEnv + Sync + 'static {
+    fn tokens_to_string(&self, tokens: &[TokenID]) -> String;
+}
+
+/// A model the engine can run. The engine builds one tokenizer at start-up and
+/// asks for a fresh `Llm` context per request.
+pub trait Backend: Send + Sync + 'static {
+    type Llm: MultiSequenceLlm + ScoringLlm;
+    type Tokenizer: Tokenizer;
+
+    /// Build the tokenizer. `special_tokens` come from the chat template and
+    /// must each be a single token in the vocabulary.
+    fn tokenizer(&self, special_tokens: &[&str]) -> Self::Tokenizer;
+
+    /// Jinja source of the model's chat template, when the model ships one.
+    fn chat_template_metadata(&self) -> Option<String>;
+
+    /// A new context that has already consumed `initial_tokens` (the system
+    /// prefix). See `LlamaLlm::new` for `context_size` and `n_seq_max`.
+    fn new_llm(
+        &self,
+        initial_tokens: &[TokenID],
+        context_size: u32,
+        n_seq_max: u32,
+    ) -> anyhow::Result<Self::Llm>;
+}
+
+// ---------------------------------------------------------------------------
+// llama.cpp — the production backend
+// ---------------------------------------------------------------------------
+
+pub struct LlamaCppBackend {
+    backend: Arc<LlamaBackend>,
+    model: Arc<LlamaModel>,
+    context_cache_dir: PathBuf,
+    pool: Arc<ContextPool>,
+}
+
+impl LlamaCppBackend {
+    /// Load the GGUF model at `config.model_path`. Blocking.
+    pub fn load(config: &InferenceConfig) -> anyhow::Result<Self> {
+        Self::load_with(Arc::new(LlamaBackend::init()?), config)
+    }
+
+    /// Load another GGUF model on the same llama.cpp backend, which can only
+    /// be initialised once per process. Blocking.
+    pub fn load_model(&self, config: &InferenceConfig) -> anyhow::Result<Self> {
+        Self::load_with(Arc::clone(&self.backend), config)
+    }
+
+    fn load_with(backend: Arc<LlamaBackend>, config: &InferenceConfig) -> anyhow::Result<Self> {
+        let model_params = LlamaModelParams::default();
+        let model = LlamaModel::load_from_file(&backend, &config.model_path, &model_params)?;
+
+        Ok(Self {
+            backend,
+            model: Arc::new(model),
+            context_cache_dir: config.context_cache_dir.clone(),
+            pool: Arc::new(ContextPool {
+                idle: Mutex::new(VecDeque::new()),
+                capacity: config.warm_contexts,
+            }),
+        })
+    }
+
+    /// Load a GGUF embedding model alongside the generation model. Blocking.
+    pub fn load_embedder(&self, model_path: &Path) -> anyhow::Result<LlamaEmbedder> {
+        LlamaEmbedder::load(Arc::clone(&self.backend), model_path)
+    }
+}
+
+impl Backend for LlamaCppBackend {
+    type Llm = PooledLlm;
+    type Tokenizer = LlamaTokenizerEnv;
+
+    fn tokenizer(&self, special_tokens: &[&str]) -> LlamaTokenizerEnv {
+        LlamaTokenizerEnv::new(self.model.clone(), special_tokens)
+    }
+
+    fn chat_template_metadata(&self) -> Option<String> {
+        self.model.meta_val_str("tokenizer.chat_template").ok()
+    }
+
+    /// Reuses a warm context holding the same system prefix when one is
+    /// idle; otherwise builds one (loading the prefix from the session file
+    /// cache when it exists).
+    fn new_llm(
+        &self,
+        initial_tokens: &[TokenID],
+        context_size: u32,
+        n_seq_max: u32,
+    ) -> anyhow::Result<PooledLlm> {
+        let key = (initial_tokens.to_vec(), n_seq_max);
+        let llm = match self.pool.take(&key) {
+            Some(mut llm) => {
+                // Drop the previous request's turns; the prefix stays resident.
+                llm.truncate(initial_tokens.len());
+                llm
+            }
+            None => LlamaLlm::new(
+                &self.backend,
+                self.model.clone(),
+                initial_tokens,
+                &self.context_cache_dir,
+                context_size,
+                n_seq_max,
+            )?,
+        };
+        Ok(PooledLlm {
+            llm: Some(llm),
+            key,
+            pool: Arc::clone(&self.pool),
+        })
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Warm context pool — contexts go back to the pool when a request finishes,
+// with the system prefix still in sequence 0 of their KV cache
+// ---------------------------------------------------------------------------
+
+/// System prefix tokens and `n_seq_max` a context was built for.
+type PoolKey = (Vec<TokenID>, u32);
+
+struct ContextPool {
+    /// Most recently returned first.
+    idle: Mutex<VecDeque<(PoolKey, IdleContext)>>,
+    /// Idle contexts kept at most; each holds a full KV cache.
+    capacity: usize,
+}
+
+/// SAFETY: llama.cpp contexts are not thread-safe, which is why `LlamaContext`
+/// is not `Send`, but they are not tied to the thread that created them
+/// either. An idle context is only reachable through the pool's mutex and is
+/// moved out before use, so exactly one thread ever touches it at a time.
+struct IdleContext(LlamaLlm);
+unsafe impl Send for IdleContext {}
+
+impl ContextPool {
+    fn take(&self, key: &PoolKey) -> Option<LlamaLlm> {
+        let mut idle = self.idle.lock().unwrap();
+        let i = idle.iter().position(|(k, _)| k == key)?;
+        idle.remove(i).map(|(_, IdleContext(llm))| llm)
+    }
+
+    fn put(&self, key: PoolKey, llm: LlamaLlm) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        idle.push_front((key, IdleContext(llm)));
+        // Evicted contexts are dropped outside the lock.
+        let keep = idle.len().min(self.capacity);
+        let evicted = idle.split_off(keep);
+        drop(idle);
+        drop(evicted);
+    }
+}
+
+/// A pooled context on loan to one request. Returned to the pool on drop,
+/// unless the thread is panicking.
+pub struct PooledLlm {
+    llm: Option<LlamaLlm>,
+    key: PoolKey,
+    pool: Arc<ContextPool>,
+}
+
+impl PooledLlm {
+    fn llm(&mut self) -> &mut LlamaLlm {
+        self.llm
+            .as_mut()
+            .expect("context already returned to the pool")
+    }
+}
+
+impl Drop for PooledLlm {
+    fn drop(&mut self) {
+        // A panic may have interrupted a decode, leaving the KV cache in an
+        // unknown state; such a context is dropped rather than reused.
+        if std::thread::panicking() {
+            return;
+        }
+        if let Some(llm) = self.llm.take() {
+            self.pool.put(std::mem::take(&mut self.key), llm);
+        }
+    }
+}
+
+impl Llm for PooledLlm {
+    fn get_canidates(&mut self) -> Canidates {
+        self.llm().get_canidates()
+    }
+
+    fn feed_tokens(&mut self, tokens: &[TokenID]) {
+        self.llm().feed_tokens(tokens);
+    }
+
+    fn n_past(&self) -> usize {
+        self.llm.as_ref().map_or(0, |llm| llm.n_past())
+    }
+
+    fn truncate(&mut self, n_past: usize) {
+        self.llm().truncate(n_past);
+    }
+}
+
+impl ScoringLlm for PooledLlm {
+    fn score_continuations(
+        &mut self,
+        continuations: &[Vec<TokenID>],
+        biases: &HashMap<TokenID, f32>,
+    ) -> anyhow::Result<Vec<ContinuationScore>> {
+        self.llm().score_continuations(continuations, biases)
+    }
+}
+
+impl MultiSequenceLlm for PooledLlm {
+    fn fork_sequence(&mut self, seq: u32) {
+        self.llm().fork_sequence(seq);
+    }
+
+    fn feed_sequences(&mut self, feeds: &[(u32, Vec<TokenID>)]) -> anyhow::Result<()> {
+        self.llm().feed_sequences(feeds)
+    }
+
+    fn sequence_canidates(&mut self, seq: u32) -> Canidates {
+        self.llm().sequence_canidates(seq)
+    }
+
+    fn truncate_sequence(&mut self, seq: u32, n_past: usize) {
+        self.llm().truncate_sequence(seq, n_past);
+    }
+}
@@ -0,0 +1,246 @@
+// Prompt formatting for the model families we run. Every template renders the
+// same three pieces: a system prefix (stable per system prompt, so its KV
+// state can be cached to disk), user turns that end by opening the
+// assistant's reply, and the marker that closes an assistant reply.
+
+use std::sync::Arc;
+
+pub trait ChatTemplate: Send + Sync {
+    /// Name used to select the template in configuration.
+    fn name(&self) -> &'static str;
+    /// Markers spliced into prompts that must each be a single special token
+    /// in the vocabulary. They are flagged in the token trie so the grammar
+    /// never matches them as plain text.
+    fn special_tokens(&self) -> &'static [&'static str];
+    /// Everything before the first user message.
+    fn system_prefix(&self, system_prompt: &str) -> String;
+    /// A user message followed by the header that opens the assistant's
+    /// reply. `first` is true for the message directly after the system prefix.
+    fn user_turn(&self, message: &str, first: bool) -> String;
+    /// Closes an assistant reply before the next user turn.
+    fn assistant_end(&self) -> &'static str;
+}
+
+/// Names accepted by `template_by_name`.
+pub const TEMPLATE_NAMES: [&str; 5] = ["llama3", "mistral", "chatml", "gemma", "phi"];
+
+pub fn template_by_name(name: &str) -> Option<Arc<dyn ChatTemplate>> {
+    let template: Arc<dyn ChatTemplate> = match name {
+        "llama3" => Arc::new(Llama3),
+        "mistral" => Arc::new(Mistral),
+        "chatml" => Arc::new(ChatMl),
+        "gemma" => Arc::new(Gemma),
+        "phi" => Arc::new(Phi),
+        _ => return None,
+    };
+    Some(template)
+}
+
+/// Pick a template from the Jinja source in the GGUF `tokenizer.chat_template`
+/// metadata by looking for each family's distinctive markers.
+pub fn detect_template(jinja: &str) -> Option<Arc<dyn ChatTemplate>> {
+    let name = if jinja.contains("<|start_header_id|>") {
+        "llama3"
+    } else if jinja.contains("<|im_start|>") {
+        "chatml"
+    } else if jinja.contains("<start_of_turn>") {
+        "gemma"
+    } else if jinja.contains("<|user|>") && jinja.contains("<|end|>") {
+        "phi"
+    } else if jinja.contains("[INST]") {
+        "mistral"
+    } else {
+        return None;
+    };
+    template_by_name(name)
+}
+
+// ---------------------------------------------------------------------------
+// Llama 3
+// ---------------------------------------------------------------------------
+
+pub struct Llama3;
+
+const ID_START_TOKEN: &str = "<|start_header_id|>";
+const ID_END_TOKEN: &str = "<|end_header_id|>";
+const END_TURN_TOKEN: &str = "<|eot_id|>";
+
+impl ChatTemplate for Llama3 {
+    fn name(&self) -> &'static str {
+        "llama3"
+    }
+
+    fn special_tokens(&self) -> &'static [&'static str] {
+        &[ID_START_TOKEN, ID_END_TOKEN, END_TURN_TOKEN]
+    }
+
+    fn system_prefix(&self, system_prompt: &str) -> String {
+        format!("{ID_START_TOKEN}system{ID_END_TOKEN}{system_prompt}{END_TURN_TOKEN}")
+    }
+
+    fn user_turn(&self, message: &str, _first: bool) -> String {
+        format!(
+            "{ID_START_TOKEN}user{ID_END_TOKEN}{message}{END_TURN_TOKEN}{ID_START_TOKEN}assistant{ID_END_TOKEN}"
+        )
+    }
+
+    fn assistant_end(&self) -> &'static str {
+        END_TURN_TOKEN
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Mistral — no system role, so the system prompt opens the first [INST] block
+// ---------------------------------------------------------------------------
+
+pub struct Mistral;
+
+impl ChatTemplate for Mistral {
+    fn name(&self) -> &'static str {
+        "mistral"
+    }
+
+    // [INST] is plain text in the older SentencePiece vocabularies; only the
+    // end-of-sequence marker is guaranteed to be a single token.
+    fn special_tokens(&self) -> &'static [&'static str] {
+        &["</s>"]
+    }
+
+    fn system_prefix(&self, system_prompt: &str) -> String {
+        format!("[INST] {system_prompt}\n\n")
+    }
+
+    fn user_turn(&self, message: &str, first: bool) -> String {
+        if first {
+            format!("{message} [/INST]")
+        } else {
+            format!("[INST] {message} [/INST]")
+        }
+    }
+
+    fn assistant_end(&self) -> &'static str {
+        "</s>"
+    }
+}
+
+// ---------------------------------------------------------------------------
+// ChatML (Qwen and others)
+// ---------------------------------------------------------------------------
+
+pub struct ChatMl;
+
+impl ChatTemplate for ChatMl {
+    fn name(&self) -> &'static str {
+        "chatml"
+    }
+
+    fn special_tokens(&self) -> &'static [&'static str] {
+        &["<|im_start|>", "<|im_end|>"]
+    }
+
+    fn system_prefix(&self, system_prompt: &str) -> String {
+        format!("<|im_start|>system\n{system_prompt}<|im_end|>\n")
+    }
+
+    fn user_turn(&self, message: &str, _first: bool) -> String {
+        format!("<|im_start|>user\n{message}<|im_end|>\n<|im_start|>assistant\n")
+    }
+
+    fn assistant_end(&self) -> &'static str {
+        "<|im_end|>\n"
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Gemma — no system role, so the system prompt opens the first user turn
+// ---------------------------------------------------------------------------
+
+pub struct Gemma;
+
+impl ChatTemplate for Gemma {
+    fn name(&self) -> &'static str {
+        "gemma"
+    }
+
+    fn special_tokens(&self) -> &'static [&'static str] {
+        &["<start_of_turn>", "<end_of_turn>"]
+    }
+
+    fn system_prefix(&self, system_prompt: &str) -> String {
+        format!("<start_of_turn>user\n{system_prompt}\n\n")
+    }
+
+    fn user_turn(&self, message: &str, first: bool) -> String {
+        let open = if first { "" } else { "<start_of_turn>user\n" };
+        format!("{open}{message}<end_of_turn>\n<start_of_turn>model\n")
+    }
+
+    fn assistant_end(&self) -> &'static str {
+        "<end_of_turn>\n"
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Phi-3
+// ---------------------------------------------------------------------------
+
+pub struct Phi;
+
+impl ChatTemplate for Phi {
+    fn name(&self) -> &'static str {
+        "phi"
+    }
+
+    fn special_tokens(&self) -> &'static [&'static str] {
+        &["<|system|>", "<|user|>", "<|assistant|>", "<|end|>"]
+    }
+
+    fn system_prefix(&self, system_prompt: &str) -> String {
+        format!("<|system|>\n{system_prompt}<|end|>\n")
+    }
+
+    fn user_turn(&self, message: &str, _first: bool) -> String {
+        format!("<|user|>\n{message}<|end|>\n<|assistant|>\n")
+    }
+
+    fn assistant_end(&self) -> &'static str {
+        "<|end|>\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_each_family_from_its_jinja_markers() {
+        let cases = [
+            (
+                "{{ '<|start_header_id|>' + message['role'] + '<|end_header_id|>' }}",
+                "llama3",
+            ),
+            ("{{ '<|im_start|>' + message['role'] + '\n' }}", "chatml"),
+            ("{{ '<start_of_turn>' + role + '\n' }}", "gemma"),
+            (
+                "{{ '<|user|>\n' + message['content'] + '<|end|>\n' }}",
+                "phi",
+            ),
+            (
+                "{{ bos_token + '[INST] ' + message['content'] + ' [/INST]' }}",
+                "mistral",
+            ),
+        ];
+        for (jinja, expected) in cases {
+            assert_eq!(detect_template(jinja).map(|t| t.name()), Some(expected));
+        }
+        assert!(detect_template("{{ message['content'] }}").is_none());
+    }
+
+    #[test]
+    fn every_name_resolves_to_its_template() {
+        for name in TEMPLATE_NAMES {
+            assert_eq!(template_by_name(name).map(|t| t.name()), Some(name));
+        }
+        assert!(template_by_name("vicuna").is_none());
+    }
+}
@@ -1,73 +1,136 @@
 use anyhow::{Context, Result};
-use csv::ReaderBuilder;
+use csv::{ReaderBuilder, StringRecord};
 use serde::Deserialize;
 use std::fs::File;
 
 use crate::grammar::VCmessage;
 
-#[derive(Debug, Deserialize)]
-struct PemazyreRow {
-    #[serde(rename = "Category")]
-    category: String,
-    #[serde(rename = "Kind")]
-    kind: String,
-    #[serde(rename = "Description")]
-    description: String,
-    #[serde(rename = "VC Agent Message")]
-    mlr_message: String,
-    #[serde(rename = "VC Agent Message - URLs")]
-    message: String,
+/// Header names of the columns holding each `VCmessage` field. Defaults to
+/// the Pemazyre export's headers. `category` and `message` are required; the
+/// other columns may be absent and are then read as empty.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CsvColumns {
+    pub category: String,
+    pub kind: String,
+    pub description: String,
+    pub mlr_message: String,
+    pub message: String,
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        Self {
+            category: "Category".to_string(),
+            kind: "Kind".to_string(),
+            description: "Description".to_string(),
+            mlr_message: "VC Agent Message".to_string(),
+            message: "VC Agent Message - URLs".to_string(),
+        }
+    }
+}
+
+/// A `VCmessage` from raw fields, cleaned as the marketing database's rows
+/// are: every field is trimmed and `{{conversation_continuer}}` tags are
+/// removed from both messages. `None` when the message is empty or `N/A`.
+pub fn clean_vc_message(
+    category: &str,
+    kind: &str,
+    description: &str,
+    mlr_message: &str,
+    message: &str,
+) -> Option<VCmessage> {
+    let clean = |s: &str| {
+        s.replace("{{conversation_continuer}}", "")
+            .trim()
+            .to_string()
+    };
+    let message = clean(message);
+    if message.is_empty() || message == "N/A" {
+        return None;
+    }
+    Some(VCmessage {
+        category: category.trim().to_string(),
+        kind: kind.trim().to_string(),
+        description: description.trim().to_string(),
+        mlr_message: clean(mlr_message),
+        message,
+    })
 }
 
 pub fn load_pemazyre_responses(csv_path: &str) -> Result<Vec<VCmessage>> {
+    let loaded = load_vc_messages_csv(csv_path, b'\t', &CsvColumns::default())?;
+    for error in &loaded.parse_errors {
+        eprintln!("Warning: Skipping {}", error);
+    }
+    println!(
+        "Loaded {} valid messages, skipped {} rows",
+        loaded.messages.len(),
+        loaded.skipped
+    );
+    Ok(loaded.messages)
+}
+
+/// Messages read by `load_vc_messages_csv`, and what it skipped.
+pub struct CsvMessages {
+    pub messages: Vec<VCmessage>,
+    /// Rows skipped, unparseable ones included.
+    pub skipped: usize,
+    /// Why each unparseable row was skipped, with its line number.
+    pub parse_errors: Vec<String>,
+}
+
+/// Load VC messages from a delimited file with a header row, cleaned by
+/// `clean_vc_message`; rows it rejects are skipped. Fails if no row is left.
+pub fn load_vc_messages_csv(
+    csv_path: &str,
+    delimiter: u8,
+    columns: &CsvColumns,
+) -> Result<CsvMessages> {
     let file =
         File::open(csv_path).with_context(|| format!("Failed to open CSV file: {}", csv_path))?;
 
     let mut reader = ReaderBuilder::new()
-        .delimiter(b'\t')
+        .delimiter(delimiter)
         .flexible(true)
         .from_reader(file);
 
+    let headers = reader.headers()?.clone();
+    let position = |name: &str| headers.iter().position(|h| h.trim() == name);
+    let required =
+        |name: &str| position(name).with_context(|| format!("{csv_path} has no {name:?} column"));
+    let category_idx = required(&columns.category)?;
+    let message_idx = required(&columns.message)?;
+    let kind_idx = position(&columns.kind);
+    let description_idx = position(&columns.description);
+    let mlr_message_idx = position(&columns.mlr_message);
+
+    let field = |record: &StringRecord, idx: Option<usize>| {
+        idx.and_then(|i| record.get(i))
+            .unwrap_or_default()
+            .to_string()
+    };
+
     let mut vc_messages = Vec::new();
     let mut skipped_count = 0;
+    let mut parse_errors = Vec::new();
 
-    for (idx, result) in reader.deserialize().enumerate() {
+    for (idx, result) in reader.records().enumerate() {
         match result {
             Ok(record) => {
-                let record: PemazyreRow = record;
-
-                if record.message == "N/A" || record.message.trim().is_empty() {
-                    skipped_count += 1;
-                    continue;
+                match clean_vc_message(
+                    &field(&record, Some(category_idx)),
+                    &field(&record, kind_idx),
+                    &field(&record, description_idx),
+                    &field(&record, mlr_message_idx),
+                    &field(&record, Some(message_idx)),
+                ) {
+                    Some(message) => vc_messages.push(message),
+                    None => skipped_count += 1,
                 }
-
-                let clean_message = record
-                    .message
-                    .trim()
-                    .replace("{{conversation_continuer}}", "")
-                    .trim()
-                    .to_string();
-                let clean_mlr_message = record
-                    .mlr_message
-                    .trim()
-                    .replace("{{conversation_continuer}}", "")
-                    .trim()
-                    .to_string();
-
-                vc_messages.push(VCmessage {
-                    category: record.category.trim().to_string(),
-                    kind: record.kind.trim().to_string(),
-                    description: record.description.trim().to_string(),
-                    mlr_message: clean_mlr_message,
-                    message: clean_message,
-                });
             }
             Err(e) => {
-                eprintln!(
-                    "Warning: Skipping row {} due to parse error: {}",
-                    idx + 2,
-                    e
-                );
+                parse_errors.push(format!("row {} due to parse error: {}", idx + 2, e));
                 skipped_count += 1;
                 continue;
             }
@@ -78,13 +141,11 @@ pub fn load_pemazyre_responses(csv_path: &str) -> Result<Vec<VCmessage>> {
         anyhow::bail!("No valid messages found in CSV file");
     }
 
-    println!(
-        "Loaded {} valid messages, skipped {} rows",
-        vc_messages.len(),
-        skipped_count
-    );
-
-    Ok(vc_messages)
+    Ok(CsvMessages {
+        messages: vc_messages,
+        skipped: skipped_count,
+        parse_errors,
+    })
 }
 
 #[cfg(test)]
@@ -101,4 +162,31 @@ mod tests {
             println!("{}: {:?}", i, msg);
         }
     }
+
+    #[test]
+    fn custom_columns_and_missing_optional_fields() {
+        let path = std::env::temp_dir().join(format!("vc_messages_{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "Topic,Text\nSafety,\"See the label {{conversation_continuer}}\"\nDosing,N/A\n",
+        )
+        .unwrap();
+        let columns = CsvColumns {
+            category: "Topic".to_string(),
+            message: "Text".to_string(),
+            ..CsvColumns::default()
+        };
+
+        let loaded = load_vc_messages_csv(path.to_str().unwrap(), b',', &columns).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.skipped, 1);
+        assert!(loaded.parse_errors.is_empty());
+        let messages = loaded.messages;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].category, "Safety");
+        assert_eq!(messages[0].message, "See the label");
+        assert_eq!(messages[0].mlr_message, "");
+        assert_eq!(messages[0].kind, "");
+    }
 }
@@ -0,0 +1,115 @@
+// In-process sentence embeddings from a GGUF embedding model (e.g. nomic-embed,
+// bge, gte) through llama.cpp's embedding mode, for hosts that cannot reach a
+// hosted embeddings API.
+
+use std::num::NonZero;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use llama_cpp_2::{
+    context::params::LlamaContextParams,
+    llama_backend::LlamaBackend,
+    llama_batch::LlamaBatch,
+    model::{AddBos, LlamaModel, params::LlamaModelParams},
+};
+
+/// Sequences one llama.cpp context can hold (`LLAMA_MAX_SEQ`).
+const MAX_SEQUENCES: usize = 64;
+
+pub struct LlamaEmbedder {
+    backend: Arc<LlamaBackend>,
+    model: LlamaModel,
+    /// Tokens per decode; longer inputs are truncated to this length.
+    context_size: u32,
+}
+
+impl LlamaEmbedder {
+    /// Load the embedding model at `model_path`. Blocking.
+    ///
+    /// llama.cpp's backend can only be initialised once per process, so the
+    /// embedder shares the one the generation model was loaded with.
+    pub(crate) fn load(backend: Arc<LlamaBackend>, model_path: &Path) -> anyhow::Result<Self> {
+        let model = LlamaModel::load_from_file(&backend, model_path, &LlamaModelParams::default())
+            .with_context(|| format!("failed to load embedding model {}", model_path.display()))?;
+        let context_size = model.n_ctx_train().clamp(1, 8192);
+        Ok(Self {
+            backend,
+            model,
+            context_size,
+        })
+    }
+
+    /// Length of every returned vector.
+    pub fn dimensions(&self) -> usize {
+        self.model.n_embd() as usize
+    }
+
+    /// One L2-normalised embedding per text, in input order. Blocking.
+    ///
+    /// Texts are decoded several at a time, one sequence each, as many as fit
+    /// the context at the longest text's length; the model's own pooling
+    /// (mean, CLS, …) produces each vector.
+    pub fn embed(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let max_tokens = self.context_size as usize;
+        let tokenized = texts
+            .iter()
+            .map(|text| {
+                let mut tokens = self.model.str_to_token(text, AddBos::Always)?;
+                tokens.truncate(max_tokens);
+                Ok(tokens)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // The KV cache is not unified, so llama.cpp gives each sequence
+        // `n_ctx / n_seq_max` cells. Size every sequence for the longest text
+        // and decode as many texts together as fit in `context_size`, one
+        // sequence each.
+        let sequence_cells = tokenized.iter().map(Vec::len).max().unwrap_or(0).max(1);
+        let n_seq_max = (max_tokens / sequence_cells).clamp(1, MAX_SEQUENCES);
+        let n_ctx = (sequence_cells * n_seq_max) as u32;
+
+        // Non-causal embedding models need a whole sequence in one ubatch.
+        let ctx_params = LlamaContextParams::default()
+            .with_n_ctx(Some(NonZero::new(n_ctx).unwrap()))
+            .with_n_batch(n_ctx)
+            .with_n_ubatch(n_ctx)
+            .with_n_seq_max(n_seq_max as u32)
+            .with_embeddings(true);
+        let mut ctx = self
+            .model
+            .new_context(&self.backend, ctx_params)
+            .context("failed to create embedding context")?;
+        let mut batch = LlamaBatch::new(n_ctx as usize, n_seq_max as i32);
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for group in tokenized.chunks(n_seq_max) {
+            ctx.clear_kv_cache();
+            batch.clear();
+            for (seq, tokens) in group.iter().enumerate() {
+                for (pos, &token) in tokens.iter().enumerate() {
+                    batch.add(token, pos as i32, &[seq as i32], pos + 1 == tokens.len())?;
+                }
+            }
+            ctx.decode(&mut batch).context("embedding decode failed")?;
+            for seq in 0..group.len() {
+                let embedding = ctx
+                    .embeddings_seq_ith(seq as i32)
+                    .context("model produced no pooled embedding")?;
+                embeddings.push(l2_normalize(embedding));
+            }
+        }
+        Ok(embeddings)
+    }
+}
+
+fn l2_normalize(v: &[f32]) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return v.to_vec();
+    }
+    v.iter().map(|x| x / norm).collect()
+}
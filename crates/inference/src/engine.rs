@@ -1,211 +1,478 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
 
-use llama_cpp_2::{
-    llama_backend::LlamaBackend,
-    model::{Special, params::LlamaModelParams, LlamaModel},
-};
+use llguidance::Constraint;
 use llguidance::toktrie::TokenizerEnv;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
 
+use crate::backend::{Backend, LlamaCppBackend, Tokenizer};
 use crate::constraints::new_default_constraint;
-use crate::grammar::GrammarFlow;
-use crate::inference::{LlamaLlm, Llm};
-use crate::llama_tokenizer::{END_TURN_TOKEN, ID_END_TOKEN, ID_START_TOKEN, LlamaTokenizerEnv};
-use crate::token::TokenID;
-use inference_types::{CategoryTopToken, InferenceEvent, StepCandidates, TokenWithProb};
+use crate::embedding::LlamaEmbedder;
+use crate::grammar::{GrammarFlow, response_text};
+use crate::inference::{Llm, MultiSequenceLlm, ScoringLlm, SequenceLlm};
+use crate::chat_template::{ChatTemplate, TEMPLATE_NAMES, detect_template, template_by_name};
+use crate::sampling::{Sampler, new_sampler};
+use crate::scoring;
+use crate::token::{Canidate, TokenID};
+use inference_types::{
+    BeamSearchResult, CategoryTopToken, InferenceEvent, RankedResponse, SamplerSettings,
+    ScoredPath, StepCandidates, StepThroughState, TokenWithProb,
+};
 
 /// Scaling constant and category name used to adjust token logits based on
 /// embedding cosine similarity between the user message and VC message examples.
 pub struct CategoryBias {
     /// The name of the VC message category (e.g. "referral").
     pub category_name: String,
-    /// Pre-scaled margin: kappa * (max_pos_similarity - max_neg_similarity).
-    pub weighted_margin: f32,
+    /// Scaling constant for the embedding margin.
+    pub kappa: f32,
     /// Raw margin before kappa multiplication: max_pos_similarity - max_neg_similarity.
     /// Stored in CategoryTopToken.sim_score for per-category weight optimization.
     pub sim_score: f32,
+    /// Learned additive prior for the category, independent of the prompt.
+    /// Corrects categories the model over- or under-chooses.
+    pub logit_offset: f32,
+}
+
+/// Widest beam `beam_search` accepts. Each critical point expands every
+/// beam into up to this many candidates, so the work grows with its square.
+pub const MAX_BEAM_WIDTH: usize = 32;
+
+/// A completed exchange replayed into the context before the current user turn.
+#[derive(Clone)]
+pub struct ConversationTurn {
+    /// The HCP message.
+    pub user: String,
+    /// The VC response the grammar produced for it.
+    pub assistant: String,
 }
 
 #[derive(Clone)]
 pub struct InferenceConfig {
     pub model_path: PathBuf,
     pub context_cache_dir: PathBuf,
+    /// KV cache capacity of each sequence in tokens. Older conversation turns
+    /// are dropped when the system prompt, history, user turn and longest
+    /// approved response would not fit.
+    pub context_size: u32,
+    /// Idle llama contexts kept with their system prompt resident, so later
+    /// requests with the same system prompt skip reloading it. Each holds
+    /// `context_size` KV cells per sequence; 0 disables reuse.
+    pub warm_contexts: usize,
+    /// Requests `generate_many` decodes at once, each in its own sequence of
+    /// one shared context. Every sequence gets its own `context_size` cells,
+    /// so that context's KV cache is `parallel_sequences + 1` times as large.
+    pub parallel_sequences: usize,
     pub max_tokens: usize,
     pub top_candidate_count: usize,
+    /// Sampling strategy used when a request does not specify its own.
+    pub sampler: SamplerSettings,
+    /// Chat template name (see `chat_template::TEMPLATE_NAMES`). `None` picks
+    /// one from the model's `tokenizer.chat_template` GGUF metadata.
+    pub chat_template: Option<String>,
 }
 
-struct InferenceEngineInner {
-    backend: LlamaBackend,
-    model: Arc<LlamaModel>,
+/// Everything needed to decode one prompt.
+pub struct GenerationRequest {
+    pub prompt: String,
+    pub grammar_flow: GrammarFlow,
+    /// Per-category embedding similarity margins used to adjust token logits
+    /// toward contextually relevant message categories.
+    pub category_biases: Vec<CategoryBias>,
+    /// Learned logit temperature T the biases were fitted at. Category
+    /// biases are multiplied by T, which ranks tokens exactly as dividing the
+    /// raw logits by T would while leaving the recorded logits raw. 1.0 leaves
+    /// the biases unchanged.
+    pub logit_temperature: f32,
+    /// Overrides `InferenceConfig::sampler` for this request.
+    pub sampler: Option<SamplerSettings>,
+    /// Earlier turns of the conversation, oldest first. Empty for a single-turn request.
+    pub history: Vec<ConversationTurn>,
+    /// Checked before every critical point of `generate` and `generate_many`;
+    /// once cancelled, the request's stream ends with `InferenceEvent::Cancelled`.
+    pub cancel: CancellationToken,
+}
+
+struct InferenceEngineInner<B: Backend> {
+    backend: B,
+    /// Shared by every request; also the grammar's `TokenizerEnv`.
+    tokenizer: Arc<B::Tokenizer>,
+    chat_template: Arc<dyn ChatTemplate>,
     config: InferenceConfig,
 }
 
 /// The main inference engine. Cheap to clone — internally reference-counted.
-#[derive(Clone)]
-pub struct InferenceEngine(Arc<InferenceEngineInner>);
+///
+/// Generic over its `Backend` so decoding can run against a scripted model in
+/// tests; production code uses the llama.cpp default.
+pub struct InferenceEngine<B: Backend = LlamaCppBackend>(Arc<InferenceEngineInner<B>>);
+
+impl<B: Backend> Clone for InferenceEngine<B> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
 
 impl InferenceEngine {
     /// Load the model. Blocking — call from `tokio::task::spawn_blocking` or
     /// before the async runtime starts.
     pub fn new(config: InferenceConfig) -> anyhow::Result<Self> {
-        let backend = LlamaBackend::init()?;
-        let model_params = LlamaModelParams::default();
-        let model = LlamaModel::load_from_file(&backend, &config.model_path, &model_params)?;
-        let model = Arc::new(model);
+        let backend = LlamaCppBackend::load(&config)?;
+        Self::with_backend(backend, config)
+    }
+
+    /// Load another generation model that shares this engine's llama.cpp
+    /// backend. Blocking.
+    ///
+    /// The context cache is keyed by prompt tokens only, so `config` should
+    /// point at a cache directory of its own.
+    pub fn load_model(&self, config: InferenceConfig) -> anyhow::Result<Self> {
+        let backend = self.0.backend.load_model(&config)?;
+        Self::with_backend(backend, config)
+    }
+
+    /// Load a GGUF embedding model that shares this engine's llama.cpp
+    /// backend. Blocking.
+    pub fn load_embedder(&self, model_path: &Path) -> anyhow::Result<LlamaEmbedder> {
+        self.0.backend.load_embedder(model_path)
+    }
+}
+
+impl<B: Backend> InferenceEngine<B> {
+    /// Build an engine around an already-loaded backend.
+    pub fn with_backend(backend: B, config: InferenceConfig) -> anyhow::Result<Self> {
+        let chat_template = match &config.chat_template {
+            Some(name) => template_by_name(name).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unknown chat template {name:?}; expected one of {}",
+                    TEMPLATE_NAMES.join(", ")
+                )
+            })?,
+            None => {
+                let jinja = backend.chat_template_metadata().ok_or_else(|| {
+                    anyhow::anyhow!("model has no tokenizer.chat_template metadata; configure a chat template explicitly")
+                })?;
+                detect_template(&jinja).ok_or_else(|| {
+                    anyhow::anyhow!("unrecognised tokenizer.chat_template; configure a chat template explicitly")
+                })?
+            }
+        };
 
+        let tokenizer = Arc::new(backend.tokenizer(chat_template.special_tokens()));
         Ok(Self(Arc::new(InferenceEngineInner {
             backend,
-            model,
+            tokenizer,
+            chat_template,
             config,
         })))
     }
 
-    /// Start generating tokens for `prompt` using the provided `grammar_flow`.
-    /// `category_biases` contains per-category embedding similarity margins used
-    /// to adjust token logits toward contextually relevant message categories.
+    /// The configuration the engine was built with.
+    pub fn config(&self) -> &InferenceConfig {
+        &self.0.config
+    }
+
+    /// Name of the chat template in use, whether configured or detected
+    /// from the model's metadata.
+    pub fn chat_template_name(&self) -> &'static str {
+        self.0.chat_template.name()
+    }
+
+    /// Start generating tokens for `request.prompt` using its `grammar_flow`.
     /// Returns immediately; generation runs on a blocking thread pool thread.
     /// Events are sent until `InferenceEvent::Done` or `InferenceEvent::Error`.
-    pub async fn generate(
+    pub async fn generate(&self, request: GenerationRequest) -> mpsc::Receiver<InferenceEvent> {
+        let (tx, rx) = mpsc::channel(64);
+        let inner = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || {
+            run_generation_blocking(&inner, &request, tx);
+        });
+        rx
+    }
+
+    /// Decode a stream of requests that share one system prompt, up to
+    /// `config.parallel_sequences` at a time: every sequence's next tokens go
+    /// through a single batched decode. Events are tagged with the request's
+    /// position in the stream; each request ends with `Done` or `Error`.
+    /// Requests whose system prompt differs from the first one's are rejected
+    /// with an `Error`.
+    pub async fn generate_many(
         &self,
-        prompt: String,
-        grammar_flow: GrammarFlow,
-        category_biases: Vec<CategoryBias>,
-    ) -> mpsc::Receiver<InferenceEvent> {
+        requests: mpsc::Receiver<GenerationRequest>,
+    ) -> mpsc::Receiver<(usize, InferenceEvent)> {
         let (tx, rx) = mpsc::channel(64);
         let inner = Arc::clone(&self.0);
         tokio::task::spawn_blocking(move || {
-            run_generation_blocking(&inner, prompt, grammar_flow, category_biases, tx);
+            run_many_blocking(&inner, requests, tx);
         });
         rx
     }
+
+    /// Start an interactive step-through session for `request`. The LLM context
+    /// and grammar constraint stay alive on a dedicated blocking thread until
+    /// every `StepSession` handle is dropped.
+    ///
+    /// Returns the handle together with the initial state, whose `pending`
+    /// step holds the candidates at the first critical point.
+    pub async fn start_step_session(
+        &self,
+        request: GenerationRequest,
+    ) -> anyhow::Result<(StepSession, StepThroughState)> {
+        let (commands_tx, commands_rx) = mpsc::channel(1);
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let inner = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || {
+            run_step_session_blocking(&inner, &request, ready_tx, commands_rx);
+        });
+        let initial = ready_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("step-through session exited before starting"))??;
+        Ok((StepSession { commands: commands_tx }, initial))
+    }
+
+    /// Score complete responses instead of committing greedily: keep the
+    /// `beam_width` most likely partial paths at every critical point until
+    /// each has finished the grammar (or `max_tokens` critical points passed).
+    /// `request.sampler` is ignored. `beam_width` must be at most
+    /// `MAX_BEAM_WIDTH`.
+    pub async fn beam_search(
+        &self,
+        request: GenerationRequest,
+        beam_width: usize,
+    ) -> anyhow::Result<BeamSearchResult> {
+        anyhow::ensure!(
+            beam_width <= MAX_BEAM_WIDTH,
+            "beam width {beam_width} exceeds the maximum of {MAX_BEAM_WIDTH}"
+        );
+        let inner = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || {
+            let mut decoder = Decoder::new(&inner, &request, 1)?;
+            decoder.beam_search(beam_width, inner.config.max_tokens)
+        })
+        .await?
+    }
+
+    /// Score every approved response in `request.grammar_flow` as a whole
+    /// continuation of the prompt and return them best first (by total
+    /// log-probability). `request.sampler` is ignored. Fails if a response
+    /// does not fit in the context after the prompt.
+    pub async fn rank_responses(
+        &self,
+        request: GenerationRequest,
+    ) -> anyhow::Result<Vec<RankedResponse>> {
+        let inner = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || {
+            let mut decoder = Decoder::new(&inner, &request, 1)?;
+            decoder.rank_responses(&request.grammar_flow)
+        })
+        .await?
+    }
 }
 
 // ---------------------------------------------------------------------------
-// Blocking generation — runs on the tokio blocking thread pool
+// Decoder — per-request decoding state shared by streaming generation and
+// interactive step-through sessions
 // ---------------------------------------------------------------------------
 
-fn run_generation_blocking(
-    inner: &InferenceEngineInner,
-    prompt: String,
-    grammar_flow: GrammarFlow,
-    category_biases: Vec<CategoryBias>,
-    tx: mpsc::Sender<InferenceEvent>,
-) {
-    // Build per-request tokenizer (just wraps Arc<LlamaModel>, cheap)
-    let tokenizer = Arc::new(LlamaTokenizerEnv::new(inner.model.clone()));
+struct Decoder<B: Backend, L: Llm = <B as Backend>::Llm> {
+    tokenizer: Arc<B::Tokenizer>,
+    llm: L,
+    constraint: Constraint,
+    /// Tokens the grammar forced at the end of the prompt, before the first
+    /// critical point.
+    grammar_prefix: Vec<TokenID>,
+    logit_bias_map: HashMap<TokenID, f32>,
+    category_info: Vec<(String, String)>,
+    category_token_ids: Vec<Vec<TokenID>>,
+    category_sim_scores: HashMap<String, f32>,
+    sampler: Box<dyn Sampler>,
+    top_candidate_count: usize,
+    full_output: String,
+    cancel: CancellationToken,
+}
 
-    // Precompute per-token logit bias map, category name/text pairs, and
-    // per-category token ID lists for per-step "best token per category" lookup.
-    let (logit_bias_map, category_info, category_token_ids) =
-        build_logit_bias_map(&category_biases, &tokenizer, &inner.model);
+impl<B: Backend> Decoder<B> {
+    /// Build the LLM context, feed the system prompt (cached to disk), the
+    /// conversation history and the user turn, and leave the decoder
+    /// positioned at the first critical point. Fails if the context cannot be
+    /// built.
+    fn new(
+        inner: &InferenceEngineInner<B>,
+        request: &GenerationRequest,
+        n_seq_max: u32,
+    ) -> anyhow::Result<Self> {
+        let system_prefix = inner
+            .chat_template
+            .system_prefix(&request.grammar_flow.system_prompt);
+        let initial_tokens = inner.tokenizer.tokenize(&system_prefix);
+        let llm = inner
+            .backend
+            .new_llm(&initial_tokens, inner.config.context_size, n_seq_max)?;
+        Ok(Self::with_llm(inner, request, llm))
+    }
+}
 
-    // Build a map of category_name → raw sim_score for populating CategoryTopToken.
-    let category_sim_scores: HashMap<String, f32> = category_biases
-        .iter()
-        .map(|b| (b.category_name.clone(), b.sim_score))
-        .collect();
+impl<B: Backend, L: Llm> Decoder<B, L> {
+    /// Like `new`, on an `llm` that already holds the system prefix.
+    fn with_llm(inner: &InferenceEngineInner<B>, request: &GenerationRequest, mut llm: L) -> Self {
+        let GenerationRequest {
+            prompt,
+            grammar_flow,
+            category_biases,
+            logit_temperature,
+            sampler,
+            history,
+            cancel,
+        } = request;
 
-    // Tokenize the system prompt and build the LLM context (cached to disk)
-    let system_prompt = grammar_flow.get_system_prompt();
-    let initial_tokens: Vec<_> = tokenizer.tokenize(&system_prompt);
-    let mut llm = LlamaLlm::new(
-        &inner.backend,
-        inner.model.clone(),
-        &initial_tokens,
-        &inner.config.context_cache_dir,
-    );
+        let template = &inner.chat_template;
 
-    // Build a fresh constraint for this request
-    let tok_env: Arc<dyn TokenizerEnv + Sync + 'static> =
-        Arc::new(LlamaTokenizerEnv::new(inner.model.clone()));
-    let mut constraint = new_default_constraint(&grammar_flow, &tok_env);
+        let tokenizer = Arc::clone(&inner.tokenizer);
 
-    // Process the grammar prompt prefix (may return tokens the LLM should see first)
-    let prefix_tokens = constraint.process_prompt(vec![]);
-    let prefix_text = tokenizer.tokens_to_string(&prefix_tokens);
-    llm.feed_tokens(&prefix_tokens);
+        // Precompute per-token logit bias map, category name/text pairs, and
+        // per-category token ID lists for per-step "best token per category" lookup.
+        let (logit_bias_map, category_info, category_token_ids) =
+            build_logit_bias_map(category_biases, *logit_temperature, tokenizer.as_ref());
 
-    // Format the user turn including any prefix from the grammar
-    let user_turn = format!(
-        "{ID_START_TOKEN}user{ID_END_TOKEN}{prompt}{END_TURN_TOKEN}{ID_START_TOKEN}assistant{ID_END_TOKEN}{prefix_text}"
-    );
-    let user_tokens: Vec<_> = tokenizer.tokenize(&user_turn);
-    llm.feed_tokens(&user_tokens);
+        // Build a map of category_name → raw sim_score for populating CategoryTopToken.
+        let category_sim_scores: HashMap<String, f32> = category_biases
+            .iter()
+            .map(|b| (b.category_name.clone(), b.sim_score))
+            .collect();
 
-    let mut full_output = String::new();
+        // Build a fresh constraint for this request
+        let tok_env: Arc<dyn TokenizerEnv + Sync + 'static> = tokenizer.clone();
+        let mut constraint = new_default_constraint(grammar_flow, &tok_env);
 
-    for _ in 0..inner.config.max_tokens {
-        let mut candidates = llm.get_canidates();
+        // Process the grammar prompt prefix (may return tokens the LLM should see first)
+        let prefix_tokens = constraint.process_prompt(vec![]);
+        let prefix_text = tokenizer.tokens_to_string(&prefix_tokens);
+        llm.feed_tokens(&prefix_tokens);
+
+        // Format the user turn including any prefix from the grammar
+        let user_turn = |first: bool| format!("{}{prefix_text}", template.user_turn(prompt, first));
+        let completed_turn = |turn: &ConversationTurn, first: bool| {
+            format!(
+                "{}{}{}",
+                template.user_turn(&turn.user, first),
+                turn.assistant,
+                template.assistant_end()
+            )
+        };
+        let user_tokens: Vec<_> = tokenizer.tokenize(&user_turn(false));
+
+        // Replay as many of the most recent earlier turns as fit, leaving room
+        // for the current turn and the longest response the grammar allows.
+        // Budgeting uses the non-first rendering, which is never shorter.
+        let longest_response = grammar_flow
+            .vc_messages
+            .iter()
+            .map(|m| tokenizer.tokenize(&response_text(m)).len())
+            .max()
+            .unwrap_or(0);
+        let mut budget = (inner.config.context_size as usize)
+            .saturating_sub(llm.n_past() + user_tokens.len() + longest_response);
+        let mut kept = 0;
+        for turn in history.iter().rev() {
+            let len = tokenizer.tokenize(&completed_turn(turn, false)).len();
+            if len > budget {
+                break;
+            }
+            budget -= len;
+            kept += 1;
+        }
+        let kept_turns = &history[history.len() - kept..];
+        for (i, turn) in kept_turns.iter().enumerate() {
+            llm.feed_tokens(&tokenizer.tokenize(&completed_turn(turn, i == 0)));
+        }
+
+        llm.feed_tokens(&tokenizer.tokenize(&user_turn(kept_turns.is_empty())));
+
+        Self {
+            tokenizer,
+            llm,
+            constraint,
+            grammar_prefix: prefix_tokens,
+            logit_bias_map,
+            category_info,
+            category_token_ids,
+            category_sim_scores,
+            sampler: new_sampler(sampler.as_ref().unwrap_or(&inner.config.sampler)),
+            top_candidate_count: inner.config.top_candidate_count,
+            full_output: String::new(),
+            cancel: cancel.clone(),
+        }
+    }
+
+    fn token_with_prob(&self, c: &Canidate) -> TokenWithProb {
+        TokenWithProb {
+            text: self.tokenizer.tokens_to_string(&[c.token_id]),
+            token_id: c.token_id,
+            probability: c.probability,
+            logit: c.logit,
+            embedding_logit: c.embedding_logit,
+        }
+    }
+
+    /// Compute the candidates at the next critical point; `chosen` is the
+    /// sampler's proposal. Returns `None` when the grammar is complete (or
+    /// nothing is allowed).
+    fn critical_point(&mut self) -> anyhow::Result<Option<StepCandidates>> {
+        let mut candidates = self.llm.get_canidates();
 
         // Apply embedding-based logit biases before any top_n sampling.
-        candidates.apply_biases(&logit_bias_map);
+        candidates.apply_biases(&self.logit_bias_map);
 
         // Capture top-N before applying the grammar mask (using adjusted logits)
         let top_alternatives: Vec<TokenWithProb> = candidates
-            .top_n(inner.config.top_candidate_count)
+            .top_n(self.top_candidate_count)
             .iter()
-            .map(|c| TokenWithProb {
-                text: tokenizer.tokens_to_string(&[c.token_id]),
-                token_id: c.token_id,
-                probability: c.probability,
-                logit: c.logit,
-                embedding_logit: c.embedding_logit,
-            })
+            .map(|c| self.token_with_prob(c))
             .collect();
 
         // Compute and apply the grammar mask
-        let mask = match constraint.compute_mask() {
-            Ok(m) => m,
-            Err(e) => {
-                let _ = tx.blocking_send(InferenceEvent::Error {
-                    message: e.to_string(),
-                });
-                return;
-            }
-        };
-        let sample_mask = match &mask.sample_mask {
-            Some(m) => m,
-            None => {
-                let _ = tx.blocking_send(InferenceEvent::Done {
-                    full_text: full_output,
-                });
-                return;
-            }
+        let mask = self.constraint.compute_mask()?;
+        let Some(sample_mask) = &mask.sample_mask else {
+            return Ok(None);
         };
         candidates.constrain(sample_mask);
 
         // Top-N after mask (adjusted logits)
         let top_constrained: Vec<TokenWithProb> = candidates
-            .top_n(inner.config.top_candidate_count)
+            .top_n(self.top_candidate_count)
             .iter()
-            .map(|c| TokenWithProb {
-                text: tokenizer.tokens_to_string(&[c.token_id]),
-                token_id: c.token_id,
-                probability: c.probability,
-                logit: c.logit,
-                embedding_logit: c.embedding_logit,
-            })
+            .map(|c| self.token_with_prob(c))
             .collect();
 
-        let chosen = match top_constrained.first() {
-            Some(c) => c.clone(),
+        let Some(chosen_id) = self.sampler.sample(&candidates) else {
+            return Ok(None);
+        };
+        // A random sampler may pick a token outside the displayed top-N; its
+        // probability is then reported as 0 since it has no share of that subset.
+        let chosen = match top_constrained.iter().find(|t| t.token_id == chosen_id) {
+            Some(t) => t.clone(),
             None => {
-                let _ = tx.blocking_send(InferenceEvent::Done {
-                    full_text: full_output,
-                });
-                return;
+                let c = candidates
+                    .get_by_id(chosen_id)
+                    .expect("sampler returned a token that is not a candidate");
+                TokenWithProb {
+                    probability: 0.0,
+                    ..self.token_with_prob(c)
+                }
             }
         };
-        let chosen_token_id = chosen.token_id;
 
         // For every category find the best-scoring prefix token using the full
         // pre-mask candidate list (O(1) per token via the HashMap index).
         // This shows all categories, not just those whose tokens happen to be in top-N.
-        let category_top_tokens: Vec<CategoryTopToken> = category_info
+        let category_top_tokens: Vec<CategoryTopToken> = self
+            .category_info
             .iter()
-            .zip(category_token_ids.iter())
+            .zip(self.category_token_ids.iter())
             .filter_map(|((cat_name, _), token_ids)| {
                 token_ids
                     .iter()
@@ -217,14 +484,9 @@ fn run_generation_blocking(
                     })
                     .map(|best| CategoryTopToken {
                         category_name: cat_name.clone(),
-                        best_token: TokenWithProb {
-                            text: tokenizer.tokens_to_string(&[best.token_id]),
-                            token_id: best.token_id,
-                            probability: best.probability,
-                            logit: best.logit,
-                            embedding_logit: best.embedding_logit,
-                        },
-                        sim_score: category_sim_scores
+                        best_token: self.token_with_prob(best),
+                        sim_score: self
+                            .category_sim_scores
                             .get(cat_name.as_str())
                             .copied()
                             .unwrap_or(0.0),
@@ -232,89 +494,619 @@ fn run_generation_blocking(
             })
             .collect();
 
-        if tx
-            .blocking_send(InferenceEvent::Token(StepCandidates {
-                chosen,
-                top_alternatives,
-                top_constrained,
-                category_top_tokens,
-            }))
-            .is_err()
-        {
-            return; // receiver dropped (client disconnected)
-        }
-
-        // Commit chosen token to the constraint
-        let commit = match constraint.commit_token(Some(chosen_token_id)) {
-            Ok(r) => r,
-            Err(e) => {
-                let _ = tx.blocking_send(InferenceEvent::Error {
-                    message: e.to_string(),
-                });
-                return;
-            }
-        };
+        Ok(Some(StepCandidates {
+            chosen,
+            top_alternatives,
+            top_constrained,
+            category_top_tokens,
+        }))
+    }
+
+    /// Commit `token_id` at the current critical point, followed by any
+    /// grammar-forced fast-forward tokens, and feed them all to the LLM.
+    ///
+    /// Returns one `StepCandidates` per fast-forward token (excluding the
+    /// committed token itself) and whether the grammar has finished.
+    fn commit(&mut self, token_id: TokenID) -> anyhow::Result<(Vec<StepCandidates>, bool)> {
+        let commit = self.constraint.commit_token(Some(token_id))?;
         let ff_tokens = commit.ff_tokens;
 
-        // Emit Token events for grammar-forced fast-forward tokens (ff_tokens[0]
-        // is the chosen token already sent above; start from index 1).
-        // These are not sampled so they carry probability=1.0 and no alternatives.
+        // Grammar-forced fast-forward tokens (ff_tokens[0] is the committed token
+        // itself; start from index 1). These are not sampled so they carry
+        // probability=1.0 and no alternatives.
+        let mut ff_steps = Vec::new();
         let mut generation_done = false;
         for &ff_id in ff_tokens.iter().skip(1) {
-            let ff_text = tokenizer.tokens_to_string(&[ff_id]);
             let ff_token = TokenWithProb {
-                text: ff_text,
+                text: self.tokenizer.tokens_to_string(&[ff_id]),
                 token_id: ff_id,
                 probability: 1.0,
                 logit: 0.0,
                 embedding_logit: 0.0,
             };
-            if tx
-                .blocking_send(InferenceEvent::Token(StepCandidates {
-                    chosen: ff_token.clone(),
-                    top_alternatives: vec![],
-                    top_constrained: vec![ff_token],
-                    category_top_tokens: vec![],
-                }))
-                .is_err()
-            {
-                return;
+            ff_steps.push(StepCandidates {
+                chosen: ff_token.clone(),
+                top_alternatives: vec![],
+                top_constrained: vec![ff_token],
+                category_top_tokens: vec![],
+            });
+            let r = self.constraint.commit_token(Some(ff_id))?;
+            if r.stop {
+                generation_done = true;
+                break;
             }
-            match constraint.commit_token(Some(ff_id)) {
-                Ok(r) if r.stop => {
-                    generation_done = true;
-                    break;
+        }
+
+        // Feed all committed tokens to the LLM KV cache
+        if ff_tokens.is_empty() {
+            self.llm.feed_tokens(&[token_id]);
+            self.full_output += &self.tokenizer.tokens_to_string(&[token_id]);
+        } else {
+            self.llm.feed_tokens(&ff_tokens);
+            self.full_output += &self.tokenizer.tokens_to_string(&ff_tokens);
+        }
+
+        Ok((ff_steps, generation_done))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Blocking generation — runs on the tokio blocking thread pool
+// ---------------------------------------------------------------------------
+
+fn run_generation_blocking<B: Backend>(
+    inner: &InferenceEngineInner<B>,
+    request: &GenerationRequest,
+    tx: mpsc::Sender<InferenceEvent>,
+) {
+    let mut send = |event| tx.blocking_send(event).is_ok();
+    let mut decoder = match Decoder::new(inner, request, 1) {
+        Ok(decoder) => decoder,
+        Err(e) => {
+            send(InferenceEvent::Error {
+                message: e.to_string(),
+            });
+            return;
+        }
+    };
+
+    for _ in 0..inner.config.max_tokens {
+        match generation_step(&mut decoder, &mut send) {
+            StepOutcome::Continue => {}
+            StepOutcome::Finished => break,
+            StepOutcome::Aborted => return,
+        }
+    }
+
+    send(InferenceEvent::Done {
+        full_text: decoder.full_output,
+    });
+}
+
+enum StepOutcome {
+    Continue,
+    Finished,
+    /// An error or cancellation was reported, or the receiver dropped (client
+    /// disconnected); no `Done` event follows.
+    Aborted,
+}
+
+/// Sample and commit one critical point plus its fast-forward tokens,
+/// passing each step to `send`, which returns false once the receiver is gone.
+fn generation_step<B: Backend, L: Llm>(
+    decoder: &mut Decoder<B, L>,
+    send: &mut impl FnMut(InferenceEvent) -> bool,
+) -> StepOutcome {
+    if decoder.cancel.is_cancelled() {
+        send(InferenceEvent::Cancelled);
+        return StepOutcome::Aborted;
+    }
+
+    let step = match decoder.critical_point() {
+        Ok(Some(s)) => s,
+        Ok(None) => return StepOutcome::Finished,
+        Err(e) => {
+            send(InferenceEvent::Error {
+                message: e.to_string(),
+            });
+            return StepOutcome::Aborted;
+        }
+    };
+    let chosen_token_id = step.chosen.token_id;
+
+    if !send(InferenceEvent::Token(step)) {
+        return StepOutcome::Aborted;
+    }
+
+    let (ff_steps, generation_done) = match decoder.commit(chosen_token_id) {
+        Ok(r) => r,
+        Err(e) => {
+            send(InferenceEvent::Error {
+                message: e.to_string(),
+            });
+            return StepOutcome::Aborted;
+        }
+    };
+    for step in ff_steps {
+        if !send(InferenceEvent::Token(step)) {
+            return StepOutcome::Aborted;
+        }
+    }
+
+    if generation_done {
+        StepOutcome::Finished
+    } else {
+        StepOutcome::Continue
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Batched generation — one context whose sequence 0 holds the shared system
+// prefix; each in-flight request decodes in a sequence forked from it, and
+// the decoders advance in lockstep so their tokens share one decode per round
+// ---------------------------------------------------------------------------
+
+struct InFlight<B: Backend> {
+    index: usize,
+    decoder: Decoder<B, SequenceLlm<B::Llm>>,
+    critical_points: usize,
+}
+
+fn run_many_blocking<B: Backend>(
+    inner: &InferenceEngineInner<B>,
+    mut requests: mpsc::Receiver<GenerationRequest>,
+    tx: mpsc::Sender<(usize, InferenceEvent)>,
+) {
+    let Some(first) = requests.blocking_recv() else {
+        return;
+    };
+    let system_prompt = first.grammar_flow.system_prompt.clone();
+    let initial_tokens = inner
+        .tokenizer
+        .tokenize(&inner.chat_template.system_prefix(&system_prompt));
+    let parallel = inner.config.parallel_sequences.max(1);
+    let shared = match inner.backend.new_llm(
+        &initial_tokens,
+        inner.config.context_size,
+        parallel as u32 + 1,
+    ) {
+        Ok(llm) => Rc::new(RefCell::new(llm)),
+        Err(e) => {
+            // No request can run without the shared context; fail each one
+            // as it arrives.
+            let mut index = 0;
+            loop {
+                let error = InferenceEvent::Error {
+                    message: e.to_string(),
+                };
+                if tx.blocking_send((index, error)).is_err() || requests.blocking_recv().is_none() {
+                    return;
                 }
-                Ok(_) => {}
-                Err(e) => {
-                    let _ = tx.blocking_send(InferenceEvent::Error {
+                index += 1;
+            }
+        }
+    };
+
+    // Slot i decodes in sequence i + 1.
+    let mut slots: Vec<Option<InFlight<B>>> = (0..parallel).map(|_| None).collect();
+    let mut queued = Some(first);
+    let mut next_index = 0;
+
+    loop {
+        // Fill free slots, waiting for a request only when nothing is in flight.
+        while let Some(free) = slots.iter().position(Option::is_none) {
+            let request = match queued.take() {
+                Some(r) => r,
+                None if slots.iter().all(Option::is_none) => match requests.blocking_recv() {
+                    Some(r) => r,
+                    None => break,
+                },
+                None => match requests.try_recv() {
+                    Ok(r) => r,
+                    Err(_) => break,
+                },
+            };
+            let index = next_index;
+            next_index += 1;
+
+            if request.grammar_flow.system_prompt != system_prompt {
+                let error = InferenceEvent::Error {
+                    message: "request does not share the batch's system prompt".to_string(),
+                };
+                if tx.blocking_send((index, error)).is_err() {
+                    return;
+                }
+                continue;
+            }
+            let llm = SequenceLlm::fork(&shared, free as u32 + 1);
+            slots[free] = Some(InFlight {
+                index,
+                decoder: Decoder::with_llm(inner, &request, llm),
+                critical_points: 0,
+            });
+        }
+        if slots.iter().all(Option::is_none) {
+            return;
+        }
+
+        let feeds: Vec<(u32, Vec<TokenID>)> = slots
+            .iter_mut()
+            .flatten()
+            .map(|f| (f.decoder.llm.seq(), f.decoder.llm.take_pending()))
+            .filter(|(_, tokens)| !tokens.is_empty())
+            .collect();
+        let fed = shared.borrow_mut().feed_sequences(&feeds);
+        if let Err(e) = fed {
+            // The sequences' caches are now in an unknown state; fail every
+            // request in flight rather than decode on from it.
+            for slot in &mut slots {
+                if let Some(f) = slot.take() {
+                    let error = InferenceEvent::Error {
                         message: e.to_string(),
-                    });
+                    };
+                    if tx.blocking_send((f.index, error)).is_err() {
+                        return;
+                    }
+                }
+            }
+            continue;
+        }
+
+        for slot in &mut slots {
+            let Some(f) = slot else {
+                continue;
+            };
+            let index = f.index;
+            let outcome = generation_step(&mut f.decoder, &mut |event| {
+                tx.blocking_send((index, event)).is_ok()
+            });
+            f.critical_points += 1;
+
+            let finished = match outcome {
+                StepOutcome::Continue => f.critical_points >= inner.config.max_tokens,
+                StepOutcome::Finished => true,
+                StepOutcome::Aborted if tx.is_closed() => return,
+                StepOutcome::Aborted => {
+                    *slot = None;
+                    continue;
+                }
+            };
+            if finished {
+                let done = InferenceEvent::Done {
+                    full_text: std::mem::take(&mut f.decoder.full_output),
+                };
+                if tx.blocking_send((index, done)).is_err() {
                     return;
                 }
+                // Dropping the decoder frees its sequence.
+                *slot = None;
             }
         }
+    }
+}
 
-        // Feed all committed tokens to the LLM KV cache
-        if ff_tokens.is_empty() {
-            llm.feed_tokens(&[chosen_token_id]);
-            full_output += &tokenizer.tokens_to_string(&[chosen_token_id]);
-        } else {
-            llm.feed_tokens(&ff_tokens);
-            full_output += &tokenizer.tokens_to_string(&ff_tokens);
+// ---------------------------------------------------------------------------
+// Interactive step-through — one blocking thread owns the decoder for the
+// lifetime of the session and serves advance commands over a channel
+// ---------------------------------------------------------------------------
+
+/// Why a step-through advance was rejected.
+#[derive(Debug, thiserror::Error)]
+pub enum StepError {
+    /// The forced token is not among the pending step's `top_constrained` candidates.
+    #[error("token {0} is not a constrained candidate at this step")]
+    TokenNotAllowed(TokenID),
+    /// Generation has already finished; there is nothing left to advance.
+    #[error("step-through session has already finished")]
+    Finished,
+    /// The session thread has exited (e.g. after a previous engine error).
+    #[error("step-through session is closed")]
+    Closed,
+    #[error(transparent)]
+    Engine(#[from] anyhow::Error),
+}
+
+struct StepCommand {
+    force_token: Option<TokenID>,
+    reply: oneshot::Sender<Result<StepThroughState, StepError>>,
+}
+
+/// Handle to a stateful decoding session that keeps its LLM context and
+/// grammar constraint alive between calls. Cheap to clone; the session ends
+/// when the last handle is dropped.
+#[derive(Clone)]
+pub struct StepSession {
+    commands: mpsc::Sender<StepCommand>,
+}
+
+impl StepSession {
+    /// Commit one critical point. Commits `force_token` if given (it must be
+    /// one of the pending step's `top_constrained` candidates), otherwise the
+    /// sampler's proposal, plus any fast-forward tokens that follow.
+    pub async fn advance(&self, force_token: Option<TokenID>) -> Result<StepThroughState, StepError> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(StepCommand { force_token, reply })
+            .await
+            .map_err(|_| StepError::Closed)?;
+        rx.await.map_err(|_| StepError::Closed)?
+    }
+}
+
+fn run_step_session_blocking<B: Backend>(
+    inner: &InferenceEngineInner<B>,
+    request: &GenerationRequest,
+    ready: oneshot::Sender<anyhow::Result<StepThroughState>>,
+    mut commands: mpsc::Receiver<StepCommand>,
+) {
+    let mut decoder = match Decoder::new(inner, request, 1) {
+        Ok(decoder) => decoder,
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
         }
+    };
+    let mut pending = match decoder.critical_point() {
+        Ok(p) => p,
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(StepThroughState {
+        committed: vec![],
+        pending: pending.clone(),
+        full_text: decoder.full_output.clone(),
+    }));
 
-        if generation_done {
-            let _ = tx.blocking_send(InferenceEvent::Done {
-                full_text: full_output,
+    let mut critical_points = 0;
+    while let Some(cmd) = commands.blocking_recv() {
+        let result = advance_step(
+            &mut decoder,
+            &mut pending,
+            cmd.force_token,
+            &mut critical_points,
+            inner.config.max_tokens,
+        );
+        let failed = matches!(result, Err(StepError::Engine(_)));
+        let _ = cmd.reply.send(result);
+        if failed {
+            return; // the constraint is in an unknown state; end the session
+        }
+    }
+}
+
+fn advance_step<B: Backend>(
+    decoder: &mut Decoder<B>,
+    pending: &mut Option<StepCandidates>,
+    force_token: Option<TokenID>,
+    critical_points: &mut usize,
+    max_tokens: usize,
+) -> Result<StepThroughState, StepError> {
+    let Some(mut step) = pending.take() else {
+        return Err(StepError::Finished);
+    };
+
+    if let Some(token_id) = force_token {
+        let Some(forced) = step.top_constrained.iter().find(|t| t.token_id == token_id) else {
+            *pending = Some(step);
+            return Err(StepError::TokenNotAllowed(token_id));
+        };
+        step.chosen = forced.clone();
+    }
+
+    let (ff_steps, generation_done) = decoder.commit(step.chosen.token_id)?;
+    *critical_points += 1;
+
+    let mut committed = vec![step];
+    committed.extend(ff_steps);
+
+    if !generation_done && *critical_points < max_tokens {
+        *pending = decoder.critical_point()?;
+    }
+
+    Ok(StepThroughState {
+        committed,
+        pending: pending.clone(),
+        full_text: decoder.full_output.clone(),
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Beam search — each beam forks the grammar constraint; the single LLM
+// context is rewound to the shared prompt and re-fed the beam's divergent
+// suffix before its logits are read
+// ---------------------------------------------------------------------------
+
+struct Beam {
+    constraint: Constraint,
+    tokens: Vec<TokenID>,
+    token_log_probs: Vec<f32>,
+    log_prob: f32,
+    complete: bool,
+}
+
+impl<B: Backend, L: Llm> Decoder<B, L> {
+    fn beam_search(
+        &mut self,
+        beam_width: usize,
+        max_tokens: usize,
+    ) -> anyhow::Result<BeamSearchResult> {
+        let beam_width = beam_width.max(1);
+        let root_position = self.llm.n_past();
+        let mut fed: Vec<TokenID> = Vec::new();
+        let mut beams = vec![Beam {
+            constraint: self.constraint.deep_clone(),
+            tokens: vec![],
+            token_log_probs: vec![],
+            log_prob: 0.0,
+            complete: false,
+        }];
+
+        for _ in 0..max_tokens {
+            if beams.iter().all(|b| b.complete) {
+                break;
+            }
+
+            let mut next = Vec::with_capacity(beam_width * beam_width);
+            for mut beam in beams {
+                if beam.complete {
+                    next.push(beam);
+                    continue;
+                }
+
+                let mask = beam.constraint.compute_mask()?;
+                let Some(sample_mask) = &mask.sample_mask else {
+                    beam.complete = true;
+                    next.push(beam);
+                    continue;
+                };
+
+                self.seek(root_position, &mut fed, &beam.tokens);
+                let mut candidates = self.llm.get_canidates();
+                candidates.apply_biases(&self.logit_bias_map);
+                candidates.constrain(sample_mask);
+
+                // Normalise over every allowed token, not just the expanded ones,
+                // so path scores are true constrained log-probabilities.
+                let max_logit = candidates
+                    .iter()
+                    .map(|c| c.logit + c.embedding_logit)
+                    .fold(f32::NEG_INFINITY, f32::max);
+                let log_norm = max_logit
+                    + candidates
+                        .iter()
+                        .map(|c| (c.logit + c.embedding_logit - max_logit).exp())
+                        .sum::<f32>()
+                        .ln();
+
+                for c in candidates.iter().take(beam_width) {
+                    let token_log_prob = c.logit + c.embedding_logit - log_norm;
+                    let mut constraint = beam.constraint.deep_clone();
+                    let commit = constraint.commit_token(Some(c.token_id))?;
+
+                    // ff_tokens[0] is the committed token itself; the rest are
+                    // grammar-forced and cost nothing.
+                    let committed = if commit.ff_tokens.is_empty() {
+                        vec![c.token_id]
+                    } else {
+                        commit.ff_tokens
+                    };
+                    let mut token_log_probs = beam.token_log_probs.clone();
+                    token_log_probs.push(token_log_prob);
+                    token_log_probs.resize(token_log_probs.len() + committed.len() - 1, 0.0);
+                    let mut tokens = beam.tokens.clone();
+                    tokens.extend(committed);
+
+                    next.push(Beam {
+                        constraint,
+                        tokens,
+                        token_log_probs,
+                        log_prob: beam.log_prob + token_log_prob,
+                        complete: commit.stop,
+                    });
+                }
+            }
+
+            // Extending a path can only lower its score, so a complete beam is
+            // never overtaken by the descendants of a beam ranked below it.
+            next.sort_by(|a, b| {
+                b.log_prob
+                    .total_cmp(&a.log_prob)
+                    .then(b.complete.cmp(&a.complete))
             });
+            next.truncate(beam_width);
+            beams = next;
+        }
+
+        // Beams cut off by `max_tokens` may outscore finished ones only
+        // because they are shorter, so they go last.
+        beams.sort_by(|a, b| {
+            b.complete
+                .cmp(&a.complete)
+                .then(b.log_prob.total_cmp(&a.log_prob))
+        });
+        let paths = beams
+            .into_iter()
+            .map(|b| ScoredPath {
+                text: self.tokenizer.tokens_to_string(&b.tokens),
+                token_ids: b.tokens,
+                token_log_probs: b.token_log_probs,
+                log_prob: b.log_prob,
+                complete: b.complete,
+            })
+            .collect();
+        Ok(BeamSearchResult { paths })
+    }
+
+    /// Leave the LLM context holding the prompt followed by `tokens`, with
+    /// logits at its last position. `fed` tracks what currently follows the
+    /// prompt so only the divergent suffix is re-decoded.
+    fn seek(&mut self, root_position: usize, fed: &mut Vec<TokenID>, tokens: &[TokenID]) {
+        if fed.as_slice() == tokens {
             return;
         }
+        let mut common = fed.iter().zip(tokens).take_while(|(a, b)| a == b).count();
+        if common == tokens.len() {
+            // `tokens` is a strict prefix of what was fed: re-decode its last
+            // token to refresh the logits.
+            common -= 1;
+        }
+        self.llm.truncate(root_position + common);
+        self.llm.feed_tokens(&tokens[common..]);
+        fed.clear();
+        fed.extend_from_slice(tokens);
     }
+}
 
-    let _ = tx.blocking_send(InferenceEvent::Done {
-        full_text: full_output,
-    });
+// ---------------------------------------------------------------------------
+// Full-sequence ranking
+// ---------------------------------------------------------------------------
+
+impl<B: Backend, L: ScoringLlm> Decoder<B, L> {
+    fn rank_responses(&mut self, grammar_flow: &GrammarFlow) -> anyhow::Result<Vec<RankedResponse>> {
+        let continuations: Vec<Vec<TokenID>> = grammar_flow
+            .vc_messages
+            .iter()
+            .map(|m| self.response_tokens(&response_text(m)))
+            .collect();
+        let scores = self
+            .llm
+            .score_continuations(&continuations, &self.logit_bias_map)?;
+
+        let mut ranked: Vec<RankedResponse> = grammar_flow
+            .vc_messages
+            .iter()
+            .zip(continuations.iter().zip(scores))
+            .map(|(m, (tokens, score))| {
+                let log_prob: f32 = score.log_probs.iter().sum();
+                RankedResponse {
+                    category_name: m.category.clone(),
+                    text: response_text(m),
+                    token_count: tokens.len(),
+                    log_prob,
+                    mean_log_prob: log_prob / tokens.len().max(1) as f32,
+                    adjusted_log_prob: score.adjusted_log_probs.iter().sum(),
+                }
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.log_prob.total_cmp(&a.log_prob));
+        Ok(ranked)
+    }
+
+    /// The tokens generation emits for `response` after the grammar prefix
+    /// already in the prompt, split where tokenizing the whole response
+    /// splits them. If the prefix tokens do not start that tokenization, the
+    /// rest of the text is tokenized on its own.
+    fn response_tokens(&self, response: &str) -> Vec<TokenID> {
+        let tokens = self.tokenizer.tokenize(response);
+        if let Some(rest) = tokens.strip_prefix(self.grammar_prefix.as_slice()) {
+            return rest.to_vec();
+        }
+        let prefix_text = self.tokenizer.tokens_to_string(&self.grammar_prefix);
+        let rest = response.strip_prefix(prefix_text.as_str()).unwrap_or(response);
+        self.tokenizer.tokenize(rest)
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -323,13 +1115,16 @@ fn run_generation_blocking(
 
 /// Build a map from token ID → logit adjustment w(v).
 ///
-/// For each category c with `weighted_margin` = kappa * s_c, the full decoded
-/// category name text is built with a leading space (as it appears mid-generation).
+/// For each category c with kappa k_c, embedding margin s_c and offset o_c, the
+/// full decoded category name text is built with a leading space (as it
+/// appears mid-generation).
 ///
 /// For each vocab token v:
 ///   text_v = decoded text of token v
 ///   C_v = { c | text_v is a non-empty prefix of the full category name text }
-///   w(v) = (1 / |C_v|) * sum_{c in C_v} weighted_margin_c
+///   w(v) = (1 / |C_v|) * sum_{c in C_v} T * (k_c * s_c + o_c)
+/// where T is the request's `logit_temperature` and each term is
+/// `scoring::category_bias`.
 ///
 /// Matching against the full name string (not individual tokenization units) means
 /// every prefix token of a category name receives the bias. For example, if a
@@ -345,8 +1140,8 @@ fn run_generation_blocking(
 ///   Used for O(1)-per-token per-step "best token per category" lookup.
 fn build_logit_bias_map(
     biases: &[CategoryBias],
-    tokenizer: &LlamaTokenizerEnv,
-    model: &LlamaModel,
+    temperature: f32,
+    tokenizer: &impl Tokenizer,
 ) -> (HashMap<TokenID, f32>, Vec<(String, String)>, Vec<Vec<TokenID>>) {
     if biases.is_empty() {
         return (HashMap::new(), vec![], vec![]);
@@ -358,18 +1153,22 @@ fn build_logit_bias_map(
         .map(|b| (b.category_name.clone(), format!(" {}", b.category_name)))
         .collect();
 
-    // (weighted_margin, full_text) — parallel to category_info
+    // (scaled bias, full_text) — parallel to category_info
     let category_texts: Vec<(f32, &str)> = biases
         .iter()
         .zip(category_info.iter())
-        .map(|(b, (_, text))| (b.weighted_margin, text.as_str()))
+        .map(|(b, (_, text))| {
+            (
+                scoring::category_bias(b.kappa, b.sim_score, b.logit_offset, temperature),
+                text.as_str(),
+            )
+        })
         .collect();
 
     let mut bias_map: HashMap<TokenID, f32> = HashMap::new();
     let mut category_token_ids: Vec<Vec<TokenID>> = vec![vec![]; biases.len()];
 
-    for (token, _) in model.tokens(Special::Tokenize) {
-        let vid = token.0 as TokenID;
+    for vid in 0..tokenizer.tok_trie().vocab_size() as TokenID {
         let text_v = tokenizer.tokens_to_string(&[vid]);
         if text_v.is_empty() {
             continue;
@@ -378,9 +1177,9 @@ fn build_logit_bias_map(
         let mut sum = 0.0f32;
         let mut count = 0usize;
 
-        for (i, (weighted_margin, category_text)) in category_texts.iter().enumerate() {
+        for (i, (bias, category_text)) in category_texts.iter().enumerate() {
             if category_text.starts_with(text_v.as_str()) {
-                sum += weighted_margin;
+                sum += bias;
                 count += 1;
                 category_token_ids[i].push(vid);
             }
@@ -393,3 +1192,382 @@ fn build_logit_bias_map(
 
     (bias_map, category_info, category_token_ids)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_template::Llama3;
+    use crate::grammar::VCmessage;
+    use crate::mock::{MockBackend, MockTokenizer};
+
+    const WORDS: &[&str] = &["Category:", " Alpha", " Beta", "\n\n", "one", "two"];
+
+    fn message(category: &str, text: &str) -> VCmessage {
+        VCmessage {
+            category: category.to_string(),
+            kind: String::new(),
+            description: String::new(),
+            mlr_message: text.to_string(),
+            message: text.to_string(),
+        }
+    }
+
+    fn config() -> InferenceConfig {
+        InferenceConfig {
+            model_path: PathBuf::new(),
+            context_cache_dir: PathBuf::new(),
+            context_size: 4096,
+            warm_contexts: 0,
+            parallel_sequences: 2,
+            max_tokens: 64,
+            top_candidate_count: 5,
+            sampler: SamplerSettings::Greedy,
+            chat_template: Some("llama3".to_string()),
+        }
+    }
+
+    /// A model whose logits are the given ones for those tokens and 0 for
+    /// every other token, whatever the context.
+    fn engine_with(
+        preferences: &[(&str, f32)],
+        config: InferenceConfig,
+    ) -> (InferenceEngine<MockBackend>, MockTokenizer) {
+        let tokenizer = MockTokenizer::new(WORDS, Llama3.special_tokens());
+        let mut logits = vec![0.0; tokenizer.vocab_size()];
+        for &(word, logit) in preferences {
+            logits[tokenizer.token_id(word).unwrap() as usize] = logit;
+        }
+        let backend = MockBackend::new(WORDS, move |_| logits.clone());
+        let engine = InferenceEngine::with_backend(backend, config).unwrap();
+        (engine, tokenizer)
+    }
+
+    /// The model mildly prefers " Alpha" over " Beta" and is indifferent to
+    /// everything else.
+    fn engine() -> (InferenceEngine<MockBackend>, MockTokenizer) {
+        engine_with(&[(" Alpha", 2.0), (" Beta", 1.0)], config())
+    }
+
+    fn request(category_biases: Vec<CategoryBias>) -> GenerationRequest {
+        let grammar_flow = GrammarFlow::new(
+            "Brand",
+            &[message("Alpha", "one"), message("Beta", "two")],
+        )
+        .unwrap();
+        GenerationRequest {
+            prompt: "Hello".to_string(),
+            grammar_flow,
+            category_biases,
+            logit_temperature: 1.0,
+            sampler: None,
+            history: vec![],
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    async fn collect(
+        engine: &InferenceEngine<MockBackend>,
+        request: GenerationRequest,
+    ) -> (Vec<StepCandidates>, String) {
+        let mut rx = engine.generate(request).await;
+        let mut steps = vec![];
+        while let Some(event) = rx.recv().await {
+            match event {
+                InferenceEvent::Token(step) => steps.push(step),
+                InferenceEvent::Done { full_text } => return (steps, full_text),
+                InferenceEvent::Error { message } => panic!("generation failed: {message}"),
+                InferenceEvent::Cancelled => panic!("generation was cancelled"),
+            }
+        }
+        panic!("generation ended without a Done event");
+    }
+
+    #[tokio::test]
+    async fn grammar_fast_forwards_once_the_category_is_chosen() {
+        let (engine, _) = engine();
+        let (steps, full_text) = collect(&engine, request(vec![])).await;
+
+        // "Category:" is shared by every response, so the grammar forces it
+        // into the prompt before the first critical point.
+        assert_eq!(full_text, " Alpha\n\none");
+        let chose_alpha = steps
+            .iter()
+            .position(|s| s.chosen.text == " Alpha")
+            .expect("the preferred category is sampled");
+        assert!(!steps[chose_alpha].top_alternatives.is_empty());
+        // Everything after the choice is forced by the grammar.
+        let forced = &steps[chose_alpha + 1..];
+        assert!(!forced.is_empty());
+        for step in forced {
+            assert!(step.top_alternatives.is_empty());
+            assert_eq!(step.chosen.probability, 1.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn category_bias_overrides_the_model_preference() {
+        let (engine, tokenizer) = engine();
+        let bias = CategoryBias {
+            category_name: "Beta".to_string(),
+            kappa: 10.0,
+            sim_score: 0.5,
+            logit_offset: 0.0,
+        };
+        let (steps, full_text) = collect(&engine, request(vec![bias])).await;
+
+        assert_eq!(full_text, " Beta\n\ntwo");
+        let step = steps
+            .iter()
+            .find(|s| s.chosen.text == " Beta")
+            .expect("the biased category is sampled");
+        assert_eq!(step.chosen.token_id, tokenizer.token_id(" Beta").unwrap());
+        assert_eq!(step.chosen.logit, 1.0);
+        assert_eq!(step.chosen.embedding_logit, 5.0);
+    }
+
+    /// The offset applies without any embedding margin, scaled by the
+    /// temperature: 0.6 alone loses to the model's 1-logit preference for
+    /// Alpha, doubled it wins.
+    #[tokio::test]
+    async fn logit_offset_is_scaled_by_temperature() {
+        let (engine, _) = engine();
+        let offset_request = |logit_temperature: f32| GenerationRequest {
+            logit_temperature,
+            ..request(vec![CategoryBias {
+                category_name: "Beta".to_string(),
+                kappa: 0.0,
+                sim_score: 0.0,
+                logit_offset: 0.6,
+            }])
+        };
+
+        let (_, full_text) = collect(&engine, offset_request(1.0)).await;
+        assert_eq!(full_text, " Alpha\n\none");
+
+        let (steps, full_text) = collect(&engine, offset_request(2.0)).await;
+        assert_eq!(full_text, " Beta\n\ntwo");
+        let step = steps.iter().find(|s| s.chosen.text == " Beta").unwrap();
+        assert!((step.chosen.embedding_logit - 1.2).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn generate_many_matches_sequential_generation() {
+        let (engine, _) = engine();
+        // Every other request is biased toward Beta.
+        let biased = [false, true, false, true, false];
+        let request_for = |biased: bool| {
+            let biases = biased.then(|| CategoryBias {
+                category_name: "Beta".to_string(),
+                kappa: 10.0,
+                sim_score: 0.5,
+                logit_offset: 0.0,
+            });
+            request(biases.into_iter().collect())
+        };
+
+        let (requests_tx, requests_rx) = mpsc::channel(biased.len());
+        for &b in &biased {
+            requests_tx.send(request_for(b)).await.unwrap();
+        }
+        drop(requests_tx);
+
+        // More requests than sequences, so slots are reused.
+        let mut rx = engine.generate_many(requests_rx).await;
+        let mut steps = vec![vec![]; biased.len()];
+        let mut full_texts = vec![None; biased.len()];
+        while let Some((index, event)) = rx.recv().await {
+            match event {
+                InferenceEvent::Token(step) => steps[index].push(step),
+                InferenceEvent::Done { full_text } => full_texts[index] = Some(full_text),
+                InferenceEvent::Error { message } => panic!("request {index} failed: {message}"),
+                InferenceEvent::Cancelled => panic!("request {index} was cancelled"),
+            }
+        }
+
+        for (i, &b) in biased.iter().enumerate() {
+            let (expected_steps, expected_text) = collect(&engine, request_for(b)).await;
+            assert_eq!(full_texts[i].as_deref(), Some(expected_text.as_str()));
+            let chosen = |steps: &[StepCandidates]| {
+                steps.iter().map(|s| s.chosen.token_id).collect::<Vec<_>>()
+            };
+            assert_eq!(chosen(&steps[i]), chosen(&expected_steps));
+        }
+    }
+
+    /// Each sequence replays only as much history as its own cells hold, so
+    /// a full batch of long conversations never overflows a sequence.
+    #[tokio::test]
+    async fn batched_histories_fit_in_each_sequence() {
+        let (engine, _) = engine_with(
+            &[(" Alpha", 2.0), (" Beta", 1.0)],
+            InferenceConfig {
+                // The system prefix alone takes about 800 cells.
+                context_size: 1100,
+                ..config()
+            },
+        );
+        let turn = ConversationTurn {
+            user: "Where is my order?".to_string(),
+            assistant: "Category: Alpha\n\none".to_string(),
+        };
+        let (requests_tx, requests_rx) = mpsc::channel(4);
+        for _ in 0..4 {
+            let mut request = request(vec![]);
+            request.history = vec![turn.clone(); 10];
+            requests_tx.send(request).await.unwrap();
+        }
+        drop(requests_tx);
+
+        let mut rx = engine.generate_many(requests_rx).await;
+        let mut full_texts = vec![None; 4];
+        while let Some((index, event)) = rx.recv().await {
+            match event {
+                InferenceEvent::Token(_) => {}
+                InferenceEvent::Done { full_text } => full_texts[index] = Some(full_text),
+                InferenceEvent::Error { message } => panic!("request {index} failed: {message}"),
+                InferenceEvent::Cancelled => panic!("request {index} was cancelled"),
+            }
+        }
+        assert!(full_texts.iter().all(|t| t.as_deref() == Some(" Alpha\n\none")));
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_stop_without_affecting_the_others() {
+        let (engine, _) = engine();
+        let cancelled = request(vec![]);
+        cancelled.cancel.cancel();
+
+        let mut rx = engine.generate(cancelled).await;
+        assert!(matches!(rx.recv().await, Some(InferenceEvent::Cancelled)));
+        assert!(rx.recv().await.is_none());
+
+        let (requests_tx, requests_rx) = mpsc::channel(3);
+        let cancelled = request(vec![]);
+        cancelled.cancel.cancel();
+        requests_tx.send(request(vec![])).await.unwrap();
+        requests_tx.send(cancelled).await.unwrap();
+        requests_tx.send(request(vec![])).await.unwrap();
+        drop(requests_tx);
+
+        let mut rx = engine.generate_many(requests_rx).await;
+        let mut outcomes = [""; 3];
+        while let Some((index, event)) = rx.recv().await {
+            match event {
+                InferenceEvent::Token(_) => assert_ne!(index, 1),
+                InferenceEvent::Done { .. } => outcomes[index] = "done",
+                InferenceEvent::Cancelled => outcomes[index] = "cancelled",
+                InferenceEvent::Error { message } => panic!("request {index} failed: {message}"),
+            }
+        }
+        assert_eq!(outcomes, ["done", "cancelled", "done"]);
+    }
+
+    #[tokio::test]
+    async fn step_session_reports_candidates_and_accepts_forced_tokens() {
+        let (engine, tokenizer) = engine();
+        let biases = vec![
+            CategoryBias {
+                category_name: "Alpha".to_string(),
+                kappa: 0.0,
+                sim_score: 0.1,
+                logit_offset: 0.0,
+            },
+            CategoryBias {
+                category_name: "Beta".to_string(),
+                kappa: 0.0,
+                sim_score: 0.2,
+                logit_offset: 0.0,
+            },
+        ];
+        let (session, mut state) = engine.start_step_session(request(biases)).await.unwrap();
+
+        // Advance past the shared "Category:" prefix to the category choice.
+        let beta = tokenizer.token_id(" Beta").unwrap();
+        let pending = loop {
+            let pending = state.pending.clone().expect("grammar finished before the category");
+            if pending.top_constrained.iter().any(|t| t.token_id == beta) {
+                break pending;
+            }
+            state = session.advance(None).await.unwrap();
+        };
+
+        assert_eq!(pending.chosen.text, " Alpha");
+        let mut allowed: Vec<_> = pending.top_constrained.iter().map(|t| t.text.as_str()).collect();
+        allowed.sort();
+        assert_eq!(allowed, [" ", " Alpha", " Beta"]);
+        let tops: Vec<_> = pending
+            .category_top_tokens
+            .iter()
+            .map(|t| (t.category_name.as_str(), t.best_token.text.as_str(), t.sim_score))
+            .collect();
+        assert_eq!(tops, [("Alpha", " Alpha", 0.1), ("Beta", " Beta", 0.2)]);
+
+        assert!(matches!(
+            session.advance(Some(0)).await,
+            Err(StepError::TokenNotAllowed(0))
+        ));
+        let state = session.advance(Some(beta)).await.unwrap();
+        assert!(state.pending.is_none());
+        assert_eq!(state.full_text, " Beta\n\ntwo");
+        assert!(matches!(session.advance(None).await, Err(StepError::Finished)));
+    }
+
+    #[tokio::test]
+    async fn beam_search_lists_complete_paths_first() {
+        // After "Category:" the grammar allows " Alpha", " Beta" or a bare
+        // " " that needs more critical points; " " then "A" together score
+        // better than " Beta".
+        let preferences = [(" Alpha", 2.0), (" ", 1.5), (" Beta", 1.0), ("A", 5.0)];
+        let (engine, _) = engine_with(&preferences, config());
+        let paths = engine.beam_search(request(vec![]), 3).await.unwrap().paths;
+        assert_eq!(paths[0].text, " Alpha\n\none");
+        assert!(paths.iter().all(|p| p.complete));
+
+        // Cut off after two critical points, the path through " A" has not
+        // reached the end of the grammar yet and goes last despite
+        // outscoring " Beta".
+        let short = InferenceConfig {
+            max_tokens: 2,
+            ..config()
+        };
+        let (engine, _) = engine_with(&preferences, short);
+        let paths = engine.beam_search(request(vec![]), 3).await.unwrap().paths;
+        let summary: Vec<_> = paths.iter().map(|p| (p.text.as_str(), p.complete)).collect();
+        assert_eq!(
+            summary,
+            [
+                (" Alpha\n\none", true),
+                (" Beta\n\ntwo", true),
+                (" Alpha\n\none", false)
+            ]
+        );
+        assert!(paths[2].log_prob > paths[1].log_prob);
+
+        assert!(engine.beam_search(request(vec![]), MAX_BEAM_WIDTH + 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ranking_scores_responses_as_generation_tokenizes_them() {
+        let (engine, _) = engine();
+        let ranked = engine.rank_responses(request(vec![])).await.unwrap();
+        let names: Vec<_> = ranked.iter().map(|r| r.category_name.as_str()).collect();
+        assert_eq!(names, ["Alpha", "Beta"]);
+
+        // The grammar already put "Category:" in the prompt; the rest is
+        // scored in the tokens generation produces.
+        let (steps, _) = collect(&engine, request(vec![])).await;
+        assert_eq!(ranked[0].token_count, steps.len());
+    }
+
+    #[tokio::test]
+    async fn responses_longer_than_the_context_fail_to_rank() {
+        let (engine, _) = engine();
+        let messages = [message("Alpha", "one"), message("Beta", &"two".repeat(3000))];
+        let request = GenerationRequest {
+            grammar_flow: GrammarFlow::new("Brand", &messages).unwrap(),
+            ..request(vec![])
+        };
+        let error = engine.rank_responses(request).await.unwrap_err();
+        assert!(error.to_string().contains("does not fit"), "{error}");
+    }
+}
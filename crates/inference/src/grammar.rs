@@ -1,8 +1,6 @@
 use askama::Template;
 use serde::{Deserialize, Serialize};
 
-use crate::llama_tokenizer::{END_TURN_TOKEN, ID_END_TOKEN, ID_START_TOKEN};
-
 // ---------------------------------------------------------------------------
 // Shared wire type
 // ---------------------------------------------------------------------------
@@ -63,6 +61,13 @@ fn lark_str_escape(s: &str) -> String {
 pub struct GrammarFlow {
     pub system_prompt: String,
     pub lark_grammar: String,
+    /// The messages the grammar was built from, in grammar order.
+    pub vc_messages: Vec<VCmessage>,
+}
+
+/// The exact text the grammar allows the model to emit for `message`.
+pub fn response_text(message: &VCmessage) -> String {
+    format!("Category: {}\n\n{}", message.category, message.message)
 }
 
 impl GrammarFlow {
@@ -73,7 +78,52 @@ impl GrammarFlow {
         }
         .render()
         .map_err(|e| anyhow::anyhow!("failed to render system_prompt template: {e}"))?;
+        Self::with_system_prompt(system_prompt, vc_messages)
+    }
 
+    /// Like `new`, but renders the system prompt from `template` instead of
+    /// the built-in one. `{{ brand_name }}` is replaced by the brand name and
+    /// `{{ messages }}` by the approved-response list; any other `{{ … }}`
+    /// placeholder is an error. The grammar is the same either way.
+    pub fn with_template(
+        brand_name: &str,
+        vc_messages: &[VCmessage],
+        template: &str,
+    ) -> anyhow::Result<Self> {
+        let mut system_prompt = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            system_prompt.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| anyhow::anyhow!("unclosed {{{{ in system prompt template"))?;
+            match rest[start + 2..start + end].trim() {
+                "brand_name" => system_prompt.push_str(brand_name),
+                "messages" => {
+                    for (i, m) in vc_messages.iter().enumerate() {
+                        if i > 0 {
+                            system_prompt.push('\n');
+                        }
+                        system_prompt.push_str(&format!(
+                            "Category: {}\nDescription: {}\nResponse: {}\n",
+                            m.category, m.description, m.message
+                        ));
+                    }
+                }
+                other => {
+                    anyhow::bail!("unknown placeholder {{{{ {other} }}}} in system prompt template")
+                }
+            }
+            rest = &rest[start + end + 2..];
+        }
+        system_prompt.push_str(rest);
+        Self::with_system_prompt(system_prompt, vc_messages)
+    }
+
+    fn with_system_prompt(
+        system_prompt: String,
+        vc_messages: &[VCmessage],
+    ) -> anyhow::Result<Self> {
         // Build one lark string literal per approved response.
         // Each literal encodes the complete output the model should produce:
         //   "Category: {name}\n\n{message text}"
@@ -81,11 +131,7 @@ impl GrammarFlow {
         // characters are emitted as fast-forward tokens.
         let response_literals = vc_messages
             .iter()
-            .map(|m| {
-                let esc_cat = lark_str_escape(&m.category);
-                let esc_msg = lark_str_escape(&m.message);
-                format!("\"Category: {esc_cat}\\n\\n{esc_msg}\"")
-            })
+            .map(|m| format!("\"{}\"", lark_str_escape(&response_text(m))))
             .collect::<Vec<_>>();
 
         let lark_grammar = GrammarTemplate { response_literals }
@@ -95,13 +141,50 @@ impl GrammarFlow {
         Ok(Self {
             system_prompt,
             lark_grammar,
+            vc_messages: vc_messages.to_vec(),
         })
     }
+}
 
-    pub fn get_system_prompt(&self) -> String {
-        format!(
-            "{ID_START_TOKEN}system{ID_END_TOKEN}{}{END_TURN_TOKEN}",
-            self.system_prompt
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(category: &str) -> VCmessage {
+        VCmessage {
+            category: category.to_string(),
+            kind: String::new(),
+            description: format!("{category} questions"),
+            mlr_message: format!("{category} answer"),
+            message: format!("{category} answer"),
+        }
+    }
+
+    #[test]
+    fn template_placeholders_are_filled_and_grammar_is_unchanged() {
+        let messages = [message("Dosing"), message("Safety")];
+        let flow = GrammarFlow::with_template(
+            "Brand",
+            &messages,
+            "About {{brand_name}}:\n{{ messages }}End.",
         )
+        .unwrap();
+
+        assert_eq!(
+            flow.system_prompt,
+            "About Brand:\n\
+             Category: Dosing\nDescription: Dosing questions\nResponse: Dosing answer\n\n\
+             Category: Safety\nDescription: Safety questions\nResponse: Safety answer\nEnd."
+        );
+        let built_in = GrammarFlow::new("Brand", &messages).unwrap();
+        assert_eq!(flow.lark_grammar, built_in.lark_grammar);
+    }
+
+    #[test]
+    fn unknown_or_unclosed_placeholders_are_rejected() {
+        let messages = [message("Dosing")];
+        let unknown = GrammarFlow::with_template("Brand", &messages, "{{ brand }}").err();
+        assert!(unknown.unwrap().to_string().contains("{{ brand }}"));
+        assert!(GrammarFlow::with_template("Brand", &messages, "Hi {{ brand_name").is_err());
     }
 }
@@ -1,13 +1,14 @@
 // manages all the context
 
+use anyhow::Context;
 use llama_cpp_2::{
     context::{params::LlamaContextParams, LlamaContext},
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
     model::LlamaModel,
-    token::LlamaToken,
+    token::{LlamaToken, data::LlamaTokenData},
 };
-use std::{num::NonZero, path::Path, sync::Arc};
+use std::{cell::RefCell, collections::HashMap, num::NonZero, path::Path, rc::Rc, sync::Arc};
 
 use crate::token::Canidate;
 
@@ -16,6 +17,78 @@ use super::token::{Canidates, TokenID};
 pub trait Llm {
     fn get_canidates(&mut self) -> Canidates;
     fn feed_tokens(&mut self, tokens: &[TokenID]);
+    /// Number of tokens currently held in the context.
+    fn n_past(&self) -> usize;
+    /// Drop every token after the first `n_past` from the context, so the next
+    /// `feed_tokens` continues from there. Logits are stale until then.
+    fn truncate(&mut self, n_past: usize);
+}
+
+/// An `Llm` that can score whole continuations of its context.
+pub trait ScoringLlm: Llm {
+    /// Score each continuation as if it were fed after the current context,
+    /// leaving the context unchanged. `biases` are additive logit adjustments
+    /// applied for the adjusted log-probabilities. Fails if a continuation
+    /// does not fit in the context or a decode fails.
+    fn score_continuations(
+        &mut self,
+        continuations: &[Vec<TokenID>],
+        biases: &HashMap<TokenID, f32>,
+    ) -> anyhow::Result<Vec<ContinuationScore>>;
+}
+
+/// An `Llm` that can also hold extra sequences starting from a copy of its
+/// context, so several requests sharing a prompt prefix decode together.
+/// Sequence IDs run from 1 to the context's `n_seq_max - 1`.
+pub trait MultiSequenceLlm: Llm {
+    /// Start sequence `seq` as a copy of the current context.
+    fn fork_sequence(&mut self, seq: u32);
+    /// Append tokens to several sequences in one batched decode. The logits
+    /// after each sequence's last new token become its `sequence_canidates`.
+    /// Fails if a decode fails, e.g. because the KV cache is full; the
+    /// sequences may then hold only part of their new tokens.
+    fn feed_sequences(&mut self, feeds: &[(u32, Vec<TokenID>)]) -> anyhow::Result<()>;
+    /// Candidates after the last `feed_sequences` for `seq`. Each feed's
+    /// candidates can be taken once.
+    fn sequence_canidates(&mut self, seq: u32) -> Canidates;
+    /// Drop every token of `seq` after the first `n_past`; 0 frees the sequence.
+    fn truncate_sequence(&mut self, seq: u32, n_past: usize);
+}
+
+/// Per-token log-probabilities of one continuation, parallel to its tokens.
+pub struct ContinuationScore {
+    /// Under the model's own softmax.
+    pub log_probs: Vec<f32>,
+    /// Under the softmax of bias-adjusted logits (logit + w(v)).
+    pub adjusted_log_probs: Vec<f32>,
+}
+
+/// Raw and bias-adjusted log-probability of `token` given logits indexed by token ID.
+pub(crate) fn token_log_probs(logits: &[f32], token: TokenID, biases: &HashMap<TokenID, f32>) -> (f32, f32) {
+    let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let adjusted_max = biases
+        .iter()
+        .filter_map(|(&v, &w)| logits.get(v as usize).map(|l| l + w))
+        .fold(max_logit, f32::max);
+
+    let (mut sum, mut adjusted_sum) = (0.0f32, 0.0f32);
+    for &l in logits {
+        sum += (l - max_logit).exp();
+        adjusted_sum += (l - adjusted_max).exp();
+    }
+    // Swap the unbiased terms of biased tokens for their biased ones.
+    for (&v, &w) in biases {
+        if let Some(&l) = logits.get(v as usize) {
+            adjusted_sum += (l + w - adjusted_max).exp() - (l - adjusted_max).exp();
+        }
+    }
+
+    let l = logits[token as usize];
+    let w = biases.get(&token).copied().unwrap_or(0.0);
+    (
+        l - max_logit - sum.ln(),
+        l + w - adjusted_max - adjusted_sum.ln(),
+    )
 }
 
 fn to_llama_tokens(tokens: &[TokenID]) -> Vec<LlamaToken> {
@@ -34,37 +107,60 @@ fn get_file_hash(tokens: &[TokenID]) -> String {
 }
 
 pub struct LlamaLlm {
-    model: &'static Arc<LlamaModel>,
+    /// Borrows `model`, so it is declared (and dropped) first.
+    ctx: LlamaContext<'static>,
+    model: Arc<LlamaModel>,
     seq_id: i32,
+    /// Sequence IDs available to the context; `seq_id` plus forked sequences.
+    n_seq_max: u32,
     current_token_position: i32,
-    ctx: LlamaContext<'static>,
+    /// Next position of each forked sequence.
+    sequence_positions: HashMap<u32, i32>,
+    /// Candidates read after each forked sequence's last feed.
+    sequence_canidates: HashMap<u32, Canidates>,
     batch: LlamaBatch<'static>,
     batch_size: usize,
 }
 
 impl LlamaLlm {
     /// If the initial tokens have been saved, load from the cache to skip
-    /// the expensive re-encoding of the system prompt.
+    /// the expensive re-encoding of the system prompt. Session files are only
+    /// valid for the context shape they were saved from, so the cache is keyed
+    /// by `context_size` and `n_seq_max` as well as the tokens.
+    ///
+    /// `context_size` is the KV cache capacity of each sequence in tokens.
+    /// `n_seq_max` > 1 reserves sequences for `MultiSequenceLlm::fork_sequence`;
+    /// pass 1 for plain generation and scoring. The cache is not unified, so
+    /// llama.cpp splits `n_ctx` evenly between the sequences, and the context
+    /// is sized `context_size * n_seq_max`.
     pub fn new(
         backend: &LlamaBackend,
         model: Arc<LlamaModel>,
         initial_tokens: &[TokenID],
         context_cache_dir: &Path,
-    ) -> Self {
+        context_size: u32,
+        n_seq_max: u32,
+    ) -> anyhow::Result<Self> {
         let batch_size = 2048_u32; // handles large system prompt (~1400-1600 tokens)
-        let context_size = 8192; // supports extended conversations (5+ exchanges)
         let seq_id = 0;
         let ctx_params = LlamaContextParams::default()
-            .with_n_ctx(Some(NonZero::new(context_size).unwrap()))
-            .with_n_batch(batch_size);
+            .with_n_ctx(Some(NonZero::new(context_size * n_seq_max).unwrap()))
+            .with_n_batch(batch_size)
+            .with_n_seq_max(n_seq_max);
 
-        // Leak the Arc to get a 'static reference — the model lives for the whole process
-        let model_ref: &'static Arc<LlamaModel> = Box::leak(Box::new(model));
-        let mut ctx = model_ref.new_context(backend, ctx_params).unwrap();
+        // SAFETY: the model is freed only once the last Arc to it drops, and
+        // `Self::model` holds one for as long as the context exists.
+        let model_ref: &'static LlamaModel = unsafe { &*Arc::as_ptr(&model) };
+        let mut ctx = model_ref
+            .new_context(backend, ctx_params)
+            .context("failed to create llama context")?;
 
         // Load from the KV cache if available to avoid recomputing the prompt
         let mut cache_file = context_cache_dir.to_path_buf();
-        cache_file.push(get_file_hash(initial_tokens));
+        cache_file.push(format!(
+            "{}-{context_size}x{n_seq_max}",
+            get_file_hash(initial_tokens)
+        ));
         let load_from_cache = cache_file.exists();
         let llama_tokens = to_llama_tokens(initial_tokens);
         let mut current_token_position = 0;
@@ -73,19 +169,23 @@ impl LlamaLlm {
             println!("Getting model ctx cache: {:?}", cache_file);
             let cached_tokens = ctx
                 .load_session_file(&cache_file, context_size as usize)
-                .unwrap();
-            assert_eq!(
-                cached_tokens, llama_tokens,
-                "llama ctx was not saved correctly"
+                .with_context(|| format!("failed to load session file {}", cache_file.display()))?;
+            anyhow::ensure!(
+                cached_tokens == llama_tokens,
+                "session file {} does not hold the system prefix",
+                cache_file.display()
             );
             current_token_position = initial_tokens.len() as i32;
         }
 
         let batch = LlamaBatch::new(batch_size as usize, 1);
         let mut llm = Self {
-            model: model_ref,
+            model,
             current_token_position,
+            sequence_positions: HashMap::new(),
+            sequence_canidates: HashMap::new(),
             seq_id,
+            n_seq_max,
             batch,
             batch_size: batch_size as usize,
             ctx,
@@ -95,10 +195,10 @@ impl LlamaLlm {
             llm.feed_tokens(initial_tokens);
             llm.ctx
                 .save_session_file(&cache_file, &llama_tokens)
-                .unwrap();
+                .with_context(|| format!("failed to save session file {}", cache_file.display()))?;
         }
 
-        llm
+        Ok(llm)
     }
 }
 
@@ -132,17 +232,265 @@ impl Llm for LlamaLlm {
         }
     }
 
+    fn n_past(&self) -> usize {
+        self.current_token_position as usize
+    }
+
+    fn truncate(&mut self, n_past: usize) {
+        self.ctx
+            .clear_kv_cache_seq(Some(self.seq_id as u32), Some(n_past as u32), None)
+            .unwrap();
+        self.current_token_position = n_past as i32;
+    }
+
     fn get_canidates(&mut self) -> Canidates {
-        let canidates: Vec<_> = self
-            .ctx
-            .candidates()
-            .map(|c| Canidate {
-                token_id: c.id().0 as TokenID,
-                probability: c.p(),
-                logit: c.logit(),
-                embedding_logit: 0.0,
+        to_canidates(self.ctx.candidates())
+    }
+}
+
+impl ScoringLlm for LlamaLlm {
+    /// Each continuation is decoded after the current context in the same
+    /// sequence and then cut off again, so the cached system prompt is shared
+    /// rather than re-encoded per continuation.
+    fn score_continuations(
+        &mut self,
+        continuations: &[Vec<TokenID>],
+        biases: &HashMap<TokenID, f32>,
+    ) -> anyhow::Result<Vec<ContinuationScore>> {
+        // The first token of every continuation is scored from the logits at
+        // the end of the shared context.
+        let mut root_logits = vec![f32::NEG_INFINITY; self.model.n_vocab() as usize];
+        for c in self.ctx.candidates() {
+            root_logits[c.id().0 as usize] = c.logit();
+        }
+
+        let mut scores: Vec<ContinuationScore> = continuations
+            .iter()
+            .map(|tokens| {
+                let mut log_probs = vec![0.0; tokens.len()];
+                let mut adjusted_log_probs = vec![0.0; tokens.len()];
+                if let Some(&first) = tokens.first() {
+                    (log_probs[0], adjusted_log_probs[0]) =
+                        token_log_probs(&root_logits, first, biases);
+                }
+                ContinuationScore {
+                    log_probs,
+                    adjusted_log_probs,
+                }
+            })
+            .collect();
+
+        let free_cells = self.sequence_cells().saturating_sub(self.n_past());
+        for tokens in continuations {
+            anyhow::ensure!(
+                tokens.len() <= free_cells,
+                "continuation of {} tokens does not fit in the {free_cells} free cells of the sequence",
+                tokens.len()
+            );
+        }
+
+        let n_past = self.current_token_position;
+        let mut logits = vec![f32::NEG_INFINITY; root_logits.len()];
+        for (tokens, score) in continuations.iter().zip(&mut scores) {
+            let scored = self.score_one(tokens, biases, score, &mut logits);
+            // Cut the continuation off even after a failed decode, so a pooled
+            // context is not reused with it still filled.
+            self.ctx
+                .clear_kv_cache_seq(Some(self.seq_id as u32), Some(n_past as u32), None)?;
+            scored?;
+        }
+
+        Ok(scores)
+    }
+}
+
+impl LlamaLlm {
+    /// KV cells each sequence gets. The cache is not unified, so llama.cpp
+    /// splits `n_ctx` evenly between the `n_seq_max` sequences.
+    fn sequence_cells(&self) -> usize {
+        self.ctx.n_ctx() as usize / self.n_seq_max as usize
+    }
+
+    /// Decode `tokens` after the current context and fill in their scores
+    /// after the first token. `logits` is scratch space of vocabulary size.
+    fn score_one(
+        &mut self,
+        tokens: &[TokenID],
+        biases: &HashMap<TokenID, f32>,
+        score: &mut ContinuationScore,
+        logits: &mut [f32],
+    ) -> anyhow::Result<()> {
+        for (chunk_idx, chunk) in tokens.chunks(self.batch_size).enumerate() {
+            let offset = chunk_idx * self.batch_size;
+            self.batch.clear();
+            for (k, &token) in chunk.iter().enumerate() {
+                let j = offset + k;
+                // Logits are only needed where a following token is scored.
+                let wants_logits = j + 1 < tokens.len();
+                self.batch.add(
+                    LlamaToken(token as i32),
+                    self.current_token_position + j as i32,
+                    &[self.seq_id],
+                    wants_logits,
+                )?;
+            }
+            self.ctx.decode(&mut self.batch)?;
+
+            for batch_idx in 0..chunk.len() {
+                let j = offset + batch_idx;
+                let Some(&next) = tokens.get(j + 1) else {
+                    continue;
+                };
+                for c in self.ctx.candidates_ith(batch_idx as i32) {
+                    logits[c.id().0 as usize] = c.logit();
+                }
+                (score.log_probs[j + 1], score.adjusted_log_probs[j + 1]) =
+                    token_log_probs(logits, next, biases);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MultiSequenceLlm for LlamaLlm {
+    fn fork_sequence(&mut self, seq: u32) {
+        assert!(seq > 0 && seq < self.n_seq_max, "sequence {seq} out of range");
+        self.ctx
+            .copy_kv_cache_seq(self.seq_id, seq as i32, None, None)
+            .unwrap();
+        self.sequence_positions
+            .insert(seq, self.current_token_position);
+    }
+
+    fn feed_sequences(&mut self, feeds: &[(u32, Vec<TokenID>)]) -> anyhow::Result<()> {
+        // (sequence, token, wants logits)
+        let entries: Vec<(u32, TokenID, bool)> = feeds
+            .iter()
+            .flat_map(|(seq, tokens)| {
+                let last = tokens.len().saturating_sub(1);
+                tokens
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, &t)| (*seq, t, i == last))
             })
             .collect();
-        Canidates::new(canidates)
+
+        for chunk in entries.chunks(self.batch_size) {
+            self.batch.clear();
+            for &(seq, token, logits) in chunk {
+                let position = self
+                    .sequence_positions
+                    .get_mut(&seq)
+                    .expect("feeding a sequence that was not forked");
+                self.batch.add(LlamaToken(token as i32), *position, &[seq as i32], logits)?;
+                *position += 1;
+            }
+            self.ctx.decode(&mut self.batch)?;
+
+            // Logits only survive until the next decode, so read them per chunk.
+            for (batch_idx, &(seq, _, logits)) in chunk.iter().enumerate() {
+                if logits {
+                    let canidates = to_canidates(self.ctx.candidates_ith(batch_idx as i32));
+                    self.sequence_canidates.insert(seq, canidates);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sequence_canidates(&mut self, seq: u32) -> Canidates {
+        self.sequence_canidates
+            .remove(&seq)
+            .expect("no candidates since the sequence was last fed")
+    }
+
+    fn truncate_sequence(&mut self, seq: u32, n_past: usize) {
+        self.ctx
+            .clear_kv_cache_seq(Some(seq), Some(n_past as u32), None)
+            .unwrap();
+        self.sequence_canidates.remove(&seq);
+        if n_past == 0 {
+            self.sequence_positions.remove(&seq);
+        } else {
+            self.sequence_positions.insert(seq, n_past as i32);
+        }
+    }
+}
+
+fn to_canidates(candidates: impl Iterator<Item = LlamaTokenData>) -> Canidates {
+    let canidates: Vec<_> = candidates
+        .map(|c| Canidate {
+            token_id: c.id().0 as TokenID,
+            probability: c.p(),
+            logit: c.logit(),
+            embedding_logit: 0.0,
+        })
+        .collect();
+    Canidates::new(canidates)
+}
+
+/// One forked sequence of a context shared by several decoders on the same
+/// thread. Fed tokens are queued until `take_pending` hands them to a
+/// `feed_sequences` call covering every sequence at once.
+pub struct SequenceLlm<L: MultiSequenceLlm> {
+    shared: Rc<RefCell<L>>,
+    seq: u32,
+    n_past: usize,
+    pending: Vec<TokenID>,
+}
+
+impl<L: MultiSequenceLlm> SequenceLlm<L> {
+    /// Fork `seq` from the shared context's current contents.
+    pub fn fork(shared: &Rc<RefCell<L>>, seq: u32) -> Self {
+        let mut llm = shared.borrow_mut();
+        llm.fork_sequence(seq);
+        Self {
+            n_past: llm.n_past(),
+            shared: Rc::clone(shared),
+            seq,
+            pending: vec![],
+        }
+    }
+
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Tokens fed since the last call, to pass to `feed_sequences`.
+    pub fn take_pending(&mut self) -> Vec<TokenID> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+impl<L: MultiSequenceLlm> Drop for SequenceLlm<L> {
+    fn drop(&mut self) {
+        self.shared.borrow_mut().truncate_sequence(self.seq, 0);
+    }
+}
+
+impl<L: MultiSequenceLlm> Llm for SequenceLlm<L> {
+    fn get_canidates(&mut self) -> Canidates {
+        assert!(self.pending.is_empty(), "sequence has unflushed tokens");
+        self.shared.borrow_mut().sequence_canidates(self.seq)
+    }
+
+    fn feed_tokens(&mut self, tokens: &[TokenID]) {
+        self.pending.extend_from_slice(tokens);
+        self.n_past += tokens.len();
+    }
+
+    fn n_past(&self) -> usize {
+        self.n_past
+    }
+
+    fn truncate(&mut self, n_past: usize) {
+        let fed = self.n_past - self.pending.len();
+        if n_past >= fed {
+            self.pending.truncate(n_past - fed);
+        } else {
+            self.pending.clear();
+            self.shared.borrow_mut().truncate_sequence(self.seq, n_past);
+        }
+        self.n_past = n_past;
     }
 }
@@ -1,13 +1,27 @@
+pub(crate) mod backend;
+pub(crate) mod chat_template;
 pub(crate) mod constraints;
-pub(crate) mod conversation_loop;
 pub(crate) mod csv_loader;
+pub(crate) mod embedding;
 pub(crate) mod grammar;
 pub(crate) mod inference;
 pub(crate) mod llama_tokenizer;
+#[cfg(test)]
+mod mock;
+pub(crate) mod sampling;
 pub(crate) mod token;
 
 pub mod engine;
+pub mod scoring;
 
-pub use engine::{CategoryBias, InferenceConfig, InferenceEngine};
+pub use backend::{Backend, LlamaCppBackend, Tokenizer};
+pub use csv_loader::{CsvColumns, CsvMessages, clean_vc_message, load_vc_messages_csv};
+pub use embedding::LlamaEmbedder;
+pub use engine::{
+    CategoryBias, ConversationTurn, GenerationRequest, InferenceConfig, InferenceEngine,
+    MAX_BEAM_WIDTH, StepError, StepSession,
+};
 pub use grammar::{GrammarFlow, VCmessage};
-pub use inference_types::{InferenceEvent, StepCandidates, TokenWithProb};
+pub use inference_types::{
+    InferenceEvent, SamplerSettings, StepCandidates, StepThroughState, TokenWithProb,
+};
@@ -5,26 +5,19 @@ use llama_cpp_2::{
 use llguidance::toktrie::{TokRxInfo, TokTrie, TokenId, TokenizerEnv};
 use std::sync::Arc;
 
+use crate::backend::Tokenizer;
 use crate::token::TokenID;
 
-// lama 3
-pub const ID_START_TOKEN: &str = "<|start_header_id|>";
-pub const ID_END_TOKEN: &str = "<|end_header_id|>";
-pub const END_TURN_TOKEN: &str = "<|eot_id|>";
-
-pub const SPECIAL_TOKENS: [&str; 3] = [ID_START_TOKEN, ID_END_TOKEN, END_TURN_TOKEN];
-
 pub struct LlamaTokenizerEnv {
     model: Arc<LlamaModel>,
     tok_trie: TokTrie,
 }
 
 impl LlamaTokenizerEnv {
-    pub fn new(model: Arc<LlamaModel>) -> Self {
-        let _tok_end_of_turn =
-            Some(model.str_to_token(END_TURN_TOKEN, AddBos::Never).unwrap()[0].0 as u32);
-
-        let mut must_have_special_token_ids: Vec<_> = SPECIAL_TOKENS
+    /// `special_tokens` are the chat template's markers (see
+    /// `ChatTemplate::special_tokens`); each must exist as a single token.
+    pub fn new(model: Arc<LlamaModel>, special_tokens: &[&str]) -> Self {
+        let mut must_have_special_token_ids: Vec<_> = special_tokens
             .iter()
             .map(|t_str| model.str_to_token(t_str, AddBos::Never).unwrap()[0])
             .collect();
@@ -69,8 +62,10 @@ impl LlamaTokenizerEnv {
         let tok_trie = TokTrie::from(&token_info, &all_words);
         Self { model, tok_trie }
     }
+}
 
-    pub fn tokens_to_string(&self, tokens: &[TokenID]) -> String {
+impl Tokenizer for LlamaTokenizerEnv {
+    fn tokens_to_string(&self, tokens: &[TokenID]) -> String {
         let res = self.model.tokens_to_str(
             &tokens
                 .iter()
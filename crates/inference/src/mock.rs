@@ -0,0 +1,273 @@
+// Scripted backend for engine tests: a greedy tokenizer over a small
+// synthetic vocabulary and an `Llm` whose logits come from a closure, so
+// decoding runs deterministically without a GGUF model on disk.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use llguidance::toktrie::{TokRxInfo, TokTrie, TokenId, TokenizerEnv};
+
+use crate::backend::{Backend, Tokenizer};
+use crate::inference::{ContinuationScore, Llm, MultiSequenceLlm, ScoringLlm, token_log_probs};
+use crate::token::{Canidate, Canidates, TokenID};
+
+/// Next-token logits (one per vocabulary entry) given every token in the context.
+pub type LogitsFn = dyn Fn(&[TokenID]) -> Vec<f32> + Send + Sync;
+
+const EOS: &str = "<eos>";
+
+/// Vocabulary used by `MockTokenizer`: the given words in order, then the
+/// chat template's special tokens and the single-character tokens that are
+/// missing, then the end-of-sequence token. Building it twice from the same
+/// arguments gives the same IDs.
+fn vocabulary(words: &[&str], special_tokens: &[&str]) -> Vec<String> {
+    let mut vocab: Vec<String> = vec![];
+    let printable = (b' '..=b'~')
+        .chain([b'\n'])
+        .map(|b| (b as char).to_string());
+    let all = words
+        .iter()
+        .chain(special_tokens)
+        .map(|w| w.to_string())
+        .chain(printable)
+        .chain([EOS.to_string()]);
+    for word in all {
+        if !vocab.contains(&word) {
+            vocab.push(word);
+        }
+    }
+    vocab
+}
+
+pub struct MockTokenizer {
+    vocab: Vec<String>,
+    tok_trie: TokTrie,
+}
+
+impl MockTokenizer {
+    /// Every printable ASCII character and `\n` is always a token, so any
+    /// ASCII text can be tokenized; `words` add longer tokens that greedy
+    /// (longest-match) tokenization prefers.
+    pub fn new(words: &[&str], special_tokens: &[&str]) -> Self {
+        let vocab = vocabulary(words, special_tokens);
+        let trie_words: Vec<Vec<u8>> = vocab
+            .iter()
+            .map(|w| {
+                let mut bytes = w.as_bytes().to_vec();
+                if special_tokens.contains(&w.as_str()) || w == EOS {
+                    bytes.insert(0, TokTrie::SPECIAL_TOKEN_MARKER);
+                }
+                bytes
+            })
+            .collect();
+        let token_info = TokRxInfo {
+            vocab_size: vocab.len() as u32,
+            tok_eos: (vocab.len() - 1) as u32,
+            tok_bos: None,
+            tok_pad: None,
+            tok_unk: None,
+            tok_end_of_turn: None,
+        };
+        let tok_trie = TokTrie::from(&token_info, &trie_words);
+        Self { vocab, tok_trie }
+    }
+
+    pub fn token_id(&self, word: &str) -> Option<TokenID> {
+        self.vocab
+            .iter()
+            .position(|w| w == word)
+            .map(|i| i as TokenID)
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+}
+
+impl Tokenizer for MockTokenizer {
+    fn tokens_to_string(&self, tokens: &[TokenID]) -> String {
+        tokens
+            .iter()
+            .map(|&t| self.vocab[t as usize].as_str())
+            .collect()
+    }
+}
+
+impl TokenizerEnv for MockTokenizer {
+    fn tok_trie(&self) -> &TokTrie {
+        &self.tok_trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.tok_trie.greedy_tokenize(s)
+    }
+}
+
+/// An `Llm` that holds its context as a plain token list and asks `logits`
+/// for the next-token distribution.
+pub struct ScriptedLlm {
+    context: Vec<TokenID>,
+    /// Tokens each sequence can hold, like the KV cells llama.cpp gives each
+    /// sequence of a context that is not unified.
+    sequence_cells: usize,
+    sequences: HashMap<u32, Vec<TokenID>>,
+    logits: Arc<LogitsFn>,
+}
+
+impl ScriptedLlm {
+    fn canidates(&self, context: &[TokenID]) -> Canidates {
+        let logits = (self.logits)(context);
+        let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = logits.iter().map(|l| (l - max_logit).exp()).sum();
+        Canidates::new(
+            logits
+                .iter()
+                .enumerate()
+                .map(|(i, &logit)| Canidate {
+                    token_id: i as TokenID,
+                    probability: (logit - max_logit).exp() / sum,
+                    logit,
+                    embedding_logit: 0.0,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Llm for ScriptedLlm {
+    fn get_canidates(&mut self) -> Canidates {
+        self.canidates(&self.context)
+    }
+
+    fn feed_tokens(&mut self, tokens: &[TokenID]) {
+        self.context.extend_from_slice(tokens);
+    }
+
+    fn n_past(&self) -> usize {
+        self.context.len()
+    }
+
+    fn truncate(&mut self, n_past: usize) {
+        self.context.truncate(n_past);
+    }
+}
+
+impl ScoringLlm for ScriptedLlm {
+    fn score_continuations(
+        &mut self,
+        continuations: &[Vec<TokenID>],
+        biases: &HashMap<TokenID, f32>,
+    ) -> anyhow::Result<Vec<ContinuationScore>> {
+        let free_cells = self.sequence_cells.saturating_sub(self.context.len());
+        if let Some(tokens) = continuations.iter().find(|t| t.len() > free_cells) {
+            anyhow::bail!(
+                "continuation of {} tokens does not fit in the {free_cells} free cells of the sequence",
+                tokens.len()
+            );
+        }
+        Ok(continuations
+            .iter()
+            .map(|tokens| {
+                let mut context = self.context.clone();
+                let (log_probs, adjusted_log_probs) = tokens
+                    .iter()
+                    .map(|&t| {
+                        let scores = token_log_probs(&(self.logits)(&context), t, biases);
+                        context.push(t);
+                        scores
+                    })
+                    .unzip();
+                ContinuationScore {
+                    log_probs,
+                    adjusted_log_probs,
+                }
+            })
+            .collect())
+    }
+}
+
+impl MultiSequenceLlm for ScriptedLlm {
+    fn fork_sequence(&mut self, seq: u32) {
+        self.sequences.insert(seq, self.context.clone());
+    }
+
+    /// Like a llama context, fails when a sequence's new tokens do not fit in
+    /// its own cells, which already hold its copy of the context.
+    fn feed_sequences(&mut self, feeds: &[(u32, Vec<TokenID>)]) -> anyhow::Result<()> {
+        for (seq, tokens) in feeds {
+            let used = self.sequences.get(seq).map_or(0, Vec::len);
+            if used + tokens.len() > self.sequence_cells {
+                anyhow::bail!(
+                    "{} tokens do not fit in the {} free cells of sequence {seq}",
+                    tokens.len(),
+                    self.sequence_cells.saturating_sub(used)
+                );
+            }
+        }
+        for (seq, tokens) in feeds {
+            self.sequences
+                .get_mut(seq)
+                .expect("feeding a sequence that was not forked")
+                .extend_from_slice(tokens);
+        }
+        Ok(())
+    }
+
+    fn sequence_canidates(&mut self, seq: u32) -> Canidates {
+        self.canidates(&self.sequences[&seq])
+    }
+
+    fn truncate_sequence(&mut self, seq: u32, n_past: usize) {
+        if n_past == 0 {
+            self.sequences.remove(&seq);
+        } else if let Some(tokens) = self.sequences.get_mut(&seq) {
+            tokens.truncate(n_past);
+        }
+    }
+}
+
+pub struct MockBackend {
+    words: Vec<&'static str>,
+    logits: Arc<LogitsFn>,
+}
+
+impl MockBackend {
+    /// `words` are the extra vocabulary entries (see `MockTokenizer::new`);
+    /// `logits` is called with the full context before every critical point.
+    pub fn new(
+        words: &[&'static str],
+        logits: impl Fn(&[TokenID]) -> Vec<f32> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            words: words.to_vec(),
+            logits: Arc::new(logits),
+        }
+    }
+}
+
+impl Backend for MockBackend {
+    type Llm = ScriptedLlm;
+    type Tokenizer = MockTokenizer;
+
+    fn tokenizer(&self, special_tokens: &[&str]) -> MockTokenizer {
+        MockTokenizer::new(&self.words, special_tokens)
+    }
+
+    fn chat_template_metadata(&self) -> Option<String> {
+        None
+    }
+
+    fn new_llm(
+        &self,
+        initial_tokens: &[TokenID],
+        context_size: u32,
+        _n_seq_max: u32,
+    ) -> anyhow::Result<ScriptedLlm> {
+        Ok(ScriptedLlm {
+            context: initial_tokens.to_vec(),
+            sequence_cells: context_size as usize,
+            sequences: HashMap::new(),
+            logits: Arc::clone(&self.logits),
+        })
+    }
+}
@@ -0,0 +1,228 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use inference_types::SamplerSettings;
+
+use crate::token::{Canidate, Canidates, TokenID};
+
+/// Picks the token to commit at a critical point. Called with the candidates
+/// left after `apply_biases` and the grammar mask, sorted by adjusted logit.
+pub trait Sampler: Send {
+    /// Returns `None` only when `candidates` is empty.
+    fn sample(&mut self, candidates: &Canidates) -> Option<TokenID>;
+}
+
+/// Build the sampler described by `settings`.
+pub fn new_sampler(settings: &SamplerSettings) -> Box<dyn Sampler> {
+    match *settings {
+        SamplerSettings::Greedy => Box::new(GreedySampler),
+        SamplerSettings::Random {
+            temperature,
+            top_k,
+            top_p,
+            min_p,
+            seed,
+        } => Box::new(RandomSampler {
+            temperature,
+            top_k,
+            top_p,
+            min_p,
+            rng: SplitMix64(seed.unwrap_or_else(clock_seed)),
+        }),
+    }
+}
+
+/// Always commits the highest adjusted-logit candidate.
+pub struct GreedySampler;
+
+impl Sampler for GreedySampler {
+    fn sample(&mut self, candidates: &Canidates) -> Option<TokenID> {
+        candidates.iter().next().map(|c| c.token_id)
+    }
+}
+
+/// Draws from the softmax over adjusted logits (logit + embedding_logit).
+///
+/// top-k, top-p and min-p are evaluated on the untempered distribution, in
+/// that order, and the temperature is then applied to the surviving
+/// candidates before drawing — the same order llama.cpp's default chain uses.
+pub struct RandomSampler {
+    temperature: f32,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+    min_p: Option<f32>,
+    rng: SplitMix64,
+}
+
+impl Sampler for RandomSampler {
+    fn sample(&mut self, candidates: &Canidates) -> Option<TokenID> {
+        // Candidates are already sorted by adjusted logit, descending.
+        let mut kept: Vec<&Canidate> = candidates.iter().collect();
+        if kept.is_empty() {
+            return None;
+        }
+        if self.temperature <= 0.0 {
+            return Some(kept[0].token_id);
+        }
+
+        if let Some(k) = self.top_k {
+            kept.truncate(k.max(1));
+        }
+
+        let probs = softmax(&kept, 1.0);
+        if let Some(p) = self.top_p {
+            let mut cumulative = 0.0;
+            let cutoff = probs
+                .iter()
+                .position(|&prob| {
+                    cumulative += prob;
+                    cumulative >= p
+                })
+                .map_or(kept.len(), |i| i + 1);
+            kept.truncate(cutoff);
+        }
+        if let Some(min_p) = self.min_p {
+            let threshold = probs[0] * min_p;
+            let cutoff = probs[..kept.len()]
+                .iter()
+                .position(|&prob| prob < threshold)
+                .unwrap_or(kept.len())
+                .max(1);
+            kept.truncate(cutoff);
+        }
+
+        let probs = softmax(&kept, self.temperature);
+        let mut target = self.rng.next_f32();
+        for (c, prob) in kept.iter().zip(probs.iter()) {
+            if target < *prob {
+                return Some(c.token_id);
+            }
+            target -= prob;
+        }
+        // Rounding left a sliver of mass unassigned; fall back to the least likely survivor.
+        kept.last().map(|c| c.token_id)
+    }
+}
+
+fn softmax(canidates: &[&Canidate], temperature: f32) -> Vec<f32> {
+    let max_logit = canidates
+        .iter()
+        .map(|c| c.logit + c.embedding_logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = canidates
+        .iter()
+        .map(|c| ((c.logit + c.embedding_logit - max_logit) / temperature).exp())
+        .collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Minimal SplitMix64 generator: small, fast and fully determined by its seed,
+/// which is all reproducible sampling needs.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(logits: &[f32]) -> Canidates {
+        Canidates::new(
+            logits
+                .iter()
+                .enumerate()
+                .map(|(i, &logit)| Canidate {
+                    token_id: i as TokenID,
+                    probability: 0.0,
+                    logit,
+                    embedding_logit: 0.0,
+                })
+                .collect(),
+        )
+    }
+
+    fn random(
+        top_k: Option<usize>,
+        top_p: Option<f32>,
+        min_p: Option<f32>,
+        seed: u64,
+    ) -> Box<dyn Sampler> {
+        new_sampler(&SamplerSettings::Random {
+            temperature: 1.0,
+            top_k,
+            top_p,
+            min_p,
+            seed: Some(seed),
+        })
+    }
+
+    #[test]
+    fn greedy_picks_highest_adjusted_logit() {
+        let mut c = candidates(&[1.0, 3.0, 2.0]);
+        let mut sampler = new_sampler(&SamplerSettings::Greedy);
+        assert_eq!(sampler.sample(&c), Some(1));
+
+        let biases = [(2, 5.0)].into_iter().collect();
+        c.apply_biases(&biases);
+        assert_eq!(sampler.sample(&c), Some(2));
+    }
+
+    #[test]
+    fn same_seed_reproduces_choices() {
+        let c = candidates(&[1.0, 0.9, 0.8, 0.7, 0.6]);
+        let mut a = random(None, None, None, 42);
+        let mut b = random(None, None, None, 42);
+        let draws_a: Vec<_> = (0..50).map(|_| a.sample(&c)).collect();
+        let draws_b: Vec<_> = (0..50).map(|_| b.sample(&c)).collect();
+        assert_eq!(draws_a, draws_b);
+        assert!(
+            draws_a.iter().any(|&t| t != Some(0)),
+            "near-uniform logits should not always pick the argmax"
+        );
+    }
+
+    #[test]
+    fn truncations_restrict_the_support() {
+        let c = candidates(&[2.0, 1.9, 0.0, -5.0]);
+        for seed in 0..100 {
+            assert_eq!(random(Some(1), None, None, seed).sample(&c), Some(0));
+            let t = random(None, None, Some(0.5), seed).sample(&c).unwrap();
+            assert!(
+                t <= 1,
+                "min_p should drop tokens far below the top probability"
+            );
+            let t = random(None, Some(0.5), None, seed).sample(&c).unwrap();
+            assert!(
+                t <= 1,
+                "top_p should keep only the head of the distribution"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_candidates_yield_none() {
+        let c = candidates(&[]);
+        assert_eq!(new_sampler(&SamplerSettings::Greedy).sample(&c), None);
+        assert_eq!(random(None, None, None, 0).sample(&c), None);
+    }
+}
@@ -0,0 +1,96 @@
+//! Category scoring shared by the engine and offline re-scoring of recorded
+//! runs, so both rank categories by the same formula.
+
+use inference_types::CategoryTopToken;
+
+/// Logit adjustment for a category:
+/// `temperature * (kappa * sim_score + logit_offset)`.
+///
+/// `sim_score` is the raw embedding margin and `logit_offset` the learned
+/// category prior; `temperature` weighs both against the model's logits.
+pub fn category_bias(kappa: f32, sim_score: f32, logit_offset: f32, temperature: f32) -> f32 {
+    temperature * (kappa * sim_score + logit_offset)
+}
+
+/// Adjusted logit of a category whose best token has the raw `logit`; the
+/// engine picks the category with the highest one.
+pub fn category_score(
+    logit: f32,
+    kappa: f32,
+    sim_score: f32,
+    logit_offset: f32,
+    temperature: f32,
+) -> f32 {
+    logit + category_bias(kappa, sim_score, logit_offset, temperature)
+}
+
+/// The category among `candidates` with the highest adjusted logit, given
+/// `constants(category_name) -> (kappa, logit_offset)`. `None` if there are
+/// no candidates.
+pub fn best_category(
+    candidates: &[CategoryTopToken],
+    constants: impl Fn(&str) -> (f32, f32),
+    temperature: f32,
+) -> Option<&CategoryTopToken> {
+    let score = |c: &CategoryTopToken| {
+        let (kappa, logit_offset) = constants(&c.category_name);
+        category_score(
+            c.best_token.logit,
+            kappa,
+            c.sim_score,
+            logit_offset,
+            temperature,
+        )
+    };
+    candidates
+        .iter()
+        .max_by(|a, b| score(a).total_cmp(&score(b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use inference_types::TokenWithProb;
+
+    fn candidate(category: &str, logit: f32, sim_score: f32) -> CategoryTopToken {
+        CategoryTopToken {
+            category_name: category.to_string(),
+            best_token: TokenWithProb {
+                text: format!(" {category}"),
+                token_id: 0,
+                probability: 0.0,
+                logit,
+                embedding_logit: 0.0,
+            },
+            sim_score,
+        }
+    }
+
+    #[test]
+    fn similarity_and_offset_can_outweigh_the_logit() {
+        let candidates = [candidate("A", 2.0, 0.0), candidate("B", 1.0, 0.2)];
+
+        let logits_only = best_category(&candidates, |_| (0.0, 0.0), 1.0).unwrap();
+        assert_eq!(logits_only.category_name, "A");
+
+        // 1 + 10 * 0.2 = 3 beats 2.
+        let with_kappa = best_category(&candidates, |_| (10.0, 0.0), 1.0).unwrap();
+        assert_eq!(with_kappa.category_name, "B");
+
+        // An offset of -1.5 leaves B a bias of 0.5: too little on its own,
+        // enough once the temperature scales it by 4.
+        let offset = |c: &str| (10.0, if c == "B" { -1.5 } else { 0.0 });
+        assert_eq!(
+            best_category(&candidates, offset, 1.0)
+                .unwrap()
+                .category_name,
+            "A"
+        );
+        assert_eq!(
+            best_category(&candidates, offset, 4.0)
+                .unwrap()
+                .category_name,
+            "B"
+        );
+    }
+}
@@ -31,6 +31,12 @@ impl Canidates {
         self.by_id.get(&token_id).map(|&i| &self.canidates[i])
     }
 
+    /// Iterate candidates in their current order (by adjusted logit once
+    /// `apply_biases` has run).
+    pub fn iter(&self) -> impl Iterator<Item = &Canidate> {
+        self.canidates.iter()
+    }
+
     /// Apply per-token logit biases from the embedding similarity algorithm.
     /// Sets each candidate's `embedding_logit` to the precomputed w(v) value.
     /// The raw `logit` field is left unchanged; adjusted logit = logit + embedding_logit.
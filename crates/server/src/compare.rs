@@ -0,0 +1,233 @@
+//! Paired comparison of two bulk-test runs.
+//!
+//! Results are joined on `example_id`, so only examples both runs contain are
+//! compared; both runs must draw on the same examples for the IDs to match.
+//! An example a run left without a category counts as wrong for that run.
+//! An example that run A got right and run B got wrong is a regression, the
+//! reverse a fix. McNemar's test asks whether the split
+//! between the two is more lopsided than chance; it ignores the examples
+//! both runs agree on.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::optimize::Accuracy;
+use crate::rescore::RecordedExample;
+
+/// McNemar's test on the discordant pairs.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct McNemar {
+    /// Chi-squared statistic with continuity correction.
+    pub chi_squared: f64,
+    /// Exact two-sided p-value (binomial with p = ½ on the discordant pairs).
+    pub p_value: f64,
+}
+
+pub fn mcnemar(regressions: usize, fixes: usize) -> McNemar {
+    let n = regressions + fixes;
+    if n == 0 {
+        return McNemar {
+            chi_squared: 0.0,
+            p_value: 1.0,
+        };
+    }
+    let diff = (regressions as f64 - fixes as f64).abs();
+    let chi_squared = (diff - 1.0).max(0.0).powi(2) / n as f64;
+    // P(X ≤ min) for X ~ Bin(n, ½), summed in log space so large n does not
+    // underflow the first term before the binomial coefficient lifts it.
+    let mut ln_term = n as f64 * 0.5f64.ln();
+    let mut tail = 0.0;
+    for i in 0..=regressions.min(fixes) {
+        tail += ln_term.exp();
+        ln_term += ((n - i) as f64 / (i + 1) as f64).ln();
+    }
+    McNemar {
+        chi_squared,
+        p_value: (2.0 * tail).min(1.0),
+    }
+}
+
+/// An example whose correctness differs between the runs.
+#[derive(Debug, Serialize)]
+pub struct ExampleChange {
+    pub example_id: i64,
+    pub example_text: String,
+    pub correct_categories: Vec<String>,
+    pub chosen_a: Option<String>,
+    pub chosen_b: Option<String>,
+}
+
+/// Accuracy of both runs on the shared examples of one category.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CategoryDelta {
+    pub category_name: String,
+    pub a: Accuracy,
+    pub b: Accuracy,
+    pub regressions: usize,
+    pub fixes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunComparison {
+    /// Examples present in both runs.
+    pub shared: usize,
+    pub only_in_a: usize,
+    pub only_in_b: usize,
+    /// Accuracy of each run on the shared examples.
+    pub a: Accuracy,
+    pub b: Accuracy,
+    /// Right in A, wrong in B.
+    pub regressions: Vec<ExampleChange>,
+    /// Wrong in A, right in B.
+    pub fixes: Vec<ExampleChange>,
+    pub mcnemar: McNemar,
+    /// Grouped by each example's first correct category, sorted by name.
+    pub categories: Vec<CategoryDelta>,
+}
+
+/// Compare run `b` against run `a`. Repeated example ids keep their first
+/// result.
+pub fn compare_runs(a: &[RecordedExample], b: &[RecordedExample]) -> RunComparison {
+    let mut by_id: HashMap<i64, &RecordedExample> = HashMap::new();
+    for ex in b {
+        by_id.entry(ex.example_id).or_insert(ex);
+    }
+
+    let mut seen = HashSet::new();
+    let mut regressions = Vec::new();
+    let mut fixes = Vec::new();
+    let mut acc_a = Accuracy::default();
+    let mut acc_b = Accuracy::default();
+    let mut categories: BTreeMap<&str, CategoryDelta> = BTreeMap::new();
+    for ex_a in a {
+        if !seen.insert(ex_a.example_id) {
+            continue;
+        }
+        let Some(ex_b) = by_id.get(&ex_a.example_id) else {
+            continue;
+        };
+        let category = &ex_a.correct_categories[0];
+        let delta = categories.entry(category).or_insert_with(|| CategoryDelta {
+            category_name: category.clone(),
+            a: Accuracy::default(),
+            b: Accuracy::default(),
+            regressions: 0,
+            fixes: 0,
+        });
+        for (acc, success) in [
+            (&mut acc_a, ex_a.success),
+            (&mut acc_b, ex_b.success),
+            (&mut delta.a, ex_a.success),
+            (&mut delta.b, ex_b.success),
+        ] {
+            acc.total += 1;
+            acc.correct += usize::from(success);
+        }
+
+        let change = || ExampleChange {
+            example_id: ex_a.example_id,
+            example_text: ex_a.example_text.clone(),
+            correct_categories: ex_a.correct_categories.clone(),
+            chosen_a: ex_a.chosen_category.clone(),
+            chosen_b: ex_b.chosen_category.clone(),
+        };
+        match (ex_a.success, ex_b.success) {
+            (true, false) => {
+                delta.regressions += 1;
+                regressions.push(change());
+            }
+            (false, true) => {
+                delta.fixes += 1;
+                fixes.push(change());
+            }
+            _ => {}
+        }
+    }
+
+    let shared = acc_a.total;
+    RunComparison {
+        shared,
+        only_in_a: seen.len() - shared,
+        only_in_b: by_id.len() - shared,
+        a: acc_a,
+        b: acc_b,
+        mcnemar: mcnemar(regressions.len(), fixes.len()),
+        regressions,
+        fixes,
+        categories: categories.into_values().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(id: i64, correct: &str, chosen: &str) -> RecordedExample {
+        RecordedExample {
+            example_id: id,
+            example_text: format!("example {id}"),
+            chosen_category: Some(chosen.to_string()),
+            success: chosen == correct,
+            correct_categories: vec![correct.to_string()],
+            candidates: vec![],
+        }
+    }
+
+    #[test]
+    fn mcnemar_matches_the_exact_binomial() {
+        assert_eq!(mcnemar(0, 0).p_value, 1.0);
+        // 2 · 0.5⁶
+        assert!((mcnemar(0, 6).p_value - 0.03125).abs() < 1e-12);
+        // 2 · (1 + 10 + 45) / 1024, χ² = (6 − 1)² / 10
+        let m = mcnemar(2, 8);
+        assert!((m.p_value - 0.109375).abs() < 1e-12);
+        assert!((m.chi_squared - 2.5).abs() < 1e-12);
+        assert_eq!(mcnemar(1, 1).p_value, 1.0);
+        // Large counts stay finite and tiny.
+        let p = mcnemar(0, 2000).p_value;
+        assert!((0.0..1e-100).contains(&p));
+    }
+
+    #[test]
+    fn joins_on_example_id_and_reports_changes_per_category() {
+        let a = [
+            example(1, "A", "A"),
+            example(2, "A", "B"),
+            example(3, "B", "B"),
+            example(4, "B", "A"),
+            example(5, "A", "A"),
+        ];
+        let b = [
+            example(2, "A", "A"),
+            example(1, "A", "B"),
+            example(3, "B", "B"),
+            example(4, "B", "B"),
+            example(6, "B", "B"),
+        ];
+
+        let c = compare_runs(&a, &b);
+
+        assert_eq!((c.shared, c.only_in_a, c.only_in_b), (4, 1, 1));
+        assert_eq!((c.a.correct, c.b.correct, c.b.total), (2, 3, 4));
+        let ids = |v: &[ExampleChange]| v.iter().map(|e| e.example_id).collect::<Vec<_>>();
+        assert_eq!(ids(&c.regressions), [1]);
+        assert_eq!(ids(&c.fixes), [2, 4]);
+        assert_eq!(c.regressions[0].chosen_b.as_deref(), Some("B"));
+
+        let per_category: Vec<_> = c
+            .categories
+            .iter()
+            .map(|d| {
+                (
+                    d.category_name.as_str(),
+                    d.a.correct,
+                    d.b.correct,
+                    d.regressions,
+                    d.fixes,
+                )
+            })
+            .collect();
+        assert_eq!(per_category, [("A", 1, 1, 1, 1), ("B", 1, 2, 0, 1)]);
+    }
+}
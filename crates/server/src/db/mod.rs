@@ -4,6 +4,8 @@
 // DATABASE_URL must point to sqlite:./app.db at build time.
 // ---------------------------------------------------------------------------
 
+use std::collections::HashMap;
+
 use anyhow::Context;
 use sqlx::SqlitePool;
 use uuid::Uuid;
@@ -12,14 +14,20 @@ use uuid::Uuid;
 // Inference sessions
 // ---------------------------------------------------------------------------
 
-/// Insert a new pending session. Returns the `obfuscated_id` (the public UUID
+/// Insert a new pending session, optionally as the next turn of a conversation
+/// (internal `conversations.id`). Returns the `obfuscated_id` (the public UUID
 /// string used as the session key in all API responses).
-pub async fn create_session(db: &SqlitePool, prompt: &str) -> anyhow::Result<String> {
+pub async fn create_session(
+    db: &SqlitePool,
+    prompt: &str,
+    conversation_id: Option<i64>,
+) -> anyhow::Result<String> {
     let obfuscated_id = Uuid::new_v4().to_string();
     sqlx::query!(
-        "INSERT INTO inference_sessions (obfuscated_id, prompt) VALUES (?, ?)",
+        "INSERT INTO inference_sessions (obfuscated_id, prompt, conversation_id) VALUES (?, ?, ?)",
         obfuscated_id,
         prompt,
+        conversation_id,
     )
     .execute(db)
     .await
@@ -59,6 +67,72 @@ pub async fn complete_session(
     Ok(())
 }
 
+/// Mark a pending or streaming session cancelled.
+pub async fn cancel_session(db: &SqlitePool, obfuscated_id: &str) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"UPDATE inference_sessions
+           SET status = 'cancelled',
+               completed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+           WHERE obfuscated_id = ? AND status IN ('pending', 'streaming')"#,
+        obfuscated_id,
+    )
+    .execute(db)
+    .await
+    .context("failed to cancel session")?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Conversations
+// ---------------------------------------------------------------------------
+
+/// Insert a new conversation. Returns `(id, obfuscated_id)`.
+pub async fn create_conversation(db: &SqlitePool) -> anyhow::Result<(i64, String)> {
+    let obfuscated_id = Uuid::new_v4().to_string();
+    let id = sqlx::query_scalar!(
+        r#"INSERT INTO conversations (obfuscated_id) VALUES (?) RETURNING id AS "id!: i64""#,
+        obfuscated_id,
+    )
+    .fetch_one(db)
+    .await
+    .context("failed to insert conversation")?;
+    Ok((id, obfuscated_id))
+}
+
+/// Resolve a public conversation ID to its internal row ID.
+pub async fn find_conversation(
+    db: &SqlitePool,
+    obfuscated_id: &str,
+) -> anyhow::Result<Option<i64>> {
+    let id = sqlx::query_scalar!(
+        r#"SELECT id AS "id!: i64" FROM conversations WHERE obfuscated_id = ?"#,
+        obfuscated_id,
+    )
+    .fetch_optional(db)
+    .await
+    .context("failed to look up conversation")?;
+    Ok(id)
+}
+
+/// Completed turns of a conversation, oldest first, as `(prompt, response)`.
+/// Turns that errored or are still streaming are skipped.
+pub async fn load_conversation_turns(
+    db: &SqlitePool,
+    conversation_id: i64,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let rows = sqlx::query!(
+        r#"SELECT prompt, result_text AS "result_text!"
+           FROM inference_sessions
+           WHERE conversation_id = ? AND status = 'complete' AND result_text IS NOT NULL
+           ORDER BY id"#,
+        conversation_id,
+    )
+    .fetch_all(db)
+    .await
+    .context("failed to load conversation turns")?;
+    Ok(rows.into_iter().map(|r| (r.prompt, r.result_text)).collect())
+}
+
 // ---------------------------------------------------------------------------
 // Inference tokens
 // ---------------------------------------------------------------------------
@@ -93,44 +167,157 @@ pub async fn insert_token(
     Ok(())
 }
 
+/// Position the next token appended to a session's token log should use.
+/// Step-through sessions append tokens across several requests.
+pub async fn next_token_position(
+    db: &SqlitePool,
+    obfuscated_session_id: &str,
+) -> anyhow::Result<i64> {
+    let next = sqlx::query_scalar!(
+        r#"SELECT COALESCE(MAX(t.position) + 1, 0) AS "next!: i64"
+           FROM inference_tokens t
+           JOIN inference_sessions s ON s.id = t.session_id
+           WHERE s.obfuscated_id = ?"#,
+        obfuscated_session_id,
+    )
+    .fetch_one(db)
+    .await
+    .context("failed to fetch next token position")?;
+    Ok(next)
+}
+
 // ---------------------------------------------------------------------------
-// Embedding constants — SQLite (kappa scaling per VC message)
+// Embedding constants — SQLite (kappa scaling and logit offset per category)
 // ---------------------------------------------------------------------------
 
-/// Retrieve the kappa constant for a given message_id, inserting the default
-/// (10.0) row if this message has not been seen before.
-pub async fn get_or_create_kappa(db: &SqlitePool, message_id: i64) -> anyhow::Result<f64> {
-    sqlx::query!(
-        "INSERT OR IGNORE INTO vc_message_constants (message_id) VALUES (?)",
-        message_id,
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CategoryConstants {
+    pub kappa: f64,
+    pub logit_offset: f64,
+}
+
+impl Default for CategoryConstants {
+    /// What a category uses until weights are applied to it.
+    fn default() -> Self {
+        Self {
+            kappa: 10.0,
+            logit_offset: 0.0,
+        }
+    }
+}
+
+/// Stored constants of an agent's categories, by category name. Categories
+/// without a row use `CategoryConstants::default()`.
+pub async fn load_category_constants(
+    db: &SqlitePool,
+    agent_id: i32,
+) -> anyhow::Result<HashMap<String, CategoryConstants>> {
+    let aid = agent_id as i64;
+    let rows = sqlx::query!(
+        "SELECT category_name, kappa, logit_offset FROM category_constants WHERE agent_id = ?",
+        aid,
     )
-    .execute(db)
+    .fetch_all(db)
     .await
-    .context("failed to upsert vc_message_constants row")?;
+    .context("failed to load category_constants")?;
 
-    let row = sqlx::query!(
-        "SELECT kappa FROM vc_message_constants WHERE message_id = ?",
-        message_id,
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            let constants = CategoryConstants {
+                kappa: r.kappa,
+                logit_offset: r.logit_offset,
+            };
+            (r.category_name, constants)
+        })
+        .collect())
+}
+
+/// Store constants for categories of `agent_id` that have none yet; existing
+/// rows are left alone. Returns how many rows were inserted.
+pub async fn insert_missing_category_constants(
+    db: &SqlitePool,
+    agent_id: i32,
+    constants: &[(String, CategoryConstants)],
+) -> anyhow::Result<u64> {
+    let aid = agent_id as i64;
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    let mut inserted = 0;
+    for (category_name, c) in constants {
+        inserted += sqlx::query!(
+            "INSERT OR IGNORE INTO category_constants (agent_id, category_name, kappa, logit_offset) \
+             VALUES (?, ?, ?, ?)",
+            aid,
+            category_name,
+            c.kappa,
+            c.logit_offset,
+        )
+        .execute(&mut *tx)
+        .await
+        .context("failed to insert category_constants row")?
+        .rows_affected();
+    }
+    tx.commit().await.context("failed to commit category constants")?;
+    Ok(inserted)
+}
+
+/// Rows of the legacy per-message constants table, by message ID.
+pub async fn load_message_constants(
+    db: &SqlitePool,
+) -> anyhow::Result<HashMap<i64, CategoryConstants>> {
+    let rows = sqlx::query!(
+        r#"SELECT message_id AS "message_id!: i64", kappa, logit_offset FROM vc_message_constants"#
     )
-    .fetch_one(db)
+    .fetch_all(db)
     .await
-    .context("failed to fetch kappa for message")?;
+    .context("failed to load vc_message_constants")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            let constants = CategoryConstants {
+                kappa: r.kappa,
+                logit_offset: r.logit_offset,
+            };
+            (r.message_id, constants)
+        })
+        .collect())
+}
 
-    Ok(row.kappa)
+/// Empty the legacy per-message constants table once it has been carried
+/// over. Returns how many rows were deleted.
+pub async fn clear_message_constants(db: &SqlitePool) -> anyhow::Result<u64> {
+    let result = sqlx::query!("DELETE FROM vc_message_constants")
+        .execute(db)
+        .await
+        .context("failed to clear vc_message_constants")?;
+    Ok(result.rows_affected())
 }
 
-/// Overwrite the kappa value for a single message.
-pub async fn set_kappa(db: &SqlitePool, message_id: i64, kappa: f64) -> anyhow::Result<()> {
-    sqlx::query!(
-        "INSERT INTO vc_message_constants (message_id, kappa) VALUES (?, ?)
-         ON CONFLICT(message_id) DO UPDATE SET kappa = excluded.kappa",
-        message_id,
-        kappa,
+/// The agent's logit temperature; 1.0 until a weight set sets one.
+pub async fn get_logit_temperature(db: &SqlitePool, agent_id: i32) -> anyhow::Result<f64> {
+    let aid = agent_id as i64;
+    let row = sqlx::query!(
+        "SELECT logit_temperature FROM agent_constants WHERE agent_id = ?",
+        aid,
     )
-    .execute(db)
+    .fetch_optional(db)
     .await
-    .context("failed to set kappa for message")?;
-    Ok(())
+    .context("failed to fetch logit temperature")?;
+    Ok(row.map_or(1.0, |r| r.logit_temperature))
+}
+
+/// The agent's active weight set, if one was ever activated.
+pub async fn get_active_weight_set_id(db: &SqlitePool, agent_id: i32) -> anyhow::Result<Option<i64>> {
+    let aid = agent_id as i64;
+    let row = sqlx::query!(
+        "SELECT active_weight_set_id FROM agent_constants WHERE agent_id = ?",
+        aid,
+    )
+    .fetch_optional(db)
+    .await
+    .context("failed to fetch active weight set")?;
+    Ok(row.and_then(|r| r.active_weight_set_id))
 }
 
 /// Return the agent_id stored in a bulk_test_run row.
@@ -145,23 +332,61 @@ pub async fn get_run_agent_id(db: &SqlitePool, run_id: i64) -> anyhow::Result<i6
     Ok(row.agent_id)
 }
 
+// ---------------------------------------------------------------------------
+// Embedding cache — SQLite
+// ---------------------------------------------------------------------------
+
+/// Cached embedding of `text` (already normalized) under `model`, if any.
+pub async fn get_cached_embedding(
+    db: &SqlitePool,
+    model: &str,
+    text: &str,
+) -> anyhow::Result<Option<Vec<f32>>> {
+    let row = sqlx::query!(
+        "SELECT embedding FROM embedding_cache WHERE model = ? AND text = ?",
+        model,
+        text,
+    )
+    .fetch_optional(db)
+    .await
+    .context("failed to read embedding_cache")?;
+    Ok(row.map(|r| {
+        r.embedding
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }))
+}
+
+/// Store the embedding of `text` (already normalized) under `model`.
+pub async fn insert_cached_embedding(
+    db: &SqlitePool,
+    model: &str,
+    text: &str,
+    embedding: &[f32],
+) -> anyhow::Result<()> {
+    let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+    sqlx::query!(
+        "INSERT OR REPLACE INTO embedding_cache (model, text, embedding) VALUES (?, ?, ?)",
+        model,
+        text,
+        bytes,
+    )
+    .execute(db)
+    .await
+    .context("failed to write embedding_cache")?;
+    Ok(())
+}
+
 // ---------------------------------------------------------------------------
 // VC database — Postgres (read-only)
 // Uses sqlx::query_as with typed structs — no query! macro because this is an
 // external schema we do not own and DATABASE_URL points to SQLite.
 // ---------------------------------------------------------------------------
 
-use inference::VCmessage;
+use inference::{VCmessage, clean_vc_message};
 use sqlx::PgPool;
 
-/// Raw row returned from the marketing `vcmessages` table.
-#[derive(sqlx::FromRow)]
-struct VcMessageRow {
-    categoryname: Option<String>,
-    categorydescription: Option<String>,
-    textcontent: Option<String>,
-}
-
 /// List all agent IDs that have at least one valid VC message.
 pub async fn list_agent_ids(vc_db: &PgPool) -> anyhow::Result<Vec<i32>> {
     let ids = sqlx::query_scalar::<_, i32>(
@@ -180,56 +405,16 @@ pub async fn list_agent_ids(vc_db: &PgPool) -> anyhow::Result<Vec<i32>> {
     Ok(ids)
 }
 
-/// Load approved VC messages for a given agent from the marketing Postgres DB.
-/// Filters out placeholder-only rows and cleans `{{conversation_continuer}}` tags.
-pub async fn load_vc_messages(
-    vc_db: &PgPool,
-    agent_id: i32,
-) -> anyhow::Result<Vec<VCmessage>> {
-    let rows = sqlx::query_as::<_, VcMessageRow>(
-        r#"SELECT categoryname, categorydescription, textcontent
-           FROM vcmessages
-           WHERE agentid    = $1
-             AND textcontent   IS NOT NULL
-             AND categoryname  IS NOT NULL
-             AND categoryname  != 'conversation_flow'
-             AND textcontent NOT LIKE '%{{conversation_flow}}%'
-             AND textcontent   != 'N/A'"#,
+/// Latest VC message version of `agent_id`, or `None` if it has no messages.
+pub async fn get_vc_message_version(vc_db: &PgPool, agent_id: i32) -> anyhow::Result<Option<i64>> {
+    let version = sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MAX(versionid)::bigint FROM vcmessages WHERE agentid = $1",
     )
     .bind(agent_id)
-    .fetch_all(vc_db)
+    .fetch_one(vc_db)
     .await
-    .context("failed to load vcmessages from marketing DB")?;
-
-    let messages: Vec<VCmessage> = rows
-        .into_iter()
-        .filter_map(|r| {
-            let category = r.categoryname?.trim().to_string();
-            let description = r
-                .categorydescription
-                .unwrap_or_default()
-                .trim()
-                .to_string();
-            let raw_text = r.textcontent?;
-            let message = raw_text
-                .replace("{{conversation_continuer}}", "")
-                .trim()
-                .to_string();
-            if message.is_empty() || category.starts_with("qpharma.") {
-                return None;
-            }
-            Some(VCmessage {
-                category,
-                kind: String::new(),
-                description,
-                mlr_message: message.clone(),
-                message,
-            })
-        })
-        .collect();
-
-    anyhow::ensure!(!messages.is_empty(), "no valid VC messages found for agent {agent_id}");
-    Ok(messages)
+    .context("failed to fetch VC message version from marketing DB")?;
+    Ok(version)
 }
 
 // ---------------------------------------------------------------------------
@@ -237,12 +422,14 @@ pub async fn load_vc_messages(
 // ---------------------------------------------------------------------------
 
 /// One HCP example message used as a test prompt.
+#[derive(Clone)]
 pub struct HcpExample {
     pub id: i32,
     pub text: String,
 }
 
 /// A VC message with its Postgres primary key.
+#[derive(Clone)]
 pub struct VcMessageWithId {
     pub id: i32,
     pub vc_message: VCmessage,
@@ -349,26 +536,21 @@ pub async fn load_vc_messages_with_ids(
     let messages = rows
         .into_iter()
         .filter_map(|r| {
-            let id = r.id?;
-            let category = r.categoryname?.trim().to_string();
-            let description = r.categorydescription.unwrap_or_default().trim().to_string();
-            let raw_text = r.textcontent?;
-            let message = raw_text
-                .replace("{{conversation_continuer}}", "")
-                .trim()
-                .to_string();
-            if message.is_empty() || category.starts_with("qpharma.") {
+            // The marketing database has no MLR copy; the message stands in.
+            let text = r.textcontent?;
+            let vc_message = clean_vc_message(
+                &r.categoryname?,
+                "",
+                &r.categorydescription.unwrap_or_default(),
+                &text,
+                &text,
+            )?;
+            if vc_message.category.starts_with("qpharma.") {
                 return None;
             }
             Some(VcMessageWithId {
-                id,
-                vc_message: VCmessage {
-                    category,
-                    kind: String::new(),
-                    description,
-                    mlr_message: message.clone(),
-                    message,
-                },
+                id: r.id?,
+                vc_message,
             })
         })
         .collect();
@@ -382,11 +564,30 @@ pub async fn load_vc_messages_with_ids(
 // ---------------------------------------------------------------------------
 
 /// Create a new bulk test run row and return its SQLite row ID.
-pub async fn create_bulk_test_run(db: &SqlitePool, agent_id: i32) -> anyhow::Result<i64> {
+/// `validation_set_id` is `None` for runs against the marketing DB examples.
+/// `weight_set_id` is the weight set the run's constants came from,
+/// `config_json` the run's configuration snapshot and `experiment` the
+/// experiment ID and variant index of a variant's run.
+pub async fn create_bulk_test_run(
+    db: &SqlitePool,
+    agent_id: i32,
+    validation_set_id: Option<i64>,
+    weight_set_id: Option<i64>,
+    config_json: &str,
+    experiment: Option<(i64, i64)>,
+) -> anyhow::Result<i64> {
     let aid = agent_id as i64;
+    let (experiment_id, variant_index) = experiment.unzip();
     let result = sqlx::query!(
-        "INSERT INTO bulk_test_runs (agent_id) VALUES (?)",
+        "INSERT INTO bulk_test_runs \
+             (agent_id, validation_set_id, weight_set_id, config, experiment_id, variant_index) \
+         VALUES (?, ?, ?, ?, ?, ?)",
         aid,
+        validation_set_id,
+        weight_set_id,
+        config_json,
+        experiment_id,
+        variant_index,
     )
     .execute(db)
     .await
@@ -394,6 +595,36 @@ pub async fn create_bulk_test_run(db: &SqlitePool, agent_id: i32) -> anyhow::Res
     Ok(result.last_insert_rowid())
 }
 
+/// Agent and validation set a bulk test run's examples came from, or `None`
+/// if the run does not exist. The validation set is `None` for the marketing
+/// DB examples.
+pub async fn get_bulk_test_run_examples_source(
+    db: &SqlitePool,
+    run_id: i64,
+) -> anyhow::Result<Option<(i64, Option<i64>)>> {
+    let row = sqlx::query!(
+        "SELECT agent_id, validation_set_id FROM bulk_test_runs WHERE id = ?",
+        run_id
+    )
+    .fetch_optional(db)
+    .await
+    .context("failed to fetch bulk_test_run examples source")?;
+    Ok(row.map(|r| (r.agent_id, r.validation_set_id)))
+}
+
+/// Configuration snapshot of a bulk test run: `None` if the run does not
+/// exist, `Some(None)` if it predates snapshots.
+pub async fn load_bulk_test_run_config(
+    db: &SqlitePool,
+    run_id: i64,
+) -> anyhow::Result<Option<Option<String>>> {
+    let row = sqlx::query!("SELECT config FROM bulk_test_runs WHERE id = ?", run_id)
+        .fetch_optional(db)
+        .await
+        .context("failed to fetch bulk_test_run config")?;
+    Ok(row.map(|r| r.config))
+}
+
 /// Persist one example result within a bulk test run.
 pub async fn insert_bulk_test_result(
     db: &SqlitePool,
@@ -434,7 +665,7 @@ pub async fn complete_bulk_test_run(
 ) -> anyhow::Result<()> {
     sqlx::query!(
         "UPDATE bulk_test_runs \
-         SET completed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'), \
+         SET status = 'complete', completed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'), \
              total = ?, success_count = ? \
          WHERE id = ?",
         total,
@@ -447,21 +678,52 @@ pub async fn complete_bulk_test_run(
     Ok(())
 }
 
+/// Mark a run as cancelled, with totals over the examples that finished.
+pub async fn cancel_bulk_test_run(
+    db: &SqlitePool,
+    run_id: i64,
+    total: i64,
+    success_count: i64,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE bulk_test_runs \
+         SET status = 'cancelled', completed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'), \
+             total = ?, success_count = ? \
+         WHERE id = ?",
+        total,
+        success_count,
+        run_id,
+    )
+    .execute(db)
+    .await
+    .context("failed to cancel bulk_test_run")?;
+    Ok(())
+}
+
 #[derive(serde::Serialize)]
 pub struct BulkTestRunSummary {
     pub id: i64,
     pub agent_id: i64,
     pub started_at: String,
     pub completed_at: Option<String>,
+    /// running | complete | cancelled
+    pub status: String,
     pub total: Option<i64>,
     pub success_count: Option<i64>,
+    /// Local validation set the run used; `None` for the marketing DB examples.
+    pub validation_set_id: Option<i64>,
+    /// Weight set active when the run started; `None` before the agent had one.
+    pub weight_set_id: Option<i64>,
+    /// Whether the run stored a configuration snapshot it can be re-run from.
+    pub has_config: bool,
 }
 
 /// List the 50 most recent bulk test runs (newest first).
 pub async fn list_bulk_test_runs(db: &SqlitePool) -> anyhow::Result<Vec<BulkTestRunSummary>> {
     let rows = sqlx::query!(
-        "SELECT id, agent_id, started_at, completed_at, total, success_count \
-         FROM bulk_test_runs ORDER BY started_at DESC LIMIT 50"
+        r#"SELECT id, agent_id, started_at, completed_at, status, total, success_count,
+                  validation_set_id, weight_set_id, config IS NOT NULL AS "has_config!: bool"
+           FROM bulk_test_runs ORDER BY started_at DESC LIMIT 50"#
     )
     .fetch_all(db)
     .await
@@ -474,8 +736,115 @@ pub async fn list_bulk_test_runs(db: &SqlitePool) -> anyhow::Result<Vec<BulkTest
             agent_id: r.agent_id,
             started_at: r.started_at,
             completed_at: r.completed_at,
+            status: r.status,
             total: r.total,
             success_count: r.success_count,
+            validation_set_id: r.validation_set_id,
+            weight_set_id: r.weight_set_id,
+            has_config: r.has_config,
+        })
+        .collect())
+}
+
+/// Create an experiment row for `variants_json` and return its ID.
+pub async fn create_bulk_test_experiment(
+    db: &SqlitePool,
+    agent_id: i32,
+    validation_set_id: Option<i64>,
+    variants_json: &str,
+) -> anyhow::Result<i64> {
+    let aid = agent_id as i64;
+    let result = sqlx::query!(
+        "INSERT INTO bulk_test_experiments (agent_id, validation_set_id, variants) VALUES (?, ?, ?)",
+        aid,
+        validation_set_id,
+        variants_json,
+    )
+    .execute(db)
+    .await
+    .context("failed to insert bulk_test_experiment")?;
+    Ok(result.last_insert_rowid())
+}
+
+/// Mark an experiment `complete`, `cancelled` or `failed`.
+pub async fn finish_bulk_test_experiment(
+    db: &SqlitePool,
+    experiment_id: i64,
+    status: &str,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE bulk_test_experiments \
+         SET status = ?, completed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') \
+         WHERE id = ?",
+        status,
+        experiment_id,
+    )
+    .execute(db)
+    .await
+    .context("failed to finish bulk_test_experiment")?;
+    Ok(())
+}
+
+pub struct BulkTestExperimentRow {
+    pub id: i64,
+    pub agent_id: i64,
+    pub validation_set_id: Option<i64>,
+    pub variants_json: String,
+    pub started_at: String,
+    pub completed_at: Option<String>,
+    pub status: String,
+}
+
+pub async fn get_bulk_test_experiment(
+    db: &SqlitePool,
+    experiment_id: i64,
+) -> anyhow::Result<Option<BulkTestExperimentRow>> {
+    let row = sqlx::query!(
+        "SELECT id, agent_id, validation_set_id, variants, started_at, completed_at, status \
+         FROM bulk_test_experiments WHERE id = ?",
+        experiment_id,
+    )
+    .fetch_optional(db)
+    .await
+    .context("failed to fetch bulk_test_experiment")?;
+    Ok(row.map(|r| BulkTestExperimentRow {
+        id: r.id,
+        agent_id: r.agent_id,
+        validation_set_id: r.validation_set_id,
+        variants_json: r.variants,
+        started_at: r.started_at,
+        completed_at: r.completed_at,
+        status: r.status,
+    }))
+}
+
+pub struct ExperimentRunRow {
+    pub run_id: i64,
+    pub variant_index: i64,
+    pub status: String,
+}
+
+/// The runs of an experiment's variants that have started.
+pub async fn list_experiment_runs(
+    db: &SqlitePool,
+    experiment_id: i64,
+) -> anyhow::Result<Vec<ExperimentRunRow>> {
+    let rows = sqlx::query!(
+        r#"SELECT id, variant_index AS "variant_index!: i64", status
+           FROM bulk_test_runs
+           WHERE experiment_id = ? AND variant_index IS NOT NULL
+           ORDER BY variant_index"#,
+        experiment_id,
+    )
+    .fetch_all(db)
+    .await
+    .context("failed to list experiment runs")?;
+    Ok(rows
+        .into_iter()
+        .map(|r| ExperimentRunRow {
+            run_id: r.id,
+            variant_index: r.variant_index,
+            status: r.status,
         })
         .collect())
 }
@@ -516,6 +885,346 @@ pub async fn load_bulk_test_results(
         .collect())
 }
 
+// ---------------------------------------------------------------------------
+// Validation sets — SQLite
+// ---------------------------------------------------------------------------
+
+/// Store a labelled example set for `agent_id` and return its row ID.
+/// Each example is `(text, correct_categories_json)`.
+pub async fn create_validation_set(
+    db: &SqlitePool,
+    agent_id: i32,
+    name: &str,
+    examples: &[(String, String)],
+) -> anyhow::Result<i64> {
+    let aid = agent_id as i64;
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    let set_id = sqlx::query!(
+        "INSERT INTO validation_sets (agent_id, name) VALUES (?, ?)",
+        aid,
+        name,
+    )
+    .execute(&mut *tx)
+    .await
+    .context("failed to insert validation_set")?
+    .last_insert_rowid();
+
+    for (text, correct_categories_json) in examples {
+        sqlx::query!(
+            "INSERT INTO validation_examples (set_id, text, correct_categories) VALUES (?, ?, ?)",
+            set_id,
+            text,
+            correct_categories_json,
+        )
+        .execute(&mut *tx)
+        .await
+        .context("failed to insert validation_example")?;
+    }
+    tx.commit().await.context("failed to commit validation set")?;
+    Ok(set_id)
+}
+
+#[derive(serde::Serialize)]
+pub struct ValidationSetSummary {
+    pub id: i64,
+    pub agent_id: i64,
+    pub name: String,
+    pub created_at: String,
+    pub example_count: i64,
+}
+
+/// List an agent's validation sets, newest first.
+pub async fn list_validation_sets(
+    db: &SqlitePool,
+    agent_id: i32,
+) -> anyhow::Result<Vec<ValidationSetSummary>> {
+    let aid = agent_id as i64;
+    let rows = sqlx::query!(
+        r#"SELECT s.id, s.agent_id, s.name, s.created_at,
+                  (SELECT COUNT(*) FROM validation_examples e WHERE e.set_id = s.id) AS "example_count!: i64"
+           FROM validation_sets s
+           WHERE s.agent_id = ? AND s.deleted_at IS NULL
+           ORDER BY s.id DESC"#,
+        aid,
+    )
+    .fetch_all(db)
+    .await
+    .context("failed to list validation_sets")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| ValidationSetSummary {
+            id: r.id,
+            agent_id: r.agent_id,
+            name: r.name,
+            created_at: r.created_at,
+            example_count: r.example_count,
+        })
+        .collect())
+}
+
+/// Agent a validation set belongs to, or `None` if the set does not exist
+/// or was deleted.
+pub async fn get_validation_set_agent_id(
+    db: &SqlitePool,
+    set_id: i64,
+) -> anyhow::Result<Option<i64>> {
+    let row = sqlx::query!(
+        "SELECT agent_id FROM validation_sets WHERE id = ? AND deleted_at IS NULL",
+        set_id
+    )
+    .fetch_optional(db)
+    .await
+    .context("failed to fetch validation_set")?;
+    Ok(row.map(|r| r.agent_id))
+}
+
+pub struct StoredValidationExample {
+    pub id: i64,
+    pub text: String,
+    pub correct_categories_json: String,
+}
+
+/// Every example in a validation set, in import order.
+pub async fn load_validation_examples(
+    db: &SqlitePool,
+    set_id: i64,
+) -> anyhow::Result<Vec<StoredValidationExample>> {
+    let rows = sqlx::query!(
+        r#"SELECT id AS "id!: i64", text, correct_categories
+           FROM validation_examples WHERE set_id = ? ORDER BY id"#,
+        set_id,
+    )
+    .fetch_all(db)
+    .await
+    .context("failed to load validation_examples")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| StoredValidationExample {
+            id: r.id,
+            text: r.text,
+            correct_categories_json: r.correct_categories,
+        })
+        .collect())
+}
+
+/// Delete a validation set's examples and mark the set deleted; its row stays
+/// so runs over it keep their `validation_set_id`. Returns `false` if it did
+/// not exist or was already deleted.
+pub async fn delete_validation_set(db: &SqlitePool, set_id: i64) -> anyhow::Result<bool> {
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    sqlx::query!("DELETE FROM validation_examples WHERE set_id = ?", set_id)
+        .execute(&mut *tx)
+        .await
+        .context("failed to delete validation_examples")?;
+    let result = sqlx::query!(
+        "UPDATE validation_sets SET deleted_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') \
+         WHERE id = ? AND deleted_at IS NULL",
+        set_id
+    )
+    .execute(&mut *tx)
+    .await
+    .context("failed to delete validation_set")?;
+    tx.commit().await.context("failed to commit validation set delete")?;
+    Ok(result.rows_affected() > 0)
+}
+
+// ---------------------------------------------------------------------------
+// Weight sets — SQLite
+// ---------------------------------------------------------------------------
+
+/// One category's constants within a weight set.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct WeightSetEntry {
+    pub category_name: String,
+    pub kappa: f64,
+    pub logit_offset: f64,
+}
+
+/// Store a new weight set for `agent_id` as its next version. Returns the
+/// row ID and the version number. The set is not activated.
+pub async fn create_weight_set(
+    db: &SqlitePool,
+    agent_id: i32,
+    name: &str,
+    source_run_id: Option<i64>,
+    logit_temperature: f64,
+    entries: &[WeightSetEntry],
+) -> anyhow::Result<(i64, i64)> {
+    let aid = agent_id as i64;
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    let version = sqlx::query!(
+        r#"SELECT COALESCE(MAX(version), 0) + 1 AS "version!: i64"
+           FROM weight_sets WHERE agent_id = ?"#,
+        aid,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .context("failed to fetch next weight set version")?
+    .version;
+    let set_id = sqlx::query!(
+        "INSERT INTO weight_sets (agent_id, version, name, source_run_id, logit_temperature) \
+         VALUES (?, ?, ?, ?, ?)",
+        aid,
+        version,
+        name,
+        source_run_id,
+        logit_temperature,
+    )
+    .execute(&mut *tx)
+    .await
+    .context("failed to insert weight_set")?
+    .last_insert_rowid();
+
+    for entry in entries {
+        sqlx::query!(
+            "INSERT INTO weight_set_entries (weight_set_id, category_name, kappa, logit_offset) \
+             VALUES (?, ?, ?, ?)",
+            set_id,
+            entry.category_name,
+            entry.kappa,
+            entry.logit_offset,
+        )
+        .execute(&mut *tx)
+        .await
+        .context("failed to insert weight_set_entry")?;
+    }
+    tx.commit().await.context("failed to commit weight set")?;
+    Ok((set_id, version))
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct WeightSetSummary {
+    pub id: i64,
+    pub agent_id: i64,
+    pub version: i64,
+    pub name: String,
+    pub source_run_id: Option<i64>,
+    pub logit_temperature: f64,
+    pub created_at: String,
+    pub entry_count: i64,
+    /// Whether this is the agent's active weight set.
+    pub active: bool,
+}
+
+/// List an agent's weight sets, newest version first.
+pub async fn list_weight_sets(
+    db: &SqlitePool,
+    agent_id: i32,
+) -> anyhow::Result<Vec<WeightSetSummary>> {
+    let aid = agent_id as i64;
+    let rows = sqlx::query!(
+        r#"SELECT w.id AS "id!: i64", w.agent_id, w.version, w.name, w.source_run_id,
+                  w.logit_temperature, w.created_at,
+                  (SELECT COUNT(*) FROM weight_set_entries e WHERE e.weight_set_id = w.id) AS "entry_count!: i64",
+                  EXISTS (SELECT 1 FROM agent_constants a
+                          WHERE a.agent_id = w.agent_id AND a.active_weight_set_id = w.id) AS "active!: bool"
+           FROM weight_sets w
+           WHERE w.agent_id = ?
+           ORDER BY w.version DESC"#,
+        aid,
+    )
+    .fetch_all(db)
+    .await
+    .context("failed to list weight_sets")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| WeightSetSummary {
+            id: r.id,
+            agent_id: r.agent_id,
+            version: r.version,
+            name: r.name,
+            source_run_id: r.source_run_id,
+            logit_temperature: r.logit_temperature,
+            created_at: r.created_at,
+            entry_count: r.entry_count,
+            active: r.active,
+        })
+        .collect())
+}
+
+/// Agent a weight set belongs to, or `None` if the set does not exist.
+pub async fn get_weight_set_agent_id(db: &SqlitePool, set_id: i64) -> anyhow::Result<Option<i64>> {
+    let row = sqlx::query!("SELECT agent_id FROM weight_sets WHERE id = ?", set_id)
+        .fetch_optional(db)
+        .await
+        .context("failed to fetch weight_set")?;
+    Ok(row.map(|r| r.agent_id))
+}
+
+/// Every entry of a weight set, ordered by category name.
+pub async fn load_weight_set_entries(
+    db: &SqlitePool,
+    set_id: i64,
+) -> anyhow::Result<Vec<WeightSetEntry>> {
+    let rows = sqlx::query!(
+        "SELECT category_name, kappa, logit_offset \
+         FROM weight_set_entries WHERE weight_set_id = ? ORDER BY category_name",
+        set_id,
+    )
+    .fetch_all(db)
+    .await
+    .context("failed to load weight_set_entries")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| WeightSetEntry {
+            category_name: r.category_name,
+            kappa: r.kappa,
+            logit_offset: r.logit_offset,
+        })
+        .collect())
+}
+
+/// Make a weight set the live one for its agent: copy its entries into
+/// `category_constants`, its temperature into `agent_constants`, and point
+/// the agent's active weight set at it. Returns `false` if it does not exist.
+pub async fn activate_weight_set(db: &SqlitePool, set_id: i64) -> anyhow::Result<bool> {
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    let Some(set) = sqlx::query!(
+        "SELECT agent_id, logit_temperature FROM weight_sets WHERE id = ?",
+        set_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .context("failed to fetch weight_set")?
+    else {
+        return Ok(false);
+    };
+
+    sqlx::query!(
+        "INSERT INTO category_constants (agent_id, category_name, kappa, logit_offset) \
+         SELECT ?, category_name, kappa, logit_offset FROM weight_set_entries \
+         WHERE weight_set_id = ? \
+         ON CONFLICT(agent_id, category_name) DO UPDATE \
+         SET kappa = excluded.kappa, logit_offset = excluded.logit_offset",
+        set.agent_id,
+        set_id,
+    )
+    .execute(&mut *tx)
+    .await
+    .context("failed to copy weight set into category_constants")?;
+
+    sqlx::query!(
+        "INSERT INTO agent_constants (agent_id, logit_temperature, active_weight_set_id) \
+         VALUES (?, ?, ?) \
+         ON CONFLICT(agent_id) DO UPDATE \
+         SET logit_temperature = excluded.logit_temperature, \
+             active_weight_set_id = excluded.active_weight_set_id",
+        set.agent_id,
+        set.logit_temperature,
+        set_id,
+    )
+    .execute(&mut *tx)
+    .await
+    .context("failed to set active weight set")?;
+
+    tx.commit().await.context("failed to commit weight set activation")?;
+    Ok(true)
+}
+
 // ---------------------------------------------------------------------------
 // Embedding margin scores — Postgres
 // ---------------------------------------------------------------------------
@@ -595,3 +1304,63 @@ pub async fn compute_embedding_margins(
 
     Ok(margins)
 }
+
+/// One positive or negative example embedding of a VC message.
+pub struct MarginExample {
+    pub message_id: i64,
+    pub category_name: String,
+    /// `true` for `kind = 'pos'`, `false` for `kind = 'neg'`.
+    pub positive: bool,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(sqlx::FromRow)]
+struct MarginExampleRow {
+    message_id: Option<i32>,
+    message_identifier: Option<String>,
+    kind: Option<String>,
+    embedding: Option<pgvector::Vector>,
+}
+
+/// Load every positive/negative example embedding that
+/// `compute_embedding_margins` compares against, for the latest version of
+/// the given agent. Rows with another `kind` are skipped.
+pub async fn load_margin_examples(
+    vc_db: &PgPool,
+    agent_id: i32,
+) -> anyhow::Result<Vec<MarginExample>> {
+    let rows = sqlx::query_as::<_, MarginExampleRow>(
+        r#"SELECT
+              f.messageid     AS message_id,
+              m.categoryName  AS message_identifier,
+              f.kind          AS kind,
+              f.embeddingData AS embedding
+            FROM vcembeddingmessagefinalparameters f
+            JOIN vcmessages m ON f.messageid = m.id
+            WHERE m.agentid = $1
+              AND m.versionid = (SELECT MAX(versionid) FROM vcmessages WHERE agentid = $1)"#,
+    )
+    .bind(agent_id)
+    .fetch_all(vc_db)
+    .await
+    .with_context(|| format!("failed to load margin examples for agent {agent_id}"))?;
+
+    let examples = rows
+        .into_iter()
+        .filter_map(|r| {
+            let positive = match r.kind?.as_str() {
+                "pos" => true,
+                "neg" => false,
+                _ => return None,
+            };
+            Some(MarginExample {
+                message_id: r.message_id? as i64,
+                category_name: r.message_identifier?,
+                positive,
+                embedding: r.embedding?.to_vec(),
+            })
+        })
+        .collect();
+
+    Ok(examples)
+}
@@ -1,8 +1,207 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use anyhow::Context;
-use serde::Deserialize;
+use futures::future::BoxFuture;
+use inference::{InferenceEngine, LlamaEmbedder};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::db;
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-3-large";
+
+/// Turns text into embedding vectors for the margin heuristic.
+///
+/// The vectors must come from the same model as the message embeddings stored
+/// in the VC database, or the margins are meaningless.
+pub trait EmbeddingProvider: Send + Sync {
+    /// Model identifier; part of the embedding cache key.
+    fn model(&self) -> &str;
+
+    /// One embedding per input, in the same order as `texts`.
+    fn embed_batch<'a>(
+        &'a self,
+        texts: &'a [&'a str],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Vec<f32>>>>;
+}
+
+/// Build the provider selected by `EMBEDDING_PROVIDER`:
+///
+/// - `openai` — OpenAI's `text-embedding-3-large`; needs `OPENAI_API_KEY`.
+/// - `openai-compatible` — any server exposing `POST {EMBEDDING_BASE_URL}/embeddings`
+///   (vLLM, llama-server, Ollama, …) serving `EMBEDDING_MODEL`. `EMBEDDING_API_KEY`
+///   is sent as a bearer token when set.
+/// - `local` — the GGUF embedding model at `EMBEDDING_MODEL_PATH`, run in-process.
+/// - `none` — no embedding biases.
+///
+/// Unset defaults to `openai` when `OPENAI_API_KEY` is set, otherwise `none`.
+/// Blocking when loading a local model.
+pub fn provider_from_env(
+    engine: &InferenceEngine,
+) -> anyhow::Result<Option<Arc<dyn EmbeddingProvider>>> {
+    let env = |key: &str| std::env::var(key).ok();
+    let kind = env("EMBEDDING_PROVIDER").unwrap_or_else(|| {
+        if env("OPENAI_API_KEY").is_some() {
+            "openai"
+        } else {
+            "none"
+        }
+        .to_string()
+    });
+
+    let provider: Arc<dyn EmbeddingProvider> = match kind.as_str() {
+        "none" => return Ok(None),
+        "openai" => {
+            let api_key =
+                env("OPENAI_API_KEY").context("EMBEDDING_PROVIDER=openai needs OPENAI_API_KEY")?;
+            Arc::new(OpenAiCompatibleProvider::openai(api_key))
+        }
+        "openai-compatible" => Arc::new(OpenAiCompatibleProvider::new(
+            env("EMBEDDING_BASE_URL")
+                .context("EMBEDDING_PROVIDER=openai-compatible needs EMBEDDING_BASE_URL")?,
+            env("EMBEDDING_MODEL")
+                .context("EMBEDDING_PROVIDER=openai-compatible needs EMBEDDING_MODEL")?,
+            env("EMBEDDING_API_KEY"),
+        )),
+        "local" => {
+            let model_path = PathBuf::from(
+                env("EMBEDDING_MODEL_PATH")
+                    .context("EMBEDDING_PROVIDER=local needs EMBEDDING_MODEL_PATH")?,
+            );
+            Arc::new(LocalProvider::new(
+                engine.load_embedder(&model_path)?,
+                &model_path,
+            ))
+        }
+        other => anyhow::bail!(
+            "unknown EMBEDDING_PROVIDER {other:?}; expected openai, openai-compatible, local or none"
+        ),
+    };
+    Ok(Some(provider))
+}
+
+// ---------------------------------------------------------------------------
+// Persistent cache in front of the provider
+// ---------------------------------------------------------------------------
+
+/// Embeddings keyed by model and normalized text in SQLite, so repeated
+/// prompts and re-run bulk tests cost no provider calls.
+pub struct CachedEmbeddings {
+    provider: Arc<dyn EmbeddingProvider>,
+    db: SqlitePool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Cache lookups since start-up, counted per text.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
 
-const OPENAI_EMBEDDINGS_URL: &str = "https://api.openai.com/v1/embeddings";
-const EMBEDDING_MODEL: &str = "text-embedding-3-large";
+impl CachedEmbeddings {
+    pub fn new(provider: Arc<dyn EmbeddingProvider>, db: SqlitePool) -> Self {
+        Self {
+            provider,
+            db,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        self.provider.model()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Embedding for a single text.
+    pub async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_batch(&[text])
+            .await?
+            .pop()
+            .context("embedding provider returned no data")
+    }
+
+    /// One embedding per input, in the same order as `texts`. Only texts
+    /// missing from the cache are sent to the provider, once each. A cache
+    /// read or write failure is logged and treated as a miss.
+    pub async fn embed_batch(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let model = self.provider.model();
+        let normalized: Vec<String> = texts.iter().map(|t| normalize(t)).collect();
+
+        let mut found: HashMap<&str, Vec<f32>> = HashMap::new();
+        let mut missing: Vec<&str> = vec![];
+        for text in &normalized {
+            if found.contains_key(text.as_str()) || missing.contains(&text.as_str()) {
+                continue;
+            }
+            match db::get_cached_embedding(&self.db, model, text).await {
+                Ok(Some(embedding)) => {
+                    found.insert(text, embedding);
+                }
+                Ok(None) => missing.push(text),
+                Err(e) => {
+                    tracing::warn!(error = %e, "embedding cache read failed");
+                    missing.push(text);
+                }
+            }
+        }
+        self.hits.fetch_add(found.len() as u64, Ordering::Relaxed);
+        self.misses
+            .fetch_add(missing.len() as u64, Ordering::Relaxed);
+        tracing::debug!(
+            model,
+            hits = found.len(),
+            misses = missing.len(),
+            "embedding cache lookup"
+        );
+
+        if !missing.is_empty() {
+            let fetched = self.provider.embed_batch(&missing).await?;
+            anyhow::ensure!(
+                fetched.len() == missing.len(),
+                "embedding provider returned {} vectors for {} texts",
+                fetched.len(),
+                missing.len()
+            );
+            for (text, embedding) in missing.into_iter().zip(fetched) {
+                if let Err(e) = db::insert_cached_embedding(&self.db, model, text, &embedding).await
+                {
+                    tracing::warn!(error = %e, "embedding cache write failed");
+                }
+                found.insert(text, embedding);
+            }
+        }
+
+        Ok(normalized
+            .iter()
+            .map(|t| found[t.as_str()].clone())
+            .collect())
+    }
+}
+
+/// Normalize text before embedding: lowercase and strip punctuation.
+fn normalize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_ascii_punctuation())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+// ---------------------------------------------------------------------------
+// OpenAI and OpenAI-compatible HTTP APIs
+// ---------------------------------------------------------------------------
 
 #[derive(Deserialize)]
 struct EmbeddingResponse {
@@ -15,99 +214,241 @@ struct EmbeddingObject {
     index: usize,
 }
 
-/// Normalize text before embedding: lowercase and strip punctuation.
-fn normalize(text: &str) -> String {
-    text.chars()
-        .filter(|c| !c.is_ascii_punctuation())
-        .collect::<String>()
-        .to_lowercase()
+pub struct OpenAiCompatibleProvider {
+    client: reqwest::Client,
+    /// e.g. `https://api.openai.com/v1`; `/embeddings` is appended.
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
 }
 
-/// Call the OpenAI embeddings API for a batch of texts and return one embedding
-/// vector per input, in the same order as `texts`.
-///
-/// All texts are normalized before sending. The OpenAI response `index` field
-/// is used to reorder results correctly regardless of response ordering.
-pub async fn get_openai_embeddings_batch(
-    texts: &[&str],
-    api_key: &str,
-) -> anyhow::Result<Vec<Vec<f32>>> {
-    if texts.is_empty() {
-        return Ok(vec![]);
-    }
-    let normalized: Vec<String> = texts.iter().map(|t| normalize(t)).collect();
-    let client = reqwest::Client::new();
-    let body = serde_json::json!({
-        "input": normalized,
-        "model": EMBEDDING_MODEL,
-    });
+impl OpenAiCompatibleProvider {
+    pub fn new(base_url: String, model: String, api_key: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+        }
+    }
+
+    /// OpenAI's `text-embedding-3-large` (3072 dimensions).
+    pub fn openai(api_key: String) -> Self {
+        Self::new(
+            OPENAI_BASE_URL.to_string(),
+            OPENAI_EMBEDDING_MODEL.to_string(),
+            Some(api_key),
+        )
+    }
+
+    /// All texts are normalized before sending. The response `index` field
+    /// is used to reorder results correctly regardless of response ordering.
+    async fn request(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let normalized: Vec<String> = texts.iter().map(|t| normalize(t)).collect();
+        let body = serde_json::json!({
+            "input": normalized,
+            "model": self.model,
+        });
+
+        let mut request = self
+            .client
+            .post(format!("{}/embeddings", self.base_url))
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let resp = request
+            .send()
+            .await
+            .with_context(|| format!("failed to send embedding request to {}", self.base_url))?;
 
-    let resp = client
-        .post(OPENAI_EMBEDDINGS_URL)
-        .bearer_auth(api_key)
-        .json(&body)
-        .send()
-        .await
-        .context("failed to send OpenAI batch embedding request")?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!(
+                "embedding API at {} returned {status}: {text}",
+                self.base_url
+            );
+        }
 
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let text = resp.text().await.unwrap_or_default();
-        anyhow::bail!("OpenAI batch embedding API returned {status}: {text}");
+        let mut parsed: EmbeddingResponse = resp
+            .json()
+            .await
+            .context("failed to parse embedding response")?;
+
+        // Sort by index so output order matches input order.
+        parsed.data.sort_by_key(|o| o.index);
+
+        anyhow::ensure!(
+            parsed.data.len() == texts.len(),
+            "embedding API returned {} embeddings for {} inputs",
+            parsed.data.len(),
+            texts.len()
+        );
+
+        Ok(parsed.data.into_iter().map(|o| o.embedding).collect())
     }
+}
 
-    let mut parsed: EmbeddingResponse = resp
-        .json()
-        .await
-        .context("failed to parse OpenAI batch embedding response")?;
+impl EmbeddingProvider for OpenAiCompatibleProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
 
-    // Sort by index so output order matches input order.
-    parsed.data.sort_by_key(|o| o.index);
+    fn embed_batch<'a>(
+        &'a self,
+        texts: &'a [&'a str],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Vec<f32>>>> {
+        Box::pin(self.request(texts))
+    }
+}
 
-    anyhow::ensure!(
-        parsed.data.len() == texts.len(),
-        "OpenAI returned {} embeddings for {} inputs",
-        parsed.data.len(),
-        texts.len()
-    );
+// ---------------------------------------------------------------------------
+// In-process GGUF embedding model
+// ---------------------------------------------------------------------------
 
-    Ok(parsed.data.into_iter().map(|o| o.embedding).collect())
+pub struct LocalProvider {
+    embedder: Arc<LlamaEmbedder>,
+    model: String,
 }
 
-/// Call the OpenAI embeddings API and return the embedding vector for `text`.
-/// The text is normalized (lowercased, punctuation removed) before sending.
-/// Uses model `text-embedding-3-large` (3072 dimensions).
-pub async fn get_openai_embedding(text: &str, api_key: &str) -> anyhow::Result<Vec<f32>> {
-    let normalized = normalize(text);
-    let client = reqwest::Client::new();
-    let body = serde_json::json!({
-        "input": normalized,
-        "model": EMBEDDING_MODEL,
-    });
+impl LocalProvider {
+    pub fn new(embedder: LlamaEmbedder, model_path: &std::path::Path) -> Self {
+        tracing::info!(
+            model = %model_path.display(),
+            dimensions = embedder.dimensions(),
+            "loaded local embedding model"
+        );
+        Self {
+            embedder: Arc::new(embedder),
+            model: model_path.display().to_string(),
+        }
+    }
+}
+
+impl EmbeddingProvider for LocalProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Texts are normalized the same way as for the HTTP providers.
+    fn embed_batch<'a>(
+        &'a self,
+        texts: &'a [&'a str],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Vec<f32>>>> {
+        let embedder = Arc::clone(&self.embedder);
+        let normalized: Vec<String> = texts.iter().map(|t| normalize(t)).collect();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                let texts: Vec<&str> = normalized.iter().map(String::as_str).collect();
+                embedder.embed(&texts)
+            })
+            .await?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use sqlx::sqlite::SqlitePoolOptions;
 
-    let resp = client
-        .post(OPENAI_EMBEDDINGS_URL)
-        .bearer_auth(api_key)
-        .json(&body)
-        .send()
-        .await
-        .context("failed to send OpenAI embedding request")?;
-
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let text = resp.text().await.unwrap_or_default();
-        anyhow::bail!("OpenAI embedding API returned {status}: {text}");
-    }
-
-    let parsed: EmbeddingResponse = resp
-        .json()
-        .await
-        .context("failed to parse OpenAI embedding response")?;
-
-    parsed
-        .data
-        .into_iter()
-        .next()
-        .map(|o| o.embedding)
-        .context("OpenAI embedding response contained no data")
+    use super::*;
+
+    /// Embeds each text as `[len]` and records every text it was asked for.
+    struct CountingProvider {
+        requested: Mutex<Vec<String>>,
+    }
+
+    impl EmbeddingProvider for CountingProvider {
+        fn model(&self) -> &str {
+            "counting"
+        }
+
+        fn embed_batch<'a>(
+            &'a self,
+            texts: &'a [&'a str],
+        ) -> BoxFuture<'a, anyhow::Result<Vec<Vec<f32>>>> {
+            let mut requested = self.requested.lock().unwrap();
+            requested.extend(texts.iter().map(|t| t.to_string()));
+            let embeddings = texts.iter().map(|t| vec![t.len() as f32]).collect();
+            Box::pin(async move { Ok(embeddings) })
+        }
+    }
+
+    async fn cache() -> (CachedEmbeddings, Arc<CountingProvider>) {
+        // One connection, or every connection opens its own in-memory database.
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("../../migrations").run(&db).await.unwrap();
+        let provider = Arc::new(CountingProvider {
+            requested: Mutex::new(vec![]),
+        });
+        (CachedEmbeddings::new(provider.clone(), db), provider)
+    }
+
+    #[tokio::test]
+    async fn second_batch_is_served_from_the_cache() {
+        let (cache, provider) = cache().await;
+        let texts = ["Is it safe?", "What's the dose?"];
+
+        let first = cache.embed_batch(&texts).await.unwrap();
+        let second = cache.embed_batch(&texts).await.unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(provider.requested.lock().unwrap().len(), 2);
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 2 });
+    }
+
+    #[tokio::test]
+    async fn texts_that_normalize_alike_share_one_entry() {
+        let (cache, provider) = cache().await;
+
+        let embeddings = cache
+            .embed_batch(&["Is it SAFE?", "is it safe", "Dose"])
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings, vec![vec![10.0], vec![10.0], vec![4.0]]);
+        assert_eq!(
+            *provider.requested.lock().unwrap(),
+            vec!["is it safe".to_string(), "dose".to_string()]
+        );
+        assert_eq!(cache.embed("IS IT SAFE").await.unwrap(), vec![10.0]);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+    }
+
+    /// Returns one vector fewer than it was asked for.
+    struct ShortProvider;
+
+    impl EmbeddingProvider for ShortProvider {
+        fn model(&self) -> &str {
+            "short"
+        }
+
+        fn embed_batch<'a>(
+            &'a self,
+            texts: &'a [&'a str],
+        ) -> BoxFuture<'a, anyhow::Result<Vec<Vec<f32>>>> {
+            let embeddings = texts.iter().skip(1).map(|_| vec![1.0]).collect();
+            Box::pin(async move { Ok(embeddings) })
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_vectors_fail_the_batch() {
+        let (cache, _) = cache().await;
+        let cache = CachedEmbeddings::new(Arc::new(ShortProvider), cache.db);
+
+        let error = cache.embed_batch(&["one", "two"]).await.unwrap_err();
+
+        assert!(error.to_string().contains("1 vectors for 2 texts"));
+    }
 }
@@ -0,0 +1,309 @@
+//! Ablation experiments: one bulk-test run per configuration variant, over
+//! the same examples, reported side by side.
+//!
+//! Every variant is compared with the first one on the examples both have
+//! run, so listing the unchanged configuration first reads as "what
+//! each change does".
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compare;
+use crate::db::CategoryConstants;
+use crate::optimize::Accuracy;
+use crate::rescore::RecordedExample;
+
+fn default_true() -> bool {
+    true
+}
+
+/// One configuration of an experiment. Fields left out keep the agent's
+/// stored configuration.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Variant {
+    /// Label in the report; `variant {n}` if empty.
+    #[serde(default)]
+    pub name: String,
+    /// `false` runs without embedding biases; logit offsets still apply.
+    #[serde(default = "default_true")]
+    pub embeddings: bool,
+    /// Kappa for every category instead of the stored ones.
+    #[serde(default)]
+    pub kappa: Option<f64>,
+    /// System prompt template with `{{ brand_name }}` and `{{ messages }}`
+    /// placeholders instead of the built-in one. The grammar is unchanged.
+    #[serde(default)]
+    pub system_prompt_template: Option<String>,
+    /// GGUF file in the main model's directory instead of the main model.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Chat template for `model`; detected from its metadata if unset.
+    #[serde(default)]
+    pub chat_template: Option<String>,
+}
+
+impl Variant {
+    /// The agent's configuration as stored.
+    pub fn baseline() -> Self {
+        Self {
+            name: String::new(),
+            embeddings: true,
+            kappa: None,
+            system_prompt_template: None,
+            model: None,
+            chat_template: None,
+        }
+    }
+
+    /// `constants` with this variant's kappa applied.
+    pub fn constants(
+        &self,
+        mut constants: BTreeMap<String, CategoryConstants>,
+    ) -> BTreeMap<String, CategoryConstants> {
+        if let Some(kappa) = self.kappa {
+            for c in constants.values_mut() {
+                c.kappa = kappa;
+            }
+        }
+        constants
+    }
+}
+
+/// Variants one experiment may have; each is a full bulk-test run.
+pub const MAX_VARIANTS: usize = 8;
+
+/// Name unnamed variants after their position and reject what cannot run:
+/// more than `MAX_VARIANTS`, more models besides `main_model` (the main
+/// model's file name) than the server loads at once (`max_models`),
+/// duplicate names, a non-finite kappa, and a chat template without a model.
+pub fn prepare_variants(
+    variants: &mut [Variant],
+    main_model: &str,
+    max_models: usize,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        variants.len() <= MAX_VARIANTS,
+        "{} variants; an experiment has at most {MAX_VARIANTS}",
+        variants.len()
+    );
+    let models: HashSet<&str> = variants
+        .iter()
+        .filter_map(|v| v.model.as_deref())
+        .filter(|&model| model != main_model)
+        .collect();
+    anyhow::ensure!(
+        models.len() <= max_models,
+        "variants use {} models besides the main one; the server loads at most {max_models}",
+        models.len()
+    );
+    let mut names = HashSet::new();
+    for (i, variant) in variants.iter_mut().enumerate() {
+        if variant.name.trim().is_empty() {
+            variant.name = format!("variant {}", i + 1);
+        }
+        anyhow::ensure!(
+            names.insert(variant.name.clone()),
+            "duplicate variant name {:?}",
+            variant.name
+        );
+        if let Some(kappa) = variant.kappa {
+            anyhow::ensure!(
+                kappa.is_finite(),
+                "variant {:?}: kappa must be finite",
+                variant.name
+            );
+        }
+        anyhow::ensure!(
+            variant.chat_template.is_none() || variant.model.is_some(),
+            "variant {:?}: chat_template needs a model",
+            variant.name
+        );
+    }
+    Ok(())
+}
+
+/// A variant's run so far.
+pub struct VariantRun {
+    pub run_id: i64,
+    /// running | complete | cancelled
+    pub status: String,
+    pub examples: Vec<RecordedExample>,
+}
+
+/// How a variant did against the first one on the examples both have run.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct VersusFirst {
+    pub shared: usize,
+    /// Accuracy of this variant minus the first one's, on the shared examples.
+    pub accuracy_delta: f64,
+    /// Right in the first variant, wrong in this one.
+    pub regressions: usize,
+    /// Wrong in the first variant, right in this one.
+    pub fixes: usize,
+    /// McNemar's exact p-value.
+    pub p_value: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariantReport {
+    #[serde(flatten)]
+    pub variant: Variant,
+    /// `None` until the variant's run starts.
+    pub run_id: Option<i64>,
+    /// pending | running | complete | cancelled
+    pub status: String,
+    /// Over the examples classified so far.
+    pub accuracy: Accuracy,
+    /// `None` for the first variant and while either has no results.
+    pub versus_first: Option<VersusFirst>,
+}
+
+/// One report per variant, in order; `runs[i]` is the run of `variants[i]`.
+pub fn variant_reports(variants: Vec<Variant>, runs: &[Option<VariantRun>]) -> Vec<VariantReport> {
+    let first = runs
+        .first()
+        .and_then(Option::as_ref)
+        .filter(|r| !r.examples.is_empty());
+    variants
+        .into_iter()
+        .enumerate()
+        .map(|(i, variant)| {
+            let Some(run) = runs.get(i).and_then(Option::as_ref) else {
+                return VariantReport {
+                    variant,
+                    run_id: None,
+                    status: "pending".to_string(),
+                    accuracy: Accuracy::default(),
+                    versus_first: None,
+                };
+            };
+            let accuracy = Accuracy {
+                correct: run.examples.iter().filter(|ex| ex.success).count(),
+                total: run.examples.len(),
+            };
+            let versus_first = first
+                .filter(|_| i > 0 && !run.examples.is_empty())
+                .map(|first| {
+                    let c = compare::compare_runs(&first.examples, &run.examples);
+                    VersusFirst {
+                        shared: c.shared,
+                        accuracy_delta: c.b.rate() - c.a.rate(),
+                        regressions: c.regressions.len(),
+                        fixes: c.fixes.len(),
+                        p_value: c.mcnemar.p_value,
+                    }
+                });
+            VariantReport {
+                variant,
+                run_id: Some(run.run_id),
+                status: run.status.clone(),
+                accuracy,
+                versus_first,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(id: i64, success: bool) -> RecordedExample {
+        RecordedExample {
+            example_id: id,
+            example_text: String::new(),
+            chosen_category: Some("A".to_string()),
+            success,
+            correct_categories: vec!["A".to_string()],
+            candidates: vec![],
+        }
+    }
+
+    fn variant(json: &str) -> Variant {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn variants_default_to_the_stored_configuration_and_get_names() {
+        assert_eq!(variant("{}"), Variant::baseline());
+
+        let mut variants = vec![
+            variant(r#"{"name": "baseline"}"#),
+            variant(r#"{"embeddings": false}"#),
+        ];
+        prepare_variants(&mut variants, "main.gguf", 1).unwrap();
+        assert_eq!(variants[1].name, "variant 2");
+
+        // The main model does not count against the variant models.
+        let mut variants = vec![variant(r#"{"model": "main.gguf"}"#)];
+        prepare_variants(&mut variants, "main.gguf", 0).unwrap();
+
+        for bad in [
+            vec![variant(r#"{"name": "a"}"#), variant(r#"{"name": "a"}"#)],
+            vec![variant(r#"{"chat_template": "llama3"}"#)],
+            vec![
+                variant(r#"{"model": "a.gguf"}"#),
+                variant(r#"{"model": "b.gguf"}"#),
+            ],
+            vec![variant("{}"); MAX_VARIANTS + 1],
+        ] {
+            assert!(prepare_variants(&mut bad.clone(), "main.gguf", 1).is_err());
+        }
+    }
+
+    #[test]
+    fn kappa_override_applies_to_every_category() {
+        let stored = BTreeMap::from([
+            (
+                "A".to_string(),
+                CategoryConstants {
+                    kappa: 4.0,
+                    logit_offset: 1.0,
+                },
+            ),
+            ("B".to_string(), CategoryConstants::default()),
+        ]);
+        let mut v = Variant::baseline();
+        assert_eq!(v.constants(stored.clone()), stored);
+
+        v.kappa = Some(0.0);
+        let overridden = v.constants(stored);
+        assert!(overridden.values().all(|c| c.kappa == 0.0));
+        assert_eq!(overridden["A"].logit_offset, 1.0);
+    }
+
+    #[test]
+    fn reports_accuracy_and_changes_against_the_first_variant() {
+        let runs = [
+            Some(VariantRun {
+                run_id: 10,
+                status: "complete".to_string(),
+                examples: vec![example(1, true), example(2, false), example(3, true)],
+            }),
+            Some(VariantRun {
+                run_id: 11,
+                status: "running".to_string(),
+                examples: vec![example(1, false), example(2, true), example(3, true)],
+            }),
+            None,
+        ];
+        let variants = vec![
+            variant(r#"{"name": "base"}"#),
+            variant(r#"{"name": "no embeddings", "embeddings": false}"#),
+            variant(r#"{"name": "kappa 0", "kappa": 0}"#),
+        ];
+
+        let reports = variant_reports(variants, &runs);
+
+        assert_eq!(reports[0].accuracy.correct, 2);
+        assert!(reports[0].versus_first.is_none());
+        let versus = reports[1].versus_first.as_ref().unwrap();
+        assert_eq!((versus.shared, versus.regressions, versus.fixes), (3, 1, 1));
+        assert_eq!(versus.accuracy_delta, 0.0);
+        assert_eq!(
+            (reports[2].run_id, reports[2].status.as_str()),
+            (None, "pending")
+        );
+    }
+}
@@ -1,23 +1,33 @@
+mod compare;
 mod db;
 mod embedding;
+mod experiment;
+mod margins;
+mod message_source;
+mod metrics;
+mod models;
 mod optimize;
+mod rescore;
 mod routes;
+mod run_config;
 mod state;
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
     Router,
-    routing::{get, post},
+    routing::{delete, get, post},
 };
-use inference::{InferenceConfig, InferenceEngine};
+use inference::{InferenceConfig, InferenceEngine, SamplerSettings};
 use tokio::sync::Mutex;
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use crate::models::{Model, Models};
 use crate::state::AppState;
 
 #[tokio::main]
@@ -36,11 +46,26 @@ async fn main() -> anyhow::Result<()> {
     sqlx::migrate!("../../migrations").run(&db).await?;
     tracing::info!("SQLite migrations applied");
 
-    // --- Marketing Postgres DB (read-only) ----------------------------------
+    // --- Marketing Postgres DB (read-only, optional) -----------------------
+    // e.g. postgres://localhost:5432/marketing?sslmode=disable (the default).
+    // `none` runs without it: agents then load from AGENT_SOURCES files only.
     let vc_database_url = std::env::var("VC_DATABASE_URL")
         .unwrap_or_else(|_| "postgres://localhost:5432/marketing?sslmode=disable".to_string());
-    let vc_db = sqlx::PgPool::connect(&vc_database_url).await?;
-    tracing::info!("Connected to marketing Postgres DB");
+    let vc_db = if vc_database_url == "none" {
+        tracing::warn!("VC_DATABASE_URL is none — agents load from AGENT_SOURCES files only");
+        None
+    } else {
+        let vc_db = sqlx::PgPool::connect(&vc_database_url).await?;
+        tracing::info!("Connected to marketing Postgres DB");
+        Some(vc_db)
+    };
+
+    // --- VC message sources -------------------------------------------------
+    let messages = tokio::task::spawn_blocking({
+        let vc_db = vc_db.clone();
+        move || message_source::MessageSources::from_env(vc_db)
+    })
+    .await??;
 
     // --- Inference engine ---------------------------------------------------
     let model_path = PathBuf::from(
@@ -51,43 +76,177 @@ async fn main() -> anyhow::Result<()> {
         std::env::var("CONTEXT_CACHE_DIR").unwrap_or_else(|_| "context_cache".to_string()),
     );
 
+    let context_size = std::env::var("CONTEXT_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(8192);
+
+    let warm_contexts = std::env::var("WARM_CONTEXTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(2);
+
+    // Bulk-test examples decoded together in one batched context
+    let parallel_sequences = std::env::var("PARALLEL_SEQUENCES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(4);
+
+    // Hash the model file in the background; the first bulk test waits for it.
+    let model = Arc::new(run_config::ModelFile::new(model_path.clone()));
+    tokio::spawn({
+        let model = Arc::clone(&model);
+        async move {
+            match model.sha256().await {
+                Ok(sha256) => tracing::info!(sha256, "Model file hashed"),
+                Err(e) => tracing::warn!(error = %e, "failed to hash model file"),
+            }
+        }
+    });
+
     let config = InferenceConfig {
         model_path,
         context_cache_dir,
+        context_size,
+        warm_contexts,
+        parallel_sequences,
         max_tokens: 200,
         top_candidate_count: 10,
+        sampler: SamplerSettings::Greedy,
+        // e.g. "llama3", "mistral", "chatml", "gemma", "phi"; unset = detect from GGUF metadata
+        chat_template: std::env::var("CHAT_TEMPLATE").ok(),
     };
 
     tracing::info!("Loading inference engine (this may take a moment)…");
     let engine = tokio::task::spawn_blocking(|| InferenceEngine::new(config)).await??;
     let engine = Arc::new(engine);
-    tracing::info!("Inference engine ready");
+    tracing::info!(
+        chat_template = engine.chat_template_name(),
+        "Inference engine ready"
+    );
+
+    // --- Embedding provider -------------------------------------------------
+    let embeddings = tokio::task::spawn_blocking({
+        let engine = Arc::clone(&engine);
+        move || embedding::provider_from_env(&engine)
+    })
+    .await??
+    .map(|provider| Arc::new(embedding::CachedEmbeddings::new(provider, db.clone())));
+    match &embeddings {
+        Some(cache) => tracing::info!(model = cache.model(), "Embedding provider ready"),
+        None => tracing::warn!("No embedding provider configured — running without embedding biases"),
+    }
+
+    let margins = tokio::task::spawn_blocking({
+        let vc_db = vc_db.clone();
+        move || margins::MarginSource::from_env(vc_db)
+    })
+    .await??
+    .map(Arc::new);
+    match &margins {
+        Some(margins) => tracing::info!(backend = margins.name(), "Embedding margin backend selected"),
+        None => tracing::warn!("No margin examples configured — running without embedding biases"),
+    }
 
     let brand_name = std::env::var("BRAND_NAME").unwrap_or_else(|_| "Pemazyre".to_string());
 
+    // Other GGUF files of the model directory that variants may load at once
+    let max_variant_models = std::env::var("MAX_VARIANT_MODELS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1);
+
     // --- App state ----------------------------------------------------------
+    let models = Arc::new(Models::new(
+        Model {
+            engine: Arc::clone(&engine),
+            file: model,
+        },
+        max_variant_models,
+    ));
     let state = AppState {
         engine,
+        models,
+        embeddings,
+        margins,
         brand_name,
         db,
         vc_db,
+        messages: Arc::new(messages),
         sessions: Arc::new(Mutex::new(HashMap::new())),
         bulk_test_sessions: Arc::new(Mutex::new(HashMap::new())),
+        step_sessions: Arc::new(Mutex::new(HashMap::new())),
+        infer_cancellations: Arc::new(Mutex::new(HashMap::new())),
+        bulk_test_cancellations: Arc::new(Mutex::new(HashMap::new())),
     };
 
+    // --- Idle step-through sessions -----------------------------------------
+    let step_session_ttl = Duration::from_secs(
+        std::env::var("STEP_SESSION_IDLE_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(600),
+    );
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            let mut sweep = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                sweep.tick().await;
+                routes::step::evict_idle_sessions(&state, step_session_ttl).await;
+            }
+        }
+    });
+
+    // --- Per-category constants ---------------------------------------------
+    if let Err(e) = routes::category_constants::carry_over_message_constants(&state).await {
+        tracing::warn!(error = %e, "failed to carry per-message constants over to categories");
+    }
+
     // --- Router -------------------------------------------------------------
     let app = Router::new()
         .route("/health", get(routes::health::handler))
         .route("/agents", get(routes::agents::list_agents))
         .route("/agents/{agent_id}/system-prompt", get(routes::agents::get_system_prompt))
+        .route(
+            "/agents/{agent_id}/validation-sets",
+            get(routes::validation_sets::list_validation_sets)
+                .post(routes::validation_sets::import_validation_set),
+        )
+        .route("/validation-sets/{set_id}", delete(routes::validation_sets::delete_validation_set))
+        .route(
+            "/agents/{agent_id}/category-constants",
+            get(routes::category_constants::category_constants_report),
+        )
+        .route("/agents/{agent_id}/weight-sets", get(routes::weight_sets::list_weight_sets))
+        .route(
+            "/agents/{agent_id}/weight-sets/rollback",
+            post(routes::weight_sets::rollback_weight_set),
+        )
+        .route("/weight-sets/{set_id}/activate", post(routes::weight_sets::activate_weight_set))
+        .route("/weight-sets/{from_id}/diff/{to_id}", get(routes::weight_sets::diff_weight_sets))
+        .route("/embeddings/cache-stats", get(routes::embeddings::cache_stats))
         .route("/infer", post(routes::infer::start_infer))
+        .route("/infer/{session_id}", delete(routes::infer::cancel_infer))
         .route("/infer/stream/{session_id}", get(routes::infer::stream_sse))
+        .route("/infer/beam", post(routes::infer::beam_search))
+        .route("/infer/rank", post(routes::infer::rank_responses))
+        .route("/step", post(routes::step::start_step))
+        .route("/step/{session_id}", delete(routes::step::close_step))
+        .route("/step/{session_id}/advance", post(routes::step::advance_step))
         .route("/bulk-test", post(routes::bulk_test::start_bulk_test))
+        .route("/bulk-test/{bulk_test_id}", delete(routes::bulk_test::cancel_bulk_test))
         .route("/bulk-test/stream/{bulk_test_id}", get(routes::bulk_test::stream_bulk_test_sse))
         .route("/bulk-tests", get(routes::bulk_test::list_bulk_tests))
+        .route("/bulk-tests/compare", get(routes::bulk_test::compare_bulk_tests))
         .route("/bulk-tests/{run_id}", get(routes::bulk_test::get_bulk_test))
+        .route("/bulk-tests/{run_id}/metrics", get(routes::bulk_test::get_bulk_test_metrics))
+        .route("/bulk-tests/{run_id}/config", get(routes::bulk_test::get_bulk_test_config))
+        .route("/bulk-tests/{run_id}/rerun", post(routes::bulk_test::rerun_bulk_test))
         .route("/bulk-tests/{run_id}/optimize", post(routes::optimize::optimize_weights))
         .route("/bulk-tests/{run_id}/apply-weights", post(routes::optimize::apply_weights))
+        .route("/bulk-tests/{run_id}/rescore", post(routes::rescore::rescore_run))
+        .route("/bulk-test-experiments/{experiment_id}", get(routes::experiment::get_experiment))
         .layer(CorsLayer::permissive())
         .with_state(state);
 
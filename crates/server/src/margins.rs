@@ -0,0 +1,366 @@
+//! Embedding margin scores, computed either by the pgvector query in the VC
+//! database or in-process from an index of the example embeddings. The index
+//! is built from the VC database or, without one, from a local file.
+//!
+//! For each VC message the margin of a prompt embedding `e` is
+//!   `max_pos cos(e, pos) - max_neg cos(e, neg)`
+//! over the message's positive and negative example embeddings. Messages
+//! missing either kind have no margin (the SQL yields NULL and the row is
+//! dropped), so the index skips them too.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+
+use crate::db::{self, MarginExample, MessageMargin};
+
+/// Example embeddings of one message, L2-normalised so cosine similarity is
+/// a dot product.
+struct IndexedMessage {
+    message_id: i64,
+    category_name: String,
+    positives: Vec<Vec<f32>>,
+    negatives: Vec<Vec<f32>>,
+}
+
+/// In-memory index of every message's positive/negative example embeddings.
+pub struct MarginIndex {
+    messages: Vec<IndexedMessage>,
+}
+
+impl MarginIndex {
+    pub fn new(examples: impl IntoIterator<Item = MarginExample>) -> Self {
+        let mut messages: Vec<IndexedMessage> = vec![];
+        for example in examples {
+            let i = match messages
+                .iter()
+                .position(|m| m.message_id == example.message_id)
+            {
+                Some(i) => i,
+                None => {
+                    messages.push(IndexedMessage {
+                        message_id: example.message_id,
+                        category_name: example.category_name,
+                        positives: vec![],
+                        negatives: vec![],
+                    });
+                    messages.len() - 1
+                }
+            };
+            let embedding = normalized(&example.embedding);
+            if example.positive {
+                messages[i].positives.push(embedding);
+            } else {
+                messages[i].negatives.push(embedding);
+            }
+        }
+        Self { messages }
+    }
+
+    /// One margin per message that has both positive and negative examples,
+    /// ordered by descending margin — the same rows `db::compute_embedding_margins`
+    /// returns.
+    pub fn margins(&self, embedding: &[f32]) -> Vec<MessageMargin> {
+        let query = normalized(embedding);
+        let max_similarity = |examples: &[Vec<f32>]| {
+            examples
+                .iter()
+                .map(|e| dot(&query, e))
+                .fold(None, |best: Option<f64>, s| {
+                    Some(best.map_or(s, |b| b.max(s)))
+                })
+        };
+
+        let mut margins: Vec<MessageMargin> = self
+            .messages
+            .iter()
+            .filter_map(|m| {
+                let pos = max_similarity(&m.positives)?;
+                let neg = max_similarity(&m.negatives)?;
+                Some(MessageMargin {
+                    message_id: m.message_id,
+                    category_name: m.category_name.clone(),
+                    margin: pos - neg,
+                })
+            })
+            .collect();
+        margins.sort_by(|a, b| b.margin.total_cmp(&a.margin));
+        margins
+    }
+}
+
+fn normalized(v: &[f32]) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return v.to_vec();
+    }
+    v.iter().map(|x| x / norm).collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (*x as f64) * (*y as f64))
+        .sum()
+}
+
+// ---------------------------------------------------------------------------
+// Margin source selected at start-up
+// ---------------------------------------------------------------------------
+
+/// Where margins are computed, from `MARGIN_BACKEND`.
+pub enum MarginSource {
+    /// `pgvector`: the cosine query runs in the VC Postgres.
+    Pgvector { vc_db: PgPool },
+    /// `memory`: each agent's example embeddings are loaded once and margins
+    /// are computed in-process. Restart to pick up new embeddings.
+    InMemory {
+        /// Where an agent's examples are loaded on first use; `None` when
+        /// every index was read from `MARGIN_EXAMPLES_PATH` at start-up.
+        vc_db: Option<PgPool>,
+        indexes: Mutex<HashMap<i32, Arc<MarginIndex>>>,
+    },
+}
+
+impl MarginSource {
+    /// Select the backend from the environment:
+    ///
+    /// - `MARGIN_BACKEND` is `pgvector` or `memory`; unset means `pgvector`
+    ///   when there is a VC database and `memory` otherwise.
+    /// - `MARGIN_EXAMPLES_PATH` points the `memory` backend at a JSON Lines
+    ///   file of example embeddings instead of the VC database, one
+    ///   `{agent_id, message_id, category, kind, embedding}` object per line
+    ///   with `kind` `pos` or `neg`. Message IDs are those of the agent's
+    ///   message source.
+    ///
+    /// `None` when there is nowhere to load examples from. Blocking when
+    /// reading the examples file.
+    pub fn from_env(vc_db: Option<PgPool>) -> anyhow::Result<Option<Self>> {
+        let examples_path = std::env::var("MARGIN_EXAMPLES_PATH").ok();
+        let backend = std::env::var("MARGIN_BACKEND").ok();
+        match (backend.as_deref(), examples_path, vc_db) {
+            (Some("pgvector"), Some(_), _) => {
+                anyhow::bail!("MARGIN_EXAMPLES_PATH needs MARGIN_BACKEND=memory")
+            }
+            (Some("pgvector"), None, None) => {
+                anyhow::bail!("MARGIN_BACKEND=pgvector needs VC_DATABASE_URL")
+            }
+            (Some("pgvector") | None, None, Some(vc_db)) => Ok(Some(Self::Pgvector { vc_db })),
+            (Some("memory") | None, Some(path), _) => {
+                let indexes = load_examples_file(Path::new(&path))?
+                    .into_iter()
+                    .map(|(agent_id, examples)| (agent_id, Arc::new(MarginIndex::new(examples))))
+                    .collect();
+                Ok(Some(Self::InMemory {
+                    vc_db: None,
+                    indexes: Mutex::new(indexes),
+                }))
+            }
+            (Some("memory"), None, Some(vc_db)) => Ok(Some(Self::InMemory {
+                vc_db: Some(vc_db),
+                indexes: Mutex::new(HashMap::new()),
+            })),
+            (Some("memory") | None, None, None) => Ok(None),
+            (Some(other), ..) => {
+                anyhow::bail!("unknown MARGIN_BACKEND {other:?}; expected pgvector or memory")
+            }
+        }
+    }
+
+    /// `pgvector`, `memory`, or `file` for a `memory` index read from
+    /// `MARGIN_EXAMPLES_PATH`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pgvector { .. } => "pgvector",
+            Self::InMemory { vc_db: Some(_), .. } => "memory",
+            Self::InMemory { vc_db: None, .. } => "file",
+        }
+    }
+
+    /// Per-message margins for `embedding` against the latest version of
+    /// the agent's messages, ordered by descending margin. An agent without
+    /// examples has no margins.
+    pub async fn margins(
+        &self,
+        embedding: &[f32],
+        agent_id: i32,
+    ) -> anyhow::Result<Vec<MessageMargin>> {
+        match self {
+            Self::Pgvector { vc_db } => {
+                db::compute_embedding_margins(vc_db, embedding, agent_id).await
+            }
+            Self::InMemory { vc_db, indexes } => {
+                let index = {
+                    let mut indexes = indexes.lock().await;
+                    match indexes.get(&agent_id) {
+                        Some(index) => Arc::clone(index),
+                        None => {
+                            let examples = match vc_db {
+                                Some(vc_db) => db::load_margin_examples(vc_db, agent_id).await?,
+                                None => vec![],
+                            };
+                            tracing::info!(
+                                agent_id,
+                                examples = examples.len(),
+                                "built margin index"
+                            );
+                            let index = Arc::new(MarginIndex::new(examples));
+                            indexes.insert(agent_id, Arc::clone(&index));
+                            index
+                        }
+                    }
+                };
+                Ok(index.margins(embedding))
+            }
+        }
+    }
+}
+
+/// An example embedding as written in `MARGIN_EXAMPLES_PATH`.
+#[derive(Deserialize)]
+struct FileExample {
+    agent_id: i32,
+    message_id: i64,
+    category: String,
+    kind: String,
+    embedding: Vec<f32>,
+}
+
+fn load_examples_file(path: &Path) -> anyhow::Result<HashMap<i32, Vec<MarginExample>>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let examples =
+        parse_examples(&text).with_context(|| format!("failed to parse {}", path.display()))?;
+    tracing::info!(
+        path = %path.display(),
+        agents = examples.len(),
+        "loaded margin examples"
+    );
+    Ok(examples)
+}
+
+/// Examples by agent. Like the Postgres loader, rows whose `kind` is neither
+/// `pos` nor `neg` are skipped.
+fn parse_examples(text: &str) -> anyhow::Result<HashMap<i32, Vec<MarginExample>>> {
+    let mut examples: HashMap<i32, Vec<MarginExample>> = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row: FileExample =
+            serde_json::from_str(line).with_context(|| format!("line {}", i + 1))?;
+        let positive = match row.kind.as_str() {
+            "pos" => true,
+            "neg" => false,
+            _ => continue,
+        };
+        examples
+            .entry(row.agent_id)
+            .or_default()
+            .push(MarginExample {
+                message_id: row.message_id,
+                category_name: row.category,
+                positive,
+                embedding: row.embedding,
+            });
+    }
+    Ok(examples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(message_id: i64, positive: bool, embedding: &[f32]) -> MarginExample {
+        MarginExample {
+            message_id,
+            category_name: format!("Category {message_id}"),
+            positive,
+            embedding: embedding.to_vec(),
+        }
+    }
+
+    /// Margin is the best positive similarity minus the best negative one.
+    #[test]
+    fn margin_is_max_positive_minus_max_negative() {
+        let index = MarginIndex::new([
+            example(1, true, &[1.0, 0.0]),
+            example(1, true, &[0.0, 1.0]),
+            example(1, false, &[-1.0, 0.0]),
+            example(1, false, &[1.0, 1.0]),
+        ]);
+
+        let margins = index.margins(&[2.0, 0.0]);
+
+        assert_eq!(margins.len(), 1);
+        let expected = 1.0 - 1.0 / 2f64.sqrt();
+        assert!((margins[0].margin - expected).abs() < 1e-6);
+        assert_eq!(margins[0].category_name, "Category 1");
+    }
+
+    /// Results come back best first, as from the SQL query.
+    #[test]
+    fn margins_are_sorted_descending() {
+        let index = MarginIndex::new([
+            example(1, true, &[0.0, 1.0]),
+            example(1, false, &[1.0, 0.0]),
+            example(2, true, &[1.0, 0.0]),
+            example(2, false, &[0.0, 1.0]),
+        ]);
+
+        let margins = index.margins(&[1.0, 0.0]);
+
+        let ids: Vec<i64> = margins.iter().map(|m| m.message_id).collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert!((margins[0].margin - 1.0).abs() < 1e-6);
+        assert!((margins[1].margin + 1.0).abs() < 1e-6);
+    }
+
+    /// A message with only positive (or only negative) examples has no margin.
+    #[test]
+    fn messages_missing_a_kind_are_skipped() {
+        let index = MarginIndex::new([
+            example(1, true, &[1.0, 0.0]),
+            example(2, false, &[1.0, 0.0]),
+            example(3, true, &[1.0, 0.0]),
+            example(3, false, &[0.0, 1.0]),
+        ]);
+
+        let margins = index.margins(&[1.0, 0.0]);
+
+        assert_eq!(margins.len(), 1);
+        assert_eq!(margins[0].message_id, 3);
+    }
+
+    #[test]
+    fn example_files_are_grouped_by_agent() {
+        let text = r#"{"agent_id": 7, "message_id": 1, "category": "Dosing", "kind": "pos", "embedding": [1.0, 0.0]}
+{"agent_id": 7, "message_id": 1, "category": "Dosing", "kind": "neg", "embedding": [0.0, 1.0]}
+
+{"agent_id": 7, "message_id": 2, "category": "Safety", "kind": "other", "embedding": [1.0, 0.0]}
+{"agent_id": 8, "message_id": 1, "category": "Cost", "kind": "pos", "embedding": [0.0, 1.0]}
+"#;
+
+        let mut examples = parse_examples(text).unwrap();
+
+        assert_eq!(examples[&7].len(), 2);
+        assert_eq!(examples[&8].len(), 1);
+        let margins = MarginIndex::new(examples.remove(&7).unwrap()).margins(&[1.0, 0.0]);
+        assert_eq!(margins.len(), 1);
+        assert_eq!(margins[0].category_name, "Dosing");
+        assert!((margins[0].margin - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unreadable_example_lines_are_reported() {
+        let Err(error) = parse_examples("{\"agent_id\": 7}\n") else {
+            panic!("an incomplete example parsed");
+        };
+        assert!(format!("{error:#}").contains("line 1"));
+    }
+}
@@ -0,0 +1,399 @@
+//! Where each agent's approved VC messages come from.
+//!
+//! By default every agent is read from the marketing Postgres. `AGENT_SOURCES`
+//! points at a YAML or JSON file that assigns agents to local files instead
+//! (with `VC_DATABASE_URL=none`, the server runs on these alone), e.g.
+//!
+//! ```yaml
+//! agents:
+//!   7:
+//!     format: tsv
+//!     path: pemazyre.csv          # relative to this file
+//!   8:
+//!     format: csv
+//!     path: agent8.csv
+//!     columns: { category: Topic, message: Text }
+//!   9:
+//!     format: yaml                # or json: a list of messages
+//!     path: agent9.yaml
+//! ```
+//!
+//! JSON and YAML files hold a list of `{category, message, kind?,
+//! description?, mlr_message?}` objects. A missing or empty `mlr_message` is
+//! the message itself, in every format. File sources are read once at
+//! start-up; a file's messages are numbered from 1 in file order, and those
+//! numbers stand in for the Postgres message IDs.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use inference::{CsvColumns, VCmessage, clean_vc_message, load_vc_messages_csv};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::db::{self, VcMessageWithId};
+
+pub trait MessageSource: Send + Sync {
+    /// Agents this source can load.
+    fn agent_ids(&self) -> BoxFuture<'_, anyhow::Result<Vec<i32>>>;
+
+    /// The agent's approved messages with their IDs, in grammar order.
+    fn load_messages(&self, agent_id: i32) -> BoxFuture<'_, anyhow::Result<Vec<VcMessageWithId>>>;
+
+    /// Version of the agent's messages, for sources that keep versions.
+    fn message_version(&self, agent_id: i32) -> BoxFuture<'_, anyhow::Result<Option<i64>>>;
+}
+
+// ---------------------------------------------------------------------------
+// Marketing Postgres
+// ---------------------------------------------------------------------------
+
+pub struct PostgresSource {
+    vc_db: PgPool,
+}
+
+impl MessageSource for PostgresSource {
+    fn agent_ids(&self) -> BoxFuture<'_, anyhow::Result<Vec<i32>>> {
+        Box::pin(db::list_agent_ids(&self.vc_db))
+    }
+
+    fn load_messages(&self, agent_id: i32) -> BoxFuture<'_, anyhow::Result<Vec<VcMessageWithId>>> {
+        Box::pin(db::load_vc_messages_with_ids(&self.vc_db, agent_id))
+    }
+
+    fn message_version(&self, agent_id: i32) -> BoxFuture<'_, anyhow::Result<Option<i64>>> {
+        Box::pin(db::get_vc_message_version(&self.vc_db, agent_id))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Local files
+// ---------------------------------------------------------------------------
+
+/// One agent's messages, read from a file at start-up.
+pub struct FileSource {
+    agent_id: i32,
+    messages: Vec<VCmessage>,
+}
+
+/// A message as written in a JSON or YAML source file.
+#[derive(Deserialize)]
+struct FileMessage {
+    category: String,
+    message: String,
+    #[serde(default)]
+    kind: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    mlr_message: Option<String>,
+}
+
+impl FileSource {
+    fn load(agent_id: i32, config: &FileFormat, path: &Path) -> anyhow::Result<Self> {
+        let path_str = path.to_str().context("source path is not UTF-8")?;
+        let messages = match config {
+            FileFormat::Csv { columns } => load_csv(path_str, b',', columns)?,
+            FileFormat::Tsv { columns } => load_csv(path_str, b'\t', columns)?,
+            FileFormat::Json | FileFormat::Yaml => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                let rows: Vec<FileMessage> = if matches!(config, FileFormat::Json) {
+                    serde_json::from_str(&text)
+                        .with_context(|| format!("failed to parse {}", path.display()))?
+                } else {
+                    serde_yaml::from_str(&text)
+                        .with_context(|| format!("failed to parse {}", path.display()))?
+                };
+                rows.into_iter()
+                    .filter_map(FileMessage::into_vc_message)
+                    .collect()
+            }
+        };
+        anyhow::ensure!(
+            !messages.is_empty(),
+            "no valid VC messages in {} for agent {agent_id}",
+            path.display()
+        );
+        let messages = messages
+            .into_iter()
+            .map(|mut m| {
+                if m.mlr_message.is_empty() {
+                    m.mlr_message = m.message.clone();
+                }
+                m
+            })
+            .collect();
+        Ok(Self { agent_id, messages })
+    }
+}
+
+/// `load_vc_messages_csv`, logging the rows it skipped.
+fn load_csv(path: &str, delimiter: u8, columns: &CsvColumns) -> anyhow::Result<Vec<VCmessage>> {
+    let loaded = load_vc_messages_csv(path, delimiter, columns)?;
+    for error in &loaded.parse_errors {
+        tracing::warn!(path, "skipping {error}");
+    }
+    if loaded.skipped > 0 {
+        tracing::info!(path, skipped = loaded.skipped, "skipped VC message rows");
+    }
+    Ok(loaded.messages)
+}
+
+impl FileMessage {
+    fn into_vc_message(self) -> Option<VCmessage> {
+        clean_vc_message(
+            &self.category,
+            &self.kind,
+            &self.description,
+            self.mlr_message.as_deref().unwrap_or_default(),
+            &self.message,
+        )
+    }
+}
+
+impl MessageSource for FileSource {
+    fn agent_ids(&self) -> BoxFuture<'_, anyhow::Result<Vec<i32>>> {
+        Box::pin(async move { Ok(vec![self.agent_id]) })
+    }
+
+    fn load_messages(&self, agent_id: i32) -> BoxFuture<'_, anyhow::Result<Vec<VcMessageWithId>>> {
+        Box::pin(async move {
+            anyhow::ensure!(agent_id == self.agent_id, "unknown agent {agent_id}");
+            Ok(self
+                .messages
+                .iter()
+                .enumerate()
+                .map(|(i, m)| VcMessageWithId {
+                    id: i as i32 + 1,
+                    vc_message: m.clone(),
+                })
+                .collect())
+        })
+    }
+
+    /// Files are read once and carry no version.
+    fn message_version(&self, _agent_id: i32) -> BoxFuture<'_, anyhow::Result<Option<i64>>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Per-agent routing
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize)]
+struct AgentSourcesConfig {
+    #[serde(default)]
+    agents: BTreeMap<i32, AgentSource>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AgentSource {
+    File {
+        path: PathBuf,
+        #[serde(flatten)]
+        format: FileFormat,
+    },
+    Postgres(PostgresMarker),
+}
+
+/// `format: postgres` — explicit form of the default.
+#[derive(Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+enum PostgresMarker {
+    Postgres,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+enum FileFormat {
+    Csv {
+        #[serde(default)]
+        columns: CsvColumns,
+    },
+    Tsv {
+        #[serde(default)]
+        columns: CsvColumns,
+    },
+    Json,
+    Yaml,
+}
+
+/// Every configured source, keyed by agent. Agents without an entry fall
+/// back to Postgres when it is connected.
+pub struct MessageSources {
+    postgres: Option<Arc<PostgresSource>>,
+    by_agent: BTreeMap<i32, Arc<dyn MessageSource>>,
+}
+
+impl MessageSources {
+    /// Read `AGENT_SOURCES` (if set) and load every file source. Blocking.
+    pub fn from_env(vc_db: Option<PgPool>) -> anyhow::Result<Self> {
+        let postgres = vc_db.map(|vc_db| Arc::new(PostgresSource { vc_db }));
+        let mut by_agent: BTreeMap<i32, Arc<dyn MessageSource>> = BTreeMap::new();
+
+        if let Ok(config_path) = std::env::var("AGENT_SOURCES") {
+            let config_path = PathBuf::from(config_path);
+            let text = std::fs::read_to_string(&config_path)
+                .with_context(|| format!("failed to read {}", config_path.display()))?;
+            let config: AgentSourcesConfig = match config_path.extension().and_then(|e| e.to_str())
+            {
+                Some("json") => serde_json::from_str(&text)?,
+                _ => serde_yaml::from_str(&text)?,
+            };
+            let base_dir = config_path.parent().unwrap_or(Path::new("."));
+
+            for (agent_id, source) in config.agents {
+                let source: Arc<dyn MessageSource> = match source {
+                    AgentSource::Postgres(_) => postgres.clone().with_context(|| {
+                        format!("agent {agent_id} is configured for Postgres but VC_DATABASE_URL is not set")
+                    })?,
+                    AgentSource::File { path, format } => {
+                        let source = FileSource::load(agent_id, &format, &base_dir.join(path))?;
+                        tracing::info!(
+                            agent_id,
+                            messages = source.messages.len(),
+                            "loaded VC messages from file"
+                        );
+                        Arc::new(source)
+                    }
+                };
+                by_agent.insert(agent_id, source);
+            }
+        }
+
+        anyhow::ensure!(
+            postgres.is_some() || !by_agent.is_empty(),
+            "no VC message source: set VC_DATABASE_URL or AGENT_SOURCES"
+        );
+        Ok(Self { postgres, by_agent })
+    }
+
+    fn source(&self, agent_id: i32) -> anyhow::Result<Arc<dyn MessageSource>> {
+        match self.by_agent.get(&agent_id) {
+            Some(source) => Ok(Arc::clone(source)),
+            None => self
+                .postgres
+                .clone()
+                .map(|pg| pg as Arc<dyn MessageSource>)
+                .with_context(|| format!("no message source configured for agent {agent_id}")),
+        }
+    }
+
+    /// Every loadable agent, sorted.
+    pub async fn agent_ids(&self) -> anyhow::Result<Vec<i32>> {
+        let mut ids: Vec<i32> = match &self.postgres {
+            Some(pg) => pg.agent_ids().await?,
+            None => vec![],
+        };
+        ids.extend(self.by_agent.keys());
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    pub async fn load_messages_with_ids(
+        &self,
+        agent_id: i32,
+    ) -> anyhow::Result<Vec<VcMessageWithId>> {
+        self.source(agent_id)?.load_messages(agent_id).await
+    }
+
+    pub async fn message_version(&self, agent_id: i32) -> anyhow::Result<Option<i64>> {
+        self.source(agent_id)?.message_version(agent_id).await
+    }
+
+    /// Like `load_messages_with_ids`, but fails when the agent has no messages.
+    pub async fn load_vc_messages(&self, agent_id: i32) -> anyhow::Result<Vec<VCmessage>> {
+        let messages: Vec<VCmessage> = self
+            .load_messages_with_ids(agent_id)
+            .await?
+            .into_iter()
+            .map(|m| m.vc_message)
+            .collect();
+        anyhow::ensure!(
+            !messages.is_empty(),
+            "no valid VC messages found for agent {agent_id}"
+        );
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_parses_every_format() {
+        let config: AgentSourcesConfig = serde_yaml::from_str(
+            r#"
+agents:
+  1: { format: postgres }
+  2: { format: tsv, path: a.tsv }
+  3: { format: csv, path: b.csv, columns: { category: Topic, message: Text } }
+  4: { format: json, path: c.json }
+  5: { format: yaml, path: d.yaml }
+"#,
+        )
+        .unwrap();
+
+        assert!(matches!(config.agents[&1], AgentSource::Postgres(_)));
+        let AgentSource::File {
+            format: FileFormat::Csv { columns },
+            ..
+        } = &config.agents[&3]
+        else {
+            panic!("agent 3 should be a CSV source");
+        };
+        assert_eq!(columns.category, "Topic");
+        assert_eq!(columns.kind, "Kind");
+        assert!(matches!(
+            config.agents[&2],
+            AgentSource::File {
+                format: FileFormat::Tsv { .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            config.agents[&4],
+            AgentSource::File {
+                format: FileFormat::Json,
+                ..
+            }
+        ));
+        assert!(matches!(
+            config.agents[&5],
+            AgentSource::File {
+                format: FileFormat::Yaml,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn messages_without_an_mlr_copy_use_the_message() {
+        let path = std::env::temp_dir().join(format!("file_source_{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{"category": "Safety", "message": "See the label"},
+                {"category": "Dosing", "message": "Once daily", "mlr_message": "Once daily [1]"}]"#,
+        )
+        .unwrap();
+
+        let source = FileSource::load(1, &FileFormat::Json, &path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mlr: Vec<_> = source
+            .unwrap()
+            .messages
+            .into_iter()
+            .map(|m| m.mlr_message)
+            .collect();
+        assert_eq!(mlr, ["See the label", "Once daily [1]"]);
+    }
+}
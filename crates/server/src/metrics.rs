@@ -0,0 +1,319 @@
+//! Classification metrics for a bulk-test run.
+//!
+//! Aggregate accuracy hides categories that fail constantly, so a run is also
+//! broken down into a confusion matrix, per-category precision / recall / F1,
+//! top-k accuracy over the recorded category candidates and the category
+//! pairs confused most often.
+//!
+//! Multi-label examples count against a single actual category: the choice
+//! itself when it is correct, otherwise the first correct category.
+
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+use inference_types::CategoryTopToken;
+use serde::Serialize;
+
+use crate::optimize::Accuracy;
+use crate::rescore::RecordedExample;
+
+/// The `k` values top-k accuracy is reported for.
+const TOP_K: [usize; 3] = [1, 3, 5];
+
+/// Number of most-confused pairs reported.
+const MAX_CONFUSED_PAIRS: usize = 10;
+
+/// The label a prediction is counted against: the prediction itself when it
+/// is one of the correct categories, otherwise the first correct category.
+pub fn actual_category<'a>(correct: &'a [String], predicted: &'a str) -> &'a str {
+    if correct.iter().any(|c| c == predicted) {
+        predicted
+    } else {
+        correct.first().map_or(predicted, String::as_str)
+    }
+}
+
+/// Counts of (actual, predicted) category pairs.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ConfusionMatrix {
+    /// Every category seen as actual or predicted, sorted by name.
+    pub categories: Vec<String>,
+    /// `counts[actual][predicted]`, indexed like `categories`.
+    pub counts: Vec<Vec<usize>>,
+}
+
+impl ConfusionMatrix {
+    pub fn from_pairs<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)> + Clone) -> Self {
+        let categories: Vec<String> = pairs
+            .clone()
+            .into_iter()
+            .flat_map(|(a, p)| [a, p])
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let index = |name: &str| categories.iter().position(|c| c == name).unwrap();
+        let mut counts = vec![vec![0; categories.len()]; categories.len()];
+        for (actual, predicted) in pairs {
+            counts[index(actual)][index(predicted)] += 1;
+        }
+        Self { categories, counts }
+    }
+
+    /// Precision, recall and F1 of every category, in matrix order.
+    pub fn per_category(&self) -> Vec<CategoryMetrics> {
+        let ratio = |n: usize, d: usize| (d > 0).then(|| n as f64 / d as f64);
+        self.categories
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let true_positives = self.counts[i][i];
+                let support: usize = self.counts[i].iter().sum();
+                let predicted: usize = self.counts.iter().map(|row| row[i]).sum();
+                let precision = ratio(true_positives, predicted);
+                let recall = ratio(true_positives, support);
+                let f1 = match (precision, recall) {
+                    (Some(p), Some(r)) if p + r > 0.0 => Some(2.0 * p * r / (p + r)),
+                    (Some(_), Some(_)) => Some(0.0),
+                    _ => None,
+                };
+                CategoryMetrics {
+                    category_name: name.clone(),
+                    support,
+                    predicted,
+                    true_positives,
+                    precision,
+                    recall,
+                    f1,
+                }
+            })
+            .collect()
+    }
+
+    /// Off-diagonal cells, most frequent first (ties by name), up to `limit`.
+    pub fn confused_pairs(&self, limit: usize) -> Vec<ConfusedPair> {
+        let mut pairs: Vec<ConfusedPair> = self
+            .counts
+            .iter()
+            .enumerate()
+            .flat_map(|(a, row)| {
+                row.iter()
+                    .enumerate()
+                    .filter(move |&(p, &count)| p != a && count > 0)
+                    .map(move |(p, &count)| ConfusedPair {
+                        actual: self.categories[a].clone(),
+                        predicted: self.categories[p].clone(),
+                        count,
+                    })
+            })
+            .collect();
+        // Rows are already in name order, so a stable sort keeps ties by name.
+        pairs.sort_by_key(|p| Reverse(p.count));
+        pairs.truncate(limit);
+        pairs
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CategoryMetrics {
+    pub category_name: String,
+    /// Examples whose actual category this is.
+    pub support: usize,
+    /// Examples classified as this category.
+    pub predicted: usize,
+    pub true_positives: usize,
+    /// `None` when the category was never predicted.
+    pub precision: Option<f64>,
+    /// `None` when the category never was the actual one.
+    pub recall: Option<f64>,
+    pub f1: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ConfusedPair {
+    pub actual: String,
+    pub predicted: String,
+    pub count: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TopKAccuracy {
+    pub k: usize,
+    pub accuracy: Accuracy,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunMetrics {
+    /// Of the examples that chose a category.
+    pub accuracy: Accuracy,
+    /// Examples that finished without choosing a category.
+    pub unclassified: usize,
+    pub confusion: ConfusionMatrix,
+    pub categories: Vec<CategoryMetrics>,
+    /// Whether a correct category is among the `k` best candidates at the
+    /// step the category was chosen, ranked by the adjusted logit the engine
+    /// sampled from. Examples without recorded candidates are left out.
+    pub top_k: Vec<TopKAccuracy>,
+    pub confused_pairs: Vec<ConfusedPair>,
+}
+
+/// Metrics of the choices recorded in `examples`.
+pub fn run_metrics(examples: &[RecordedExample]) -> RunMetrics {
+    let classified: Vec<(&str, &RecordedExample)> = examples
+        .iter()
+        .filter_map(|ex| Some((ex.chosen_category.as_deref()?, ex)))
+        .collect();
+    let confusion = ConfusionMatrix::from_pairs(
+        classified
+            .iter()
+            .map(|&(chosen, ex)| (actual_category(&ex.correct_categories, chosen), chosen)),
+    );
+    let accuracy = Accuracy {
+        correct: classified
+            .iter()
+            .filter(|(chosen, ex)| ex.correct_categories.iter().any(|c| c == chosen))
+            .count(),
+        total: classified.len(),
+    };
+
+    // Candidate categories of each example, best first.
+    let adjusted = |c: &CategoryTopToken| c.best_token.logit + c.best_token.embedding_logit;
+    let rankings: Vec<(Vec<&str>, &[String])> = examples
+        .iter()
+        .filter(|ex| !ex.candidates.is_empty())
+        .map(|ex| {
+            let mut ranked: Vec<_> = ex.candidates.iter().collect();
+            ranked.sort_by(|a, b| adjusted(b).total_cmp(&adjusted(a)));
+            let names = ranked.iter().map(|c| c.category_name.as_str()).collect();
+            (names, ex.correct_categories.as_slice())
+        })
+        .collect();
+    let top_k = TOP_K
+        .iter()
+        .map(|&k| TopKAccuracy {
+            k,
+            accuracy: Accuracy {
+                correct: rankings
+                    .iter()
+                    .filter(|(ranked, correct)| {
+                        ranked
+                            .iter()
+                            .take(k)
+                            .any(|name| correct.iter().any(|c| c == name))
+                    })
+                    .count(),
+                total: rankings.len(),
+            },
+        })
+        .collect();
+
+    RunMetrics {
+        accuracy,
+        unclassified: examples.len() - classified.len(),
+        categories: confusion.per_category(),
+        confused_pairs: confusion.confused_pairs(MAX_CONFUSED_PAIRS),
+        confusion,
+        top_k,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use inference_types::TokenWithProb;
+
+    use super::*;
+
+    fn candidate(category: &str, logit: f32, embedding_logit: f32) -> CategoryTopToken {
+        CategoryTopToken {
+            category_name: category.to_string(),
+            best_token: TokenWithProb {
+                text: format!(" {category}"),
+                token_id: 0,
+                probability: 0.0,
+                logit,
+                embedding_logit,
+            },
+            sim_score: 0.0,
+        }
+    }
+
+    fn example(chosen: Option<&str>, correct: &[&str]) -> RecordedExample {
+        RecordedExample {
+            example_id: 0,
+            example_text: String::new(),
+            chosen_category: chosen.map(str::to_string),
+            success: chosen.is_some_and(|c| correct.contains(&c)),
+            correct_categories: correct.iter().map(|c| c.to_string()).collect(),
+            candidates: vec![],
+        }
+    }
+
+    #[test]
+    fn correct_prediction_counts_against_itself_in_multi_label_examples() {
+        let correct = vec!["A".to_string(), "B".to_string()];
+        assert_eq!(actual_category(&correct, "B"), "B");
+        assert_eq!(actual_category(&correct, "C"), "A");
+    }
+
+    #[test]
+    fn per_category_metrics_and_confused_pairs() {
+        let examples = [
+            example(Some("A"), &["A"]),
+            example(Some("A"), &["A"]),
+            example(Some("A"), &["B"]),
+            example(Some("A"), &["B"]),
+            example(Some("B"), &["B"]),
+            example(Some("A"), &["C"]),
+            example(None, &["C"]),
+        ];
+
+        let m = run_metrics(&examples);
+
+        assert_eq!(
+            m.accuracy,
+            Accuracy {
+                correct: 3,
+                total: 6
+            }
+        );
+        assert_eq!(m.unclassified, 1);
+        assert_eq!(m.confusion.categories, ["A", "B", "C"]);
+        assert_eq!(m.confusion.counts, [[2, 0, 0], [2, 1, 0], [1, 0, 0]]);
+
+        let a = &m.categories[0];
+        assert_eq!((a.support, a.predicted, a.true_positives), (2, 5, 2));
+        assert_eq!(a.precision, Some(0.4));
+        assert_eq!(a.recall, Some(1.0));
+        // C is never predicted and never right.
+        let c = &m.categories[2];
+        assert_eq!((c.precision, c.recall, c.f1), (None, Some(0.0), None));
+
+        let pairs: Vec<_> = m
+            .confused_pairs
+            .iter()
+            .map(|p| (p.actual.as_str(), p.predicted.as_str(), p.count))
+            .collect();
+        assert_eq!(pairs, [("B", "A", 2), ("C", "A", 1)]);
+    }
+
+    #[test]
+    fn top_k_ranks_candidates_by_adjusted_logit() {
+        let mut first = example(Some("A"), &["A"]);
+        first.candidates = vec![candidate("A", 1.0, 2.0), candidate("B", 2.0, 0.0)];
+        // Correct category ranked third of four.
+        let mut third = example(Some("B"), &["C"]);
+        third.candidates = vec![
+            candidate("A", 2.0, 0.0),
+            candidate("B", 3.0, 0.0),
+            candidate("C", 0.0, 1.0),
+            candidate("D", 0.5, 0.0),
+        ];
+        let unscored = example(Some("A"), &["A"]);
+
+        let m = run_metrics(&[first, third, unscored]);
+
+        let top_k: Vec<_> = m.top_k.iter().map(|t| (t.k, t.accuracy.correct)).collect();
+        assert_eq!(top_k, [(1, 1), (3, 2), (5, 2)]);
+        assert!(m.top_k.iter().all(|t| t.accuracy.total == 2));
+    }
+}
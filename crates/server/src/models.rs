@@ -0,0 +1,215 @@
+//! Generation models bulk tests can run on: the one loaded at startup, and
+//! other GGUF files from its directory, loaded the first time a variant asks
+//! for them. Only `MAX_VARIANT_MODELS` of those are loaded at a time.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Context;
+use inference::{InferenceConfig, InferenceEngine};
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::run_config::ModelFile;
+
+/// A loaded engine and the file it was loaded from.
+#[derive(Clone)]
+pub struct Model {
+    pub engine: Arc<InferenceEngine>,
+    pub file: Arc<ModelFile>,
+}
+
+/// A variant model, loaded by the first request for it while later ones wait.
+struct Slot {
+    model: Arc<OnceCell<Model>>,
+    last_used: Instant,
+}
+
+impl Slot {
+    /// Loaded, and held by nothing but this slot: no request is waiting on
+    /// it and no run is using its engine, so dropping the slot frees it.
+    fn idle(&self) -> bool {
+        Arc::strong_count(&self.model) == 1
+            && self
+                .model
+                .get()
+                .is_some_and(|model| Arc::strong_count(&model.engine) == 1)
+    }
+}
+
+pub struct Models {
+    main: Model,
+    /// Variant models loaded at most.
+    capacity: usize,
+    /// Variant models by file name, including those still loading. Only idle
+    /// ones are dropped, so every variant model alive is in here.
+    loaded: Mutex<HashMap<String, Slot>>,
+}
+
+impl Models {
+    /// `capacity` variant models are loaded at most; loading another unloads
+    /// the idle one used least recently, and fails while all are in use. 0
+    /// allows only the main model.
+    pub fn new(main: Model, capacity: usize) -> Self {
+        Self {
+            main,
+            capacity,
+            loaded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The model loaded at startup.
+    pub fn main(&self) -> &Model {
+        &self.main
+    }
+
+    /// File name of the model loaded at startup.
+    pub fn main_file_name(&self) -> &str {
+        let path = &self.main.engine.config().model_path;
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+    }
+
+    /// Variant models loaded at most.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The model in `file_name` of the main model's directory, loaded on
+    /// first use with `chat_template` (`None` detects it from the GGUF
+    /// metadata) and otherwise the main model's settings. A model already
+    /// loaded with another chat template is an error.
+    pub async fn get(&self, file_name: &str, chat_template: Option<&str>) -> anyhow::Result<Model> {
+        anyhow::ensure!(
+            !file_name.is_empty() && Path::new(file_name).file_name() == Some(file_name.as_ref()),
+            "model must be a file name in the model directory, not {file_name:?}"
+        );
+        let main_config = self.main.engine.config();
+        let main_path = &main_config.model_path;
+        if main_path.file_name() == Some(file_name.as_ref()) {
+            check_chat_template(&self.main, file_name, chat_template)?;
+            return Ok(self.main.clone());
+        }
+
+        let mut config = main_config.clone();
+        config.model_path = main_path.with_file_name(file_name);
+        config.chat_template = chat_template.map(str::to_string);
+        // The context cache is keyed by prompt tokens only; models sharing a
+        // tokenizer must not share cached contexts.
+        config.context_cache_dir = main_config.context_cache_dir.join(file_name);
+        anyhow::ensure!(
+            config.model_path.is_file(),
+            "no model file {}",
+            config.model_path.display()
+        );
+
+        // Loading takes a while; only requests for the same model wait on it.
+        let cell = self.slot(file_name).await?;
+        let model = match cell.get_or_try_init(|| self.load(file_name, config)).await {
+            Ok(model) => model.clone(),
+            Err(e) => {
+                let mut loaded = self.loaded.lock().await;
+                if loaded
+                    .get(file_name)
+                    .is_some_and(|slot| Arc::ptr_eq(&slot.model, &cell) && !cell.initialized())
+                {
+                    loaded.remove(file_name);
+                }
+                return Err(e);
+            }
+        };
+        check_chat_template(&model, file_name, chat_template)?;
+        Ok(model)
+    }
+
+    /// The slot of `file_name`, made room for and added if it has none.
+    async fn slot(&self, file_name: &str) -> anyhow::Result<Arc<OnceCell<Model>>> {
+        let mut loaded = self.loaded.lock().await;
+        if let Some(slot) = loaded.get_mut(file_name) {
+            slot.last_used = Instant::now();
+            return Ok(Arc::clone(&slot.model));
+        }
+        anyhow::ensure!(
+            self.capacity > 0,
+            "model {file_name} is not the main model, and variant models are disabled"
+        );
+        if loaded.len() >= self.capacity {
+            let oldest = loaded
+                .iter()
+                .filter(|(_, slot)| slot.idle())
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(name, _)| name.clone())
+                .with_context(|| {
+                    format!(
+                        "cannot load model {file_name}: all {} variant models are in use",
+                        self.capacity
+                    )
+                })?;
+            loaded.remove(&oldest);
+            tracing::info!(model = oldest, "Unloading variant model");
+        }
+        let model = Arc::new(OnceCell::new());
+        loaded.insert(
+            file_name.to_string(),
+            Slot {
+                model: Arc::clone(&model),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(model)
+    }
+
+    async fn load(&self, file_name: &str, config: InferenceConfig) -> anyhow::Result<Model> {
+        tracing::info!(model = file_name, "Loading variant model");
+        let engine = tokio::task::spawn_blocking({
+            let main = Arc::clone(&self.main.engine);
+            let config = config.clone();
+            move || {
+                std::fs::create_dir_all(&config.context_cache_dir).with_context(|| {
+                    format!("failed to create {}", config.context_cache_dir.display())
+                })?;
+                main.load_model(config)
+            }
+        })
+        .await??;
+        tracing::info!(
+            model = file_name,
+            chat_template = engine.chat_template_name(),
+            "Variant model ready"
+        );
+        Ok(Model {
+            engine: Arc::new(engine),
+            file: Arc::new(ModelFile::new(config.model_path)),
+        })
+    }
+
+    /// The model a run recorded with `model_path` ran on: the main model if
+    /// the path is the main model's, otherwise the file of the same name in
+    /// the main model's directory.
+    pub async fn recorded(&self, model_path: &str, chat_template: &str) -> anyhow::Result<Model> {
+        if Path::new(model_path) == self.main.engine.config().model_path {
+            return Ok(self.main.clone());
+        }
+        let file_name = Path::new(model_path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .with_context(|| format!("recorded model path {model_path:?} has no file name"))?;
+        self.get(file_name, Some(chat_template)).await
+    }
+}
+
+fn check_chat_template(
+    model: &Model,
+    file_name: &str,
+    chat_template: Option<&str>,
+) -> anyhow::Result<()> {
+    let loaded = model.engine.chat_template_name();
+    match chat_template {
+        Some(requested) if requested != loaded => anyhow::bail!(
+            "model {file_name} is loaded with chat template {loaded}, not {requested}"
+        ),
+        _ => Ok(()),
+    }
+}
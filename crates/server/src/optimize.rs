@@ -1,29 +1,52 @@
-//! Per-category embedding weight optimisation via gradient descent.
+//! Per-category embedding weight optimisation.
 //!
 //! ## Problem
 //! The inference engine scores each category as:
-//!   `total_score[c] = logit[c] + kappa[c] * sim_score[c]`
+//!   `total_score[c] = logit[c] / T + offset[c] + kappa[c] * sim_score[c]`
 //!
 //! This module finds per-category kappa values that maximise correct
-//! classifications over a validation set, subject to kappa ≥ 0 (so we
-//! never *penalise* a category for having a high embedding similarity).
+//! classifications over a validation set, by default subject to kappa ≥ 0
+//! (so we never *penalise* a category for having a high embedding
+//! similarity). `OptimizerConfig::allow_negative` lifts that constraint.
 //!
-//! ## Algorithm — Gradient Descent with Cross-Entropy Loss
+//! The per-category `offset` (a class prior) and the global temperature `T`
+//! are fitted jointly with kappa when `learn_offsets` / `learn_temperature`
+//! are set; otherwise they stay at 0 and 1. With either enabled, categories
+//! without embedding data are fitted too.
 //!
-//! For each example, compute a softmax distribution over category scores and
-//! minimise the cross-entropy between that distribution and the ground-truth
-//! one-hot for the correct category.  This is a smooth surrogate for
-//! maximising classification accuracy.
+//! ## Objectives
 //!
-//! Optimiser: **Adam** (`β₁=0.9, β₂=0.999, ε=1e-8`) for stable convergence.
+//! **Cross-entropy** (default): for each example, compute a softmax
+//! distribution over category scores and minimise the cross-entropy between
+//! that distribution and the ground-truth one-hot for the correct category.
+//! This is a smooth surrogate for maximising classification accuracy.
 //!
-//! After each Adam update: clamp `kappa[i] = kappa[i].max(0.0)` to enforce
-//! non-negativity, so the embedding can only *help* a category.
+//! **Hinge**: multi-class margin loss
+//! `max(0, margin + best_incorrect_score − best_correct_score)`, which stops
+//! pushing once an example is classified with enough margin.
+//!
+//! Both minimise with **Adam** (defaults `β₁=0.9, β₂=0.999, ε=1e-8`) plus an
+//! L2 penalty that encourages minimum kappa and offsets and a temperature
+//! near 1. After each Adam update, kappa is clamped to ≥ 0 unless negatives
+//! are allowed.
+//!
+//! **Coordinate search** maximises accuracy directly: it sweeps the
+//! categories, setting each kappa to the value that classifies the most
+//! examples correctly with the others fixed, until a sweep changes nothing.
 //!
 //! Initialise kappa = 0 (minimum starting point per minimum-norm requirement).
+//!
+//! ## Held-out evaluation
+//! Accuracy measured on the examples the weights were fitted on is optimistic,
+//! especially for small example sets. `cross_validate` fits on k−1 folds and
+//! scores the remaining one, so the spread of fold accuracies shows whether a
+//! gain generalises.
 
 use std::collections::HashMap;
 
+use inference::scoring;
+use serde::{Deserialize, Serialize};
+
 /// Logit and embedding similarity score for one category in one example.
 pub struct CategoryScore {
     pub logit: f32,
@@ -43,22 +66,225 @@ pub struct ExampleData {
 // Hyper-parameters
 // ---------------------------------------------------------------------------
 
-const N_ITERS: usize = 3000;
-const LR: f64 = 0.05;
-const LAMBDA: f64 = 1e-4; // L2 regularisation — encourages minimum kappa
-const BETA1: f64 = 0.9;
-const BETA2: f64 = 0.999;
-const EPS: f64 = 1e-8;
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Objective {
+    #[default]
+    CrossEntropy,
+    Hinge,
+    CoordinateSearch,
+}
+
+/// Optimiser settings; every field defaults to the values the optimiser has
+/// always used.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OptimizerConfig {
+    pub objective: Objective,
+    /// Adam steps, or the maximum number of sweeps for coordinate search.
+    pub iterations: usize,
+    pub learning_rate: f64,
+    /// L2 regularisation — encourages minimum kappa and offsets and a
+    /// temperature near 1. Unused by coordinate search.
+    pub l2: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub epsilon: f64,
+    /// Required score gap for the hinge objective.
+    pub hinge_margin: f64,
+    /// Let kappa go below zero, so a category can be penalised for a high
+    /// embedding similarity.
+    pub allow_negative: bool,
+    /// Also fit a per-category additive logit offset (a class prior).
+    pub learn_offsets: bool,
+    /// Also fit a global temperature that the raw logits are divided by.
+    /// Coordinate search leaves it at 1.
+    pub learn_temperature: bool,
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        Self {
+            objective: Objective::CrossEntropy,
+            iterations: 3000,
+            learning_rate: 0.05,
+            l2: 1e-4,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+            hinge_margin: 1.0,
+            allow_negative: false,
+            learn_offsets: false,
+            learn_temperature: false,
+        }
+    }
+}
+
+/// Most iterations a request may ask for.
+const MAX_ITERATIONS: usize = 100_000;
+
+/// The loss curve is thinned to about this many points.
+const MAX_LOSS_POINTS: usize = 200;
+
+impl OptimizerConfig {
+    /// Reject settings the optimiser cannot run with.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_ITERATIONS).contains(&self.iterations) {
+            return Err(format!("iterations must be in 1..={MAX_ITERATIONS}"));
+        }
+        if !(self.learning_rate > 0.0 && self.learning_rate.is_finite()) {
+            return Err("learning_rate must be positive".into());
+        }
+        if !(self.l2 >= 0.0 && self.l2.is_finite()) {
+            return Err("l2 must be non-negative".into());
+        }
+        if !(0.0..1.0).contains(&self.beta1) || !(0.0..1.0).contains(&self.beta2) {
+            return Err("beta1 and beta2 must be in [0, 1)".into());
+        }
+        if self.epsilon <= 0.0 || self.epsilon.is_nan() {
+            return Err("epsilon must be positive".into());
+        }
+        if !(self.hinge_margin >= 0.0 && self.hinge_margin.is_finite()) {
+            return Err("hinge_margin must be non-negative".into());
+        }
+        Ok(())
+    }
+}
+
+/// Objective value after a given iteration. For coordinate search the loss
+/// is the error rate after each sweep (iteration 0 is the all-zero start).
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct LossPoint {
+    pub iteration: usize,
+    pub loss: f64,
+}
+
+/// Fitted scoring parameters:
+/// `score[c] = logit[c] / temperature + offset[c] + kappa[c] * sim_score[c]`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Weights {
+    /// Category name → kappa. Categories without embedding data are omitted.
+    pub kappa: HashMap<String, f64>,
+    /// Category name → additive logit offset. Empty unless offsets were learned.
+    pub offsets: HashMap<String, f64>,
+    pub temperature: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            kappa: HashMap::new(),
+            offsets: HashMap::new(),
+            temperature: 1.0,
+        }
+    }
+}
+
+impl Weights {
+    /// Kappa and offset of one category, with `fallback_kappa` for categories
+    /// that have no fitted kappa.
+    pub fn constants(&self, name: &str, fallback_kappa: f64) -> (f32, f32) {
+        let kappa = self.kappa.get(name).copied().unwrap_or(fallback_kappa);
+        let offset = self.offsets.get(name).copied().unwrap_or(0.0);
+        (kappa as f32, offset as f32)
+    }
+
+    /// Adjusted logit of one category as the engine computes it. That is the
+    /// fitted score times the temperature, so both rank categories alike.
+    pub fn score(&self, name: &str, s: &CategoryScore, fallback_kappa: f64) -> f32 {
+        let (kappa, offset) = self.constants(name, fallback_kappa);
+        scoring::category_score(s.logit, kappa, s.sim_score, offset, self.temperature as f32)
+    }
+}
+
+pub struct Optimized {
+    pub weights: Weights,
+    pub loss_curve: Vec<LossPoint>,
+}
+
+/// Smallest inverse temperature the optimiser may reach (temperature ≤ 100).
+const MIN_LOGIT_SCALE: f64 = 0.01;
+
+/// One usable example, flattened for fast iteration.
+struct ExFlat {
+    /// `(cat_idx, logit, sim)`, sorted by category index.
+    scores: Vec<(usize, f64, f64)>,
+    /// Indexed by position in `scores`.
+    correct_mask: Vec<bool>,
+}
+
+/// Every parameter in one vector, so Adam can step them together:
+/// `[kappa_0 … kappa_n-1, offset_0 … offset_n-1, logit_scale]`, where
+/// `logit_scale` is the inverse temperature.
+struct Layout {
+    n_cats: usize,
+}
+
+impl Layout {
+    fn len(&self) -> usize {
+        2 * self.n_cats + 1
+    }
+    fn kappa(&self, cat: usize) -> usize {
+        cat
+    }
+    fn offset(&self, cat: usize) -> usize {
+        self.n_cats + cat
+    }
+    fn scale(&self) -> usize {
+        2 * self.n_cats
+    }
+    fn initial(&self) -> Vec<f64> {
+        let mut theta = vec![0.0; self.len()];
+        theta[self.scale()] = 1.0;
+        theta
+    }
+}
+
+impl ExFlat {
+    fn adjusted_scores(&self, layout: &Layout, theta: &[f64]) -> Vec<f64> {
+        self.scores
+            .iter()
+            .map(|&(idx, logit, sim)| {
+                theta[layout.scale()] * logit
+                    + theta[layout.offset(idx)]
+                    + theta[layout.kappa(idx)] * sim
+            })
+            .collect()
+    }
+
+    /// Whether the highest-scoring category is a correct one.
+    fn is_correct(&self, layout: &Layout, theta: &[f64]) -> bool {
+        let s = self.adjusted_scores(layout, theta);
+        (0..s.len())
+            .max_by(|&a, &b| s[a].total_cmp(&s[b]))
+            .is_some_and(|best| self.correct_mask[best])
+    }
+
+    /// Add `weight` times the gradient of position `pos`'s score to `grad`.
+    fn add_score_gradient(&self, layout: &Layout, pos: usize, weight: f64, grad: &mut [f64]) {
+        let (idx, logit, sim) = self.scores[pos];
+        grad[layout.kappa(idx)] += weight * sim;
+        grad[layout.offset(idx)] += weight;
+        grad[layout.scale()] += weight * logit;
+    }
+}
 
-/// Find per-category kappa values that maximise classification accuracy.
+/// Find per-category kappa values that maximise classification accuracy,
+/// optionally with per-category logit offsets and a global temperature.
 ///
-/// Returns a map from category name → optimal kappa (≥ 0).  Categories with
-/// `sim_score = 0.0` on every example are omitted (no embedding data).
+/// Returns the fitted weights (kappa ≥ 0 unless `allow_negative`) and the
+/// loss curve.  Categories with `sim_score = 0.0` on every example get no
+/// kappa (no embedding data).
 ///
-/// Returns `None` when there are fewer than 2 categories or no usable examples.
-pub fn optimize_weights(examples: &[ExampleData]) -> Option<HashMap<String, f64>> {
-    // Collect the ordered set of categories that have at least one non-zero sim_score.
-    let categories: Vec<String> = {
+/// Returns `None` when there are fewer than 2 categories to fit or no usable
+/// examples.
+pub fn optimize_weights(examples: &[&ExampleData], config: &OptimizerConfig) -> Option<Optimized> {
+    let learn_priors = config.learn_offsets || config.learn_temperature;
+
+    // Collect the ordered set of categories, noting which have at least one
+    // non-zero sim_score. Kappa alone can only move categories with
+    // embedding data, so without offsets or temperature the rest are dropped.
+    let (categories, has_sim): (Vec<String>, Vec<bool>) = {
         let mut seen: HashMap<String, bool> = HashMap::new();
         for ex in examples {
             for (name, score) in &ex.category_scores {
@@ -68,13 +294,12 @@ pub fn optimize_weights(examples: &[ExampleData]) -> Option<HashMap<String, f64>
                 }
             }
         }
-        let mut cats: Vec<String> = seen
+        let mut cats: Vec<(String, bool)> = seen
             .into_iter()
-            .filter(|(_, has_data)| *has_data)
-            .map(|(name, _)| name)
+            .filter(|(_, has_data)| *has_data || learn_priors)
             .collect();
         cats.sort();
-        cats
+        cats.into_iter().unzip()
     };
 
     let n_cats = categories.len();
@@ -92,6 +317,7 @@ pub fn optimize_weights(examples: &[ExampleData]) -> Option<HashMap<String, f64>
     // and at least one incorrect category in our set.
     let usable: Vec<&ExampleData> = examples
         .iter()
+        .copied()
         .filter(|ex| {
             let has_correct = ex
                 .correct_categories
@@ -109,14 +335,6 @@ pub fn optimize_weights(examples: &[ExampleData]) -> Option<HashMap<String, f64>
         return None;
     }
 
-    // Pre-extract per-example data into flat arrays for efficient iteration.
-    // For each example we store all (cat_idx, logit, sim_score) triples and
-    // the set of correct category indices.
-    struct ExFlat {
-        scores: Vec<(usize, f64, f64)>, // (cat_idx, logit, sim)
-        correct_mask: Vec<bool>,        // indexed by position in `scores`
-    }
-
     let flat: Vec<ExFlat> = usable
         .iter()
         .map(|ex| {
@@ -129,11 +347,6 @@ pub fn optimize_weights(examples: &[ExampleData]) -> Option<HashMap<String, f64>
                 })
                 .collect();
             scores.sort_by_key(|(idx, _, _)| *idx);
-            let correct_mask: Vec<bool> = scores
-                .iter()
-                .map(|(_, _, _)| false) // placeholder; fill below
-                .collect();
-            let _ = correct_mask; // suppress warning
             let correct_mask: Vec<bool> = scores
                 .iter()
                 .map(|(idx, _, _)| {
@@ -143,75 +356,478 @@ pub fn optimize_weights(examples: &[ExampleData]) -> Option<HashMap<String, f64>
                         .unwrap_or(false)
                 })
                 .collect();
-            ExFlat { scores, correct_mask }
+            ExFlat {
+                scores,
+                correct_mask,
+            }
         })
         .collect();
 
-    // ---------------------------------------------------------------------------
-    // Adam optimiser state
-    // ---------------------------------------------------------------------------
-    let mut kappa = vec![0.0f64; n_cats];
-    let mut m = vec![0.0f64; n_cats]; // first moment
-    let mut v = vec![0.0f64; n_cats]; // second moment
-
-    for iter in 1..=N_ITERS {
-        let mut grad = vec![0.0f64; n_cats];
-
-        for ex in &flat {
-            // Compute scores: s[k] = logit[k] + kappa[cat_idx[k]] * sim[k]
-            let n = ex.scores.len();
-            let mut s: Vec<f64> = ex
-                .scores
+    // Which entries of the parameter vector are fitted; the rest keep their
+    // initial values (kappa 0, offset 0, scale 1).
+    let layout = Layout { n_cats };
+    let mut learnable = vec![false; layout.len()];
+    for (cat, &has_sim) in has_sim.iter().enumerate() {
+        learnable[layout.kappa(cat)] = has_sim;
+        learnable[layout.offset(cat)] = config.learn_offsets;
+    }
+    learnable[layout.scale()] = config.learn_temperature;
+
+    let (theta, loss_curve) = match config.objective {
+        Objective::CrossEntropy | Objective::Hinge => {
+            gradient_descent(&flat, &layout, &learnable, config)
+        }
+        Objective::CoordinateSearch => coordinate_search(&flat, &layout, &learnable, config),
+    };
+
+    let weights = Weights {
+        kappa: categories
+            .iter()
+            .enumerate()
+            .filter(|&(cat, _)| has_sim[cat])
+            .map(|(cat, name)| (name.clone(), theta[layout.kappa(cat)]))
+            .collect(),
+        offsets: if config.learn_offsets {
+            categories
                 .iter()
-                .map(|(idx, logit, sim)| logit + kappa[*idx] * sim)
-                .collect();
+                .enumerate()
+                .map(|(cat, name)| (name.clone(), theta[layout.offset(cat)]))
+                .collect()
+        } else {
+            HashMap::new()
+        },
+        temperature: 1.0 / theta[layout.scale()],
+    };
+    Some(Optimized {
+        weights,
+        loss_curve,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Gradient objectives
+// ---------------------------------------------------------------------------
+
+/// Adam over the mean per-example loss plus an L2 penalty pulling kappa and
+/// the offsets toward 0 and the logit scale toward 1.
+fn gradient_descent(
+    flat: &[ExFlat],
+    layout: &Layout,
+    learnable: &[bool],
+    config: &OptimizerConfig,
+) -> (Vec<f64>, Vec<LossPoint>) {
+    let mut theta = layout.initial();
+    let prior = layout.initial();
+    let mut m = vec![0.0f64; layout.len()]; // first moment
+    let mut v = vec![0.0f64; layout.len()]; // second moment
+    let mut loss_curve = vec![];
+    let record_every = config.iterations.div_ceil(MAX_LOSS_POINTS);
+    let n_ex = flat.len() as f64;
+
+    for iter in 1..=config.iterations {
+        let mut grad = vec![0.0f64; layout.len()];
+        let mut loss = 0.0;
+        for ex in flat {
+            loss += match config.objective {
+                Objective::Hinge => hinge_loss(ex, layout, &theta, config.hinge_margin, &mut grad),
+                _ => cross_entropy_loss(ex, layout, &theta, &mut grad),
+            };
+        }
+
+        // Add L2 regularisation: λ/2 · ‖θ − prior‖², gradient λ · (θ − prior)
+        let mut penalty = 0.0;
+        for i in 0..layout.len() {
+            if learnable[i] {
+                let d = theta[i] - prior[i];
+                penalty += d * d;
+                grad[i] = grad[i] / n_ex + config.l2 * d;
+            }
+        }
+        loss = loss / n_ex + 0.5 * config.l2 * penalty;
+        if iter % record_every == 0 || iter == 1 {
+            loss_curve.push(LossPoint {
+                iteration: iter,
+                loss,
+            });
+        }
+
+        // Adam update.
+        let lr_t = config.learning_rate * (1.0 - config.beta2.powi(iter as i32)).sqrt()
+            / (1.0 - config.beta1.powi(iter as i32));
+        for i in (0..layout.len()).filter(|&i| learnable[i]) {
+            m[i] = config.beta1 * m[i] + (1.0 - config.beta1) * grad[i];
+            v[i] = config.beta2 * v[i] + (1.0 - config.beta2) * grad[i] * grad[i];
+            theta[i] -= lr_t * m[i] / (v[i].sqrt() + config.epsilon);
+        }
+        for cat in 0..layout.n_cats {
+            if !config.allow_negative {
+                // Non-negativity constraint.
+                theta[layout.kappa(cat)] = theta[layout.kappa(cat)].max(0.0);
+            }
+        }
+        theta[layout.scale()] = theta[layout.scale()].max(MIN_LOGIT_SCALE);
+    }
+
+    (theta, loss_curve)
+}
+
+/// Softmax cross-entropy against a uniform target over the correct
+/// categories. Adds the example's gradient to `grad` and returns its loss.
+fn cross_entropy_loss(ex: &ExFlat, layout: &Layout, theta: &[f64], grad: &mut [f64]) -> f64 {
+    // Target: uniform over correct categories.
+    let n_correct = ex.correct_mask.iter().filter(|&&b| b).count();
+    if n_correct == 0 {
+        return 0.0;
+    }
+    let target_val = 1.0 / n_correct as f64;
+
+    // Numerically stable softmax.
+    let s = ex.adjusted_scores(layout, theta);
+    let s_max = s.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = s.iter().map(|x| (x - s_max).exp()).sum::<f64>().ln();
+
+    // Cross-entropy gradient w.r.t. each score: prob[k] - target[k], chained
+    // through the score's parameters.
+    let mut loss = 0.0;
+    for (pos, &score) in s.iter().enumerate() {
+        let log_prob = score - s_max - log_sum;
+        let target = if ex.correct_mask[pos] {
+            target_val
+        } else {
+            0.0
+        };
+        loss -= target * log_prob;
+        ex.add_score_gradient(layout, pos, log_prob.exp() - target, grad);
+    }
+    loss
+}
+
+/// Multi-class hinge loss on the best correct vs the best incorrect
+/// category. Adds the example's (sub)gradient to `grad` and returns its loss.
+fn hinge_loss(ex: &ExFlat, layout: &Layout, theta: &[f64], margin: f64, grad: &mut [f64]) -> f64 {
+    let s = ex.adjusted_scores(layout, theta);
+    let best = |correct: bool| {
+        (0..s.len())
+            .filter(|&pos| ex.correct_mask[pos] == correct)
+            .max_by(|&a, &b| s[a].total_cmp(&s[b]))
+    };
+    let (Some(y), Some(j)) = (best(true), best(false)) else {
+        return 0.0;
+    };
+    let loss = margin + s[j] - s[y];
+    if loss <= 0.0 {
+        return 0.0;
+    }
+    ex.add_score_gradient(layout, j, 1.0, grad);
+    ex.add_score_gradient(layout, y, -1.0, grad);
+    loss
+}
+
+// ---------------------------------------------------------------------------
+// Coordinate search
+// ---------------------------------------------------------------------------
+
+/// Maximise accuracy one kappa or offset at a time, leaving the temperature
+/// at 1. With the other parameters fixed, an example's correctness as a
+/// function of one category's parameter is constant or flips at a single
+/// threshold, so each coordinate is solved exactly by sweeping the sorted
+/// thresholds.
+fn coordinate_search(
+    flat: &[ExFlat],
+    layout: &Layout,
+    learnable: &[bool],
+    config: &OptimizerConfig,
+) -> (Vec<f64>, Vec<LossPoint>) {
+    let mut theta = layout.initial();
+    let error_rate = |theta: &[f64]| {
+        let correct = flat
+            .iter()
+            .filter(|ex| ex.is_correct(layout, theta))
+            .count();
+        1.0 - correct as f64 / flat.len() as f64
+    };
+    let mut loss_curve = vec![LossPoint {
+        iteration: 0,
+        loss: error_rate(&theta),
+    }];
 
-            // Numerically stable softmax.
-            let s_max = s.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
-            for x in s.iter_mut() {
-                *x = (*x - s_max).exp();
+    // (category, whether the coordinate is its kappa rather than its offset)
+    let mut coordinates: Vec<(usize, bool)> = vec![];
+    for cat in 0..layout.n_cats {
+        if learnable[layout.kappa(cat)] {
+            coordinates.push((cat, true));
+        }
+        if learnable[layout.offset(cat)] {
+            coordinates.push((cat, false));
+        }
+    }
+
+    for sweep in 1..=config.iterations {
+        let mut changed = false;
+        for &(cat, is_kappa) in &coordinates {
+            let param = if is_kappa {
+                layout.kappa(cat)
+            } else {
+                layout.offset(cat)
+            };
+            let best = best_coordinate(flat, layout, &theta, cat, is_kappa, config.allow_negative);
+            if best != theta[param] {
+                theta[param] = best;
+                changed = true;
             }
-            let sum: f64 = s.iter().sum();
-            let prob: Vec<f64> = s.iter().map(|x| x / sum).collect();
+        }
+        loss_curve.push(LossPoint {
+            iteration: sweep,
+            loss: error_rate(&theta),
+        });
+        if !changed {
+            break;
+        }
+    }
+
+    (theta, loss_curve)
+}
 
-            // Target: uniform over correct categories.
-            let n_correct = ex.correct_mask.iter().filter(|&&b| b).count();
-            if n_correct == 0 {
+/// The value of category `cat`'s kappa (which adds `value * sim` to its
+/// score) or offset (which adds `value`) that classifies the most
+/// examples correctly with every other parameter fixed. The current value is
+/// kept unless another is strictly better, so each change fixes at least one
+/// more example and the search terminates; among equally good new values the
+/// smallest magnitude wins.
+fn best_coordinate(
+    flat: &[ExFlat],
+    layout: &Layout,
+    theta: &[f64],
+    cat: usize,
+    is_kappa: bool,
+    allow_negative: bool,
+) -> f64 {
+    let current_value = if is_kappa {
+        theta[layout.kappa(cat)]
+    } else {
+        theta[layout.offset(cat)]
+    };
+    // Offsets are never clamped.
+    let allow_negative = allow_negative || !is_kappa;
+    // For each example: always correct (`base`), never, or correct only
+    // above (`above`) or below (`below`) a threshold on the parameter.
+    let mut base = 0usize;
+    let mut above: Vec<f64> = vec![];
+    let mut below: Vec<f64> = vec![];
+    for ex in flat {
+        let s = ex.adjusted_scores(layout, theta);
+        let mut best_correct = f64::NEG_INFINITY;
+        let mut best_incorrect = f64::NEG_INFINITY;
+        let mut own = None;
+        for (pos, &(cat_idx, _, sim)) in ex.scores.iter().enumerate() {
+            if cat_idx == cat {
+                let slope = if is_kappa { sim } else { 1.0 };
+                // Score with this parameter's contribution removed.
+                own = Some((s[pos] - current_value * slope, slope, ex.correct_mask[pos]));
+            } else if ex.correct_mask[pos] {
+                best_correct = best_correct.max(s[pos]);
+            } else {
+                best_incorrect = best_incorrect.max(s[pos]);
+            }
+        }
+        let Some((fixed, slope, own_correct)) = own else {
+            base += usize::from(best_correct > best_incorrect);
+            continue;
+        };
+        // This category's score is `fixed + t * slope`; it has to beat (if
+        // correct) or stay under (if incorrect) `bar`.
+        let (bar, beats) = if own_correct {
+            if best_correct > best_incorrect {
+                base += 1;
                 continue;
             }
-            let target_val = 1.0 / n_correct as f64;
-
-            // Cross-entropy gradient w.r.t. kappa:
-            // ∂L/∂kappa[c] = Σ_k sim[k] * (prob[k] - target[k])   (where k iterates over positions)
-            // but only for k where cat_idx[k] == c.
-            for pos in 0..n {
-                let (cat_idx, _, sim) = ex.scores[pos];
-                let target = if ex.correct_mask[pos] { target_val } else { 0.0 };
-                let delta = prob[pos] - target;
-                grad[cat_idx] += sim * delta;
+            (best_incorrect, true)
+        } else {
+            if best_correct <= best_incorrect {
+                continue;
             }
+            (best_correct, false)
+        };
+        if slope == 0.0 {
+            base += usize::from((fixed > bar) == beats);
+            continue;
         }
+        let threshold = (bar - fixed) / slope;
+        // fixed + t·slope > bar  ⇔  t > threshold when slope > 0.
+        if (slope > 0.0) == beats {
+            above.push(threshold);
+        } else {
+            below.push(threshold);
+        }
+    }
+    above.sort_by(f64::total_cmp);
+    below.sort_by(f64::total_cmp);
+    let correct_at = |t: f64| {
+        base + above.partition_point(|&th| th < t)
+            + (below.len() - below.partition_point(|&th| th <= t))
+    };
+
+    // Accuracy only changes at thresholds, so midpoints (and a point past
+    // each end) cover every attainable value.
+    let mut thresholds: Vec<f64> = above.iter().chain(&below).copied().collect();
+    thresholds.sort_by(f64::total_cmp);
+    thresholds.dedup();
+    let mut candidates = vec![current_value, 0.0];
+    if let (Some(first), Some(last)) = (thresholds.first(), thresholds.last()) {
+        candidates.push(first - 1.0);
+        candidates.push(last + 1.0);
+    }
+    candidates.extend(thresholds.windows(2).map(|w| (w[0] + w[1]) / 2.0));
+    if !allow_negative {
+        candidates.retain(|&t| t >= 0.0);
+    }
+
+    let best = candidates.iter().map(|&t| correct_at(t)).max().unwrap_or(0);
+    if correct_at(current_value) >= best {
+        return current_value;
+    }
+    candidates
+        .into_iter()
+        .filter(|&t| correct_at(t) == best)
+        .min_by(|a, b| a.abs().total_cmp(&b.abs()))
+        .unwrap_or(current_value)
+}
+
+// ---------------------------------------------------------------------------
+// Held-out evaluation
+// ---------------------------------------------------------------------------
 
-        let n_ex = flat.len() as f64;
+/// Correct classifications out of the examples scored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Accuracy {
+    pub correct: usize,
+    pub total: usize,
+}
 
-        // Add L2 regularisation gradient: λ * kappa
-        for i in 0..n_cats {
-            grad[i] = grad[i] / n_ex + LAMBDA * kappa[i];
+impl Accuracy {
+    pub fn rate(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.correct as f64 / self.total as f64
         }
+    }
+}
 
-        // Adam update.
-        let lr_t = LR * (1.0 - BETA2.powi(iter as i32)).sqrt() / (1.0 - BETA1.powi(iter as i32));
-        for i in 0..n_cats {
-            m[i] = BETA1 * m[i] + (1.0 - BETA1) * grad[i];
-            v[i] = BETA2 * v[i] + (1.0 - BETA2) * grad[i] * grad[i];
-            kappa[i] -= lr_t * m[i] / (v[i].sqrt() + EPS);
-            // Non-negativity constraint.
-            kappa[i] = kappa[i].max(0.0);
+/// Score `examples` with `weights`: each picks the category with the highest
+/// adjusted score, using `fallback_kappa` for categories without a fitted
+/// kappa. Examples without scores are not counted.
+pub fn accuracy(examples: &[&ExampleData], weights: &Weights, fallback_kappa: f64) -> Accuracy {
+    let mut acc = Accuracy::default();
+    for ex in examples {
+        let best = ex.category_scores.iter().max_by(|(a, sa), (b, sb)| {
+            weights
+                .score(a, sa, fallback_kappa)
+                .total_cmp(&weights.score(b, sb, fallback_kappa))
+        });
+        let Some((name, _)) = best else {
+            continue;
+        };
+        acc.total += 1;
+        if ex.correct_categories.contains(name) {
+            acc.correct += 1;
         }
     }
+    acc
+}
+
+/// A permutation of `0..n`, reproducible for a given seed (SplitMix64 driving
+/// a Fisher–Yates shuffle).
+pub fn shuffled_indices(n: usize, seed: u64) -> Vec<usize> {
+    let mut state = seed;
+    let mut next = move || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    let mut indices: Vec<usize> = (0..n).collect();
+    for i in (1..n).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        indices.swap(i, j);
+    }
+    indices
+}
+
+/// Fit on the training examples and score both them and the held-out ones.
+/// If the fit fails (e.g. no embedding data in the training examples) every
+/// category keeps `fallback_kappa`.
+pub fn fit_and_score(
+    train: &[&ExampleData],
+    held_out: &[&ExampleData],
+    config: &OptimizerConfig,
+    fallback_kappa: f64,
+) -> HeldOutScore {
+    let weights = optimize_weights(train, config).map(|o| o.weights);
+    let fitted = weights.is_some();
+    let weights = weights.unwrap_or_default();
+    HeldOutScore {
+        fitted,
+        train: accuracy(train, &weights, fallback_kappa),
+        held_out: accuracy(held_out, &weights, fallback_kappa),
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HeldOutScore {
+    /// `false` when no weights could be fitted on the training examples.
+    pub fitted: bool,
+    /// In-sample accuracy on the examples the weights were fitted on.
+    pub train: Accuracy,
+    pub held_out: Accuracy,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CrossValidation {
+    pub folds: Vec<HeldOutScore>,
+    /// Mean held-out accuracy over the folds.
+    pub mean_accuracy: f64,
+    /// Sample standard deviation of the held-out accuracies.
+    pub std_accuracy: f64,
+}
+
+/// k-fold cross-validation: `examples` (already shuffled) are cut into `k`
+/// contiguous folds of near-equal size, and each fold is scored with weights
+/// fitted on the other `k − 1`. Requires `2 <= k <= examples.len()`.
+pub fn cross_validate(
+    examples: &[&ExampleData],
+    k: usize,
+    config: &OptimizerConfig,
+    fallback_kappa: f64,
+) -> CrossValidation {
+    assert!(k >= 2 && k <= examples.len(), "invalid fold count {k}");
+    let n = examples.len();
+    let folds: Vec<HeldOutScore> = (0..k)
+        .map(|fold| {
+            let (start, end) = (fold * n / k, (fold + 1) * n / k);
+            let held_out = &examples[start..end];
+            let train: Vec<&ExampleData> = examples[..start]
+                .iter()
+                .chain(&examples[end..])
+                .copied()
+                .collect();
+            fit_and_score(&train, held_out, config, fallback_kappa)
+        })
+        .collect();
 
-    let result: HashMap<String, f64> = categories.into_iter().zip(kappa).collect();
-    Some(result)
+    let rates: Vec<f64> = folds.iter().map(|f| f.held_out.rate()).collect();
+    let mean_accuracy = rates.iter().sum::<f64>() / k as f64;
+    let variance = rates
+        .iter()
+        .map(|r| (r - mean_accuracy).powi(2))
+        .sum::<f64>()
+        / (k - 1) as f64;
+    CrossValidation {
+        folds,
+        mean_accuracy,
+        std_accuracy: variance.sqrt(),
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -238,7 +854,10 @@ mod tests {
             ]),
             correct_categories: vec!["A".to_string()],
         };
-        let weights = optimize_weights(&[ex]).expect("should return weights");
+        let weights = optimize_weights(&[&ex], &OptimizerConfig::default())
+            .expect("should return weights")
+            .weights
+            .kappa;
         assert!(
             weights["A"] > weights["B"],
             "kappa_A ({}) should exceed kappa_B ({})",
@@ -268,7 +887,11 @@ mod tests {
                 correct_categories: vec!["A".to_string()],
             },
         ];
-        let weights = optimize_weights(&examples).expect("should return weights");
+        let refs: Vec<&ExampleData> = examples.iter().collect();
+        let weights = optimize_weights(&refs, &OptimizerConfig::default())
+            .expect("should return weights")
+            .weights
+            .kappa;
         for (cat, w) in &weights {
             assert!(*w >= 0.0, "kappa for {cat} is negative: {w}");
         }
@@ -284,6 +907,194 @@ mod tests {
             ]),
             correct_categories: vec!["A".to_string()],
         };
-        assert!(optimize_weights(&[ex]).is_none());
+        assert!(optimize_weights(&[&ex], &OptimizerConfig::default()).is_none());
+    }
+
+    /// Every objective fixes the easy case, and gradient losses fall.
+    #[test]
+    fn every_objective_fixes_the_easy_case() {
+        let ex = ExampleData {
+            category_scores: HashMap::from([
+                ("A".to_string(), make_score(1.0, 2.0)),
+                ("B".to_string(), make_score(2.0, 0.5)),
+            ]),
+            correct_categories: vec!["A".to_string()],
+        };
+        for objective in [
+            Objective::CrossEntropy,
+            Objective::Hinge,
+            Objective::CoordinateSearch,
+        ] {
+            let config = OptimizerConfig {
+                objective,
+                ..OptimizerConfig::default()
+            };
+            let result = optimize_weights(&[&ex], &config).expect("should return weights");
+            assert_eq!(
+                accuracy(&[&ex], &result.weights, 0.0).correct,
+                1,
+                "{objective:?}"
+            );
+            let first = result.loss_curve.first().unwrap().loss;
+            let last = result.loss_curve.last().unwrap().loss;
+            assert!(last < first, "{objective:?}: loss {first} -> {last}");
+        }
+    }
+
+    /// A correct category with a negative margin can only win by penalising
+    /// similarity, which needs `allow_negative`.
+    #[test]
+    fn negative_kappa_only_when_allowed() {
+        let ex = ExampleData {
+            category_scores: HashMap::from([
+                ("A".to_string(), make_score(1.0, -0.5)),
+                ("B".to_string(), make_score(2.0, 0.5)),
+            ]),
+            correct_categories: vec!["A".to_string()],
+        };
+        for objective in [Objective::CrossEntropy, Objective::CoordinateSearch] {
+            let clamped = OptimizerConfig {
+                objective,
+                ..OptimizerConfig::default()
+            };
+            let weights = optimize_weights(&[&ex], &clamped).unwrap().weights;
+            assert!(weights.kappa.values().all(|k| *k >= 0.0), "{objective:?}");
+            assert_eq!(accuracy(&[&ex], &weights, 0.0).correct, 0);
+
+            let free = OptimizerConfig {
+                allow_negative: true,
+                ..clamped
+            };
+            let weights = optimize_weights(&[&ex], &free).unwrap().weights;
+            assert_eq!(accuracy(&[&ex], &weights, 0.0).correct, 1, "{objective:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        assert!(OptimizerConfig::default().validate().is_ok());
+        for bad in [
+            OptimizerConfig {
+                iterations: 0,
+                ..OptimizerConfig::default()
+            },
+            OptimizerConfig {
+                learning_rate: -1.0,
+                ..OptimizerConfig::default()
+            },
+            OptimizerConfig {
+                beta2: 1.0,
+                ..OptimizerConfig::default()
+            },
+        ] {
+            assert!(bad.validate().is_err());
+        }
+    }
+
+    /// Same seed, same permutation; every index appears exactly once.
+    #[test]
+    fn shuffle_is_a_reproducible_permutation() {
+        let a = shuffled_indices(50, 7);
+        assert_eq!(a, shuffled_indices(50, 7));
+        assert_ne!(a, shuffled_indices(50, 8));
+        let mut sorted = a.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..50).collect::<Vec<_>>());
+    }
+
+    /// Every example is held out exactly once across the folds.
+    #[test]
+    fn folds_partition_the_examples() {
+        let examples: Vec<ExampleData> = (0..7)
+            .map(|i| ExampleData {
+                category_scores: HashMap::from([
+                    ("A".to_string(), make_score(1.0, 0.9)),
+                    ("B".to_string(), make_score(1.5, 0.1 * i as f32)),
+                ]),
+                correct_categories: vec!["A".to_string()],
+            })
+            .collect();
+        let refs: Vec<&ExampleData> = examples.iter().collect();
+
+        let cv = cross_validate(&refs, 3, &OptimizerConfig::default(), 0.0);
+
+        assert_eq!(cv.folds.len(), 3);
+        let held_out: usize = cv.folds.iter().map(|f| f.held_out.total).sum();
+        assert_eq!(held_out, 7);
+        for fold in &cv.folds {
+            assert_eq!(fold.train.total + fold.held_out.total, 7);
+        }
+        // "A" always wins once its kappa is raised, in and out of sample.
+        assert!((cv.mean_accuracy - 1.0).abs() < 1e-9);
+        assert!(cv.std_accuracy.abs() < 1e-9);
+    }
+
+    /// Accuracy picks the best adjusted score, with the fallback for
+    /// categories that have no weight.
+    #[test]
+    fn accuracy_uses_fallback_kappa_for_missing_categories() {
+        let ex = ExampleData {
+            category_scores: HashMap::from([
+                ("A".to_string(), make_score(1.0, 0.5)),
+                ("B".to_string(), make_score(2.0, 0.0)),
+            ]),
+            correct_categories: vec!["A".to_string()],
+        };
+        let none = Weights::default();
+        assert_eq!(accuracy(&[&ex], &none, 0.0).correct, 0);
+        assert_eq!(accuracy(&[&ex], &none, 10.0).correct, 1);
+    }
+
+    /// Without embedding data only a class prior can overturn a logit bias
+    /// that every example shares.
+    #[test]
+    fn offsets_correct_a_shared_logit_bias() {
+        let examples: Vec<ExampleData> = [(2.0, 1.0), (2.5, 2.0), (1.8, 1.5)]
+            .into_iter()
+            .map(|(b_logit, a_logit)| ExampleData {
+                category_scores: HashMap::from([
+                    ("A".to_string(), make_score(a_logit, 0.0)),
+                    ("B".to_string(), make_score(b_logit, 0.0)),
+                ]),
+                correct_categories: vec!["A".to_string()],
+            })
+            .collect();
+        let refs: Vec<&ExampleData> = examples.iter().collect();
+        for objective in [Objective::CrossEntropy, Objective::CoordinateSearch] {
+            let config = OptimizerConfig {
+                objective,
+                learn_offsets: true,
+                ..OptimizerConfig::default()
+            };
+            let weights = optimize_weights(&refs, &config).unwrap().weights;
+            assert!(weights.kappa.is_empty(), "{objective:?}");
+            assert!(weights.offsets["A"] > weights.offsets["B"], "{objective:?}");
+            assert_eq!(accuracy(&refs, &weights, 0.0).correct, 3, "{objective:?}");
+        }
+    }
+
+    /// Logits that are too sharp relative to the similarity signal are
+    /// softened by a temperature above 1.
+    #[test]
+    fn temperature_rebalances_logits_against_similarity() {
+        let ex = ExampleData {
+            category_scores: HashMap::from([
+                ("A".to_string(), make_score(1.0, 1.0)),
+                ("B".to_string(), make_score(3.0, 0.0)),
+            ]),
+            correct_categories: vec!["A".to_string()],
+        };
+        let config = OptimizerConfig {
+            learn_temperature: true,
+            ..OptimizerConfig::default()
+        };
+        let weights = optimize_weights(&[&ex], &config).unwrap().weights;
+        assert!(weights.offsets.is_empty());
+        assert!(
+            weights.temperature > 1.0,
+            "temperature {}",
+            weights.temperature
+        );
+        assert_eq!(accuracy(&[&ex], &weights, 0.0).correct, 1);
     }
 }
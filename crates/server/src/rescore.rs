@@ -0,0 +1,211 @@
+//! Offline re-scoring of recorded bulk-test runs.
+//!
+//! Each stored result keeps, for the step where the category was chosen, the
+//! best token logit and embedding margin of every category. That is enough to
+//! re-rank the categories under candidate weights without running the model
+//! again. Ranking goes through `inference::scoring`, the formula the engine
+//! applies while generating, so a re-scored choice is the one a new run with
+//! those weights would make at that step.
+
+use inference::scoring;
+use inference_types::CategoryTopToken;
+use serde::{Deserialize, Serialize};
+
+use crate::db::StoredBulkTestResult;
+use crate::metrics::{ConfusionMatrix, actual_category};
+use crate::optimize::{Accuracy, Weights};
+
+// The steps JSON as stored by bulk_test, reduced to the field scoring needs.
+#[derive(Deserialize)]
+struct SlimStep {
+    category_top_tokens: Vec<CategoryTopToken>,
+}
+
+/// A stored result that can be re-scored.
+pub struct RecordedExample {
+    pub example_id: i64,
+    pub example_text: String,
+    pub chosen_category: Option<String>,
+    pub success: bool,
+    pub correct_categories: Vec<String>,
+    /// Best token and margin per category at the first step that had any;
+    /// empty if no step did (e.g. a run recorded before they were stored).
+    pub candidates: Vec<CategoryTopToken>,
+}
+
+impl RecordedExample {
+    /// `None` if the result has no correct categories or unreadable JSON.
+    pub fn from_stored(row: StoredBulkTestResult) -> Option<Self> {
+        let correct_categories: Vec<String> =
+            serde_json::from_str(&row.correct_categories_json).ok()?;
+        if correct_categories.is_empty() {
+            return None;
+        }
+        let steps: Vec<SlimStep> = serde_json::from_str(&row.steps_json).ok()?;
+        let candidates = steps
+            .into_iter()
+            .map(|s| s.category_top_tokens)
+            .find(|c| !c.is_empty())
+            .unwrap_or_default();
+        Some(Self {
+            example_id: row.example_id,
+            example_text: row.example_text,
+            chosen_category: row.chosen_category,
+            success: row.success,
+            correct_categories,
+            candidates,
+        })
+    }
+}
+
+/// One example's choice as recorded and under the candidate weights.
+#[derive(Debug, Serialize)]
+pub struct RescoredExample {
+    pub example_id: i64,
+    pub original_category: Option<String>,
+    pub rescored_category: String,
+    pub correct_categories: Vec<String>,
+    pub was_correct: bool,
+    pub now_correct: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rescored {
+    pub examples: Vec<RescoredExample>,
+    /// Accuracy of the recorded choices on the same examples.
+    pub original_accuracy: Accuracy,
+    pub rescored_accuracy: Accuracy,
+    /// Examples that were wrong and are now right.
+    pub fixed: Vec<i64>,
+    /// Examples that were right and are now wrong.
+    pub broken: Vec<i64>,
+    /// Of the re-scored choices.
+    pub confusion: ConfusionMatrix,
+}
+
+/// Re-rank every example's categories under `weights`, with `fallback_kappa`
+/// for categories the weights leave out. Examples without candidates are
+/// left out.
+pub fn rescore(examples: &[RecordedExample], weights: &Weights, fallback_kappa: f64) -> Rescored {
+    let temperature = weights.temperature as f32;
+    let rescored: Vec<RescoredExample> = examples
+        .iter()
+        .filter_map(|ex| {
+            let best = scoring::best_category(
+                &ex.candidates,
+                |name| weights.constants(name, fallback_kappa),
+                temperature,
+            )?;
+            Some(RescoredExample {
+                example_id: ex.example_id,
+                original_category: ex.chosen_category.clone(),
+                now_correct: ex.correct_categories.contains(&best.category_name),
+                rescored_category: best.category_name.clone(),
+                correct_categories: ex.correct_categories.clone(),
+                was_correct: ex.success,
+            })
+        })
+        .collect();
+
+    let count = |correct: fn(&RescoredExample) -> bool| Accuracy {
+        correct: rescored.iter().filter(|e| correct(e)).count(),
+        total: rescored.len(),
+    };
+    let flipped = |from: bool| {
+        rescored
+            .iter()
+            .filter(|e| e.was_correct == from && e.now_correct != from)
+            .map(|e| e.example_id)
+            .collect()
+    };
+    let confusion = ConfusionMatrix::from_pairs(rescored.iter().map(|e| {
+        let predicted = e.rescored_category.as_str();
+        (actual_category(&e.correct_categories, predicted), predicted)
+    }));
+    Rescored {
+        original_accuracy: count(|e| e.was_correct),
+        rescored_accuracy: count(|e| e.now_correct),
+        fixed: flipped(false),
+        broken: flipped(true),
+        confusion,
+        examples: rescored,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use inference_types::TokenWithProb;
+
+    use super::*;
+
+    fn candidate(category: &str, logit: f32, sim_score: f32) -> CategoryTopToken {
+        CategoryTopToken {
+            category_name: category.to_string(),
+            best_token: TokenWithProb {
+                text: format!(" {category}"),
+                token_id: 0,
+                probability: 0.0,
+                logit,
+                embedding_logit: 0.0,
+            },
+            sim_score,
+        }
+    }
+
+    fn example(id: i64, chosen: &str, correct: &str, b_sim: f32) -> RecordedExample {
+        RecordedExample {
+            example_id: id,
+            example_text: String::new(),
+            chosen_category: Some(chosen.to_string()),
+            success: chosen == correct,
+            correct_categories: vec![correct.to_string()],
+            candidates: vec![candidate("A", 2.0, 0.0), candidate("B", 1.0, b_sim)],
+        }
+    }
+
+    #[test]
+    fn reports_flips_between_recorded_and_rescored_choices() {
+        // Recorded with kappa 10, so B won wherever its margin exceeded 0.1.
+        let examples = [
+            example(1, "B", "B", 0.12),
+            example(2, "A", "A", 0.0),
+            example(3, "B", "A", 0.12),
+            example(4, "B", "B", 0.3),
+        ];
+        // Kappa 20 with an offset of -2 raises B's bar to a margin of 0.15.
+        let weights = Weights {
+            kappa: HashMap::from([("B".to_string(), 20.0)]),
+            offsets: HashMap::from([("B".to_string(), -2.0)]),
+            temperature: 1.0,
+        };
+
+        let r = rescore(&examples, &weights, 10.0);
+
+        let choices: Vec<_> = r
+            .examples
+            .iter()
+            .map(|e| e.rescored_category.as_str())
+            .collect();
+        assert_eq!(choices, ["A", "A", "A", "B"]);
+        assert_eq!(
+            r.original_accuracy,
+            Accuracy {
+                correct: 3,
+                total: 4
+            }
+        );
+        assert_eq!(
+            r.rescored_accuracy,
+            Accuracy {
+                correct: 3,
+                total: 4
+            }
+        );
+        assert_eq!(r.fixed, [3]);
+        assert_eq!(r.broken, [1]);
+        assert_eq!(r.confusion.categories, ["A", "B"]);
+        assert_eq!(r.confusion.counts, [[2, 0], [1, 1]]);
+    }
+}
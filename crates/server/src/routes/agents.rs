@@ -5,14 +5,13 @@ use axum::{
 };
 use inference::GrammarFlow;
 
-use crate::db;
 use crate::state::AppState;
 
 /// GET /agents
 ///
 /// Returns the sorted list of agent IDs that have at least one valid VC message.
 pub async fn list_agents(State(state): State<AppState>) -> Result<Json<Vec<i32>>, StatusCode> {
-    let ids = db::list_agent_ids(&state.vc_db).await.map_err(|e| {
+    let ids = state.messages.agent_ids().await.map_err(|e| {
         tracing::error!(error = %e, "failed to list agent IDs");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
@@ -27,7 +26,9 @@ pub async fn get_system_prompt(
     Path(agent_id): Path<i32>,
     State(state): State<AppState>,
 ) -> Result<String, StatusCode> {
-    let vc_messages = db::load_vc_messages(&state.vc_db, agent_id)
+    let vc_messages = state
+        .messages
+        .load_vc_messages(agent_id)
         .await
         .map_err(|e| {
             tracing::error!(agent_id, error = %e, "failed to load VC messages for system prompt");
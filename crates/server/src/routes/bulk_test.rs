@@ -1,22 +1,30 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
+use std::sync::Arc;
 
 use axum::{
     Json,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::sse::{Event, KeepAlive, Sse},
 };
-use inference::{CategoryBias, GrammarFlow, InferenceEvent};
+use inference::{GenerationRequest, GrammarFlow, InferenceEngine, InferenceEvent, SamplerSettings};
 use inference_types::{BulkTestEvent, CategoryTopToken, StepCandidates, TokenWithProb};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt as _;
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::db;
-use crate::embedding;
+use crate::compare::{self, RunComparison};
+use crate::db::{self, CategoryConstants};
+use crate::experiment::{self, Variant};
+use crate::metrics::{self, RunMetrics};
+use crate::models::Model;
+use crate::rescore::RecordedExample;
+use crate::routes::{self, infer};
+use crate::run_config::{self, Environment, RunConfig};
 use crate::state::AppState;
 
 const EMBEDDING_BATCH_SIZE: usize = 20;
@@ -51,28 +59,88 @@ impl SlimStep {
 #[derive(Deserialize)]
 pub struct BulkTestRequest {
     pub agent_id: i32,
+    /// Sampling strategy for every example in the run. With a seeded random
+    /// sampler, repeated runs measure how stable category selection is under noise.
+    #[serde(default)]
+    pub sampler: Option<SamplerSettings>,
+    /// Run against this imported validation set instead of the marketing
+    /// database's HCP examples.
+    #[serde(default)]
+    pub validation_set_id: Option<i64>,
+    /// Run one bulk test per variant over the same examples, as an
+    /// experiment, instead of a single run.
+    #[serde(default)]
+    pub variants: Vec<Variant>,
 }
 
 #[derive(Serialize)]
 pub struct BulkTestResponse {
     pub bulk_test_id: String,
-    pub run_id: i64,
+    /// The run of a single bulk test.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<i64>,
+    /// The experiment of a request with variants.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment_id: Option<i64>,
 }
 
 /// POST /bulk-test
 ///
-/// Loads all HCP example messages for the agent, generates embeddings in
+/// Loads all HCP example messages for the agent (or the examples of the
+/// requested validation set), generates embeddings in
 /// batches of 20, spawns an async task that runs inference on every example
 /// in parallel, and returns a `bulk_test_id` that the client can stream via
 /// GET /bulk-test/stream/{bulk_test_id}.
+///
+/// With `variants`, runs them one after another as an experiment instead
+/// and returns its `experiment_id` (see GET /bulk-test-experiments/{id});
+/// experiments are not streamed. 400 if a variant is invalid or its model
+/// cannot be loaded, or if there are more variants or models than
+/// `prepare_variants` allows.
 pub async fn start_bulk_test(
     State(state): State<AppState>,
-    Json(body): Json<BulkTestRequest>,
+    Json(mut body): Json<BulkTestRequest>,
 ) -> Result<Json<BulkTestResponse>, StatusCode> {
+    experiment::prepare_variants(
+        &mut body.variants,
+        state.models.main_file_name(),
+        state.models.capacity(),
+    )
+    .map_err(|e| {
+        tracing::warn!(error = %e, "invalid bulk test variants");
+        StatusCode::BAD_REQUEST
+    })?;
+    let base = load_run_base(&state, &body).await?;
+    if body.variants.is_empty() {
+        let setup = prepare_run(&state, &base, &Variant::baseline()).await?;
+        return launch_bulk_test(&state, setup).await;
+    }
+    routes::experiment::launch_experiment(&state, &base, body.variants).await
+}
+
+/// What every run of a request shares, whatever its variant.
+pub(crate) struct RunBase {
+    pub(crate) agent_id: i32,
+    pub(crate) validation_set_id: Option<i64>,
+    sampler: SamplerSettings,
+    messages_with_ids: Vec<db::VcMessageWithId>,
+    pub(crate) examples: Vec<db::HcpExample>,
+    correct_categories_by_example: HashMap<i32, Vec<String>>,
+    vc_message_version: Option<i64>,
+    weight_set_id: Option<i64>,
+    category_constants: BTreeMap<String, CategoryConstants>,
+    logit_temperature: f32,
+}
+
+/// Load the agent's messages, the test examples and the stored settings a
+/// run of `body` starts from.
+async fn load_run_base(state: &AppState, body: &BulkTestRequest) -> Result<RunBase, StatusCode> {
     let agent_id = body.agent_id;
 
-    // Load VC messages with their postgres IDs so we can check success.
-    let messages_with_ids = db::load_vc_messages_with_ids(&state.vc_db, agent_id)
+    // Load VC messages with their IDs so we can check success.
+    let messages_with_ids = state
+        .messages
+        .load_messages_with_ids(agent_id)
         .await
         .map_err(|e| {
             tracing::error!(agent_id, error = %e, "failed to load VC messages with IDs");
@@ -84,63 +152,206 @@ pub async fn start_bulk_test(
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    // Build the GrammarFlow (system prompt + lark grammar) once for this agent.
-    let vc_messages: Vec<_> = messages_with_ids
-        .iter()
-        .map(|m| m.vc_message.clone())
-        .collect();
-    let grammar_flow = GrammarFlow::new(&state.brand_name, &vc_messages).map_err(|e| {
-        tracing::error!(error = %e, "failed to build GrammarFlow for bulk test");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    // Build a map: category_name → vcmessage id.
-    // The grammar output starts with "Category: {name}\n\n", so we parse the
-    // category name directly from the generated text prefix instead of doing a
-    // full-string match (which breaks on any whitespace/encoding difference).
-    let category_to_id: HashMap<String, i32> = messages_with_ids
-        .iter()
-        .map(|m| (m.vc_message.category.clone(), m.id))
-        .collect();
+    // Load the test prompts and each one's correct categories, either from a
+    // local validation set or from the marketing database.
+    let (examples, correct_categories_by_example) = match body.validation_set_id {
+        Some(set_id) => load_validation_set_examples(state, agent_id, set_id).await?,
+        None => {
+            let Some(vc_db) = &state.vc_db else {
+                tracing::error!(
+                    agent_id,
+                    "bulk tests without a validation set need VC_DATABASE_URL for the HCP examples"
+                );
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            };
+            load_marketing_examples(vc_db, agent_id, &messages_with_ids).await?
+        }
+    };
 
-    // Build a map: vcmessage id → category name (for correct_categories lookup).
-    let id_to_category: HashMap<i32, String> = messages_with_ids
-        .iter()
-        .map(|m| (m.id, m.vc_message.category.clone()))
-        .collect();
+    if examples.is_empty() {
+        tracing::warn!(agent_id, "no HCP example messages found — nothing to test");
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-    // Load HCP example messages (the test prompts).
-    let examples = db::load_hcp_example_messages(&state.vc_db, agent_id)
+    // Snapshot everything the run depends on, so it can be replayed.
+    let vc_message_version = state
+        .messages
+        .message_version(agent_id)
         .await
         .map_err(|e| {
-            tracing::error!(agent_id, error = %e, "failed to load HCP example messages");
+            tracing::error!(agent_id, error = %e, "failed to look up VC message version");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
+    let weight_set_id = db::get_active_weight_set_id(&state.db, agent_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to look up active weight set");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let stored_constants = infer::category_constants(&state.db, agent_id).await;
+    Ok(RunBase {
+        agent_id,
+        validation_set_id: body.validation_set_id,
+        sampler: body
+            .sampler
+            .clone()
+            .unwrap_or_else(|| state.engine.config().sampler.clone()),
+        category_constants: run_config::effective_constants(&stored_constants, &messages_with_ids),
+        messages_with_ids,
+        examples,
+        correct_categories_by_example,
+        vc_message_version,
+        weight_set_id,
+        logit_temperature: infer::logit_temperature(&state.db, agent_id).await,
+    })
+}
 
-    if examples.is_empty() {
-        tracing::warn!(agent_id, "no HCP example messages found — nothing to test");
-        return Err(StatusCode::BAD_REQUEST);
-    }
+/// A bulk test ready to launch, from a request or a replayed snapshot.
+pub(crate) struct RunSetup {
+    engine: Arc<InferenceEngine>,
+    pub(crate) config: RunConfig,
+    grammar_flow: GrammarFlow,
+    messages_with_ids: Vec<db::VcMessageWithId>,
+    examples: Vec<db::HcpExample>,
+    correct_categories_by_example: HashMap<i32, Vec<String>>,
+}
 
-    // Load the correct-answer map: example_id → Vec<vcmessage_id>.
-    let correct_answers = db::load_correct_answer_map(&state.vc_db, agent_id)
+/// The run of `base` with `variant`'s changes: its model, system prompt
+/// template, kappa and embeddings. Loads the variant's model the first time.
+pub(crate) async fn prepare_run(
+    state: &AppState,
+    base: &RunBase,
+    variant: &Variant,
+) -> Result<RunSetup, StatusCode> {
+    let model = match &variant.model {
+        Some(file_name) => state
+            .models
+            .get(file_name, variant.chat_template.as_deref())
+            .await
+            .map_err(|e| {
+                tracing::warn!(variant = %variant.name, error = %e, "cannot load variant model");
+                StatusCode::BAD_REQUEST
+            })?,
+        None => state.models.main().clone(),
+    };
+
+    // Build the GrammarFlow (system prompt + lark grammar) once for this run.
+    let vc_messages: Vec<_> = base
+        .messages_with_ids
+        .iter()
+        .map(|m| m.vc_message.clone())
+        .collect();
+    let grammar_flow = match &variant.system_prompt_template {
+        Some(template) => GrammarFlow::with_template(&state.brand_name, &vc_messages, template)
+            .map_err(|e| {
+                tracing::warn!(variant = %variant.name, error = %e, "invalid system prompt template");
+                StatusCode::BAD_REQUEST
+            })?,
+        None => GrammarFlow::new(&state.brand_name, &vc_messages).map_err(|e| {
+            tracing::error!(error = %e, "failed to build GrammarFlow for bulk test");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    };
+
+    let environment = Environment::of(&model, state, variant.embeddings)
         .await
         .map_err(|e| {
-            tracing::error!(agent_id, error = %e, "failed to load correct answer map");
+            tracing::error!(error = %e, "failed to read the server's run environment");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
+    let config = RunConfig {
+        agent_id: base.agent_id,
+        validation_set_id: base.validation_set_id,
+        brand_name: state.brand_name.clone(),
+        environment,
+        system_prompt_template: variant.system_prompt_template.clone(),
+        system_prompt_sha256: run_config::system_prompt_sha256(&grammar_flow),
+        lark_grammar: grammar_flow.lark_grammar.clone(),
+        vc_message_version: base.vc_message_version,
+        vc_messages: run_config::record_messages(&base.messages_with_ids),
+        sampler: base.sampler.clone(),
+        weight_set_id: base.weight_set_id,
+        category_constants: variant.constants(base.category_constants.clone()),
+        logit_temperature: base.logit_temperature,
+    };
+
+    Ok(RunSetup {
+        engine: model.engine,
+        config,
+        grammar_flow,
+        messages_with_ids: base.messages_with_ids.clone(),
+        examples: base.examples.clone(),
+        correct_categories_by_example: base.correct_categories_by_example.clone(),
+    })
+}
+
+/// Embed `setup`'s examples, record the run with its snapshot and spawn the
+/// tasks that classify every example and stream the results.
+async fn launch_bulk_test(
+    state: &AppState,
+    setup: RunSetup,
+) -> Result<Json<BulkTestResponse>, StatusCode> {
+    let embeddings = if setup.config.environment.embedding_model.is_some() {
+        embed_examples(state, &setup.examples).await
+    } else {
+        vec![]
+    };
+
+    // Create the SSE channel and register the receiver.
+    let (tx, rx) = mpsc::channel::<BulkTestEvent>(setup.examples.len() + 4);
+    let bulk_test_id = Uuid::new_v4().to_string();
+    state
+        .bulk_test_sessions
+        .lock()
+        .await
+        .insert(bulk_test_id.clone(), rx);
+
+    // Persist a run record so results survive past the SSE connection.
+    let run_id = create_run(state, &setup.config, None).await?;
+
+    // DELETE /bulk-test/{bulk_test_id} cancels this token; it stops both the
+    // producer below and every in-flight generation.
+    let cancel = CancellationToken::new();
+    state
+        .bulk_test_cancellations
+        .lock()
+        .await
+        .insert(bulk_test_id.clone(), cancel.clone());
 
-    // Generate embeddings for all examples in batches of EMBEDDING_BATCH_SIZE.
-    let example_embeddings: Vec<Vec<f32>> = match std::env::var("OPENAI_API_KEY") {
-        Err(_) => {
-            tracing::warn!("OPENAI_API_KEY not set — running bulk test without embedding biases");
-            vec![vec![]; examples.len()]
+    tokio::spawn({
+        let state = state.clone();
+        let bulk_test_id = bulk_test_id.clone();
+        async move {
+            run_examples(&state, setup, run_id, embeddings.into(), &cancel, Some(tx)).await;
+            state
+                .bulk_test_cancellations
+                .lock()
+                .await
+                .remove(&bulk_test_id);
         }
-        Ok(api_key) => {
+    });
+
+    Ok(Json(BulkTestResponse {
+        bulk_test_id,
+        run_id: Some(run_id),
+        experiment_id: None,
+    }))
+}
+
+/// Embeddings of `examples` in batches of EMBEDDING_BATCH_SIZE; texts
+/// embedded by an earlier run come from the cache. Empty without a provider.
+pub(crate) async fn embed_examples(state: &AppState, examples: &[db::HcpExample]) -> Vec<Vec<f32>> {
+    match &state.embeddings {
+        None => {
+            tracing::warn!("no embedding provider — running bulk test without embedding biases");
+            vec![]
+        }
+        Some(provider) => {
+            let before = provider.stats();
             let texts: Vec<&str> = examples.iter().map(|e| e.text.as_str()).collect();
             let mut all_embeddings = Vec::with_capacity(texts.len());
             for chunk in texts.chunks(EMBEDDING_BATCH_SIZE) {
-                match embedding::get_openai_embeddings_batch(chunk, &api_key).await {
+                match provider.embed_batch(chunk).await {
                     Ok(batch) => all_embeddings.extend(batch),
                     Err(e) => {
                         tracing::warn!(error = %e, "batch embedding failed — using empty vectors for this chunk");
@@ -148,230 +359,542 @@ pub async fn start_bulk_test(
                     }
                 }
             }
+            let after = provider.stats();
+            tracing::info!(
+                cache_hits = after.hits - before.hits,
+                cache_misses = after.misses - before.misses,
+                "bulk test examples embedded"
+            );
             all_embeddings
         }
-    };
+    }
+}
 
-    // Create the SSE channel and register the receiver.
-    let (tx, rx) = mpsc::channel::<BulkTestEvent>(examples.len() + 4);
-    let bulk_test_id = Uuid::new_v4().to_string();
-    state
-        .bulk_test_sessions
-        .lock()
-        .await
-        .insert(bulk_test_id.clone(), rx);
+/// Record a run of `config`; `experiment` is the experiment ID and variant
+/// index of a variant's run.
+pub(crate) async fn create_run(
+    state: &AppState,
+    config: &RunConfig,
+    experiment: Option<(i64, i64)>,
+) -> Result<i64, StatusCode> {
+    let config_json = serde_json::to_string(config).map_err(|e| {
+        tracing::error!(error = %e, "failed to serialise bulk test config");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    db::create_bulk_test_run(
+        &state.db,
+        config.agent_id,
+        config.validation_set_id,
+        config.weight_set_id,
+        &config_json,
+        experiment,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "failed to create bulk_test_run row");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
 
-    // Persist a run record so results survive past the SSE connection.
-    let run_id = db::create_bulk_test_run(&state.db, agent_id)
-        .await
-        .map_err(|e| {
-            tracing::error!(error = %e, "failed to create bulk_test_run row");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+/// Classify every example of `setup` into run `run_id`, sending each result
+/// to `tx` if given, and finalise the run row. `embeddings[i]` is example
+/// `i`'s embedding; runs recorded without an embedding model ignore them.
+/// Returns when the run has completed or `cancel` has stopped it.
+pub(crate) async fn run_examples(
+    state: &AppState,
+    setup: RunSetup,
+    run_id: i64,
+    embeddings: Arc<[Vec<f32>]>,
+    cancel: &CancellationToken,
+    tx: Option<mpsc::Sender<BulkTestEvent>>,
+) {
+    let RunSetup {
+        engine,
+        config,
+        grammar_flow,
+        messages_with_ids,
+        examples,
+        correct_categories_by_example,
+    } = setup;
+    let agent_id = config.agent_id;
+
+    // The grammar output starts with "Category: {name}\n\n", so we parse the
+    // category name directly from the generated text prefix instead of doing a
+    // full-string match (which breaks on any whitespace/encoding difference).
+    let category_names: Vec<String> = messages_with_ids
+        .iter()
+        .map(|m| m.vc_message.category.clone())
+        .collect();
 
-    // Spawn the async task that runs all inference jobs.
-    let engine = state.engine.clone();
-    let vc_db = state.vc_db.clone();
     let sqlite_db = state.db.clone();
+    let margins = state.margins.clone();
+    let use_embeddings = config.environment.embedding_model.is_some();
+    let logit_temperature = config.logit_temperature;
+    let sampler = Some(config.sampler);
+    let category_constants: HashMap<_, _> = config.category_constants.into_iter().collect();
     let total = examples.len();
 
-    // Run inference sequentially: one LlamaContext in memory at a time.
-    // Each iteration loads the system-prompt KV cache from disk, processes
-    // the user turn, generates, then drops the context before the next test.
-    tokio::spawn(async move {
-        for (example, embedding_vec) in examples.into_iter().zip(example_embeddings.into_iter()) {
-            // Shadow to avoid moving into closure before needed.
-            // Compute per-category biases using the pre-fetched embedding.
-            let category_biases = if embedding_vec.is_empty() {
-                vec![]
-            } else {
-                compute_category_biases_from_embedding(
-                    &vc_db,
-                    &sqlite_db,
-                    &embedding_vec,
-                    agent_id,
-                )
-                .await
-            };
+    // Compute each example's biases and queue its request; the engine
+    // decodes up to `parallel_sequences` examples at once, each in its own
+    // sequence of one context that shares the cached system prompt. Results
+    // arrive in completion order, tagged with the example's index.
+    let prompts: Vec<String> = examples.iter().map(|e| e.text.clone()).collect();
+    let (requests_tx, requests_rx) = mpsc::channel::<GenerationRequest>(8);
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            for (index, prompt) in prompts.into_iter().enumerate() {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                // Compute per-category biases using the pre-fetched embedding.
+                // Runs without example embeddings to compare against get
+                // only the logit offsets.
+                let embedding_vec = embeddings
+                    .get(index)
+                    .filter(|_| use_embeddings)
+                    .map_or(&[][..], Vec::as_slice);
+                let margins = match &margins {
+                    Some(margins) if !embedding_vec.is_empty() => {
+                        match margins.margins(embedding_vec, agent_id).await {
+                            Ok(m) => m,
+                            Err(e) => {
+                                tracing::warn!(error = ?e, "margin query failed during bulk test — skipping embedding biases");
+                                vec![]
+                            }
+                        }
+                    }
+                    _ => vec![],
+                };
+                let category_biases = infer::category_biases_from_margins(
+                    &category_constants,
+                    &messages_with_ids,
+                    &margins,
+                );
 
-            // Run inference — creates one LlamaContext, awaits completion, then drops it.
-            let mut infer_rx = engine
-                .generate(example.text.clone(), grammar_flow.clone(), category_biases)
-                .await;
+                let request = GenerationRequest {
+                    prompt,
+                    grammar_flow: grammar_flow.clone(),
+                    category_biases,
+                    logit_temperature,
+                    sampler: sampler.clone(),
+                    history: vec![],
+                    cancel: cancel.clone(),
+                };
+                if requests_tx.send(request).await.is_err() {
+                    break; // generation stopped
+                }
+            }
+        }
+    });
 
-            let mut steps = Vec::new();
-            let mut full_text: Option<String> = None;
+    let mut infer_rx = engine.generate_many(requests_rx).await;
+    let mut steps_by_example: HashMap<usize, Vec<StepCandidates>> = HashMap::new();
+    let mut completed: usize = 0;
 
-            while let Some(event) = infer_rx.recv().await {
-                match event {
-                    InferenceEvent::Token(step) => steps.push(step),
-                    InferenceEvent::Done { full_text: ft } => {
-                        full_text = Some(ft);
-                        break;
-                    }
-                    InferenceEvent::Error { message } => {
-                        tracing::warn!(
-                            example_id = example.id,
-                            error = %message,
-                            "inference error during bulk test"
-                        );
-                        break;
-                    }
-                }
+    while let Some((index, event)) = infer_rx.recv().await {
+        let example = &examples[index];
+        let full_text = match event {
+            InferenceEvent::Token(step) => {
+                steps_by_example.entry(index).or_default().push(step);
+                continue;
+            }
+            InferenceEvent::Done { full_text } => Some(full_text),
+            InferenceEvent::Error { message } => {
+                tracing::warn!(
+                    example_id = example.id,
+                    error = %message,
+                    "inference error during bulk test"
+                );
+                None
             }
+            InferenceEvent::Cancelled => {
+                steps_by_example.remove(&index);
+                continue;
+            }
+        };
+        let steps = steps_by_example.remove(&index).unwrap_or_default();
+        completed += 1;
+        let correct_categories = correct_categories_by_example
+            .get(&example.id)
+            .cloned()
+            .unwrap_or_default();
 
-            // Determine the chosen category and whether this is a success.
-            //
-            // full_output may start with:
-            //   " {name}\n\n{message}"  — when process_prompt() forces "Category: "
-            //   "Category: {name}\n\n{message}"  — when the prefix is not forced
-            // Both cases may have a leading space from SentencePiece.
-            // Normalise by trimming whitespace and stripping "Category:" if present,
-            // then use longest-match.  No "\n\n" suffix check is needed because
-            // longest-match already prevents false-positive prefix matches
-            // (e.g. "Safety" vs "Safety Information").
-            let (chosen_category, success) = match &full_text {
-                None => (None, false),
-                Some(ft) => {
-                    let ft_norm = {
-                        let s = ft.trim_start();
-                        let s = s.strip_prefix("Category:").unwrap_or(s);
-                        s.trim_start()
-                    };
-                    tracing::debug!(
+        // Determine the chosen category and whether this is a success.
+        //
+        // full_output may start with:
+        //   " {name}\n\n{message}"  — when process_prompt() forces "Category: "
+        //   "Category: {name}\n\n{message}"  — when the prefix is not forced
+        // Both cases may have a leading space from SentencePiece.
+        // Normalise by trimming whitespace and stripping "Category:" if present,
+        // then use longest-match.  No "\n\n" suffix check is needed because
+        // longest-match already prevents false-positive prefix matches
+        // (e.g. "Safety" vs "Safety Information").
+        let (chosen_category, success) = match &full_text {
+            None => (None, false),
+            Some(ft) => {
+                let ft_norm = {
+                    let s = ft.trim_start();
+                    let s = s.strip_prefix("Category:").unwrap_or(s);
+                    s.trim_start()
+                };
+                tracing::debug!(
+                    example_id = example.id,
+                    full_text_prefix = %&ft.chars().take(80).collect::<String>(),
+                    normalized_prefix = %&ft_norm.chars().take(80).collect::<String>(),
+                    "bulk test full_text prefix"
+                );
+                let cat = category_names
+                    .iter()
+                    .filter(|name| ft_norm.starts_with(name.as_str()))
+                    .max_by_key(|name| name.len())
+                    .cloned();
+                if cat.is_none() {
+                    tracing::warn!(
                         example_id = example.id,
                         full_text_prefix = %&ft.chars().take(80).collect::<String>(),
                         normalized_prefix = %&ft_norm.chars().take(80).collect::<String>(),
-                        "bulk test full_text prefix"
+                        categories = ?category_names,
+                        "no category matched full_text prefix"
                     );
-                    let cat = category_to_id
-                        .keys()
-                        .filter(|name| ft_norm.starts_with(name.as_str()))
-                        .max_by_key(|name| name.len())
-                        .cloned();
-                    if cat.is_none() {
-                        tracing::warn!(
-                            example_id = example.id,
-                            full_text_prefix = %&ft.chars().take(80).collect::<String>(),
-                            normalized_prefix = %&ft_norm.chars().take(80).collect::<String>(),
-                            categories = ?category_to_id.keys().collect::<Vec<_>>(),
-                            "no category matched full_text prefix"
-                        );
-                    }
-                    let chosen_id = cat
-                        .as_deref()
-                        .and_then(|c| category_to_id.get(c))
-                        .copied();
-                    let ok = match chosen_id {
-                        None => false,
-                        Some(id) => correct_answers
-                            .get(&example.id)
-                            .map(|ids| ids.contains(&id))
-                            .unwrap_or(false),
-                    };
-                    (cat, ok)
                 }
-            };
-
-            let correct_categories: Vec<String> = correct_answers
-                .get(&example.id)
-                .map(|ids| {
-                    ids.iter()
-                        .filter_map(|id| id_to_category.get(id))
-                        .cloned()
-                        .collect()
-                })
-                .unwrap_or_default();
-
-            // Persist to SQLite before streaming so the result is durable even
-            // if the client disconnects mid-run.
-            let correct_cats_json =
-                serde_json::to_string(&correct_categories).unwrap_or_else(|_| "[]".to_string());
-            let slim: Vec<SlimStep> = steps.iter().map(SlimStep::from_step).collect();
-            let steps_json = serde_json::to_string(&slim).unwrap_or_else(|_| "[]".to_string());
-            if let Err(e) = db::insert_bulk_test_result(
-                &sqlite_db,
-                run_id,
-                example.id,
-                &example.text,
-                chosen_category.as_deref(),
-                &correct_cats_json,
-                success,
-                &steps_json,
-            )
-            .await
-            {
-                tracing::warn!(error = %e, "failed to persist bulk_test_result");
+                let ok = cat.as_ref().is_some_and(|c| correct_categories.contains(c));
+                (cat, ok)
             }
+        };
 
-            let result = BulkTestEvent::Result {
-                example_id: example.id,
-                example_text: example.text,
-                chosen_category,
-                correct_categories,
-                success,
-                steps,
-            };
-            if tx.send(result).await.is_err() {
-                break; // client disconnected
-            }
+        // Persist to SQLite before streaming so the result is durable even
+        // if the client disconnects mid-run.
+        let correct_cats_json =
+            serde_json::to_string(&correct_categories).unwrap_or_else(|_| "[]".to_string());
+        let slim: Vec<SlimStep> = steps.iter().map(SlimStep::from_step).collect();
+        let steps_json = serde_json::to_string(&slim).unwrap_or_else(|_| "[]".to_string());
+        if let Err(e) = db::insert_bulk_test_result(
+            &sqlite_db,
+            run_id,
+            example.id,
+            &example.text,
+            chosen_category.as_deref(),
+            &correct_cats_json,
+            success,
+            &steps_json,
+        )
+        .await
+        {
+            tracing::warn!(error = %e, "failed to persist bulk_test_result");
         }
 
-        // success_count is tallied in the SSE adapter from the Result events.
+        let Some(tx) = &tx else { continue };
+        let result = BulkTestEvent::Result {
+            example_id: example.id,
+            example_text: example.text.clone(),
+            chosen_category,
+            correct_categories,
+            success,
+            steps,
+        };
+        if tx.send(result).await.is_err() {
+            // Client disconnected: stop the remaining examples too.
+            cancel.cancel();
+            break;
+        }
+    }
+
+    let success_count = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM bulk_test_results WHERE run_id = ? AND success = 1",
+        run_id,
+    )
+    .fetch_one(&sqlite_db)
+    .await
+    .unwrap_or(0);
+
+    if cancel.is_cancelled() {
+        tracing::info!(run_id, completed, total, "bulk test cancelled");
+        if let Some(tx) = &tx {
+            let _ = tx
+                .send(BulkTestEvent::Cancelled {
+                    completed,
+                    success_count: success_count as usize,
+                })
+                .await;
+        }
+        if let Err(e) =
+            db::cancel_bulk_test_run(&sqlite_db, run_id, completed as i64, success_count).await
+        {
+            tracing::warn!(error = %e, "failed to mark bulk_test_run cancelled");
+        }
+        return;
+    }
+
+    // success_count is tallied in the SSE adapter from the Result events.
+    if let Some(tx) = &tx {
         let _ = tx
             .send(BulkTestEvent::Done {
                 total,
                 success_count: 0,
             })
             .await;
+    }
 
-        // Finalise the run row with totals (best-effort).
-        let success_count = sqlx::query_scalar!(
-            "SELECT COUNT(*) FROM bulk_test_results WHERE run_id = ? AND success = 1",
-            run_id,
-        )
-        .fetch_one(&sqlite_db)
+    // Finalise the run row with totals (best-effort).
+    if let Err(e) =
+        db::complete_bulk_test_run(&sqlite_db, run_id, total as i64, success_count).await
+    {
+        tracing::warn!(error = %e, "failed to complete bulk_test_run row");
+    }
+}
+
+/// A run's stored snapshot: 404 if the run does not exist, 409 if it
+/// predates snapshots.
+async fn load_run_config(state: &AppState, run_id: i64) -> Result<RunConfig, StatusCode> {
+    let json = db::load_bulk_test_run_config(&state.db, run_id)
         .await
-        .unwrap_or(0);
-        if let Err(e) =
-            db::complete_bulk_test_run(&sqlite_db, run_id, total as i64, success_count).await
-        {
-            tracing::warn!(error = %e, "failed to complete bulk_test_run row");
-        }
-    });
+        .map_err(|e| {
+            tracing::error!(run_id, error = %e, "failed to load bulk test run config");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?
+        .ok_or_else(|| {
+            tracing::warn!(run_id, "bulk test run has no configuration snapshot");
+            StatusCode::CONFLICT
+        })?;
+    serde_json::from_str(&json).map_err(|e| {
+        tracing::error!(run_id, error = %e, "unreadable bulk test run config");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
 
-    Ok(Json(BulkTestResponse { bulk_test_id, run_id }))
+/// A snapshot replayed on this server: the model it recorded (`None` if that
+/// cannot be loaded), its rendered system prompt and grammar, and what would
+/// make the replay differ from the recorded run.
+struct Replay {
+    model: Option<Model>,
+    grammar_flow: GrammarFlow,
+    mismatches: Vec<String>,
 }
 
-/// Compute per-category embedding biases from a pre-fetched embedding vector.
-/// Mirrors `compute_category_biases` in routes/infer.rs but accepts a Vec<f32>
-/// directly instead of generating one from an API call.
-async fn compute_category_biases_from_embedding(
-    vc_db: &sqlx::PgPool,
-    sqlite_db: &sqlx::SqlitePool,
-    embedding: &[f32],
-    agent_id: i32,
-) -> Vec<CategoryBias> {
-    let margins = match db::compute_embedding_margins(vc_db, embedding, agent_id).await {
-        Ok(m) => m,
+async fn replay(state: &AppState, config: &RunConfig) -> Result<Replay, StatusCode> {
+    let grammar_flow = config.grammar_flow().map_err(|e| {
+        tracing::error!(error = %e, "failed to build GrammarFlow from run config");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let recorded = &config.environment;
+    let model = match state
+        .models
+        .recorded(&recorded.model_path, &recorded.chat_template)
+        .await
+    {
+        Ok(model) => model,
         Err(e) => {
-            tracing::warn!(error = ?e, "margin query failed during bulk test — skipping biases");
-            return vec![];
+            return Ok(Replay {
+                model: None,
+                grammar_flow,
+                mismatches: vec![format!("model: {e:#}")],
+            });
         }
     };
+    let current = Environment::of(&model, state, true).await.map_err(|e| {
+        tracing::error!(error = %e, "failed to read the server's run environment");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Replay {
+        mismatches: config.mismatches(&current, &grammar_flow),
+        model: Some(model),
+        grammar_flow,
+    })
+}
 
-    let mut biases = Vec::with_capacity(margins.len());
-    for m in &margins {
-        let kappa = db::get_or_create_kappa(sqlite_db, m.message_id)
-            .await
-            .unwrap_or(10.0);
-        biases.push(CategoryBias {
-            category_name: m.category_name.clone(),
-            weighted_margin: (kappa * m.margin) as f32,
-            sim_score: m.margin as f32,
-        });
+/// POST /bulk-tests/{run_id}/rerun
+///
+/// Starts a new bulk test with the stored configuration of `run_id`: the same
+/// VC messages, system prompt template, model, brand name, sampler, category
+/// constants and logit temperature, whatever the agent uses now. Validation-set
+/// runs replay the set; marketing-database runs re-read its current HCP
+/// examples and correct answers, matched to the recorded messages by category
+/// name. Responds like POST /bulk-test.
+///
+/// Returns 404 for an unknown run, and 409 if the run has no snapshot or the
+/// server's model, chat template, decoding limits, embedding model or margin
+/// backend, or the rendered system prompt or grammar, have changed since
+/// (see GET /bulk-tests/{run_id}/config).
+pub async fn rerun_bulk_test(
+    Path(run_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<Json<BulkTestResponse>, StatusCode> {
+    let config = load_run_config(&state, run_id).await?;
+    let Replay {
+        model,
+        grammar_flow,
+        mismatches,
+    } = replay(&state, &config).await?;
+    let Some(model) = model.filter(|_| mismatches.is_empty()) else {
+        tracing::warn!(run_id, ?mismatches, "cannot replay bulk test run exactly");
+        return Err(StatusCode::CONFLICT);
+    };
+
+    let agent_id = config.agent_id;
+    let messages_with_ids = config.messages_with_ids();
+    let (examples, correct_categories_by_example) = match config.validation_set_id {
+        Some(set_id) => load_validation_set_examples(&state, agent_id, set_id).await?,
+        None => {
+            let Some(vc_db) = &state.vc_db else {
+                tracing::error!(
+                    agent_id,
+                    "replaying a run without a validation set needs VC_DATABASE_URL for the HCP examples"
+                );
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            };
+            // Correct answers name the agent's current message IDs, which
+            // change when its messages are re-published; the recorded ones
+            // would match none of them.
+            let current_messages = state
+                .messages
+                .load_messages_with_ids(agent_id)
+                .await
+                .map_err(|e| {
+                    tracing::error!(agent_id, error = %e, "failed to load VC messages with IDs");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            load_marketing_examples(vc_db, agent_id, &current_messages).await?
+        }
+    };
+    if examples.is_empty() {
+        tracing::warn!(run_id, agent_id, "no examples left to replay");
+        return Err(StatusCode::BAD_REQUEST);
     }
-    biases
+
+    tracing::info!(run_id, agent_id, "replaying bulk test run");
+    launch_bulk_test(
+        &state,
+        RunSetup {
+            engine: model.engine,
+            config,
+            grammar_flow,
+            messages_with_ids,
+            examples,
+            correct_categories_by_example,
+        },
+    )
+    .await
+}
+
+/// Test prompts plus each example's correct category names, keyed by example ID.
+type LabelledExamples = (Vec<db::HcpExample>, HashMap<i32, Vec<String>>);
+
+/// HCP examples and correct answers from the marketing database. Correct
+/// answers are stored as message IDs and mapped to category names through
+/// `messages_with_ids`, which must be the agent's current messages.
+async fn load_marketing_examples(
+    vc_db: &sqlx::PgPool,
+    agent_id: i32,
+    messages_with_ids: &[db::VcMessageWithId],
+) -> Result<LabelledExamples, StatusCode> {
+    let examples = db::load_hcp_example_messages(vc_db, agent_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to load HCP example messages");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Load the correct-answer map: example_id → Vec<vcmessage_id>.
+    let correct_answers = db::load_correct_answer_map(vc_db, agent_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to load correct answer map");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let id_to_category: HashMap<i32, &str> = messages_with_ids
+        .iter()
+        .map(|m| (m.id, m.vc_message.category.as_str()))
+        .collect();
+    let correct_categories = correct_answers
+        .into_iter()
+        .map(|(example_id, ids)| {
+            let categories = ids
+                .iter()
+                .filter_map(|id| id_to_category.get(id))
+                .map(|c| c.to_string())
+                .collect();
+            (example_id, categories)
+        })
+        .collect();
+
+    Ok((examples, correct_categories))
+}
+
+/// Examples of an imported validation set. 404 if the set does not exist,
+/// 400 if it belongs to another agent.
+async fn load_validation_set_examples(
+    state: &AppState,
+    agent_id: i32,
+    set_id: i64,
+) -> Result<LabelledExamples, StatusCode> {
+    let owner = db::get_validation_set_agent_id(&state.db, set_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(set_id, error = %e, "failed to look up validation set");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if owner != agent_id as i64 {
+        tracing::warn!(
+            set_id,
+            agent_id,
+            owner,
+            "validation set belongs to another agent"
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let rows = db::load_validation_examples(&state.db, set_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(set_id, error = %e, "failed to load validation examples");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut examples = Vec::with_capacity(rows.len());
+    let mut correct_categories = HashMap::with_capacity(rows.len());
+    for row in rows {
+        // Results store example IDs as the `i32` Postgres uses.
+        let id = i32::try_from(row.id).map_err(|_| {
+            tracing::error!(
+                set_id,
+                example_id = row.id,
+                "validation example ID out of range"
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let categories: Vec<String> =
+            serde_json::from_str(&row.correct_categories_json).unwrap_or_default();
+        correct_categories.insert(id, categories);
+        examples.push(db::HcpExample { id, text: row.text });
+    }
+    Ok((examples, correct_categories))
+}
+
+/// DELETE /bulk-test/:bulk_test_id
+///
+/// Cancels a running bulk test. Examples already decoded keep their results;
+/// the stream ends with a `cancelled` event and the run row is marked
+/// `cancelled`. 404 if the run is unknown or has already finished.
+pub async fn cancel_bulk_test(
+    Path(bulk_test_id): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let Some(cancel) = state
+        .bulk_test_cancellations
+        .lock()
+        .await
+        .remove(&bulk_test_id)
+    else {
+        return StatusCode::NOT_FOUND;
+    };
+    cancel.cancel();
+    // Drop the receiver if no client has connected to the stream yet.
+    state.bulk_test_sessions.lock().await.remove(&bulk_test_id);
+    StatusCode::NO_CONTENT
 }
 
 /// GET /bulk-test/stream/:bulk_test_id
@@ -410,6 +933,9 @@ pub async fn stream_bulk_test_sse(
             BulkTestEvent::Error { message } => {
                 tracing::error!(error = %message, "bulk test error event");
             }
+            BulkTestEvent::Cancelled { completed, .. } => {
+                tracing::info!(completed, success_count, "bulk test cancelled");
+            }
         }
 
         // Patch the Done event with the real accumulated success count.
@@ -474,7 +1000,7 @@ pub async fn get_bulk_test(
                 .map(SlimStep::into_step)
                 .collect();
             Some(StoredTestResult {
-                example_id: r.example_id as i32,
+                example_id: i32::try_from(r.example_id).ok()?,
                 example_text: r.example_text,
                 chosen_category: r.chosen_category,
                 correct_categories,
@@ -486,3 +1012,128 @@ pub async fn get_bulk_test(
 
     Ok(Json(results))
 }
+
+/// The run's results that can be analysed, and how many were not; 404 for
+/// a run with no results.
+async fn load_recorded_examples(
+    state: &AppState,
+    run_id: i64,
+) -> Result<(Vec<RecordedExample>, usize), StatusCode> {
+    let rows = db::load_bulk_test_results(&state.db, run_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(run_id, error = %e, "failed to load bulk test results");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if rows.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let total = rows.len();
+    let examples: Vec<RecordedExample> = rows
+        .into_iter()
+        .filter_map(RecordedExample::from_stored)
+        .collect();
+    let skipped = total - examples.len();
+    Ok((examples, skipped))
+}
+
+/// Response body for GET /bulk-tests/{run_id}/metrics
+#[derive(Serialize)]
+pub struct BulkTestMetrics {
+    #[serde(flatten)]
+    pub metrics: RunMetrics,
+    /// Results without correct categories or with unreadable JSON.
+    pub examples_skipped: usize,
+}
+
+/// GET /bulk-tests/{run_id}/metrics
+///
+/// Confusion matrix, per-category precision / recall / F1, top-k accuracy
+/// and the most-confused category pairs of the run's recorded choices.
+/// Returns 404 for a run with no results.
+pub async fn get_bulk_test_metrics(
+    Path(run_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<Json<BulkTestMetrics>, StatusCode> {
+    let (examples, examples_skipped) = load_recorded_examples(&state, run_id).await?;
+    Ok(Json(BulkTestMetrics {
+        metrics: metrics::run_metrics(&examples),
+        examples_skipped,
+    }))
+}
+
+/// Response body for GET /bulk-tests/{run_id}/config
+#[derive(Serialize)]
+pub struct BulkTestConfig {
+    #[serde(flatten)]
+    pub config: RunConfig,
+    /// Why a re-run would not reproduce the run; empty if it would.
+    pub mismatches: Vec<String>,
+}
+
+/// GET /bulk-tests/{run_id}/config
+///
+/// The configuration snapshot the run was started with, and what has
+/// changed on the server since. Returns 404 for an unknown run, 409 for a
+/// run that predates snapshots.
+pub async fn get_bulk_test_config(
+    Path(run_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<Json<BulkTestConfig>, StatusCode> {
+    let config = load_run_config(&state, run_id).await?;
+    let mismatches = replay(&state, &config).await?.mismatches;
+    Ok(Json(BulkTestConfig { config, mismatches }))
+}
+
+#[derive(Deserialize)]
+pub struct CompareQuery {
+    pub a: i64,
+    pub b: i64,
+}
+
+/// GET /bulk-tests/compare?a={run_id}&b={run_id}
+///
+/// Compares run `b` against run `a` on the examples both contain:
+/// regressions, fixes, McNemar's test on the accuracy difference and
+/// per-category accuracy. Returns 404 if either run has no results, 400 if
+/// the runs' examples come from different agents or validation sets (their
+/// example IDs would not refer to the same examples).
+pub async fn compare_bulk_tests(
+    Query(query): Query<CompareQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<RunComparison>, StatusCode> {
+    let mut sources = Vec::with_capacity(2);
+    for run_id in [query.a, query.b] {
+        let source = db::get_bulk_test_run_examples_source(&state.db, run_id)
+            .await
+            .map_err(|e| {
+                tracing::error!(run_id, error = %e, "failed to look up bulk test run");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        sources.push(source);
+    }
+    if sources[0] != sources[1] {
+        tracing::warn!(
+            a = query.a,
+            b = query.b,
+            ?sources,
+            "compared runs have different examples"
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let (a, _) = load_recorded_examples(&state, query.a).await?;
+    let (b, _) = load_recorded_examples(&state, query.b).await?;
+    let comparison = compare::compare_runs(&a, &b);
+    tracing::info!(
+        a = query.a,
+        b = query.b,
+        shared = comparison.shared,
+        regressions = comparison.regressions.len(),
+        fixes = comparison.fixes.len(),
+        p_value = comparison.mcnemar.p_value,
+        "compared bulk test runs"
+    );
+    Ok(Json(comparison))
+}
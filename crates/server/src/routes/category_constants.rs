@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::Serialize;
+
+use crate::db::{self, CategoryConstants, VcMessageWithId};
+use crate::state::AppState;
+
+/// Constants for each category of `messages` (one agent's messages, every
+/// version) from the legacy per-message rows. A category takes the row of
+/// its newest message — the highest id — that has one.
+fn carry_over(
+    legacy: &HashMap<i64, CategoryConstants>,
+    messages: &[VcMessageWithId],
+) -> BTreeMap<String, CategoryConstants> {
+    let mut newest: BTreeMap<String, (i64, CategoryConstants)> = BTreeMap::new();
+    for msg in messages {
+        let id = msg.id as i64;
+        let Some(&constants) = legacy.get(&id) else {
+            continue;
+        };
+        let entry = newest
+            .entry(msg.vc_message.category.clone())
+            .or_insert((id, constants));
+        if id > entry.0 {
+            *entry = (id, constants);
+        }
+    }
+    newest
+        .into_iter()
+        .map(|(category, (_, constants))| (category, constants))
+        .collect()
+}
+
+/// Carry the per-message kappa and offset rows in `vc_message_constants`
+/// over to per-category constants, then empty that table. Categories that
+/// already have constants keep them. If any agent's messages cannot be
+/// loaded the table is kept, so the next start retries.
+pub(crate) async fn carry_over_message_constants(state: &AppState) -> anyhow::Result<()> {
+    let legacy = db::load_message_constants(&state.db).await?;
+    if legacy.is_empty() {
+        return Ok(());
+    }
+    let mut complete = true;
+    for agent_id in state.messages.agent_ids().await? {
+        let messages = match state.messages.load_messages_with_ids(agent_id).await {
+            Ok(m) => m,
+            Err(e) => {
+                tracing::warn!(agent_id, error = %e, "cannot carry over message constants");
+                complete = false;
+                continue;
+            }
+        };
+        let carried: Vec<(String, CategoryConstants)> =
+            carry_over(&legacy, &messages).into_iter().collect();
+        let inserted = db::insert_missing_category_constants(&state.db, agent_id, &carried).await?;
+        let stored = db::load_category_constants(&state.db, agent_id).await?;
+        let report = report(&messages, &stored);
+        tracing::info!(
+            agent_id,
+            carried_over = inserted,
+            defaulted = ?report.defaulted,
+            "carried per-message constants over to categories"
+        );
+    }
+    if complete {
+        let cleared = db::clear_message_constants(&state.db).await?;
+        tracing::info!(rows = cleared, "cleared per-message constants");
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StoredCategory {
+    pub category_name: String,
+    #[serde(flatten)]
+    pub constants: CategoryConstants,
+}
+
+/// Which of an agent's categories have stored constants.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CategoryConstantsReport {
+    /// Categories with stored constants, carried over from earlier message
+    /// versions or applied since.
+    pub carried_over: Vec<StoredCategory>,
+    /// Categories without stored constants, running on the defaults.
+    pub defaulted: Vec<String>,
+    /// Stored constants for categories the agent no longer has.
+    pub orphaned: Vec<String>,
+}
+
+fn report(
+    messages: &[VcMessageWithId],
+    stored: &HashMap<String, CategoryConstants>,
+) -> CategoryConstantsReport {
+    let categories: BTreeSet<&String> = messages.iter().map(|m| &m.vc_message.category).collect();
+    let mut carried_over = vec![];
+    let mut defaulted = vec![];
+    for &category in &categories {
+        match stored.get(category) {
+            Some(&constants) => carried_over.push(StoredCategory {
+                category_name: category.clone(),
+                constants,
+            }),
+            None => defaulted.push(category.clone()),
+        }
+    }
+    let mut orphaned: Vec<String> = stored
+        .keys()
+        .filter(|c| !categories.contains(c))
+        .cloned()
+        .collect();
+    orphaned.sort();
+    CategoryConstantsReport {
+        carried_over,
+        defaulted,
+        orphaned,
+    }
+}
+
+/// GET /agents/:agent_id/category-constants
+///
+/// Reports which of the agent's current categories have stored kappa and
+/// offset values and which run on the defaults (kappa 10, offset 0).
+pub async fn category_constants_report(
+    Path(agent_id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Json<CategoryConstantsReport>, StatusCode> {
+    let messages = state
+        .messages
+        .load_messages_with_ids(agent_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to load VC messages for constants report");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let stored = db::load_category_constants(&state.db, agent_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to load category constants");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(report(&messages, &stored)))
+}
+
+#[cfg(test)]
+mod tests {
+    use inference::VCmessage;
+
+    use super::*;
+
+    fn message(id: i32, category: &str) -> VcMessageWithId {
+        VcMessageWithId {
+            id,
+            vc_message: VCmessage {
+                category: category.to_string(),
+                kind: String::new(),
+                description: String::new(),
+                message: format!("message {id}"),
+                mlr_message: format!("message {id}"),
+            },
+        }
+    }
+
+    fn constants(kappa: f64) -> CategoryConstants {
+        CategoryConstants {
+            kappa,
+            logit_offset: 0.0,
+        }
+    }
+
+    /// Versions 1 and 2 of "Safety" both had rows; version 2's wins.
+    /// "Dosing" only had a row on its old message.
+    #[test]
+    fn newest_message_row_is_carried_over() {
+        let messages = [
+            message(1, "Safety"),
+            message(2, "Dosing"),
+            message(11, "Safety"),
+            message(12, "Dosing"),
+            message(13, "Efficacy"),
+        ];
+        let legacy = HashMap::from([
+            (1, constants(4.0)),
+            (11, constants(7.0)),
+            (2, constants(3.0)),
+        ]);
+
+        let carried = carry_over(&legacy, &messages);
+
+        assert_eq!(
+            carried,
+            BTreeMap::from([
+                ("Dosing".to_string(), constants(3.0)),
+                ("Safety".to_string(), constants(7.0)),
+            ])
+        );
+    }
+
+    #[test]
+    fn report_splits_stored_defaulted_and_orphaned() {
+        let messages = [message(1, "Safety"), message(2, "Dosing")];
+        let stored = HashMap::from([
+            ("Safety".to_string(), constants(7.0)),
+            ("Retired".to_string(), constants(2.0)),
+        ]);
+
+        let report = report(&messages, &stored);
+
+        assert_eq!(
+            report.carried_over,
+            [StoredCategory {
+                category_name: "Safety".to_string(),
+                constants: constants(7.0),
+            }]
+        );
+        assert_eq!(report.defaulted, ["Dosing"]);
+        assert_eq!(report.orphaned, ["Retired"]);
+    }
+}
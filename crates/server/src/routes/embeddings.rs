@@ -0,0 +1,16 @@
+use axum::{Json, extract::State, http::StatusCode};
+
+use crate::embedding::CacheStats;
+use crate::state::AppState;
+
+/// GET /embeddings/cache-stats
+///
+/// Embedding cache hits and misses since start-up. 404 when no embedding
+/// provider is configured.
+pub async fn cache_stats(State(state): State<AppState>) -> Result<Json<CacheStats>, StatusCode> {
+    state
+        .embeddings
+        .as_ref()
+        .map(|cache| Json(cache.stats()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
@@ -0,0 +1,199 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::db;
+use crate::experiment::{self, Variant, VariantReport, VariantRun};
+use crate::rescore::RecordedExample;
+use crate::routes::bulk_test::{self, BulkTestResponse, RunBase};
+use crate::state::AppState;
+
+/// Prepare a run of `base` for every variant, then run them one after
+/// another in the background as one experiment. Examples are embedded once
+/// for all variants. The experiment is cancelled like a single bulk test,
+/// through DELETE /bulk-test/{bulk_test_id}.
+pub(crate) async fn launch_experiment(
+    state: &AppState,
+    base: &RunBase,
+    variants: Vec<Variant>,
+) -> Result<Json<BulkTestResponse>, StatusCode> {
+    // Load every variant's model and render its prompt before anything runs,
+    // so a bad variant fails the request rather than the experiment.
+    let mut setups = Vec::with_capacity(variants.len());
+    for variant in &variants {
+        setups.push(bulk_test::prepare_run(state, base, variant).await?);
+    }
+    let embeddings: Arc<[Vec<f32>]> = if setups
+        .iter()
+        .any(|s| s.config.environment.embedding_model.is_some())
+    {
+        bulk_test::embed_examples(state, &base.examples)
+            .await
+            .into()
+    } else {
+        Arc::new([])
+    };
+
+    let variants_json = serde_json::to_string(&variants).map_err(|e| {
+        tracing::error!(error = %e, "failed to serialise experiment variants");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let experiment_id = db::create_bulk_test_experiment(
+        &state.db,
+        base.agent_id,
+        base.validation_set_id,
+        &variants_json,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "failed to create bulk_test_experiment row");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let bulk_test_id = Uuid::new_v4().to_string();
+    let cancel = CancellationToken::new();
+    state
+        .bulk_test_cancellations
+        .lock()
+        .await
+        .insert(bulk_test_id.clone(), cancel.clone());
+
+    tracing::info!(
+        experiment_id,
+        agent_id = base.agent_id,
+        variants = variants.len(),
+        "bulk test experiment started"
+    );
+    tokio::spawn({
+        let state = state.clone();
+        let bulk_test_id = bulk_test_id.clone();
+        async move {
+            let mut status = "complete";
+            for (index, setup) in setups.into_iter().enumerate() {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let experiment = Some((experiment_id, index as i64));
+                let Ok(run_id) = bulk_test::create_run(&state, &setup.config, experiment).await
+                else {
+                    status = "failed";
+                    break;
+                };
+                bulk_test::run_examples(
+                    &state,
+                    setup,
+                    run_id,
+                    Arc::clone(&embeddings),
+                    &cancel,
+                    None,
+                )
+                .await;
+            }
+            state
+                .bulk_test_cancellations
+                .lock()
+                .await
+                .remove(&bulk_test_id);
+
+            if cancel.is_cancelled() {
+                status = "cancelled";
+            }
+            tracing::info!(experiment_id, status, "bulk test experiment finished");
+            if let Err(e) = db::finish_bulk_test_experiment(&state.db, experiment_id, status).await
+            {
+                tracing::warn!(error = %e, "failed to finish bulk_test_experiment row");
+            }
+        }
+    });
+
+    Ok(Json(BulkTestResponse {
+        bulk_test_id,
+        run_id: None,
+        experiment_id: Some(experiment_id),
+    }))
+}
+
+/// Response body for GET /bulk-test-experiments/{experiment_id}
+#[derive(Serialize)]
+pub struct ExperimentReport {
+    pub id: i64,
+    pub agent_id: i64,
+    pub validation_set_id: Option<i64>,
+    /// running | complete | cancelled | failed
+    pub status: String,
+    pub started_at: String,
+    pub completed_at: Option<String>,
+    /// In run order; each one is compared with the first.
+    pub variants: Vec<VariantReport>,
+}
+
+/// GET /bulk-test-experiments/{experiment_id}
+///
+/// Accuracy of every variant of an experiment so far, and how each one
+/// differs from the first on the examples both have run. 404 if the
+/// experiment does not exist.
+pub async fn get_experiment(
+    Path(experiment_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<Json<ExperimentReport>, StatusCode> {
+    let row = db::get_bulk_test_experiment(&state.db, experiment_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(experiment_id, error = %e, "failed to load bulk test experiment");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let variants: Vec<Variant> = serde_json::from_str(&row.variants_json).map_err(|e| {
+        tracing::error!(experiment_id, error = %e, "unreadable experiment variants");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let run_rows = db::list_experiment_runs(&state.db, experiment_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(experiment_id, error = %e, "failed to list experiment runs");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let mut runs: Vec<Option<VariantRun>> = std::iter::repeat_with(|| None)
+        .take(variants.len())
+        .collect();
+    for run in run_rows {
+        let Some(slot) = usize::try_from(run.variant_index)
+            .ok()
+            .and_then(|i| runs.get_mut(i))
+        else {
+            continue;
+        };
+        let results = db::load_bulk_test_results(&state.db, run.run_id)
+            .await
+            .map_err(|e| {
+                tracing::error!(run_id = run.run_id, error = %e, "failed to load bulk test results");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        *slot = Some(VariantRun {
+            run_id: run.run_id,
+            status: run.status,
+            examples: results
+                .into_iter()
+                .filter_map(RecordedExample::from_stored)
+                .collect(),
+        });
+    }
+
+    Ok(Json(ExperimentReport {
+        id: row.id,
+        agent_id: row.agent_id,
+        validation_set_id: row.validation_set_id,
+        status: row.status,
+        started_at: row.started_at,
+        completed_at: row.completed_at,
+        variants: experiment::variant_reports(variants, &runs),
+    }))
+}
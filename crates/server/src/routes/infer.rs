@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
 
 use axum::{
@@ -6,24 +7,38 @@ use axum::{
     http::StatusCode,
     response::sse::{Event, KeepAlive, Sse},
 };
-use inference::{CategoryBias, GrammarFlow, InferenceEvent};
+use inference::{
+    CategoryBias, ConversationTurn, GenerationRequest, GrammarFlow, InferenceEvent,
+    MAX_BEAM_WIDTH, SamplerSettings,
+};
+use inference_types::{BeamSearchResult, RankedResponse};
 use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt as _;
+use tokio_util::sync::CancellationToken;
 
-use crate::db;
-use crate::embedding;
+use crate::db::{self, CategoryConstants, MessageMargin, VcMessageWithId};
 use crate::state::AppState;
 
 #[derive(Deserialize)]
 pub struct InferRequest {
     pub prompt: String,
     pub agent_id: i32,
+    /// Overrides the server's default sampling strategy for this request.
+    #[serde(default)]
+    pub sampler: Option<SamplerSettings>,
+    /// Continue this conversation; its completed turns are replayed before the
+    /// prompt. Omit to start a new conversation.
+    #[serde(default)]
+    pub conversation_id: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct InferResponse {
     pub session_id: String,
+    /// Pass back as `conversation_id` to ask a follow-up question.
+    pub conversation_id: String,
 }
 
 /// POST /infer
@@ -32,99 +47,302 @@ pub struct InferResponse {
 /// renders the Askama system-prompt and lark-grammar templates), computes
 /// per-category embedding logit biases from the user's message similarity,
 /// creates an inference session in SQLite, kicks off generation, and returns
-/// the public `session_id`.
+/// the public `session_id`. The session is recorded as the next turn of the
+/// requested conversation (or of a new one), and earlier completed turns are
+/// replayed so follow-up questions are classified in context.
 pub async fn start_infer(
     State(state): State<AppState>,
     Json(body): Json<InferRequest>,
 ) -> Result<Json<InferResponse>, StatusCode> {
-    // Load the latest VC messages for the chosen agent from marketing Postgres
-    let vc_messages = db::load_vc_messages(&state.vc_db, body.agent_id)
+    let mut request =
+        prepare_generation(&state, body.prompt.clone(), body.agent_id, body.sampler).await?;
+
+    // Resolve (or start) the conversation and load its earlier turns
+    let (conversation_id, conversation_public_id) = match body.conversation_id {
+        Some(public_id) => {
+            let id = db::find_conversation(&state.db, &public_id)
+                .await
+                .map_err(|e| {
+                    tracing::error!(error = %e, "failed to look up conversation");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            (id, public_id)
+        }
+        None => db::create_conversation(&state.db).await.map_err(|e| {
+            tracing::error!(error = %e, "failed to create conversation");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    };
+    request.history = db::load_conversation_turns(&state.db, conversation_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "failed to load conversation turns");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .map(|(user, assistant)| ConversationTurn { user, assistant })
+        .collect();
+
+    // Persist session to SQLite
+    let session_id = db::create_session(&state.db, &body.prompt, Some(conversation_id))
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "failed to create session");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Start generation — non-blocking
+    state
+        .infer_cancellations
+        .lock()
+        .await
+        .insert(session_id.clone(), request.cancel.clone());
+    let rx = state.engine.generate(request).await;
+
+    // Store receiver so the SSE handler can pick it up
+    state.sessions.lock().await.insert(session_id.clone(), rx);
+
+    Ok(Json(InferResponse {
+        session_id,
+        conversation_id: conversation_public_id,
+    }))
+}
+
+/// Build the engine request shared by every inference endpoint: loads the
+/// latest VC messages for the agent from its message source, renders the
+/// system prompt and lark grammar via Askama templates, and computes
+/// embedding-based logit biases for the prompt.
+pub(crate) async fn prepare_generation(
+    state: &AppState,
+    prompt: String,
+    agent_id: i32,
+    sampler: Option<SamplerSettings>,
+) -> Result<GenerationRequest, StatusCode> {
+    let messages = state
+        .messages
+        .load_messages_with_ids(agent_id)
         .await
         .map_err(|e| {
-            tracing::error!(agent_id = body.agent_id, error = %e, "failed to load VC messages");
+            tracing::error!(agent_id, error = %e, "failed to load VC messages");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
+    if messages.is_empty() {
+        tracing::error!(agent_id, "no valid VC messages found for agent");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let vc_messages: Vec<_> = messages.iter().map(|m| m.vc_message.clone()).collect();
 
-    // Render the system prompt and lark grammar via Askama templates
     let grammar_flow = GrammarFlow::new(&state.brand_name, &vc_messages).map_err(|e| {
         tracing::error!(error = %e, "failed to build GrammarFlow");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Compute embedding-based logit biases for the user's prompt.
-    let category_biases = compute_category_biases(&state, &body.prompt, body.agent_id).await;
+    let category_biases = compute_category_biases(state, &prompt, agent_id, &messages).await;
+    let logit_temperature = logit_temperature(&state.db, agent_id).await;
 
-    // Persist session to SQLite
-    let session_id = db::create_session(&state.db, &body.prompt)
+    Ok(GenerationRequest {
+        prompt,
+        grammar_flow,
+        category_biases,
+        logit_temperature,
+        sampler,
+        history: vec![],
+        cancel: CancellationToken::new(),
+    })
+}
+
+/// DELETE /infer/:session_id
+///
+/// Cancels a running generation. The engine stops at its next critical point
+/// and the stream ends with a `cancelled` event; the session row is marked
+/// `cancelled`. 404 if the session is unknown or has already finished.
+pub async fn cancel_infer(
+    Path(session_id): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    let Some(cancel) = state.infer_cancellations.lock().await.remove(&session_id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    cancel.cancel();
+    // Drop the receiver if no client has connected to the stream yet.
+    state.sessions.lock().await.remove(&session_id);
+
+    match db::cancel_session(&state.db, &session_id).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to mark session cancelled");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+fn default_beam_width() -> usize {
+    4
+}
+
+#[derive(Deserialize)]
+pub struct BeamSearchRequest {
+    pub prompt: String,
+    pub agent_id: i32,
+    /// Number of partial paths kept at each critical point, from 1 to
+    /// `MAX_BEAM_WIDTH`.
+    #[serde(default = "default_beam_width")]
+    pub beam_width: usize,
+}
+
+/// POST /infer/beam
+///
+/// Same setup as POST /infer, but scores whole responses with a constrained
+/// beam search and returns the surviving paths (best first) in one response
+/// instead of streaming a greedy generation. Returns 422 if `beam_width` is
+/// 0 or above `MAX_BEAM_WIDTH`.
+pub async fn beam_search(
+    State(state): State<AppState>,
+    Json(body): Json<BeamSearchRequest>,
+) -> Result<Json<BeamSearchResult>, StatusCode> {
+    if !(1..=MAX_BEAM_WIDTH).contains(&body.beam_width) {
+        tracing::warn!(beam_width = body.beam_width, "beam width out of range");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let request = prepare_generation(&state, body.prompt, body.agent_id, None).await?;
+
+    let result = state
+        .engine
+        .beam_search(request, body.beam_width)
         .await
         .map_err(|e| {
-            tracing::error!(error = %e, "failed to create session");
+            tracing::error!(error = %e, "beam search failed");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    // Start generation — non-blocking
-    let rx = state.engine.generate(body.prompt, grammar_flow, category_biases).await;
+    Ok(Json(result))
+}
 
-    // Store receiver so the SSE handler can pick it up
-    state.sessions.lock().await.insert(session_id.clone(), rx);
+#[derive(Deserialize)]
+pub struct RankRequest {
+    pub prompt: String,
+    pub agent_id: i32,
+}
+
+/// POST /infer/rank
+///
+/// Scores every approved response for the agent as a complete continuation of
+/// the prompt and returns all of them, best first, with total, length-normalized
+/// and embedding-adjusted log-probabilities.
+pub async fn rank_responses(
+    State(state): State<AppState>,
+    Json(body): Json<RankRequest>,
+) -> Result<Json<Vec<RankedResponse>>, StatusCode> {
+    let request = prepare_generation(&state, body.prompt, body.agent_id, None).await?;
+
+    let ranked = state.engine.rank_responses(request).await.map_err(|e| {
+        tracing::error!(error = %e, "response ranking failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
-    Ok(Json(InferResponse { session_id }))
+    Ok(Json(ranked))
 }
 
 /// Compute per-category logit biases for the given prompt.
 ///
 /// Steps:
-/// 1. Get OpenAI embedding of the prompt.
-/// 2. Query Postgres for per-category margin scores.
-/// 3. Fetch (or create with default 10.0) kappa for each message from SQLite.
-/// 4. Return Vec<CategoryBias> with weighted_margin = kappa * margin.
+/// 1. Embed the prompt with the configured provider.
+/// 2. Compute per-category margin scores (pgvector query or in-memory index).
+/// 3. Combine them with each category's stored kappa and logit offset
+///    (`category_biases_from_margins`).
 ///
-/// On any failure the function logs a warning and returns an empty vec so that
-/// generation proceeds normally without embedding guidance.
-async fn compute_category_biases(
+/// If embedding or the margin query fails (or is not configured) the function
+/// logs a warning and every category gets only its logit offset, so
+/// generation proceeds without embedding guidance.
+pub(crate) async fn compute_category_biases(
     state: &AppState,
     prompt: &str,
     agent_id: i32,
+    messages: &[VcMessageWithId],
 ) -> Vec<CategoryBias> {
-    let api_key = match std::env::var("OPENAI_API_KEY") {
-        Ok(k) => k,
-        Err(_) => {
-            tracing::warn!("OPENAI_API_KEY not set — skipping embedding logit biases");
-            return vec![];
-        }
+    let margins = match (&state.embeddings, &state.margins) {
+        (Some(provider), Some(margins)) => match provider.embed(prompt).await {
+            Ok(embedding) => match margins.margins(&embedding, agent_id).await {
+                Ok(m) => m,
+                Err(e) => {
+                    tracing::warn!(error = ?e, "margin query failed — skipping embedding biases");
+                    vec![]
+                }
+            },
+            Err(e) => {
+                tracing::warn!(error = %e, "prompt embedding failed — skipping embedding biases");
+                vec![]
+            }
+        },
+        _ => vec![],
     };
 
-    let embedding = match embedding::get_openai_embedding(prompt, &api_key).await {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::warn!(error = %e, "OpenAI embedding failed — skipping logit biases");
-            return vec![];
-        }
-    };
+    let constants = category_constants(&state.db, agent_id).await;
+    category_biases_from_margins(&constants, messages, &margins)
+}
 
-    let margins = match db::compute_embedding_margins(&state.vc_db, &embedding, agent_id).await {
-        Ok(m) => m,
-        Err(e) => {
-            tracing::warn!(error = ?e, "margin query failed — skipping logit biases");
-            return vec![];
-        }
-    };
+/// The agent's stored constants by category name, or none (so every
+/// category uses the defaults) if they cannot be read.
+pub(crate) async fn category_constants(
+    db: &SqlitePool,
+    agent_id: i32,
+) -> HashMap<String, CategoryConstants> {
+    db::load_category_constants(db, agent_id)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!(agent_id, error = %e, "failed to load category constants — using defaults");
+            Default::default()
+        })
+}
 
-    let mut biases = Vec::with_capacity(margins.len());
-    for m in &margins {
-        let kappa = db::get_or_create_kappa(&state.db, m.message_id)
-            .await
-            .unwrap_or(10.0);
+/// One bias per category of `messages`, in grammar order: `kappa * margin`
+/// from the category's best-scoring message (0 without a margin) plus the
+/// category's logit offset. Categories missing from `stored` use the defaults.
+pub(crate) fn category_biases_from_margins(
+    stored: &HashMap<String, CategoryConstants>,
+    messages: &[VcMessageWithId],
+    margins: &[MessageMargin],
+) -> Vec<CategoryBias> {
+    let mut biases: Vec<CategoryBias> = Vec::new();
+    for message in messages {
+        let category_name = &message.vc_message.category;
+        if biases.iter().any(|b| &b.category_name == category_name) {
+            continue;
+        }
+        // Margins come sorted best first.
+        let margin = margins.iter().find(|m| &m.category_name == category_name);
+        if let Some(m) = margin {
+            tracing::debug!(
+                category = %category_name,
+                message_id = m.message_id,
+                margin = m.margin,
+                "best-matching message"
+            );
+        }
+        let constants = stored.get(category_name).copied().unwrap_or_default();
+        let sim_score = margin.map_or(0.0, |m| m.margin);
         biases.push(CategoryBias {
-            category_name: m.category_name.clone(),
-            weighted_margin: (kappa * m.margin) as f32,
-            sim_score: m.margin as f32,
+            category_name: category_name.clone(),
+            kappa: constants.kappa as f32,
+            sim_score: sim_score as f32,
+            logit_offset: constants.logit_offset as f32,
         });
     }
-
     biases
 }
 
+/// The agent's learned logit temperature, or 1.0 if it cannot be read.
+pub(crate) async fn logit_temperature(db: &SqlitePool, agent_id: i32) -> f32 {
+    match db::get_logit_temperature(db, agent_id).await {
+        Ok(t) => t as f32,
+        Err(e) => {
+            tracing::warn!(agent_id, error = %e, "failed to read logit temperature — using 1.0");
+            1.0
+        }
+    }
+}
+
 /// GET /infer/stream/:session_id
 ///
 /// Streams `InferenceEvent` values as Server-Sent Events.
@@ -150,7 +368,26 @@ pub async fn stream_sse(
     let sid2 = sid.clone();
     let mut position: i64 = 0;
 
+    // Cancel the generation if the client disconnects mid-stream, instead of
+    // waiting for the engine to notice on its next send.
+    let cancellations = state.infer_cancellations.clone();
+    let cancel_on_disconnect = cancellations
+        .lock()
+        .await
+        .get(&sid)
+        .map(|c| c.clone().drop_guard());
+
     let stream = ReceiverStream::new(rx).map(move |event| {
+        // Keep the guard alive for as long as the stream is.
+        let _ = &cancel_on_disconnect;
+        if !matches!(event, InferenceEvent::Token(_)) {
+            let cancellations = cancellations.clone();
+            let sid = sid2.clone();
+            tokio::spawn(async move {
+                cancellations.lock().await.remove(&sid);
+            });
+        }
+
         // Persist token events to SQLite in a fire-and-forget task
         match &event {
             InferenceEvent::Token(step) => {
@@ -183,6 +420,9 @@ pub async fn stream_sse(
             InferenceEvent::Error { message } => {
                 tracing::error!(session_id = %sid2, error = %message, "inference error");
             }
+            InferenceEvent::Cancelled => {
+                tracing::info!(session_id = %sid2, "inference cancelled");
+            }
         }
 
         let data = serde_json::to_string(&event).unwrap_or_else(|_| "{}".to_string());
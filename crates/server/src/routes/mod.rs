@@ -1,5 +1,12 @@
 pub mod agents;
 pub mod bulk_test;
+pub mod category_constants;
+pub mod embeddings;
+pub mod experiment;
 pub mod health;
 pub mod infer;
 pub mod optimize;
+pub mod rescore;
+pub mod step;
+pub mod validation_sets;
+pub mod weight_sets;
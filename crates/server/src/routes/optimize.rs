@@ -5,11 +5,14 @@ use axum::{
     extract::{Path, State},
     http::StatusCode,
 };
-use inference_types::CategoryTopToken;
 use serde::{Deserialize, Serialize};
 
 use crate::db;
-use crate::optimize::{CategoryScore, ExampleData};
+use crate::optimize::{
+    self, Accuracy, CategoryScore, CrossValidation, ExampleData, LossPoint, OptimizerConfig,
+};
+use crate::rescore::RecordedExample;
+use crate::routes::weight_sets;
 use crate::state::AppState;
 
 /// Request body for POST /bulk-tests/{run_id}/apply-weights
@@ -17,90 +20,179 @@ use crate::state::AppState;
 pub struct ApplyWeightsRequest {
     /// Per-category kappa values as returned by the optimize endpoint.
     pub weights: HashMap<String, f64>,
+    /// Per-category logit offsets; categories left out keep their offset.
+    #[serde(default)]
+    pub offsets: HashMap<String, f64>,
+    /// Agent-wide logit temperature; left unchanged when omitted.
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    /// Name of the new weight set; defaults to one naming the run.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
 /// Response body for POST /bulk-tests/{run_id}/apply-weights
 #[derive(Serialize)]
 pub struct ApplyWeightsResponse {
-    /// Number of messages whose kappa was updated.
+    /// Number of categories whose kappa was updated.
     pub updated: usize,
-    /// Category names that appeared in `weights` but had no matching VC messages.
+    /// Category names that appeared in `weights` or `offsets` but had no
+    /// matching VC messages.
     pub unmatched_categories: Vec<String>,
+    /// The new, now active, weight set.
+    pub weight_set_id: i64,
+    pub version: i64,
 }
 
 /// POST /bulk-tests/{run_id}/apply-weights
 ///
-/// Saves the supplied per-category kappa values to SQLite so they are used by
-/// future inference runs.  For each category, every VC message belonging to
-/// that category (for the agent that owns this run) has its kappa updated.
-/// The values are the new absolute kappa — the old kappa is NOT used.
+/// Saves the supplied per-category kappa values as a new weight set version
+/// for the agent that owns this run and activates it, so they are used by
+/// future inference runs.  Each category named in `weights` gets that
+/// kappa; the agent's other categories keep their current values.  The
+/// values are the new absolute kappa — the old kappa is NOT used.  Offsets
+/// are applied the same way, and a temperature for the whole agent.  The
+/// agent's first apply also stores the values it replaces as version 1, so
+/// it can be rolled back.
+///
+/// Returns 400 for a temperature that is not positive and finite.
 pub async fn apply_weights(
     Path(run_id): Path<i64>,
     State(state): State<AppState>,
     Json(body): Json<ApplyWeightsRequest>,
 ) -> Result<Json<ApplyWeightsResponse>, StatusCode> {
+    if body
+        .temperature
+        .is_some_and(|t| !(t > 0.0 && t.is_finite()))
+    {
+        tracing::warn!(run_id, temperature = ?body.temperature, "invalid logit temperature");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
     // Look up which agent this run belongs to.
-    let agent_id = db::get_run_agent_id(&state.db, run_id)
-        .await
-        .map_err(|e| {
-            tracing::error!(run_id, error = %e, "failed to get agent_id for run");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    let agent_id = db::get_run_agent_id(&state.db, run_id).await.map_err(|e| {
+        tracing::error!(run_id, error = %e, "failed to get agent_id for run");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })? as i32;
 
-    // Load all VC messages for this agent to build category → message_ids.
-    let messages = db::load_vc_messages_with_ids(&state.vc_db, agent_id as i32)
+    // Load all VC messages for this agent.
+    let messages = state
+        .messages
+        .load_messages_with_ids(agent_id)
         .await
         .map_err(|e| {
             tracing::error!(agent_id, error = %e, "failed to load VC messages for apply-weights");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    // Build category_name → Vec<message_id>.
-    let mut category_to_ids: HashMap<String, Vec<i64>> = HashMap::new();
-    for msg in &messages {
-        category_to_ids
-            .entry(msg.vc_message.category.clone())
-            .or_default()
-            .push(msg.id as i64);
+    let internal = |e: anyhow::Error| {
+        tracing::error!(agent_id, error = %e, "failed to save weight set");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let mut entries = weight_sets::live_entries(&state.db, agent_id, &messages)
+        .await
+        .map_err(internal)?;
+    let current_temperature = db::get_logit_temperature(&state.db, agent_id)
+        .await
+        .map_err(internal)?;
+
+    // Keep the pre-versioning values as version 1.
+    let existing = db::list_weight_sets(&state.db, agent_id)
+        .await
+        .map_err(internal)?;
+    if existing.is_empty() {
+        let (initial_id, _) = db::create_weight_set(
+            &state.db,
+            agent_id,
+            "Initial",
+            None,
+            current_temperature,
+            &entries,
+        )
+        .await
+        .map_err(internal)?;
+        db::activate_weight_set(&state.db, initial_id)
+            .await
+            .map_err(internal)?;
     }
 
     let mut updated = 0usize;
-    let mut unmatched_categories: Vec<String> = Vec::new();
-
-    for (category_name, kappa) in &body.weights {
-        match category_to_ids.get(category_name.as_str()) {
-            None => unmatched_categories.push(category_name.clone()),
-            Some(ids) => {
-                for &message_id in ids {
-                    if let Err(e) = db::set_kappa(&state.db, message_id, *kappa).await {
-                        tracing::warn!(
-                            message_id, category = %category_name, error = %e,
-                            "failed to set kappa"
-                        );
-                    } else {
-                        updated += 1;
-                    }
-                }
-            }
+    for entry in &mut entries {
+        if let Some(&kappa) = body.weights.get(&entry.category_name) {
+            entry.kappa = kappa;
+            updated += 1;
+        }
+        if let Some(&offset) = body.offsets.get(&entry.category_name) {
+            entry.logit_offset = offset;
         }
     }
+    let mut unmatched_categories: Vec<String> = body
+        .weights
+        .keys()
+        .chain(body.offsets.keys())
+        .filter(|c| !entries.iter().any(|e| &e.category_name == *c))
+        .cloned()
+        .collect();
+    unmatched_categories.sort();
+    unmatched_categories.dedup();
+
+    let name = body
+        .name
+        .unwrap_or_else(|| format!("Optimised on run {run_id}"));
+    let (weight_set_id, version) = db::create_weight_set(
+        &state.db,
+        agent_id,
+        &name,
+        Some(run_id),
+        body.temperature.unwrap_or(current_temperature),
+        &entries,
+    )
+    .await
+    .map_err(internal)?;
+    db::activate_weight_set(&state.db, weight_set_id)
+        .await
+        .map_err(internal)?;
 
     tracing::info!(
         run_id,
         agent_id,
+        weight_set_id,
+        version,
         updated,
+        offsets = body.offsets.len(),
+        temperature = ?body.temperature,
         unmatched = unmatched_categories.len(),
         "applied optimised kappa values"
     );
 
-    Ok(Json(ApplyWeightsResponse { updated, unmatched_categories }))
+    Ok(Json(ApplyWeightsResponse {
+        updated,
+        unmatched_categories,
+        weight_set_id,
+        version,
+    }))
 }
 
-// Re-use the SlimStep definition from bulk_test (private there), so we
-// duplicate the minimal serde-only version we need here.
-#[derive(Deserialize)]
-struct SlimStep {
-    category_top_tokens: Vec<CategoryTopToken>,
+/// Kappa a category has until weights are applied (see `db::CategoryConstants`);
+/// categories the optimiser leaves out are scored with it.
+pub(crate) const DEFAULT_KAPPA: f64 = 10.0;
+
+/// Request body for POST /bulk-tests/{run_id}/optimize. Every field is
+/// optional; an empty body fits on all examples as before.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct OptimizeRequest {
+    /// Fraction of examples held out as a test set, never seen by the fit.
+    pub test_fraction: f64,
+    /// Fraction of the remaining examples held out to validate a single fit.
+    /// Ignored when `folds` is set.
+    pub validation_fraction: f64,
+    /// Run k-fold cross-validation over the non-test examples.
+    pub folds: Option<usize>,
+    /// Seed for the shuffle that assigns examples to splits.
+    pub seed: u64,
+    /// Objective and hyper-parameters; omitted fields keep their defaults.
+    pub optimizer: OptimizerConfig,
 }
 
 /// Response body for POST /bulk-tests/{run_id}/optimize
@@ -110,24 +202,64 @@ pub struct OptimizeResponse {
     /// A value of 1.0 means "same as current kappa"; >1.0 means increase the
     /// embedding influence for this category; <1.0 means decrease it.
     pub weights: HashMap<String, f64>,
+    /// Per-category logit offsets; empty unless `optimizer.learn_offsets`.
+    pub offsets: HashMap<String, f64>,
+    /// Fitted logit temperature; `None` unless `optimizer.learn_temperature`.
+    pub temperature: Option<f64>,
     /// Number of examples used in the optimisation.
     pub examples_used: usize,
     /// Number of examples skipped (no embedding data or no correct categories).
     pub examples_skipped: usize,
+    /// In-sample accuracy of `weights` on the examples they were fitted on.
+    pub train_accuracy: Accuracy,
+    /// Accuracy of a fit on the training split, on the validation split.
+    pub validation_accuracy: Option<Accuracy>,
+    /// Accuracy of `weights` on the held-out test split.
+    pub test_accuracy: Option<Accuracy>,
+    pub cross_validation: Option<CrossValidation>,
+    /// Objective value over the final fit's iterations.
+    pub loss_curve: Vec<LossPoint>,
 }
 
 /// POST /bulk-tests/{run_id}/optimize
 ///
 /// Reads all results for the given bulk test run, builds per-example
-/// per-category (logit, sim_score) matrices, and solves for the
-/// minimum-norm ridge-regression weights that maximise classification accuracy.
+/// per-category (logit, sim_score) matrices, and fits the per-category kappa
+/// values (plus, on request, logit offsets and a temperature) that maximise
+/// classification accuracy.
+///
+/// The objective and its hyper-parameters come from `optimizer` (softmax
+/// cross-entropy with the historical defaults when omitted); the response
+/// includes the final fit's loss curve.
 ///
-/// Returns 422 if the run contains no usable embedding data (e.g. an old run
-/// recorded before `sim_score` was added to CategoryTopToken).
+/// Examples are shuffled with `seed` and a `test_fraction` is held out. The
+/// returned weights are fitted on the rest; a `validation_fraction` split or
+/// k-fold cross-validation over the rest estimates how well such a fit
+/// generalises.
+///
+/// Returns 400 for invalid split parameters, and 422 if the run contains no
+/// usable embedding data (e.g. an old run recorded before `sim_score` was
+/// added to CategoryTopToken).
 pub async fn optimize_weights(
     Path(run_id): Path<i64>,
     State(state): State<AppState>,
+    body: Option<Json<OptimizeRequest>>,
 ) -> Result<Json<OptimizeResponse>, StatusCode> {
+    let request = body.map(|Json(r)| r).unwrap_or_default();
+    let valid_fraction = |f: f64| (0.0..1.0).contains(&f);
+    if !valid_fraction(request.test_fraction)
+        || !valid_fraction(request.validation_fraction)
+        || request.folds.is_some_and(|k| k < 2)
+    {
+        tracing::warn!(run_id, "invalid optimise split parameters");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if let Err(e) = request.optimizer.validate() {
+        tracing::warn!(run_id, error = %e, "invalid optimiser config");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let config = &request.optimizer;
+
     let rows = db::load_bulk_test_results(&state.db, run_id)
         .await
         .map_err(|e| {
@@ -139,74 +271,89 @@ pub async fn optimize_weights(
         return Err(StatusCode::NOT_FOUND);
     }
 
-    let mut examples_used = 0usize;
-    let mut examples_skipped = 0usize;
-    let mut example_data: Vec<ExampleData> = Vec::with_capacity(rows.len());
-
-    for row in &rows {
-        // Parse correct categories.
-        let correct_categories: Vec<String> =
-            match serde_json::from_str(&row.correct_categories_json) {
-                Ok(v) => v,
-                Err(_) => {
-                    examples_skipped += 1;
-                    continue;
-                }
-            };
-
-        if correct_categories.is_empty() {
-            examples_skipped += 1;
-            continue;
-        }
+    let total = rows.len();
+    let example_data: Vec<ExampleData> = rows
+        .into_iter()
+        .filter_map(RecordedExample::from_stored)
+        .filter(|ex| !ex.candidates.is_empty())
+        .map(|ex| ExampleData {
+            category_scores: ex
+                .candidates
+                .into_iter()
+                .map(|ct| {
+                    (
+                        ct.category_name,
+                        CategoryScore {
+                            logit: ct.best_token.logit,
+                            sim_score: ct.sim_score,
+                        },
+                    )
+                })
+                .collect(),
+            correct_categories: ex.correct_categories,
+        })
+        .collect();
+    let examples_used = example_data.len();
+    let examples_skipped = total - examples_used;
 
-        // Parse steps JSON → find first step with category_top_tokens populated.
-        let steps: Vec<SlimStep> = match serde_json::from_str(&row.steps_json) {
-            Ok(v) => v,
-            Err(_) => {
-                examples_skipped += 1;
-                continue;
-            }
-        };
-
-        let first_scored_step = steps
-            .into_iter()
-            .find(|s| !s.category_top_tokens.is_empty());
-
-        let scored_step = match first_scored_step {
-            Some(s) => s,
-            None => {
-                examples_skipped += 1;
-                continue;
-            }
-        };
-
-        let category_scores: HashMap<String, CategoryScore> = scored_step
-            .category_top_tokens
-            .into_iter()
-            .map(|ct| {
-                (
-                    ct.category_name,
-                    CategoryScore {
-                        logit: ct.best_token.logit,
-                        sim_score: ct.sim_score,
-                    },
-                )
-            })
-            .collect();
-
-        examples_used += 1;
-        example_data.push(ExampleData {
-            category_scores,
-            correct_categories,
-        });
-    }
+    // Shuffle, then cut: [test | validation | train].
+    let shuffled: Vec<&ExampleData> = optimize::shuffled_indices(example_data.len(), request.seed)
+        .into_iter()
+        .map(|i| &example_data[i])
+        .collect();
+    let n_test = (shuffled.len() as f64 * request.test_fraction).round() as usize;
+    let (test, dev) = shuffled.split_at(n_test);
 
-    match crate::optimize::optimize_weights(&example_data) {
-        Some(weights) => Ok(Json(OptimizeResponse {
+    let cross_validation = match request.folds {
+        Some(k) if k > dev.len() => {
+            tracing::warn!(
+                run_id,
+                folds = k,
+                examples = dev.len(),
+                "more folds than examples"
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Some(k) => Some(optimize::cross_validate(dev, k, config, DEFAULT_KAPPA)),
+        None => None,
+    };
+    let validation_accuracy = if request.folds.is_none() && request.validation_fraction > 0.0 {
+        let n_validation = (dev.len() as f64 * request.validation_fraction).round() as usize;
+        let (validation, train) = dev.split_at(n_validation);
+        Some(optimize::fit_and_score(train, validation, config, DEFAULT_KAPPA).held_out)
+    } else {
+        None
+    };
+
+    match optimize::optimize_weights(dev, config) {
+        Some(optimize::Optimized {
             weights,
-            examples_used,
-            examples_skipped,
-        })),
+            loss_curve,
+        }) => {
+            let train_accuracy = optimize::accuracy(dev, &weights, DEFAULT_KAPPA);
+            let test_accuracy =
+                (!test.is_empty()).then(|| optimize::accuracy(test, &weights, DEFAULT_KAPPA));
+            tracing::info!(
+                run_id,
+                objective = ?config.objective,
+                train = train_accuracy.rate(),
+                test = test_accuracy.map(|a| a.rate()),
+                cv_mean = cross_validation.as_ref().map(|cv| cv.mean_accuracy),
+                "optimised kappa values"
+            );
+            Ok(Json(OptimizeResponse {
+                weights: weights.kappa,
+                offsets: weights.offsets,
+                temperature: config.learn_temperature.then_some(weights.temperature),
+                examples_used,
+                examples_skipped,
+                train_accuracy,
+                validation_accuracy,
+                test_accuracy,
+                cross_validation,
+                loss_curve,
+            }))
+        }
         None => {
             tracing::warn!(
                 run_id,
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::optimize::Weights;
+use crate::rescore::{self, RecordedExample, Rescored};
+use crate::routes::optimize::DEFAULT_KAPPA;
+use crate::state::AppState;
+
+fn default_kappa() -> f64 {
+    DEFAULT_KAPPA
+}
+
+fn default_temperature() -> f64 {
+    1.0
+}
+
+/// Request body for POST /bulk-tests/{run_id}/rescore: a candidate weight
+/// set, shaped like the optimize response.
+#[derive(Deserialize)]
+pub struct RescoreRequest {
+    /// Per-category kappa values.
+    #[serde(default)]
+    pub weights: HashMap<String, f64>,
+    /// Per-category logit offsets; 0 for categories left out.
+    #[serde(default)]
+    pub offsets: HashMap<String, f64>,
+    /// Kappa of categories missing from `weights`.
+    #[serde(default = "default_kappa")]
+    pub fallback_kappa: f64,
+    #[serde(default = "default_temperature")]
+    pub temperature: f64,
+}
+
+/// Response body for POST /bulk-tests/{run_id}/rescore
+#[derive(Serialize)]
+pub struct RescoreResponse {
+    #[serde(flatten)]
+    pub rescored: Rescored,
+    /// Results without per-category candidates or correct categories.
+    pub examples_skipped: usize,
+}
+
+/// POST /bulk-tests/{run_id}/rescore
+///
+/// Re-ranks each stored result's categories under the candidate weights and
+/// reports the new choices against the recorded ones: accuracy before and
+/// after, the examples fixed and broken, and a confusion matrix. Nothing is
+/// stored and the model is not run.
+///
+/// Returns 400 for a temperature that is not positive and finite, 404 for a
+/// run with no results.
+pub async fn rescore_run(
+    Path(run_id): Path<i64>,
+    State(state): State<AppState>,
+    Json(body): Json<RescoreRequest>,
+) -> Result<Json<RescoreResponse>, StatusCode> {
+    if !(body.temperature > 0.0 && body.temperature.is_finite()) {
+        tracing::warn!(
+            run_id,
+            temperature = body.temperature,
+            "invalid logit temperature"
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let rows = db::load_bulk_test_results(&state.db, run_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(run_id, error = %e, "failed to load bulk_test_results for rescore");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if rows.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let total = rows.len();
+    let examples: Vec<RecordedExample> = rows
+        .into_iter()
+        .filter_map(RecordedExample::from_stored)
+        .collect();
+    let weights = Weights {
+        kappa: body.weights,
+        offsets: body.offsets,
+        temperature: body.temperature,
+    };
+    let rescored = rescore::rescore(&examples, &weights, body.fallback_kappa);
+    let examples_skipped = total - rescored.examples.len();
+    tracing::info!(
+        run_id,
+        original = rescored.original_accuracy.rate(),
+        rescored = rescored.rescored_accuracy.rate(),
+        fixed = rescored.fixed.len(),
+        broken = rescored.broken.len(),
+        "re-scored run"
+    );
+
+    Ok(Json(RescoreResponse {
+        rescored,
+        examples_skipped,
+    }))
+}
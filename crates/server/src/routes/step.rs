@@ -0,0 +1,196 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use inference::{SamplerSettings, StepError, StepSession, StepThroughState};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::routes::infer::prepare_generation;
+use crate::state::AppState;
+
+/// A live step-through session and when a request last used it.
+pub struct OpenStepSession {
+    session: StepSession,
+    last_used: Instant,
+}
+
+#[derive(Deserialize)]
+pub struct StartStepRequest {
+    pub prompt: String,
+    pub agent_id: i32,
+    /// Overrides the server's default sampling strategy for this session.
+    #[serde(default)]
+    pub sampler: Option<SamplerSettings>,
+}
+
+#[derive(Serialize)]
+pub struct StartStepResponse {
+    pub session_id: String,
+    pub state: StepThroughState,
+}
+
+#[derive(Deserialize)]
+pub struct AdvanceStepRequest {
+    /// Token to commit instead of the argmax proposal. Must be one of the
+    /// pending step's `top_constrained` candidates.
+    #[serde(default)]
+    pub token_id: Option<u32>,
+}
+
+/// POST /step
+///
+/// Starts an interactive step-through session. Builds the `GrammarFlow` and
+/// embedding biases exactly like POST /infer, but instead of generating to
+/// completion the engine stops at the first critical point and waits for
+/// POST /step/{session_id}/advance.
+pub async fn start_step(
+    State(state): State<AppState>,
+    Json(body): Json<StartStepRequest>,
+) -> Result<Json<StartStepResponse>, StatusCode> {
+    let request =
+        prepare_generation(&state, body.prompt.clone(), body.agent_id, body.sampler).await?;
+
+    let session_id = db::create_session(&state.db, &body.prompt, None)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "failed to create session");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let (session, initial) = state
+        .engine
+        .start_step_session(request)
+        .await
+        .map_err(|e| {
+            tracing::error!(session_id = %session_id, error = %e, "failed to start step-through session");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let _ = db::set_session_streaming(&state.db, &session_id).await;
+
+    if initial.pending.is_some() {
+        state
+            .step_sessions
+            .lock()
+            .await
+            .insert(
+                session_id.clone(),
+                OpenStepSession {
+                    session,
+                    last_used: Instant::now(),
+                },
+            );
+    } else if let Err(e) = db::complete_session(&state.db, &session_id, &initial.full_text).await {
+        tracing::warn!(error = %e, "failed to complete session");
+    }
+
+    Ok(Json(StartStepResponse {
+        session_id,
+        state: initial,
+    }))
+}
+
+/// POST /step/{session_id}/advance
+///
+/// Commits one critical point — the forced `token_id` if given, otherwise the
+/// argmax proposal — plus any grammar fast-forward tokens, persists them to
+/// the session's token log, and returns the candidates at the next critical
+/// point. The session is closed automatically once generation finishes.
+///
+/// Returns 404 for unknown sessions and 422 if `token_id` is not a
+/// constrained candidate at the pending step.
+pub async fn advance_step(
+    Path(session_id): Path<String>,
+    State(state): State<AppState>,
+    Json(body): Json<AdvanceStepRequest>,
+) -> Result<Json<StepThroughState>, StatusCode> {
+    // Clone the handle out so the map is not locked while the engine decodes.
+    let session = {
+        let mut sessions = state.step_sessions.lock().await;
+        let open = sessions.get_mut(&session_id).ok_or(StatusCode::NOT_FOUND)?;
+        open.last_used = Instant::now();
+        open.session.clone()
+    };
+
+    let step_state = match session.advance(body.token_id).await {
+        Ok(s) => s,
+        Err(StepError::TokenNotAllowed(token_id)) => {
+            tracing::warn!(session_id = %session_id, token_id, "rejected forced token");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        Err(e) => {
+            tracing::error!(session_id = %session_id, error = %e, "step-through advance failed");
+            state.step_sessions.lock().await.remove(&session_id);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut position = db::next_token_position(&state.db, &session_id)
+        .await
+        .unwrap_or(0);
+    for step in &step_state.committed {
+        if let Err(e) = db::insert_token(
+            &state.db,
+            &session_id,
+            position,
+            &step.chosen.text,
+            step.chosen.token_id as i64,
+            step.chosen.probability as f64,
+            step.chosen.logit as f64,
+        )
+        .await
+        {
+            tracing::warn!(error = %e, "failed to persist token");
+        }
+        position += 1;
+    }
+
+    if step_state.pending.is_none() {
+        state.step_sessions.lock().await.remove(&session_id);
+        if let Err(e) = db::complete_session(&state.db, &session_id, &step_state.full_text).await {
+            tracing::warn!(error = %e, "failed to complete session");
+        }
+    }
+
+    Ok(Json(step_state))
+}
+
+/// DELETE /step/{session_id}
+///
+/// Abandons a step-through session and frees its LLM context.
+pub async fn close_step(
+    Path(session_id): Path<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    match state.step_sessions.lock().await.remove(&session_id) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Close every step-through session no request has used for `idle_ttl`,
+/// freeing its blocking thread and LLM context, and mark it cancelled.
+pub async fn evict_idle_sessions(state: &AppState, idle_ttl: Duration) {
+    let evicted: Vec<String> = {
+        let mut sessions = state.step_sessions.lock().await;
+        let idle: Vec<String> = sessions
+            .iter()
+            .filter(|(_, open)| open.last_used.elapsed() >= idle_ttl)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &idle {
+            sessions.remove(id);
+        }
+        idle
+    };
+    for session_id in evicted {
+        tracing::info!(session_id = %session_id, "closed idle step-through session");
+        if let Err(e) = db::cancel_session(&state.db, &session_id).await {
+            tracing::warn!(error = %e, "failed to mark idle session cancelled");
+        }
+    }
+}
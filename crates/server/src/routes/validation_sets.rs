@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::state::AppState;
+
+/// One line of an imported JSONL validation set.
+#[derive(Deserialize)]
+struct ValidationLine {
+    text: String,
+    correct_categories: Vec<String>,
+}
+
+/// Parse JSONL into `(text, correct_categories)` pairs, skipping blank
+/// lines. Errors name the 1-based line number.
+fn parse_jsonl(jsonl: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut examples = vec![];
+    for (i, line) in jsonl.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed: ValidationLine =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {e}", i + 1))?;
+        let text = parsed.text.trim().to_string();
+        if text.is_empty() {
+            return Err(format!("line {}: empty text", i + 1));
+        }
+        let categories = parsed
+            .correct_categories
+            .iter()
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect();
+        examples.push((text, categories));
+    }
+    Ok(examples)
+}
+
+#[derive(Deserialize)]
+pub struct ImportValidationSetRequest {
+    pub name: String,
+    /// One `{"text": ..., "correct_categories": [...]}` object per line.
+    pub jsonl: String,
+}
+
+#[derive(Serialize)]
+pub struct ImportValidationSetResponse {
+    pub id: i64,
+    pub example_count: usize,
+    /// Labels that match none of the agent's current categories. The set is
+    /// still imported; examples labelled only with these can never pass.
+    pub unknown_categories: Vec<String>,
+}
+
+/// POST /agents/:agent_id/validation-sets
+///
+/// Imports a JSONL validation set for the agent into SQLite. Returns 400 if
+/// any line fails to parse or the set is empty.
+pub async fn import_validation_set(
+    Path(agent_id): Path<i32>,
+    State(state): State<AppState>,
+    Json(body): Json<ImportValidationSetRequest>,
+) -> Result<Json<ImportValidationSetResponse>, StatusCode> {
+    let examples = parse_jsonl(&body.jsonl).map_err(|e| {
+        tracing::warn!(agent_id, error = %e, "invalid validation set JSONL");
+        StatusCode::BAD_REQUEST
+    })?;
+    if examples.is_empty() {
+        tracing::warn!(agent_id, "validation set has no examples");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let categories: HashSet<String> = state
+        .messages
+        .load_vc_messages(agent_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to load VC messages for validation set");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .map(|m| m.category)
+        .collect();
+    let mut unknown_categories: Vec<String> = examples
+        .iter()
+        .flat_map(|(_, cats)| cats)
+        .filter(|c| !categories.contains(*c))
+        .cloned()
+        .collect();
+    unknown_categories.sort();
+    unknown_categories.dedup();
+
+    let rows: Vec<(String, String)> = examples
+        .into_iter()
+        .map(|(text, cats)| {
+            let json = serde_json::to_string(&cats).unwrap_or_else(|_| "[]".to_string());
+            (text, json)
+        })
+        .collect();
+    let id = db::create_validation_set(&state.db, agent_id, &body.name, &rows)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to store validation set");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ImportValidationSetResponse {
+        id,
+        example_count: rows.len(),
+        unknown_categories,
+    }))
+}
+
+/// GET /agents/:agent_id/validation-sets
+///
+/// Lists the agent's imported validation sets, newest first.
+pub async fn list_validation_sets(
+    Path(agent_id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<db::ValidationSetSummary>>, StatusCode> {
+    db::list_validation_sets(&state.db, agent_id)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to list validation sets");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// DELETE /validation-sets/:set_id
+///
+/// Deletes a validation set. Runs that used it keep their results and the
+/// set's ID, so they are still compared only with runs over the same set.
+pub async fn delete_validation_set(
+    Path(set_id): Path<i64>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    match db::delete_validation_set(&state.db, set_id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            tracing::error!(set_id, error = %e, "failed to delete validation set");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lines_and_skips_blanks() {
+        let jsonl = r#"{"text": " Is it safe? ", "correct_categories": ["Safety", " "]}
+
+{"text": "Dose?", "correct_categories": []}
+"#;
+        let examples = parse_jsonl(jsonl).unwrap();
+        assert_eq!(
+            examples,
+            vec![
+                ("Is it safe?".to_string(), vec!["Safety".to_string()]),
+                ("Dose?".to_string(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let jsonl =
+            "{\"text\": \"ok\", \"correct_categories\": []}\n{\"text\": \"missing labels\"}\n";
+        let err = parse_jsonl(jsonl).unwrap_err();
+        assert!(err.starts_with("line 2:"), "{err}");
+    }
+}
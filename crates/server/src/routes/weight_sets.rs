@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::db::{self, VcMessageWithId, WeightSetEntry, WeightSetSummary};
+use crate::state::AppState;
+
+/// The constants inference currently uses for each category of `messages`,
+/// as weight set entries ordered by category name.
+pub(crate) async fn live_entries(
+    db: &SqlitePool,
+    agent_id: i32,
+    messages: &[VcMessageWithId],
+) -> anyhow::Result<Vec<WeightSetEntry>> {
+    let stored = db::load_category_constants(db, agent_id).await?;
+    let categories: BTreeSet<&String> = messages.iter().map(|m| &m.vc_message.category).collect();
+    Ok(categories
+        .into_iter()
+        .map(|category_name| {
+            let constants = stored.get(category_name).copied().unwrap_or_default();
+            WeightSetEntry {
+                category_name: category_name.clone(),
+                kappa: constants.kappa,
+                logit_offset: constants.logit_offset,
+            }
+        })
+        .collect())
+}
+
+/// One category whose constants differ between two weight sets. `None` when
+/// the category is missing from that set.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct EntryChange {
+    pub category_name: String,
+    pub kappa_from: Option<f64>,
+    pub kappa_to: Option<f64>,
+    pub logit_offset_from: Option<f64>,
+    pub logit_offset_to: Option<f64>,
+}
+
+/// Categories whose kappa or offset differs between `from` and `to`,
+/// ordered by name.
+fn diff_entries(from: &[WeightSetEntry], to: &[WeightSetEntry]) -> Vec<EntryChange> {
+    let mut pairs: BTreeMap<&str, (Option<&WeightSetEntry>, Option<&WeightSetEntry>)> =
+        BTreeMap::new();
+    for entry in from {
+        pairs.entry(&entry.category_name).or_default().0 = Some(entry);
+    }
+    for entry in to {
+        pairs.entry(&entry.category_name).or_default().1 = Some(entry);
+    }
+    pairs
+        .into_iter()
+        .filter_map(|(category_name, (a, b))| {
+            let unchanged = matches!((a, b), (Some(a), Some(b))
+                if a.kappa == b.kappa && a.logit_offset == b.logit_offset);
+            if unchanged {
+                return None;
+            }
+            Some(EntryChange {
+                category_name: category_name.to_string(),
+                kappa_from: a.map(|e| e.kappa),
+                kappa_to: b.map(|e| e.kappa),
+                logit_offset_from: a.map(|e| e.logit_offset),
+                logit_offset_to: b.map(|e| e.logit_offset),
+            })
+        })
+        .collect()
+}
+
+/// Summary of one weight set; 404 if it does not exist.
+async fn find_weight_set(state: &AppState, set_id: i64) -> Result<WeightSetSummary, StatusCode> {
+    let agent_id = db::get_weight_set_agent_id(&state.db, set_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(set_id, error = %e, "failed to look up weight set");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    db::list_weight_sets(&state.db, agent_id as i32)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to list weight sets");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .find(|w| w.id == set_id)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Activate a weight set and return its updated summary.
+async fn activate(state: &AppState, set_id: i64) -> Result<WeightSetSummary, StatusCode> {
+    match db::activate_weight_set(&state.db, set_id).await {
+        Ok(true) => {}
+        Ok(false) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!(set_id, error = %e, "failed to activate weight set");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let summary = find_weight_set(state, set_id).await?;
+    tracing::info!(
+        set_id,
+        agent_id = summary.agent_id,
+        version = summary.version,
+        "activated weight set"
+    );
+    Ok(summary)
+}
+
+/// GET /agents/:agent_id/weight-sets
+///
+/// Lists the agent's weight sets, newest version first.
+pub async fn list_weight_sets(
+    Path(agent_id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<WeightSetSummary>>, StatusCode> {
+    db::list_weight_sets(&state.db, agent_id)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to list weight sets");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+#[derive(Serialize)]
+pub struct WeightSetDiff {
+    pub from: WeightSetSummary,
+    pub to: WeightSetSummary,
+    pub changes: Vec<EntryChange>,
+}
+
+/// GET /weight-sets/:from_id/diff/:to_id
+///
+/// Per-category kappa and offset changes from one weight set to another; the
+/// temperatures are in the two summaries. Returns 400 if the sets belong to
+/// different agents.
+pub async fn diff_weight_sets(
+    Path((from_id, to_id)): Path<(i64, i64)>,
+    State(state): State<AppState>,
+) -> Result<Json<WeightSetDiff>, StatusCode> {
+    let from = find_weight_set(&state, from_id).await?;
+    let to = find_weight_set(&state, to_id).await?;
+    if from.agent_id != to.agent_id {
+        tracing::warn!(from_id, to_id, "weight sets belong to different agents");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let load = |set_id: i64| {
+        let db = state.db.clone();
+        async move {
+            db::load_weight_set_entries(&db, set_id).await.map_err(|e| {
+                tracing::error!(set_id, error = %e, "failed to load weight set entries");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+        }
+    };
+    let changes = diff_entries(&load(from_id).await?, &load(to_id).await?);
+    Ok(Json(WeightSetDiff { from, to, changes }))
+}
+
+/// POST /weight-sets/:set_id/activate
+///
+/// Makes the weight set the one inference uses for its agent.
+pub async fn activate_weight_set(
+    Path(set_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<Json<WeightSetSummary>, StatusCode> {
+    activate(&state, set_id).await.map(Json)
+}
+
+/// POST /agents/:agent_id/weight-sets/rollback
+///
+/// Activates the version before the active one. Returns 409 if the agent
+/// has no active weight set or it is the oldest version.
+pub async fn rollback_weight_set(
+    Path(agent_id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Json<WeightSetSummary>, StatusCode> {
+    let sets = db::list_weight_sets(&state.db, agent_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(agent_id, error = %e, "failed to list weight sets");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    // Newest first, so the previous version follows the active one.
+    let Some(previous) = sets
+        .iter()
+        .position(|w| w.active)
+        .and_then(|i| sets.get(i + 1))
+    else {
+        tracing::warn!(agent_id, "no earlier weight set to roll back to");
+        return Err(StatusCode::CONFLICT);
+    };
+    activate(&state, previous.id).await.map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(category: &str, kappa: f64, logit_offset: f64) -> WeightSetEntry {
+        WeightSetEntry {
+            category_name: category.to_string(),
+            kappa,
+            logit_offset,
+        }
+    }
+
+    #[test]
+    fn diff_lists_changed_added_and_removed_categories() {
+        let from = [
+            entry("A", 10.0, 0.0),
+            entry("B", 10.0, 0.0),
+            entry("C", 5.0, 0.0),
+        ];
+        let to = [
+            entry("A", 10.0, 0.0),
+            entry("B", 10.0, 0.5),
+            entry("D", 2.0, 0.0),
+        ];
+
+        let changes = diff_entries(&from, &to);
+
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| {
+                (
+                    c.category_name.as_str(),
+                    c.kappa_from,
+                    c.kappa_to,
+                    c.logit_offset_to,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("B", Some(10.0), Some(10.0), Some(0.5)),
+                ("C", Some(5.0), None, None),
+                ("D", None, Some(2.0), Some(0.0)),
+            ]
+        );
+    }
+}
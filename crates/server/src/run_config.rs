@@ -0,0 +1,396 @@
+//! Configuration snapshot of a bulk-test run.
+//!
+//! Every run stores what it was started with: the messages the system prompt
+//! and grammar were rendered from, the sampler, the category constants and
+//! logit temperature, and the parts that come from the server itself — model
+//! file, chat template, decoding limits, embedding model and margin backend.
+//! A re-run replays the stored messages, sampler and weights, so it is only
+//! exact while the server-side parts are unchanged; `RunConfig::mismatches`
+//! lists the ones that are not.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use inference::{GrammarFlow, SamplerSettings, VCmessage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+use crate::db::{CategoryConstants, VcMessageWithId};
+use crate::models::Model;
+use crate::state::AppState;
+
+/// The GGUF model file the engine was loaded from.
+pub struct ModelFile {
+    path: PathBuf,
+    sha256: OnceCell<String>,
+}
+
+impl ModelFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            sha256: OnceCell::new(),
+        }
+    }
+
+    /// Hex SHA-256 of the file. Hashed once on a blocking thread; callers
+    /// arriving while it runs wait for the same result.
+    pub async fn sha256(&self) -> anyhow::Result<&str> {
+        let hash = self
+            .sha256
+            .get_or_try_init(|| {
+                let path = self.path.clone();
+                async move { tokio::task::spawn_blocking(move || hash_file(&path)).await? }
+            })
+            .await?;
+        Ok(hash)
+    }
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to hash {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The parts of a run's configuration that come from the server rather
+/// than from the request or the database.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Environment {
+    pub model_path: String,
+    pub model_sha256: String,
+    pub chat_template: String,
+    pub max_tokens: usize,
+    pub top_candidate_count: usize,
+    /// `None` for a run without embedding biases.
+    pub embedding_model: Option<String>,
+    pub margin_backend: String,
+}
+
+impl Environment {
+    /// The environment of a run on `model`. Waits for the model's hash the
+    /// first time. `embeddings: false` records a run without embedding
+    /// biases even if the server has a provider.
+    pub async fn of(model: &Model, state: &AppState, embeddings: bool) -> anyhow::Result<Self> {
+        let config = model.engine.config();
+        Ok(Self {
+            model_path: config.model_path.display().to_string(),
+            model_sha256: model.file.sha256().await?.to_string(),
+            chat_template: model.engine.chat_template_name().to_string(),
+            max_tokens: config.max_tokens,
+            top_candidate_count: config.top_candidate_count,
+            embedding_model: state
+                .embeddings
+                .as_ref()
+                .filter(|_| embeddings)
+                .map(|e| e.model().to_string()),
+            margin_backend: state
+                .margins
+                .as_ref()
+                .map_or("none", |m| m.name())
+                .to_string(),
+        })
+    }
+}
+
+/// A VC message with the ID correct answers refer to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    pub id: i32,
+    #[serde(flatten)]
+    pub message: VCmessage,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub agent_id: i32,
+    /// `None` for runs against the marketing database's HCP examples, which
+    /// are re-read when the run is replayed.
+    pub validation_set_id: Option<i64>,
+    pub brand_name: String,
+    #[serde(flatten)]
+    pub environment: Environment,
+    /// Template the system prompt was rendered from (see
+    /// `GrammarFlow::with_template`); `None` for the built-in one.
+    #[serde(default)]
+    pub system_prompt_template: Option<String>,
+    /// Hash of the rendered system prompt.
+    pub system_prompt_sha256: String,
+    pub lark_grammar: String,
+    /// Version of the agent's messages in the marketing database; `None`
+    /// for file sources.
+    pub vc_message_version: Option<i64>,
+    /// In grammar order.
+    pub vc_messages: Vec<RecordedMessage>,
+    /// A random sampler without a seed does not replay the same choices.
+    pub sampler: SamplerSettings,
+    /// The agent's active weight set; `None` before one was activated.
+    pub weight_set_id: Option<i64>,
+    /// Constants of every category, defaults included.
+    pub category_constants: BTreeMap<String, CategoryConstants>,
+    pub logit_temperature: f32,
+}
+
+/// `messages` as recorded in a `RunConfig`.
+pub fn record_messages(messages: &[VcMessageWithId]) -> Vec<RecordedMessage> {
+    messages
+        .iter()
+        .map(|m| RecordedMessage {
+            id: m.id,
+            message: m.vc_message.clone(),
+        })
+        .collect()
+}
+
+/// The constants every category of `messages` runs with: its stored ones,
+/// or the defaults.
+pub fn effective_constants(
+    stored: &HashMap<String, CategoryConstants>,
+    messages: &[VcMessageWithId],
+) -> BTreeMap<String, CategoryConstants> {
+    messages
+        .iter()
+        .map(|m| {
+            let name = &m.vc_message.category;
+            (name.clone(), stored.get(name).copied().unwrap_or_default())
+        })
+        .collect()
+}
+
+/// Hash of the system prompt `grammar_flow` renders.
+pub fn system_prompt_sha256(grammar_flow: &GrammarFlow) -> String {
+    format!(
+        "{:x}",
+        Sha256::digest(grammar_flow.system_prompt.as_bytes())
+    )
+}
+
+impl RunConfig {
+    /// The system prompt and grammar rendered from the recorded messages.
+    pub fn grammar_flow(&self) -> anyhow::Result<GrammarFlow> {
+        let vc_messages: Vec<_> = self.vc_messages.iter().map(|m| m.message.clone()).collect();
+        match &self.system_prompt_template {
+            Some(template) => GrammarFlow::with_template(&self.brand_name, &vc_messages, template),
+            None => GrammarFlow::new(&self.brand_name, &vc_messages),
+        }
+    }
+
+    /// The recorded messages in the form the message sources return them.
+    pub fn messages_with_ids(&self) -> Vec<VcMessageWithId> {
+        self.vc_messages
+            .iter()
+            .map(|m| VcMessageWithId {
+                id: m.id,
+                vc_message: m.message.clone(),
+            })
+            .collect()
+    }
+
+    /// What would make a replay differ from the recorded run: changed
+    /// server-side settings, and a system prompt or grammar that renders
+    /// differently from the recorded messages (`grammar_flow`) than it did.
+    /// `current` is the environment of the recorded model with embeddings
+    /// on. The model path alone may change as long as the file's hash does
+    /// not.
+    pub fn mismatches(&self, current: &Environment, grammar_flow: &GrammarFlow) -> Vec<String> {
+        let recorded = &self.environment;
+        let mut mismatches = Vec::new();
+        let mut check = |name: &str, was: String, now: String| {
+            if was != now {
+                mismatches.push(format!("{name}: recorded {was}, now {now}"));
+            }
+        };
+        check(
+            "model_sha256",
+            recorded.model_sha256.clone(),
+            current.model_sha256.clone(),
+        );
+        check(
+            "chat_template",
+            recorded.chat_template.clone(),
+            current.chat_template.clone(),
+        );
+        check(
+            "max_tokens",
+            recorded.max_tokens.to_string(),
+            current.max_tokens.to_string(),
+        );
+        check(
+            "top_candidate_count",
+            recorded.top_candidate_count.to_string(),
+            current.top_candidate_count.to_string(),
+        );
+        // A run without embeddings replays without them either way.
+        if let Some(model) = &recorded.embedding_model {
+            check(
+                "embedding_model",
+                model.clone(),
+                current.embedding_model.clone().unwrap_or_else(|| "none".to_string()),
+            );
+        }
+        check(
+            "margin_backend",
+            recorded.margin_backend.clone(),
+            current.margin_backend.clone(),
+        );
+        check(
+            "system_prompt_sha256",
+            self.system_prompt_sha256.clone(),
+            system_prompt_sha256(grammar_flow),
+        );
+        if self.lark_grammar != grammar_flow.lark_grammar {
+            mismatches.push("lark_grammar: renders differently".to_string());
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i32, category: &str) -> VcMessageWithId {
+        VcMessageWithId {
+            id,
+            vc_message: VCmessage {
+                category: category.to_string(),
+                kind: String::new(),
+                description: String::new(),
+                mlr_message: format!("{category} message"),
+                message: format!("{category} message"),
+            },
+        }
+    }
+
+    fn environment() -> Environment {
+        Environment {
+            model_path: "models/a.gguf".to_string(),
+            model_sha256: "abc".to_string(),
+            chat_template: "llama3".to_string(),
+            max_tokens: 200,
+            top_candidate_count: 10,
+            embedding_model: Some("text-embedding-3-large".to_string()),
+            margin_backend: "postgres".to_string(),
+        }
+    }
+
+    fn config(messages: &[VcMessageWithId], flow: &GrammarFlow) -> RunConfig {
+        let stored = HashMap::from([(
+            "Dosing".to_string(),
+            CategoryConstants {
+                kappa: 4.0,
+                logit_offset: -0.5,
+            },
+        )]);
+        RunConfig {
+            agent_id: 7,
+            validation_set_id: Some(3),
+            brand_name: "Brand".to_string(),
+            environment: environment(),
+            system_prompt_template: None,
+            system_prompt_sha256: system_prompt_sha256(flow),
+            lark_grammar: flow.lark_grammar.clone(),
+            vc_message_version: Some(12),
+            vc_messages: record_messages(messages),
+            sampler: SamplerSettings::Greedy,
+            weight_set_id: Some(2),
+            category_constants: effective_constants(&stored, messages),
+            logit_temperature: 1.5,
+        }
+    }
+
+    #[test]
+    fn records_every_category_and_round_trips_through_json() {
+        let messages = [message(1, "Dosing"), message(2, "Safety")];
+        let vc: Vec<_> = messages.iter().map(|m| m.vc_message.clone()).collect();
+        let flow = GrammarFlow::new("Brand", &vc).unwrap();
+        let config = config(&messages, &flow);
+
+        assert_eq!(config.category_constants["Dosing"].kappa, 4.0);
+        assert_eq!(
+            config.category_constants["Safety"],
+            CategoryConstants::default()
+        );
+
+        let json = serde_json::to_string(&config).unwrap();
+        let back: RunConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.environment, config.environment);
+        assert_eq!(back.category_constants, config.category_constants);
+        let ids: Vec<_> = back.messages_with_ids().iter().map(|m| m.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(back.messages_with_ids()[1].vc_message.category, "Safety");
+    }
+
+    #[test]
+    fn mismatches_name_changed_settings_and_grammar() {
+        let messages = [message(1, "Dosing"), message(2, "Safety")];
+        let vc: Vec<_> = messages.iter().map(|m| m.vc_message.clone()).collect();
+        let flow = GrammarFlow::new("Brand", &vc).unwrap();
+        let config = config(&messages, &flow);
+
+        let mut moved = environment();
+        moved.model_path = "/elsewhere/a.gguf".to_string();
+        assert!(config.mismatches(&moved, &flow).is_empty());
+
+        let mut changed = environment();
+        changed.model_sha256 = "def".to_string();
+        changed.max_tokens = 100;
+        let other_flow = GrammarFlow::new("Other", &vc[..1]).unwrap();
+        let names: Vec<_> = config
+            .mismatches(&changed, &other_flow)
+            .iter()
+            .map(|m| m.split(':').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "model_sha256",
+                "max_tokens",
+                "system_prompt_sha256",
+                "lark_grammar"
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_without_embeddings_replay_on_any_embedding_setup() {
+        let messages = [message(1, "Dosing")];
+        let vc: Vec<_> = messages.iter().map(|m| m.vc_message.clone()).collect();
+        let flow = GrammarFlow::new("Brand", &vc).unwrap();
+        let mut config = config(&messages, &flow);
+
+        let mut without = environment();
+        without.embedding_model = None;
+        let names = |c: &RunConfig, env: &Environment| {
+            c.mismatches(env, &flow)
+                .iter()
+                .map(|m| m.split(':').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&config, &without), ["embedding_model"]);
+
+        config.environment.embedding_model = None;
+        assert!(names(&config, &without).is_empty());
+        assert!(names(&config, &environment()).is_empty());
+    }
+
+    #[test]
+    fn replays_the_recorded_system_prompt_template() {
+        let messages = [message(1, "Dosing")];
+        let vc: Vec<_> = messages.iter().map(|m| m.vc_message.clone()).collect();
+        let template = "Answer for {{ brand_name }}.\n{{ messages }}";
+        let flow = GrammarFlow::with_template("Brand", &vc, template).unwrap();
+        let mut config = config(&messages, &flow);
+        config.system_prompt_template = Some(template.to_string());
+
+        let replayed = config.grammar_flow().unwrap();
+        assert_eq!(replayed.system_prompt, flow.system_prompt);
+        assert!(config.mismatches(&environment(), &replayed).is_empty());
+    }
+}
@@ -5,23 +5,55 @@ use inference::InferenceEngine;
 use inference_types::{BulkTestEvent, InferenceEvent};
 use sqlx::{PgPool, SqlitePool};
 use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+use crate::embedding::CachedEmbeddings;
+use crate::margins::MarginSource;
+use crate::message_source::MessageSources;
+use crate::models::Models;
+use crate::routes::step::OpenStepSession;
 
 /// Shared application state threaded through every Axum handler.
 #[derive(Clone)]
 pub struct AppState {
     /// Inference engine (model loaded once at startup).
     pub engine: Arc<InferenceEngine>,
+    /// Models bulk tests can run on: `engine` with its file, whose hash goes
+    /// into run snapshots, and the other models experiment variants load.
+    pub models: Arc<Models>,
+    /// Embedding model for the margin heuristic (from `EMBEDDING_PROVIDER`),
+    /// behind the SQLite embedding cache. `None` runs inference without
+    /// embedding biases.
+    pub embeddings: Option<Arc<CachedEmbeddings>>,
+    /// Where embedding margins are computed (from `MARGIN_BACKEND`); `None`
+    /// when there are no example embeddings to compare against.
+    pub margins: Option<Arc<MarginSource>>,
     /// Brand name used in the system prompt (from `BRAND_NAME` env var).
     pub brand_name: String,
     /// SQLite pool — application-owned tables (inference sessions, tokens).
     /// Compile-time checked via `sqlx::query!` with `DATABASE_URL=sqlite:./app.db`.
     pub db: SqlitePool,
     /// Postgres pool — marketing VC database, read-only queries only.
-    pub vc_db: PgPool,
+    /// `None` when `VC_DATABASE_URL` is unset: agents come from local files,
+    /// and embedding biases and bulk tests are unavailable.
+    pub vc_db: Option<PgPool>,
+    /// Per-agent VC message sources (Postgres or local files, from `AGENT_SOURCES`).
+    pub messages: Arc<MessageSources>,
     /// In-memory map of session obfuscated_id → live receiver for that stream.
     /// Removed and owned by the SSE handler when the client connects.
     pub sessions: Arc<Mutex<HashMap<String, mpsc::Receiver<InferenceEvent>>>>,
     /// In-memory map of bulk_test_id → live receiver for that bulk test stream.
     /// Removed and owned by the SSE handler when the client connects.
     pub bulk_test_sessions: Arc<Mutex<HashMap<String, mpsc::Receiver<BulkTestEvent>>>>,
+    /// In-memory map of session obfuscated_id → cancellation token for its
+    /// generation. Removed when the stream ends or the client cancels it.
+    pub infer_cancellations: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    /// In-memory map of bulk_test_id → cancellation token for that run.
+    /// Removed when the run ends or the client cancels it.
+    pub bulk_test_cancellations: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    /// In-memory map of session obfuscated_id → live step-through session.
+    /// Removed when the session finishes, the client deletes it or it has
+    /// been idle for `STEP_SESSION_IDLE_SECS`, which drops the LLM context
+    /// held by the session thread.
+    pub step_sessions: Arc<Mutex<HashMap<String, OpenStepSession>>>,
 }
@@ -0,0 +1,12 @@
+-- Multi-turn conversations. Each turn is an inference_sessions row: `prompt`
+-- is the HCP message and `result_text` the VC response chosen by the grammar.
+CREATE TABLE IF NOT EXISTS conversations (
+    id            INTEGER PRIMARY KEY AUTOINCREMENT,
+    obfuscated_id TEXT    NOT NULL UNIQUE,
+    created_at    TEXT    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+-- NULL for sessions that are not part of a conversation (e.g. step-through).
+ALTER TABLE inference_sessions ADD COLUMN conversation_id INTEGER REFERENCES conversations(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS idx_sessions_conversation ON inference_sessions(conversation_id, id);
@@ -0,0 +1,6 @@
+-- inference_sessions.status gains 'cancelled' (set by DELETE /infer/{session_id}).
+
+-- running | complete | cancelled
+ALTER TABLE bulk_test_runs ADD COLUMN status TEXT NOT NULL DEFAULT 'running';
+
+UPDATE bulk_test_runs SET status = 'complete' WHERE completed_at IS NOT NULL;
@@ -0,0 +1,9 @@
+-- Embeddings already fetched from the configured provider, keyed by model and
+-- the normalized text that was embedded. `embedding` is little-endian f32s.
+CREATE TABLE IF NOT EXISTS embedding_cache (
+    model      TEXT NOT NULL,
+    text       TEXT NOT NULL,
+    embedding  BLOB NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (model, text)
+);
@@ -0,0 +1,21 @@
+-- Labelled HCP example sets imported from JSONL, an alternative to the
+-- vchcpexamplemessages tables in the marketing database for bulk tests.
+CREATE TABLE IF NOT EXISTS validation_sets (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    agent_id   INTEGER NOT NULL,
+    name       TEXT    NOT NULL,
+    created_at TEXT    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+-- correct_categories — JSON array of category names
+CREATE TABLE IF NOT EXISTS validation_examples (
+    id                 INTEGER PRIMARY KEY AUTOINCREMENT,
+    set_id             INTEGER NOT NULL REFERENCES validation_sets(id) ON DELETE CASCADE,
+    text               TEXT    NOT NULL,
+    correct_categories TEXT    NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_validation_examples_set ON validation_examples(set_id, id);
+
+-- NULL for runs against the marketing database's examples.
+ALTER TABLE bulk_test_runs ADD COLUMN validation_set_id INTEGER REFERENCES validation_sets(id) ON DELETE SET NULL;
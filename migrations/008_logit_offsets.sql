@@ -0,0 +1,9 @@
+-- Learned per-category additive prior, applied with kappa * margin. Stored
+-- per message like kappa; every message of a category gets the same value.
+ALTER TABLE vc_message_constants ADD COLUMN logit_offset REAL NOT NULL DEFAULT 0.0;
+
+-- Per-agent logit temperature learned alongside kappa and the offsets.
+CREATE TABLE IF NOT EXISTS agent_constants (
+    agent_id          INTEGER PRIMARY KEY,
+    logit_temperature REAL    NOT NULL DEFAULT 1.0
+);
@@ -0,0 +1,30 @@
+-- Versioned snapshots of an agent's kappa values, logit offsets and logit
+-- temperature. vc_message_constants and agent_constants keep holding the
+-- live values; activating a weight set copies its values there.
+CREATE TABLE IF NOT EXISTS weight_sets (
+    id                INTEGER PRIMARY KEY AUTOINCREMENT,
+    agent_id          INTEGER NOT NULL,
+    -- 1, 2, 3, … per agent
+    version           INTEGER NOT NULL,
+    name              TEXT    NOT NULL,
+    -- Bulk test run the weights were optimised on, if any.
+    source_run_id     INTEGER REFERENCES bulk_test_runs(id) ON DELETE SET NULL,
+    logit_temperature REAL    NOT NULL,
+    created_at        TEXT    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    UNIQUE (agent_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS weight_set_entries (
+    weight_set_id INTEGER NOT NULL REFERENCES weight_sets(id) ON DELETE CASCADE,
+    message_id    INTEGER NOT NULL,
+    category_name TEXT    NOT NULL,
+    kappa         REAL    NOT NULL,
+    logit_offset  REAL    NOT NULL,
+    PRIMARY KEY (weight_set_id, message_id)
+);
+
+-- NULL until the agent's first weight set is activated.
+ALTER TABLE agent_constants ADD COLUMN active_weight_set_id INTEGER REFERENCES weight_sets(id);
+
+-- The agent's active weight set when the run started.
+ALTER TABLE bulk_test_runs ADD COLUMN weight_set_id INTEGER REFERENCES weight_sets(id);
@@ -0,0 +1,36 @@
+-- Kappa and logit offset keyed by agent + category name, so they survive a
+-- new VC message version (which gives every message a new id).
+-- vc_message_constants is kept only until the server has carried its rows
+-- over; that needs the message → category mapping from the message sources,
+-- so it happens at startup rather than here.
+CREATE TABLE IF NOT EXISTS category_constants (
+    agent_id      INTEGER NOT NULL,
+    category_name TEXT    NOT NULL,
+    kappa         REAL    NOT NULL DEFAULT 10.0,
+    logit_offset  REAL    NOT NULL DEFAULT 0.0,
+    PRIMARY KEY (agent_id, category_name)
+);
+
+-- Weight set entries become one row per category. Every message of a
+-- category was saved with the same values; the lowest message id wins.
+CREATE TABLE weight_set_categories (
+    weight_set_id INTEGER NOT NULL REFERENCES weight_sets(id) ON DELETE CASCADE,
+    category_name TEXT    NOT NULL,
+    kappa         REAL    NOT NULL,
+    logit_offset  REAL    NOT NULL,
+    PRIMARY KEY (weight_set_id, category_name)
+);
+
+INSERT OR IGNORE INTO weight_set_categories (weight_set_id, category_name, kappa, logit_offset)
+SELECT weight_set_id, category_name, kappa, logit_offset
+FROM weight_set_entries
+ORDER BY weight_set_id, message_id;
+
+DROP TABLE weight_set_entries;
+ALTER TABLE weight_set_categories RENAME TO weight_set_entries;
+
+-- The active weight set of every agent is already live, so copy it over.
+INSERT OR IGNORE INTO category_constants (agent_id, category_name, kappa, logit_offset)
+SELECT a.agent_id, e.category_name, e.kappa, e.logit_offset
+FROM agent_constants a
+JOIN weight_set_entries e ON e.weight_set_id = a.active_weight_set_id;
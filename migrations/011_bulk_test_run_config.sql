@@ -0,0 +1,4 @@
+-- Everything a bulk test run was started with, as JSON (see
+-- server::run_config::RunConfig), so the run can be replayed. NULL for runs
+-- started before snapshots were recorded.
+ALTER TABLE bulk_test_runs ADD COLUMN config TEXT;
@@ -0,0 +1,18 @@
+-- A group of bulk test runs over the same examples, one per configuration
+-- variant, started by one POST /bulk-test.
+CREATE TABLE IF NOT EXISTS bulk_test_experiments (
+    id                INTEGER PRIMARY KEY AUTOINCREMENT,
+    agent_id          INTEGER NOT NULL,
+    validation_set_id INTEGER REFERENCES validation_sets(id) ON DELETE SET NULL,
+    -- JSON array of server::experiment::Variant, in run order
+    variants          TEXT    NOT NULL,
+    started_at        TEXT    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    completed_at      TEXT,
+    -- running | complete | cancelled | failed
+    status            TEXT    NOT NULL DEFAULT 'running'
+);
+
+-- Runs of an experiment, created as each variant starts.
+ALTER TABLE bulk_test_runs ADD COLUMN experiment_id INTEGER REFERENCES bulk_test_experiments(id) ON DELETE SET NULL;
+-- Position of the run's variant in bulk_test_experiments.variants.
+ALTER TABLE bulk_test_runs ADD COLUMN variant_index INTEGER;
@@ -0,0 +1,5 @@
+-- Deleting a validation set only marks it deleted, so runs and experiments
+-- over it keep its ID rather than having it cleared by ON DELETE SET NULL,
+-- which would make them look like runs over the marketing database's
+-- examples.
+ALTER TABLE validation_sets ADD COLUMN deleted_at TEXT;